@@ -1,27 +1,80 @@
-use std::{
-    collections::BTreeMap,
-    io::{self, Cursor},
-};
+use std::{cell::RefCell, collections::BTreeMap, io::Cursor, sync::Arc};
 
-use anyhow::{anyhow, bail};
+use anyhow::bail;
 use futures::{
     channel::mpsc::{self},
     select_biased,
-    stream::{repeat, SelectAll, SplitSink, SplitStream},
+    stream::{repeat, SelectAll, SplitSink},
     FutureExt, SinkExt, StreamExt,
 };
 use gloo::net::websocket::{futures::WebSocket, Message, WebSocketError};
 use gloo::timers::future::TimeoutFuture;
 use sqlsync::{
-    coordinator::CoordinatorDocument,
-    replication::{ReplicationMsg, ReplicationProtocol, ReplicationSource},
-    MemoryJournal, MemoryJournalFactory, WasmReducer,
+    coordinator::{CoordinatorDocument, CoordinatorMetrics, NoopMetrics},
+    persistence::FramePersistence,
+    replication::{Compression, Heartbeat, ReplicationMsg, ReplicationProtocol},
+    server::{ClientError, ClientId, ClientSink, CoordinatorServer, SendError},
+    unixtime::unix_timestamp_milliseconds,
+    MemoryJournal, MemoryJournalFactory, WasmModule, WasmReducer, WasmReducerConfig,
 };
-use worker::{console_error, console_log, Error, State};
+use worker::{console_error, console_log, wasm_bindgen_futures::spawn_local, Error, State};
 
 use crate::{object_id_to_journal_id, persistence::Persistence};
 
-type Document = CoordinatorDocument<MemoryJournal, WasmReducer>;
+type Server<P, M> = CoordinatorServer<MemoryJournal, WasmReducer, P, ClientOutbox, M>;
+
+// the maximum amount of time spent applying mutations in a single step, a
+// mutation which can't be applied within it is rejected
+const STEP_BUDGET_MS: i64 = 500;
+
+// every document's reducer shares the worker's memory, so cap each reducer
+// at 64MiB rather than letting one document take down the rest
+const REDUCER_MAX_MEMORY_PAGES: u32 = 1024;
+
+// a mutation which runs for more than roughly 100 million wasm instructions
+// is rejected, so that a reducer stuck in a loop can't wedge the document
+const REDUCER_FUEL_PER_MUTATION: u64 = 100_000_000;
+
+fn reducer_config() -> WasmReducerConfig {
+    WasmReducerConfig {
+        max_memory_pages: Some(REDUCER_MAX_MEMORY_PAGES),
+        fuel_per_mutation: Some(REDUCER_FUEL_PER_MUTATION),
+        ..Default::default()
+    }
+}
+
+thread_local! {
+    // compiled reducers by digest, shared by every document in this isolate
+    static REDUCERS: RefCell<BTreeMap<String, Arc<WasmModule>>> = RefCell::new(BTreeMap::new());
+}
+
+/// returns the compiled reducer with this digest, if it has been compiled
+pub fn cached_reducer(digest: &str) -> Option<Arc<WasmModule>> {
+    REDUCERS.with(|reducers| reducers.borrow().get(digest).cloned())
+}
+
+/// compile a reducer and cache it, so later documents only need to
+/// instantiate it
+pub fn compile_reducer(digest: &str, reducer_bytes: &[u8]) -> worker::Result<Arc<WasmModule>> {
+    let module = WasmReducer::compile(reducer_bytes, &reducer_config())
+        .map_err(|e| Error::RustError(e.to_string()))?;
+    REDUCERS.with(|reducers| {
+        reducers
+            .borrow_mut()
+            .insert(digest.to_owned(), module.clone())
+    });
+    Ok(module)
+}
+
+// ping clients after 10s of silence, and drop them if they don't respond
+// within another 10s
+const HEARTBEAT: Heartbeat = Heartbeat { interval_ms: 10_000, timeout_ms: 10_000 };
+const HEARTBEAT_TICK_MS: u32 = 1000;
+
+// the number of messages buffered for each client, which must fit a full
+// window of frames. clients which fall further behind than this are dropped
+// rather than stalling every other client
+const CLIENT_OUTBOX_DEPTH: usize = 128;
 
 pub struct Coordinator {
     accept_queue: mpsc::Sender<WebSocket>,
@@ -30,7 +83,7 @@ pub struct Coordinator {
 impl Coordinator {
     pub async fn init(
         state: &State,
-        reducer_bytes: Vec<u8>,
+        reducer: Arc<WasmModule>,
     ) -> worker::Result<(Coordinator, CoordinatorTask)> {
         let id = object_id_to_journal_id(state.id())?;
         let (accept_queue_tx, accept_queue_rx) = mpsc::channel(10);
@@ -47,17 +100,20 @@ impl Coordinator {
         let doc = CoordinatorDocument::open(
             storage,
             MemoryJournalFactory,
-            WasmReducer::new(reducer_bytes.as_slice())
+            WasmReducer::from_module_with_config(reducer, reducer_config())
                 .map_err(|e| Error::RustError(e.to_string()))?,
         )
         .map_err(|e| Error::RustError(e.to_string()))?;
 
+        let protocol = ReplicationProtocol::new()
+            .with_compression(Compression::Lz4)
+            .with_heartbeat(HEARTBEAT);
+
         Ok((
             Self { accept_queue: accept_queue_tx },
             CoordinatorTask {
                 accept_queue: accept_queue_rx,
-                persistence,
-                doc,
+                server: CoordinatorServer::new(doc, persistence).with_protocol(protocol),
             },
         ))
     }
@@ -67,21 +123,32 @@ impl Coordinator {
     }
 }
 
-pub struct CoordinatorTask {
+/// CoordinatorTask connects websocket clients to a CoordinatorServer, which
+/// persists the document's storage frames to any FramePersistence
+/// implementation and reports events to metrics
+pub struct CoordinatorTask<P = Persistence, M = NoopMetrics> {
     accept_queue: mpsc::Receiver<WebSocket>,
-    persistence: Persistence,
-    doc: Document,
+    server: Server<P, M>,
 }
 
-impl CoordinatorTask {
+impl<P, M> CoordinatorTask<P, M> {
+    /// report events from this task to metrics
+    pub fn with_metrics<N: CoordinatorMetrics>(self, metrics: N) -> CoordinatorTask<P, N> {
+        CoordinatorTask {
+            accept_queue: self.accept_queue,
+            server: self.server.with_metrics(metrics),
+        }
+    }
+}
+
+impl<P: FramePersistence, M: CoordinatorMetrics> CoordinatorTask<P, M> {
     // into_task consumes the Coordinator and runs it as a task
     pub async fn into_task(mut self) {
-        let mut clients: BTreeMap<usize, Client> = BTreeMap::new();
         let mut messages = SelectAll::new();
-        let mut next_client_idx = 0;
 
         const STEP_MIN_MS: u32 = 100;
         let mut step_trigger = TimeoutFuture::new(STEP_MIN_MS).fuse();
+        let mut heartbeat_tick = TimeoutFuture::new(HEARTBEAT_TICK_MS).fuse();
 
         // NOTE TO CODE REVIEWERS:
         // `select_biased!` is full of foot guns (see: [1] and [2])
@@ -101,54 +168,65 @@ impl CoordinatorTask {
                 // handle steps
                 _ = step_trigger => {
                     // apply any pending changes to the document
-                    if let Err(e) = self.step().await {
+                    let result = self.server.step(STEP_BUDGET_MS);
+
+                    // pick up where we left off if we ran out of time
+                    if self.server.has_pending_work() {
+                        step_trigger = TimeoutFuture::new(STEP_MIN_MS).fuse();
+                    }
+
+                    if let Err(e) = result {
                         console_error!("error stepping: {:?}", e);
                         continue;
                     }
 
+                    // drop outputs which clients have already received
+                    if let Err(e) = self.server.prune_outputs() {
+                        console_error!("error pruning outputs: {:?}", e);
+                    }
+
                     // persist document state to storage
-                    if let Err(e) = self.persist().await {
+                    if let Err(e) = self.server.persist().await {
                         console_error!("error persisting: {:?}", e);
                         continue;
                     }
 
-                    // sync all clients
-                    for (_, client) in clients.iter_mut() {
-                        if let Err(e) = client.sync(&self.doc).await {
-                            console_error!("error syncing: {:?}", e);
-                            continue;
-                        }
+                    // drop frames which are no longer needed from memory
+                    if let Err(e) = self.server.truncate() {
+                        console_error!("error truncating storage: {:?}", e);
                     }
+
+                    // tell clients about any mutations the document rejected,
+                    // and sync all clients
+                    log_dropped(self.server.send_rejections());
+                    log_dropped(self.server.sync());
+                },
+
+                // ping idle clients, and drop clients which stopped responding
+                _ = heartbeat_tick => {
+                    heartbeat_tick = TimeoutFuture::new(HEARTBEAT_TICK_MS).fuse();
+                    log_dropped(self.server.tick(unix_timestamp_milliseconds()));
                 },
 
                 // handle new clients
                 socket = self.accept_queue.select_next_some() => {
-                    let (mut client, reader) = Client::init(socket);
-                    if let Err(e) = client.start_replication(&self.doc).await {
-                        console_error!("error starting replication: {:?}", e);
-                        continue;
+                    let (writer, reader) = socket.split();
+                    let (outbox, outbox_rx) = mpsc::channel(CLIENT_OUTBOX_DEPTH);
+                    spawn_local(write_outbox(writer, outbox_rx));
+                    match self.server.connect(ClientOutbox(outbox)) {
+                        Ok(client_id) => messages.push(repeat(client_id).zip(reader)),
+                        Err(e) => console_error!("error starting replication: {:?}", e),
                     }
-                    next_client_idx += 1;
-                    let client_idx = next_client_idx;
-                    clients.insert(client_idx, client);
-                    messages.push(repeat(client_idx).zip(reader));
                 },
 
                 // handle messages from clients
-                (client_idx, msg) = messages.select_next_some() => {
-                    let client = match clients.get_mut(&client_idx) {
-                        Some(client ) => client,
-                        None => {
-                            console_error!("received message from unknown client {}", client_idx);
-                            continue;
-                        }
-                    };
-                    if let Err(e) = client.handle_message(&mut self.doc, msg).await {
-                        console_error!("error handling message from client {}: {:?}", client_idx, e);
+                (client_id, msg) = messages.select_next_some() => {
+                    if let Err(e) = self.handle_message(client_id, msg) {
+                        console_error!("error handling message from client {}: {:?}", client_id, e);
                         // remove client; note, we don't have to remove the
                         // reader from messages because SelectAll handles that
                         // automatically
-                        clients.remove(&client_idx);
+                        self.server.disconnect(client_id);
                     } else {
                         // schedule a step whenever we receive messages from a client
                         step_trigger = TimeoutFuture::new(STEP_MIN_MS).fuse();
@@ -158,66 +236,9 @@ impl CoordinatorTask {
         }
     }
 
-    async fn step(&mut self) -> anyhow::Result<()> {
-        while self.doc.has_pending_work() {
-            self.doc.step()?;
-        }
-
-        Ok(())
-    }
-
-    async fn persist(&mut self) -> anyhow::Result<()> {
-        let mut next_lsn = self.persistence.expected_lsn();
-        while let Some(frame) = self.doc.read_lsn(next_lsn)? {
-            self.persistence
-                .write_lsn(next_lsn, frame.to_owned())
-                .await
-                .map_err(|e| anyhow!(e.to_string()))?;
-            next_lsn = self.persistence.expected_lsn();
-        }
-
-        Ok(())
-    }
-}
-
-struct Client {
-    protocol: ReplicationProtocol,
-    writer: SplitSink<WebSocket, Message>,
-}
-
-impl Client {
-    fn init(socket: WebSocket) -> (Self, SplitStream<WebSocket>) {
-        let (writer, reader) = socket.split();
-        let protocol = ReplicationProtocol::new();
-        (Self { protocol, writer }, reader)
-    }
-
-    async fn start_replication(&mut self, doc: &Document) -> anyhow::Result<()> {
-        let msg = self.protocol.start(doc);
-        self.send_msg(msg).await
-    }
-
-    async fn sync(&mut self, doc: &Document) -> anyhow::Result<()> {
-        while let Some((msg, mut frame)) = self.protocol.sync(doc)? {
-            console_log!("sending message {:?}", msg);
-            let mut buf = Cursor::new(vec![]);
-            bincode::serialize_into(&mut buf, &msg)?;
-            io::copy(&mut frame, &mut buf)?;
-            self.writer.send(Message::Bytes(buf.into_inner())).await?;
-        }
-
-        Ok(())
-    }
-
-    async fn send_msg(&mut self, msg: ReplicationMsg) -> anyhow::Result<()> {
-        let data = bincode::serialize(&msg)?;
-        console_log!("sending message {:?}", msg);
-        Ok(self.writer.send(Message::Bytes(data)).await?)
-    }
-
-    async fn handle_message(
+    fn handle_message(
         &mut self,
-        doc: &mut Document,
+        client_id: ClientId,
         msg: Result<Message, WebSocketError>,
     ) -> anyhow::Result<()> {
         match msg {
@@ -225,10 +246,7 @@ impl Client {
                 let mut cursor = Cursor::new(bytes);
                 let msg: ReplicationMsg = bincode::deserialize_from(&mut cursor)?;
                 console_log!("received message {:?}", msg);
-                if let Some(resp) = self.protocol.handle(doc, msg, &mut cursor)? {
-                    self.send_msg(resp).await?;
-                }
-                Ok(())
+                Ok(self.server.handle(client_id, msg, &mut cursor)?)
             }
 
             Ok(Message::Text(_)) => {
@@ -241,3 +259,46 @@ impl Client {
         }
     }
 }
+
+fn log_dropped(dropped: Vec<(ClientId, ClientError)>) {
+    for (client_id, e) in dropped {
+        console_error!("dropped client {}: {:?}", client_id, e);
+    }
+}
+
+/// ClientOutbox queues a client's messages for write_outbox
+struct ClientOutbox(mpsc::Sender<Message>);
+
+impl ClientSink for ClientOutbox {
+    fn try_send(&mut self, msg: ReplicationMsg, frame: &[u8]) -> Result<(), SendError> {
+        console_log!("sending message {:?}", msg);
+        let mut data = bincode::serialize(&msg).expect("replication messages always serialize");
+        data.extend_from_slice(frame);
+        self.0.try_send(Message::Bytes(data)).map_err(|e| {
+            if e.is_full() {
+                SendError::Full
+            } else {
+                SendError::Disconnected
+            }
+        })
+    }
+}
+
+/// write queued messages to the socket until the client is dropped, which
+/// lets a slow socket back up without blocking the coordinator
+async fn write_outbox(
+    mut writer: SplitSink<WebSocket, Message>,
+    mut outbox: mpsc::Receiver<Message>,
+) {
+    while let Some(msg) = outbox.next().await {
+        if let Err(e) = writer.send(msg).await {
+            console_error!("error writing to client: {:?}", e);
+            return;
+        }
+    }
+
+    // the client was dropped, close the socket so that it reconnects
+    if let Err(e) = writer.close().await {
+        console_error!("error closing client socket: {:?}", e);
+    }
+}
@@ -1,4 +1,4 @@
-use coordinator::Coordinator;
+use coordinator::{cached_reducer, compile_reducer, Coordinator};
 use js_sys::{ArrayBuffer, Reflect, Uint8Array};
 use sqlsync::JournalId;
 use wasm_bindgen::JsCast;
@@ -40,28 +40,36 @@ impl DurableObject for DocumentCoordinator {
                 Some((_, v)) => v,
                 None => return Response::error("Bad Request", 400),
             };
-            let bucket = self.env.bucket(REDUCER_BUCKET)?;
-            let object = bucket
-                .get(format!("{}.wasm", reducer_digest))
-                .execute()
-                .await?;
-            let reducer_bytes = match object {
-                Some(object) => {
-                    object
-                        .body()
-                        .ok_or_else(|| Error::RustError("reducer not found in bucket".to_string()))?
-                        .bytes()
-                        .await?
-                }
+            let reducer = match cached_reducer(&reducer_digest) {
+                Some(reducer) => reducer,
                 None => {
-                    return Response::error(
-                        format!("reducer {} not found in bucket", reducer_digest),
-                        404,
-                    )
+                    let bucket = self.env.bucket(REDUCER_BUCKET)?;
+                    let object = bucket
+                        .get(format!("{}.wasm", reducer_digest))
+                        .execute()
+                        .await?;
+                    let reducer_bytes = match object {
+                        Some(object) => {
+                            object
+                                .body()
+                                .ok_or_else(|| {
+                                    Error::RustError("reducer not found in bucket".to_string())
+                                })?
+                                .bytes()
+                                .await?
+                        }
+                        None => {
+                            return Response::error(
+                                format!("reducer {} not found in bucket", reducer_digest),
+                                404,
+                            )
+                        }
+                    };
+                    compile_reducer(&reducer_digest, &reducer_bytes)?
                 }
             };
 
-            let (coordinator, task) = Coordinator::init(&self.state, reducer_bytes).await?;
+            let (coordinator, task) = Coordinator::init(&self.state, reducer).await?;
             spawn_local(task.into_task());
             self.coordinator = Some(coordinator);
         }
@@ -162,7 +170,11 @@ async fn main(req: Request, env: Env, _ctx: Context) -> Result<Response> {
             if let Some(id) = ctx.param("id") {
                 console_log!("forwarding request to document with id: {}", id);
                 let namespace = ctx.durable_object(DURABLE_OBJECT_NAME)?;
-                let id = JournalId::from_base58(id).map_err(|e| Error::RustError(e.to_string()))?;
+                // document ids are durable object ids, which are 256 bits
+                let id = match JournalId::from_base58_checked(id, 256) {
+                    Ok(id) => id,
+                    Err(e) => return Response::error(format!("Invalid document id: {}", e), 400),
+                };
                 let id = match namespace.id_from_string(&id.to_hex()) {
                     Ok(id) => id,
                     Err(e) => {
@@ -1,7 +1,12 @@
 use std::io::Cursor;
 
 use js_sys::Uint8Array;
-use sqlsync::{replication::ReplicationDestination, JournalId, Lsn, LsnRange};
+use sqlsync::{
+    frame_checksum,
+    persistence::FramePersistence,
+    replication::{replay_frames, ReplicationDestination},
+    JournalId, Lsn, LsnRange,
+};
 use wasm_bindgen::JsValue;
 use worker::*;
 
@@ -26,12 +31,41 @@ impl Persistence {
         Ok(Self { range, storage })
     }
 
-    /// the next lsn that should be written to storage
-    pub fn expected_lsn(&self) -> Lsn {
-        self.range.next()
+    /// replay every persisted frame into dest
+    pub async fn replay<T: ReplicationDestination>(
+        &self,
+        id: JournalId,
+        dest: &mut T,
+    ) -> Result<()> {
+        let frames = self.read_range(self.range).await?;
+        replay_frames(
+            dest,
+            id,
+            frames
+                .into_iter()
+                .map(|(lsn, frame)| (lsn, Cursor::new(frame))),
+        )
+        .map_err(|e| Error::RustError(e.to_string()))?;
+        Ok(())
     }
+}
+
+impl FramePersistence for Persistence {
+    type Error = Error;
+
+    fn range(&self) -> LsnRange {
+        self.range
+    }
+
+    async fn write_lsn(&mut self, lsn: Lsn, frame: Vec<u8>) -> Result<()> {
+        if lsn != self.expected_lsn() {
+            return Err(Error::RustError(format!(
+                "expected to persist lsn {} but received lsn {}",
+                self.expected_lsn(),
+                lsn
+            )));
+        }
 
-    pub async fn write_lsn(&mut self, lsn: Lsn, frame: Vec<u8>) -> Result<()> {
         let obj = js_sys::Object::new();
 
         // get the new range, assuming the write goes through
@@ -48,6 +82,14 @@ impl Persistence {
         let key = format!("lsn-{}", lsn);
         js_sys::Reflect::set(&obj, &JsValue::from_str(&key), &uint8_array)?;
 
+        // store the frame's checksum alongside it so corruption is caught on replay
+        let key = format!("crc-{}", lsn);
+        js_sys::Reflect::set(
+            &obj,
+            &JsValue::from_str(&key),
+            &JsValue::from(frame_checksum(&frame)),
+        )?;
+
         // write to storage
         self.storage.put_multiple_raw(obj).await?;
 
@@ -56,18 +98,26 @@ impl Persistence {
         Ok(())
     }
 
-    pub async fn replay<T: ReplicationDestination>(
-        &self,
-        id: JournalId,
-        dest: &mut T,
-    ) -> Result<()> {
-        for lsn in 0..self.range.next() {
-            console_log!("replaying lsn {}", lsn);
+    async fn read_range(&self, range: LsnRange) -> Result<Vec<(Lsn, Vec<u8>)>> {
+        let mut frames = Vec::new();
+        for lsn in self.range.intersect(&range).iter() {
+            console_log!("reading lsn {}", lsn);
             let key = format!("lsn-{}", lsn);
-            let mut frame = Cursor::new(self.storage.get::<serde_bytes::ByteBuf>(&key).await?);
-            dest.write_lsn(id, lsn, &mut frame)
-                .map_err(|e| Error::RustError(e.to_string()))?;
+            let frame = self.storage.get::<serde_bytes::ByteBuf>(&key).await?;
+
+            // frames persisted before checksums were introduced have none
+            let key = format!("crc-{}", lsn);
+            if let Ok(crc) = self.storage.get::<u32>(&key).await {
+                if crc != frame_checksum(&frame) {
+                    return Err(Error::RustError(format!(
+                        "persisted frame at lsn {} does not match its checksum",
+                        lsn
+                    )));
+                }
+            }
+
+            frames.push((lsn, frame.into_vec()));
         }
-        Ok(())
+        Ok(frames)
     }
 }
@@ -36,9 +36,9 @@ async fn reducer(mutation: Vec<u8>) -> Result<(), ReducerError> {
             log::debug!("appending task({}): {}", id, description);
             execute!(
                 "insert into tasks (id, description, completed, created_at)
-                    values (?, ?, false, datetime('now'))",
-                id,
-                description
+                    values (:id, :description, false, datetime('now'))",
+                ":id" => id,
+                ":description" => description
             )
             .await?;
         }
@@ -4,7 +4,7 @@
 
 use std::cell::Cell;
 use std::ffi::{c_void, CStr, CString};
-use std::mem::{size_of, ManuallyDrop, MaybeUninit};
+use std::mem::{size_of, MaybeUninit};
 use std::os::raw::{c_char, c_int};
 use std::ptr::null_mut;
 use std::rc::Rc;
@@ -71,6 +71,31 @@ pub trait File {
         // information is written to disk in the same order as calls to xWrite()
         ffi::SQLITE_IOCAP_SEQUENTIAL
     }
+
+    /// Acquire or upgrade to the lock `level`. Returns `false` if the lock is held by another
+    /// handle, in which case SQLite will report SQLITE_BUSY. The default implementation performs
+    /// no locking.
+    ///
+    /// int (*xLock)(sqlite3_file*, int);
+    #[allow(unused_variables)]
+    fn lock(&mut self, level: LockLevel) -> VfsResult<bool> {
+        Ok(true)
+    }
+
+    /// Downgrade to the lock `level`, which is either [LockLevel::Shared] or [LockLevel::None].
+    ///
+    /// int (*xUnlock)(sqlite3_file*, int);
+    #[allow(unused_variables)]
+    fn unlock(&mut self, level: LockLevel) -> VfsResult<()> {
+        Ok(())
+    }
+
+    /// Check if another handle holds a [LockLevel::Reserved] or higher lock on the file.
+    ///
+    /// int (*xCheckReservedLock)(sqlite3_file*, int *pResOut);
+    fn reserved(&self) -> VfsResult<bool> {
+        Ok(false)
+    }
 }
 
 /// Allow boxing files, so you can easily return different optimized impls depending on OpenKind
@@ -104,6 +129,18 @@ impl File for Box<dyn File> {
     fn sync(&mut self) -> VfsResult<()> {
         self.as_mut().sync()
     }
+
+    fn lock(&mut self, level: LockLevel) -> VfsResult<bool> {
+        self.as_mut().lock(level)
+    }
+
+    fn unlock(&mut self, level: LockLevel) -> VfsResult<()> {
+        self.as_mut().unlock(level)
+    }
+
+    fn reserved(&self) -> VfsResult<bool> {
+        self.as_ref().reserved()
+    }
 }
 
 /// Allow File to be an unsafe pointer
@@ -149,6 +186,18 @@ impl<T: File> File for FilePtr<T> {
     fn sync(&mut self) -> VfsResult<()> {
         unsafe { (*self.0).sync() }
     }
+
+    fn lock(&mut self, level: LockLevel) -> VfsResult<bool> {
+        unsafe { (*self.0).lock(level) }
+    }
+
+    fn unlock(&mut self, level: LockLevel) -> VfsResult<()> {
+        unsafe { (*self.0).unlock(level) }
+    }
+
+    fn reserved(&self) -> VfsResult<bool> {
+        unsafe { (*self.0).reserved() }
+    }
 }
 
 /// A sqlite vfs
@@ -257,15 +306,95 @@ pub enum OpenAccess {
     CreateNew,
 }
 
+/// The level of a file lock, in increasing order of exclusivity.
+///
+/// See https://sqlite.org/c3ref/c_lock_exclusive.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LockLevel {
+    /// No locks are held.
+    None,
+
+    /// The file may be read but not written.
+    Shared,
+
+    /// The handle intends to write to the file, other handles may continue to read.
+    Reserved,
+
+    /// The handle is waiting for readers to finish, new shared locks are refused.
+    Pending,
+
+    /// The handle may write to the file, no other locks are held.
+    Exclusive,
+}
+
 struct State<V> {
     vfs: V,
     io_methods: ffi::sqlite3_io_methods,
     last_error: Rc<Cell<Option<VfsError>>>,
 }
 
+/// A virtual file system registered with SQLite by [register].
+///
+/// Dropping the handle unregisters the vfs and frees it, so the handle must outlive every
+/// connection opened with the vfs.
+pub struct VfsHandle {
+    // sqlite refers to the name for as long as the vfs is registered
+    name: CString,
+    vfs: *mut ffi::sqlite3_vfs,
+    free_state: unsafe fn(*mut c_void),
+}
+
+impl VfsHandle {
+    /// The name the vfs is registered under.
+    pub fn name(&self) -> &str {
+        self.name.to_str().expect("vfs name is valid utf-8")
+    }
+
+    /// Unregister the vfs from SQLite and free it.
+    pub fn unregister(mut self) -> Result<(), VfsError> {
+        unsafe { self.unregister_inner() }
+    }
+
+    unsafe fn unregister_inner(&mut self) -> Result<(), VfsError> {
+        if self.vfs.is_null() {
+            return Ok(());
+        }
+
+        let result = ffi::sqlite3_vfs_unregister(self.vfs);
+        if result != ffi::SQLITE_OK {
+            // sqlite may still refer to the vfs, so it's not safe to free it
+            return Err(result);
+        }
+
+        let vfs = Box::from_raw(self.vfs);
+        (self.free_state)(vfs.pAppData);
+        self.vfs = null_mut();
+        Ok(())
+    }
+}
+
+// SAFETY: the handle only touches the vfs to free it once unregistered. while
+// registered, sqlite calls into the vfs from whichever thread is using one of
+// its connections, which the caller is already responsible for.
+unsafe impl Send for VfsHandle {}
+
+impl Drop for VfsHandle {
+    fn drop(&mut self) {
+        if let Err(err) = unsafe { self.unregister_inner() } {
+            log::warn!("failed to unregister vfs {:?}: {}", self.name, err);
+        }
+    }
+}
+
+unsafe fn free_state<V>(ptr: *mut c_void) {
+    drop(Box::from_raw(ptr as *mut State<V>));
+}
+
 /// Register a virtual file system ([Vfs]) to SQLite.
-pub fn register<F: File, V: Vfs<File = F>>(name: &str, vfs: V) -> Result<(), RegisterError> {
-    let name = ManuallyDrop::new(CString::new(name)?);
+///
+/// The vfs remains registered until the returned [VfsHandle] is dropped.
+pub fn register<F: File, V: Vfs<File = F>>(name: &str, vfs: V) -> Result<VfsHandle, RegisterError> {
+    let name = CString::new(name)?;
     let io_methods = ffi::sqlite3_io_methods {
         iVersion: 3,
         xClose: Some(io::close::<F>),
@@ -320,12 +449,14 @@ pub fn register<F: File, V: Vfs<File = F>>(name: &str, vfs: V) -> Result<(), Reg
 
     let result = unsafe { ffi::sqlite3_vfs_register(vfs, false as i32) };
     if result != ffi::SQLITE_OK {
+        unsafe {
+            drop(Box::from_raw(vfs));
+            free_state::<V>(ptr as _);
+        }
         return Err(RegisterError::Register(result));
     }
 
-    // TODO: return object that allows to unregister (and cleanup the memory)?
-
-    Ok(())
+    Ok(VfsHandle { name, vfs, free_state: free_state::<V> })
 }
 
 // TODO: add to [Vfs]?
@@ -755,33 +886,56 @@ mod io {
     }
 
     /// Lock a file.
-    pub unsafe extern "C" fn lock<F>(p_file: *mut ffi::sqlite3_file, _e_lock: c_int) -> c_int {
-        log::trace!("lock");
+    pub unsafe extern "C" fn lock<F: File>(p_file: *mut ffi::sqlite3_file, e_lock: c_int) -> c_int {
+        log::trace!("lock level={}", e_lock);
 
-        // reset last error
-        if file_state::<F>(p_file, true).is_err() {
-            return ffi::SQLITE_IOERR_LOCK;
-        }
+        let state = match file_state::<F>(p_file, true) {
+            Ok(f) => f,
+            Err(_) => return ffi::SQLITE_IOERR_LOCK,
+        };
 
-        // TODO: implement locking
-        ffi::SQLITE_OK
+        let level = match LockLevel::from_raw(e_lock) {
+            Some(level) => level,
+            None => return ffi::SQLITE_IOERR_LOCK,
+        };
+
+        match state.file.lock(level) {
+            Ok(true) => ffi::SQLITE_OK,
+            Ok(false) => ffi::SQLITE_BUSY,
+            Err(err) => {
+                state.set_last_error(err);
+                ffi::SQLITE_IOERR_LOCK
+            }
+        }
     }
 
     /// Unlock a file.
-    pub unsafe extern "C" fn unlock<F>(p_file: *mut ffi::sqlite3_file, _e_lock: c_int) -> c_int {
-        log::trace!("unlock");
+    pub unsafe extern "C" fn unlock<F: File>(
+        p_file: *mut ffi::sqlite3_file,
+        e_lock: c_int,
+    ) -> c_int {
+        log::trace!("unlock level={}", e_lock);
 
-        // reset last error
-        if file_state::<F>(p_file, true).is_err() {
+        let state = match file_state::<F>(p_file, true) {
+            Ok(f) => f,
+            Err(_) => return ffi::SQLITE_IOERR_UNLOCK,
+        };
+
+        let level = match LockLevel::from_raw(e_lock) {
+            Some(level) => level,
+            None => return ffi::SQLITE_IOERR_UNLOCK,
+        };
+
+        if let Err(err) = state.file.unlock(level) {
+            state.set_last_error(err);
             return ffi::SQLITE_IOERR_UNLOCK;
         }
 
-        // TODO: implement locking
         ffi::SQLITE_OK
     }
 
     /// Check if another file-handle holds a RESERVED lock on a file.
-    pub unsafe extern "C" fn check_reserved_lock<F>(
+    pub unsafe extern "C" fn check_reserved_lock<F: File>(
         p_file: *mut ffi::sqlite3_file,
         p_res_out: *mut c_int,
     ) -> c_int {
@@ -792,17 +946,15 @@ mod io {
             Err(_) => return ffi::SQLITE_IOERR_CHECKRESERVEDLOCK,
         };
 
-        match p_res_out.as_mut() {
-            Some(p_res_out) => {
-                *p_res_out = false as i32;
-            }
-            None => {
-                state.set_last_error(null_ptr_error());
-                return ffi::SQLITE_IOERR_CHECKRESERVEDLOCK;
-            }
+        if let Err(err) = state.file.reserved().and_then(|reserved| {
+            let p_res_out: &mut c_int = p_res_out.as_mut().ok_or_else(null_ptr_error)?;
+            *p_res_out = reserved as i32;
+            Ok(())
+        }) {
+            state.set_last_error(err);
+            return ffi::SQLITE_IOERR_CHECKRESERVEDLOCK;
         }
 
-        // TODO: implement locking
         ffi::SQLITE_OK
     }
 
@@ -972,6 +1124,19 @@ unsafe fn file_state<'a, F>(
     Ok(ext)
 }
 
+impl LockLevel {
+    fn from_raw(lock: c_int) -> Option<Self> {
+        match lock {
+            ffi::SQLITE_LOCK_NONE => Some(Self::None),
+            ffi::SQLITE_LOCK_SHARED => Some(Self::Shared),
+            ffi::SQLITE_LOCK_RESERVED => Some(Self::Reserved),
+            ffi::SQLITE_LOCK_PENDING => Some(Self::Pending),
+            ffi::SQLITE_LOCK_EXCLUSIVE => Some(Self::Exclusive),
+            _ => None,
+        }
+    }
+}
+
 impl OpenOptions {
     fn from_flags(flags: i32) -> Option<Self> {
         Some(OpenOptions {
@@ -1046,3 +1211,219 @@ impl From<std::ffi::NulError> for RegisterError {
         Self::Nul(err)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+
+    use rusqlite::{Connection, ErrorCode, OpenFlags};
+
+    use super::*;
+
+    /// the contents of a file along with the lock held by each open handle
+    #[derive(Default)]
+    struct MemEntry {
+        data: Vec<u8>,
+        locks: HashMap<usize, LockLevel>,
+    }
+
+    #[derive(Default, Clone)]
+    struct MemVfs {
+        files: Rc<RefCell<HashMap<CString, Rc<RefCell<MemEntry>>>>>,
+        next_handle: Rc<Cell<usize>>,
+    }
+
+    struct MemFile {
+        handle: usize,
+        entry: Rc<RefCell<MemEntry>>,
+    }
+
+    impl Vfs for MemVfs {
+        type File = MemFile;
+
+        fn open(&mut self, path: &CStr, _opts: OpenOptions) -> VfsResult<Self::File> {
+            let entry = self
+                .files
+                .borrow_mut()
+                .entry(path.into())
+                .or_default()
+                .clone();
+            let handle = self.next_handle.get();
+            self.next_handle.set(handle + 1);
+            Ok(MemFile { handle, entry })
+        }
+
+        fn delete(&mut self, path: &CStr) -> VfsResult<()> {
+            self.files.borrow_mut().remove(path);
+            Ok(())
+        }
+
+        fn exists(&mut self, path: &CStr) -> VfsResult<bool> {
+            Ok(self.files.borrow().contains_key(path))
+        }
+    }
+
+    impl MemFile {
+        /// the highest lock held by any other handle
+        fn others(&self) -> LockLevel {
+            let entry = self.entry.borrow();
+            let others = entry
+                .locks
+                .iter()
+                .filter(|(handle, _)| **handle != self.handle);
+            others
+                .map(|(_, level)| *level)
+                .max()
+                .unwrap_or(LockLevel::None)
+        }
+    }
+
+    impl File for MemFile {
+        fn file_size(&self) -> VfsResult<u64> {
+            Ok(self.entry.borrow().data.len() as u64)
+        }
+
+        fn truncate(&mut self, size: u64) -> VfsResult<()> {
+            self.entry.borrow_mut().data.truncate(size as usize);
+            Ok(())
+        }
+
+        fn write(&mut self, pos: u64, buf: &[u8]) -> VfsResult<usize> {
+            let data = &mut self.entry.borrow_mut().data;
+            let (start, end) = (pos as usize, pos as usize + buf.len());
+            if data.len() < end {
+                data.resize(end, 0);
+            }
+            data[start..end].copy_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn read(&mut self, pos: u64, buf: &mut [u8]) -> VfsResult<usize> {
+            let data = &self.entry.borrow().data;
+            let start = (pos as usize).min(data.len());
+            let end = (pos as usize + buf.len()).min(data.len());
+            buf.fill(0);
+            buf[..end - start].copy_from_slice(&data[start..end]);
+            Ok(end - start)
+        }
+
+        fn sync(&mut self) -> VfsResult<()> {
+            Ok(())
+        }
+
+        fn lock(&mut self, level: LockLevel) -> VfsResult<bool> {
+            let others = self.others();
+            let ok = match level {
+                LockLevel::None => true,
+                LockLevel::Shared => others < LockLevel::Pending,
+                LockLevel::Reserved | LockLevel::Pending => others < LockLevel::Reserved,
+                LockLevel::Exclusive => others == LockLevel::None,
+            };
+            if ok {
+                self.entry.borrow_mut().locks.insert(self.handle, level);
+            }
+            Ok(ok)
+        }
+
+        fn unlock(&mut self, level: LockLevel) -> VfsResult<()> {
+            self.entry.borrow_mut().locks.insert(self.handle, level);
+            Ok(())
+        }
+
+        fn reserved(&self) -> VfsResult<bool> {
+            Ok(self.others() >= LockLevel::Reserved)
+        }
+    }
+
+    impl Drop for MemFile {
+        fn drop(&mut self) {
+            self.entry.borrow_mut().locks.remove(&self.handle);
+        }
+    }
+
+    #[test]
+    fn test_reserved_blocks_exclusive() -> VfsResult<()> {
+        let mut vfs = MemVfs::default();
+        let path = CString::new("main.db").unwrap();
+        let opts = OpenOptions {
+            kind: OpenKind::MainDb,
+            access: OpenAccess::Create,
+            delete_on_close: false,
+        };
+        let mut a = vfs.open(&path, opts.clone())?;
+        let mut b = vfs.open(&path, opts)?;
+
+        assert!(a.lock(LockLevel::Shared)?);
+        assert!(a.lock(LockLevel::Reserved)?);
+        assert!(b.lock(LockLevel::Shared)?);
+        assert!(b.reserved()?);
+        assert!(!b.lock(LockLevel::Exclusive)?);
+
+        // a shared lock still blocks exclusive access
+        a.unlock(LockLevel::Shared)?;
+        assert!(!b.reserved()?);
+        assert!(!b.lock(LockLevel::Exclusive)?);
+
+        a.unlock(LockLevel::None)?;
+        assert!(b.lock(LockLevel::Exclusive)?);
+        assert!(!a.lock(LockLevel::Shared)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_locks_serialize_connections() -> rusqlite::Result<()> {
+        let _vfs = register("test-locks", MemVfs::default()).unwrap();
+        let open = || {
+            let flags = OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE;
+            let conn = Connection::open_with_flags_and_vfs("main.db", flags, "test-locks")?;
+            conn.busy_timeout(Duration::ZERO)?;
+            Ok::<_, rusqlite::Error>(conn)
+        };
+        let a = open()?;
+        let b = open()?;
+
+        a.execute_batch("CREATE TABLE t (x); BEGIN IMMEDIATE; INSERT INTO t VALUES (1)")?;
+        match b.execute("INSERT INTO t VALUES (2)", []) {
+            Err(rusqlite::Error::SqliteFailure(err, _)) => {
+                assert_eq!(err.code, ErrorCode::DatabaseBusy)
+            }
+            other => panic!("expected SQLITE_BUSY, got {:?}", other),
+        }
+
+        a.execute_batch("COMMIT")?;
+        b.execute("INSERT INTO t VALUES (2)", [])?;
+        let count: i64 = a.query_row("SELECT COUNT(*) FROM t", [], |row| row.get(0))?;
+        assert_eq!(count, 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_unregister() -> rusqlite::Result<()> {
+        let open = || {
+            Connection::open_with_flags_and_vfs("main.db", OpenFlags::default(), "test-unregister")
+        };
+
+        let vfs = register("test-unregister", MemVfs::default()).unwrap();
+        assert_eq!(vfs.name(), "test-unregister");
+        open()?.execute_batch("CREATE TABLE t (x)")?;
+        drop(vfs);
+
+        match open() {
+            Err(rusqlite::Error::SqliteFailure(_, Some(msg))) => {
+                assert_eq!(msg, "no such vfs: test-unregister")
+            }
+            other => panic!("expected missing vfs error, got {:?}", other.map(|_| ())),
+        }
+
+        // the name can be reused once the previous vfs is unregistered
+        let vfs = register("test-unregister", MemVfs::default()).unwrap();
+        open()?.execute_batch("CREATE TABLE t (x)")?;
+        vfs.unregister().unwrap();
+        assert!(open().is_err());
+
+        Ok(())
+    }
+}
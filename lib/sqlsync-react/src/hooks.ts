@@ -24,7 +24,8 @@ export function useSQLSync(): SQLSync {
   return value;
 }
 
-type MutateFn<M> = (mutation: M) => Promise<void>;
+// resolves to the lsn of the mutation, see SQLSync.waitForSync
+type MutateFn<M> = (mutation: M) => Promise<number>;
 type UseMutateFn<M> = (docId: DocId) => MutateFn<M>;
 
 type UseQueryFn = <R = Row>(docId: DocId, query: ParameterizedQuery | string) => QueryState<R>;
@@ -0,0 +1,205 @@
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, Data, DeriveInput, Fields, Ident};
+
+/// sqlsync_mutation turns an enum into a reducer mutation which can be shared
+/// by a reducer and its clients, so both sides always agree on how mutations
+/// are encoded. see sqlsync_reducer::sqlsync_mutation
+#[proc_macro_attribute]
+pub fn sqlsync_mutation(attr: TokenStream, item: TokenStream) -> TokenStream {
+    // the codec defaults to bincode, and can be set with codec = Json
+    let mut codec = Ident::new("Bincode", Span::call_site());
+    let parser = syn::meta::parser(|meta| {
+        if meta.path.is_ident("codec") {
+            codec = meta.value()?.parse()?;
+            Ok(())
+        } else {
+            Err(meta.error("unsupported sqlsync_mutation argument"))
+        }
+    });
+    parse_macro_input!(attr with parser);
+
+    let input = parse_macro_input!(item as DeriveInput);
+    expand(input, codec)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+fn expand(input: DeriveInput, codec: Ident) -> syn::Result<TokenStream2> {
+    let Data::Enum(data) = &input.data else {
+        return Err(syn::Error::new_spanned(
+            &input.ident,
+            "sqlsync_mutation can only be used on an enum",
+        ));
+    };
+    if !input.generics.params.is_empty() {
+        // mutations are decoded into an owned value, and the reducer has no
+        // way to pick generic parameters
+        return Err(syn::Error::new_spanned(
+            &input.generics,
+            "sqlsync_mutation can't be used on a generic enum",
+        ));
+    }
+
+    let name = &input.ident;
+    let vis = &input.vis;
+
+    let mut constructors = Vec::new();
+    for variant in data.variants.iter() {
+        let variant_name = &variant.ident;
+        let fn_name = constructor_name(variant_name)?;
+        let doc = format!("construct a {}::{} mutation", name, variant_name);
+
+        let (args, construct) = match &variant.fields {
+            Fields::Unit => (vec![], quote!(Self::#variant_name)),
+            Fields::Named(fields) => {
+                let names: Vec<_> = fields.named.iter().map(|f| &f.ident).collect();
+                let args = fields
+                    .named
+                    .iter()
+                    .map(|f| {
+                        let (ident, ty) = (&f.ident, &f.ty);
+                        quote!(#ident: #ty)
+                    })
+                    .collect();
+                (args, quote!(Self::#variant_name { #(#names),* }))
+            }
+            Fields::Unnamed(fields) => {
+                let names: Vec<_> = (0..fields.unnamed.len())
+                    .map(|i| format_ident!("arg{}", i))
+                    .collect();
+                let args = fields
+                    .unnamed
+                    .iter()
+                    .zip(names.iter())
+                    .map(|(f, ident)| {
+                        let ty = &f.ty;
+                        quote!(#ident: #ty)
+                    })
+                    .collect();
+                (args, quote!(Self::#variant_name(#(#names),*)))
+            }
+        };
+
+        constructors.push(quote! {
+            #[doc = #doc]
+            #vis fn #fn_name(#(#args),*) -> Self {
+                #construct
+            }
+        });
+    }
+
+    Ok(quote! {
+        #[derive(sqlsync_reducer::serde::Serialize, sqlsync_reducer::serde::Deserialize)]
+        #[serde(crate = "sqlsync_reducer::serde")]
+        #input
+
+        #[allow(dead_code)]
+        impl #name {
+            /// the codec used to encode this mutation, which the reducer
+            /// should declare with init_reducer!(reducer, codec = ...)
+            #vis const CODEC: sqlsync_reducer::mutation::MutationCodec =
+                sqlsync_reducer::mutation::MutationCodec::#codec;
+
+            /// encode this mutation, the result can be passed to
+            /// LocalDocument::mutate and decoded by the reducer with decode
+            #vis fn encode(&self) -> ::std::result::Result<
+                ::std::vec::Vec<u8>,
+                sqlsync_reducer::mutation::MutationError,
+            > {
+                Self::CODEC.encode(self)
+            }
+
+            /// decode a mutation which was encoded with encode
+            #vis fn decode(
+                mutation: &[u8],
+            ) -> ::std::result::Result<Self, sqlsync_reducer::mutation::MutationError> {
+                Self::CODEC.decode(mutation)
+            }
+
+            #(#constructors)*
+        }
+    })
+}
+
+/// derive FromRow for a struct, each field is read from the column at the
+/// same position in the row. see sqlsync_reducer::guest_reactor::FromRow
+#[proc_macro_derive(FromRow)]
+pub fn derive_from_row(item: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(item as DeriveInput);
+    expand_from_row(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+fn expand_from_row(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let Data::Struct(data) = &input.data else {
+        return Err(syn::Error::new_spanned(
+            &input.ident,
+            "FromRow can only be derived for a struct",
+        ));
+    };
+
+    let construct = match &data.fields {
+        Fields::Named(fields) => {
+            let gets = fields.named.iter().enumerate().map(|(idx, f)| {
+                let ident = &f.ident;
+                quote!(#ident: row.get(#idx)?)
+            });
+            quote!(Self { #(#gets),* })
+        }
+        Fields::Unnamed(fields) => {
+            let gets = (0..fields.unnamed.len()).map(|idx| quote!(row.get(#idx)?));
+            quote!(Self(#(#gets),*))
+        }
+        Fields::Unit => {
+            return Err(syn::Error::new_spanned(
+                &input.ident,
+                "FromRow can't be derived for a unit struct",
+            ))
+        }
+    };
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics sqlsync_reducer::guest_reactor::FromRow for #name #ty_generics
+        #where_clause
+        {
+            fn from_row(
+                row: &sqlsync_reducer::types::Row,
+            ) -> ::std::result::Result<Self, sqlsync_reducer::types::ReducerError> {
+                ::std::result::Result::Ok(#construct)
+            }
+        }
+    })
+}
+
+/// the name of the constructor for a variant, e.g. CreateTask becomes
+/// create_task
+fn constructor_name(variant: &Ident) -> syn::Result<Ident> {
+    let chars: Vec<char> = variant.to_string().chars().collect();
+    let mut name = String::new();
+    for (i, &c) in chars.iter().enumerate() {
+        if c.is_uppercase() && i > 0 {
+            // start a new word unless we are in the middle of an acronym
+            let prev = chars[i - 1];
+            let next_is_lower = chars.get(i + 1).is_some_and(|n| n.is_lowercase());
+            if !prev.is_uppercase() || next_is_lower {
+                name.push('_');
+            }
+        }
+        name.extend(c.to_lowercase());
+    }
+
+    if name == "encode" || name == "decode" {
+        return Err(syn::Error::new_spanned(
+            variant,
+            format!("the {} constructor would conflict with {}()", name, name),
+        ));
+    }
+
+    // variants like Type produce constructor names which are keywords
+    Ok(syn::parse_str::<Ident>(&name).unwrap_or_else(|_| Ident::new_raw(&name, Span::call_site())))
+}
@@ -1,7 +1,10 @@
 // build guest.wasm using: `cargo build --target wasm32-unknown-unknown --example guest`
 
 use serde::{Deserialize, Serialize};
-use sqlsync_reducer::{execute, init_reducer, query, types::ReducerError};
+use sqlsync_reducer::{
+    execute, init_reducer, query,
+    types::{MutationContext, ReducerError},
+};
 
 #[derive(Serialize, Deserialize, Debug)]
 enum Mutation {
@@ -9,10 +12,10 @@ enum Mutation {
     Delete(String),
 }
 
-async fn reducer(mutation: Vec<u8>) -> Result<(), ReducerError> {
+async fn reducer(mutation: Vec<u8>, context: MutationContext) -> Result<(), ReducerError> {
     let mutation: Mutation = bincode::deserialize(&mutation)?;
 
-    log::info!("received mutation: {:?}", mutation);
+    log::info!("received mutation: {:?} in context {:?}", mutation, context);
 
     log::info!("running query and execute at the same time");
 
@@ -48,4 +51,4 @@ async fn reducer(mutation: Vec<u8>) -> Result<(), ReducerError> {
     Ok(())
 }
 
-init_reducer!(reducer);
+init_reducer!(reducer, context);
@@ -5,7 +5,7 @@ use std::collections::BTreeMap;
 use serde::{Deserialize, Serialize};
 use sqlsync_reducer::{
     host_ffi::{register_log_handler, WasmFFI},
-    types::{ErrorResponse, ExecResponse, QueryResponse, Request},
+    types::{ErrorResponse, ExecResponse, MutationContext, QueryResponse, Request, SqliteError},
 };
 use wasmi::{Engine, Linker, Module, Store};
 
@@ -29,30 +29,31 @@ fn main() -> anyhow::Result<()> {
     let module = Module::new(&engine, &wasm_bytes[..])?;
     let mut linker = Linker::new(&engine);
 
-    register_log_handler(&mut linker)?;
+    register_log_handler(&mut linker, log::LevelFilter::Trace)?;
 
     let mut store = Store::new(&engine, WasmFFI::uninitialized());
     let instance = linker.instantiate(&mut store, &module)?.start(&mut store)?;
 
     // initialize the FFI
-    let ffi = WasmFFI::initialized(&store, &instance)?;
+    let mut ffi = WasmFFI::initialized(&store, &instance)?;
     (*store.data_mut()) = ffi;
 
     // initialize the reducer
     ffi.init_reducer(&mut store)?;
+    (*store.data_mut()) = ffi;
 
     let mutation = Mutation::Set("hello".to_string(), "world".to_string());
     let mutation = &bincode::serialize(&mutation)?;
 
     // kick off the reducer
-    let mut requests = ffi.reduce(&mut store, mutation)?;
+    let mut requests = ffi.reduce(&mut store, &MutationContext::default(), mutation)?;
 
     while let Some(requests_inner) = requests {
         // process requests
         let mut responses = BTreeMap::new();
         for (id, req) in requests_inner {
             match req {
-                Request::Query { sql, params } => {
+                Request::Query { sql, params, .. } => {
                     log::info!("received query request: {} {:?}", sql, params);
                     let ptr = ffi.encode(
                         &mut store,
@@ -63,21 +64,32 @@ fn main() -> anyhow::Result<()> {
                     )?;
                     responses.insert(id, ptr);
                 }
-                Request::Exec { sql, params } => {
+                Request::ExecBatch { sql } => {
+                    log::info!("received exec batch request: {}", sql);
+                    let ptr = ffi.encode_exec_response(
+                        &mut store,
+                        &Ok::<_, ErrorResponse>(ExecResponse { changes: 1, last_insert_rowid: 1 }),
+                    )?;
+                    responses.insert(id, ptr);
+                }
+                Request::Exec { sql, params, .. } => {
                     log::info!("received exec request: {} {:?}", sql, params);
                     if sql == "FAIL" {
-                        let ptr = ffi.encode(
+                        let ptr = ffi.encode_exec_response(
                             &mut store,
-                            &Err::<ExecResponse, _>(ErrorResponse::SqliteError {
+                            &Err::<ExecResponse, _>(ErrorResponse::SqliteError(SqliteError {
                                 code: 1,
                                 message: "error".to_string(),
-                            }),
+                            })),
                         )?;
                         responses.insert(id, ptr);
                     } else {
-                        let ptr = ffi.encode(
+                        let ptr = ffi.encode_exec_response(
                             &mut store,
-                            &Ok::<_, ErrorResponse>(ExecResponse { changes: 1 }),
+                            &Ok::<_, ErrorResponse>(ExecResponse {
+                                changes: 1,
+                                last_insert_rowid: 1,
+                            }),
                         )?;
                         responses.insert(id, ptr);
                     }
@@ -1,8 +1,13 @@
-use std::{collections::BTreeMap, mem::MaybeUninit, panic, sync::Once};
+use std::{
+    collections::BTreeMap,
+    mem::MaybeUninit,
+    panic,
+    sync::{Mutex, Once},
+};
 
 use serde::{de::DeserializeOwned, Serialize};
 
-use crate::types::LogRecord;
+use crate::types::{LogRecord, PanicRecord};
 
 pub type FFIBuf = Vec<u8>;
 pub type FFIBufPtr = *mut u8;
@@ -105,13 +110,26 @@ pub fn ffi_buf_len(ptr: FFIBufPtr) -> FFIBufLen {
 
 extern "C" {
     fn host_log(log_req: FFIBufPtr);
+    fn host_log_level() -> u32;
+}
+
+/// the most verbose level the host wants to receive log records at
+pub fn host_max_log_level() -> log::LevelFilter {
+    match unsafe { host_log_level() } {
+        0 => log::LevelFilter::Off,
+        1 => log::LevelFilter::Error,
+        2 => log::LevelFilter::Warn,
+        3 => log::LevelFilter::Info,
+        4 => log::LevelFilter::Debug,
+        _ => log::LevelFilter::Trace,
+    }
 }
 
 pub struct FFILogger;
 
 impl FFILogger {
-    pub fn init(&'static self, max_level: log::Level) -> Result<(), log::SetLoggerError> {
-        log::set_logger(self).map(|_| log::set_max_level(max_level.to_level_filter()))
+    pub fn init(&'static self, max_level: log::LevelFilter) -> Result<(), log::SetLoggerError> {
+        log::set_logger(self).map(|_| log::set_max_level(max_level))
     }
 }
 
@@ -138,8 +156,24 @@ pub fn install_panic_hook() {
     });
 }
 
+// the most recent panic, kept until the host asks for it with ffi_last_panic
+static LAST_PANIC: Mutex<Option<PanicRecord>> = Mutex::new(None);
+
 fn panic_hook(info: &panic::PanicInfo) {
+    *LAST_PANIC.lock().unwrap() = Some(info.into());
+
     let record: LogRecord = info.into();
     let record_ptr = fbm().encode(&record).unwrap();
     unsafe { host_log(record_ptr) }
 }
+
+/// ffi_last_panic is called by the host after the reducer traps, it returns
+/// the serialized PanicRecord of the panic which caused the trap, if any
+///
+/// # Panics
+/// Panics if the record can't be serialized.
+#[no_mangle]
+pub fn ffi_last_panic() -> FFIBufPtr {
+    let record = LAST_PANIC.lock().unwrap().take();
+    fbm().encode(&record).unwrap()
+}
@@ -13,7 +13,7 @@ use crate::{
     guest_ffi::{fbm, FFIBufPtr},
     types::{
         ErrorResponse, ExecResponse, QueryResponse, ReducerError, Request, RequestId, Requests,
-        Responses, SqliteValue,
+        Responses, Row, SqliteValue,
     },
 };
 
@@ -29,13 +29,34 @@ pub fn reactor() -> &'static mut Reactor {
     }
 }
 
-type ReducerTask = Pin<Box<dyn Future<Output = Result<(), ReducerError>>>>;
+/// ReducerOutput is implemented by the values a reducer may return, the
+/// output of each mutation is passed back to the host
+pub trait ReducerOutput {
+    fn into_output(self) -> Option<Vec<u8>>;
+}
+
+impl ReducerOutput for () {
+    fn into_output(self) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+impl ReducerOutput for Vec<u8> {
+    fn into_output(self) -> Option<Vec<u8>> {
+        Some(self)
+    }
+}
+
+type ReducerTask = Pin<Box<dyn Future<Output = Result<Option<Vec<u8>>, ReducerError>>>>;
 
 #[derive(Default)]
 pub struct Reactor {
     task: Option<ReducerTask>,
     request_id_generator: RequestId,
 
+    // the output of the last reducer task to complete
+    output: Option<Vec<u8>>,
+
     // requests from guest -> host
     requests: Requests,
     // responses from host -> guest
@@ -71,6 +92,7 @@ impl Reactor {
             panic!("Reducer task already running");
         }
         self.task = Some(task);
+        self.output = None;
     }
 
     pub fn step(&mut self, responses: Responses) -> Result<Requests, ReducerError> {
@@ -89,7 +111,7 @@ impl Reactor {
         if let Some(mut task) = self.task.take() {
             let mut ctx = Context::from_waker(futures::task::noop_waker_ref());
             match task.as_mut().poll(&mut ctx) {
-                Poll::Ready(result) => result?,
+                Poll::Ready(result) => self.output = result?,
                 Poll::Pending => {
                     self.task = Some(task);
                 }
@@ -127,7 +149,22 @@ pub fn raw_query(
     sql: String,
     params: Vec<SqliteValue>,
 ) -> ResponseFuture<Result<QueryResponse, ErrorResponse>> {
-    let request = Request::Query { sql, params };
+    let request = Request::Query {
+        sql,
+        params,
+        named_params: BTreeMap::new(),
+    };
+    let id = reactor().queue_request(request);
+    ResponseFuture::new(id)
+}
+
+/// like raw_query, but binds parameters by name, each name must include its
+/// prefix (i.e. ":id")
+pub fn raw_query_named(
+    sql: String,
+    named_params: BTreeMap<String, SqliteValue>,
+) -> ResponseFuture<Result<QueryResponse, ErrorResponse>> {
+    let request = Request::Query { sql, params: vec![], named_params };
     let id = reactor().queue_request(request);
     ResponseFuture::new(id)
 }
@@ -136,44 +173,160 @@ pub fn raw_execute(
     sql: String,
     params: Vec<SqliteValue>,
 ) -> ResponseFuture<Result<ExecResponse, ErrorResponse>> {
-    let request = Request::Exec { sql, params };
+    let request = Request::Exec {
+        sql,
+        params,
+        named_params: BTreeMap::new(),
+    };
+    let id = reactor().queue_request(request);
+    ResponseFuture::new(id)
+}
+
+/// like raw_execute, but binds parameters by name, each name must include its
+/// prefix (i.e. ":id")
+pub fn raw_execute_named(
+    sql: String,
+    named_params: BTreeMap<String, SqliteValue>,
+) -> ResponseFuture<Result<ExecResponse, ErrorResponse>> {
+    let request = Request::Exec { sql, params: vec![], named_params };
     let id = reactor().queue_request(request);
     ResponseFuture::new(id)
 }
 
+pub fn raw_execute_batch(sql: String) -> ResponseFuture<Result<ExecResponse, ErrorResponse>> {
+    let request = Request::ExecBatch { sql };
+    let id = reactor().queue_request(request);
+    ResponseFuture::new(id)
+}
+
+/// FromRow is implemented by types which can be decoded from a query row,
+/// derive it for a struct to read each field from the column at the same
+/// position in the row
+///
+/// ```ignore
+/// #[derive(FromRow)]
+/// struct Task { id: i64, description: String }
+///
+/// let tasks: Vec<Task> = query!("SELECT id, description FROM tasks").await?.rows_as()?;
+/// ```
+pub trait FromRow: Sized {
+    fn from_row(row: &Row) -> Result<Self, ReducerError>;
+}
+
+pub use sqlsync_reducer_macros::FromRow;
+
+impl QueryResponse {
+    /// decode every row into a T
+    pub fn rows_as<T: FromRow>(&self) -> Result<Vec<T>, ReducerError> {
+        self.rows.iter().map(T::from_row).collect()
+    }
+}
+
+/// query!(sql, args...) binds each arg to a positional parameter, while
+/// query!(sql, ":name" => arg, ...) binds each arg to a named parameter
+/// the two forms can't be mixed in a single query
+///
+/// ```compile_fail
+/// use sqlsync_reducer::query;
+/// query!("SELECT :a, ?", ":a" => 1, 2);
+/// ```
 #[macro_export]
 macro_rules! query {
-    ($sql:expr $(, $arg:expr)*) => {
+    ($sql:expr $(, $name:literal => $arg:expr)+ $(,)?) => {
+        sqlsync_reducer::guest_reactor::raw_query_named(
+            $sql.into(),
+            [$(($name.to_string(), $arg.into())),+].into_iter().collect(),
+        )
+    };
+    ($sql:expr $(, $arg:expr)* $(,)?) => {
         sqlsync_reducer::guest_reactor::raw_query($sql.into(), vec![$($arg.into()),*])
     };
+    ($($tt:tt)*) => {
+        compile_error!("query! can't mix positional and named parameters")
+    };
 }
 
+/// execute! accepts positional or named parameters, see query!
 #[macro_export]
 macro_rules! execute {
-    ($sql:expr $(, $arg:expr)*) => {
+    ($sql:expr $(, $name:literal => $arg:expr)+ $(,)?) => {
+        sqlsync_reducer::guest_reactor::raw_execute_named(
+            $sql.into(),
+            [$(($name.to_string(), $arg.into())),+].into_iter().collect(),
+        )
+    };
+    ($sql:expr $(, $arg:expr)* $(,)?) => {
         sqlsync_reducer::guest_reactor::raw_execute($sql.into(), vec![$($arg.into()),*])
     };
+    ($($tt:tt)*) => {
+        compile_error!("execute! can't mix positional and named parameters")
+    };
+}
+
+/// execute_batch! runs a string containing any number of statements, which
+/// don't accept parameters
+#[macro_export]
+macro_rules! execute_batch {
+    ($sql:expr) => {
+        sqlsync_reducer::guest_reactor::raw_execute_batch($sql.into())
+    };
 }
 
 #[macro_export]
 macro_rules! init_reducer {
-    // fn should be (Vec<u8>) -> Future<Output = Result<(), ReducerError>>
+    // fn should be (Vec<u8>) -> Future<Output = Result<T, ReducerError>>
+    // where T is either () or Vec<u8>, see ReducerOutput
     ($fn:ident) => {
+        sqlsync_reducer::init_reducer!(@ffi |mutation, _context| $fn(mutation));
+    };
+
+    // fn should be (Vec<u8>, MutationContext) -> Future<Output = Result<T, ReducerError>>
+    ($fn:ident, context) => {
+        sqlsync_reducer::init_reducer!(@ffi |mutation, context| $fn(mutation, context));
+    };
+
+    // codec is a MutationCodec variant, the host rejects mutations which
+    // don't match it before calling the reducer
+    ($fn:ident, codec = $codec:ident) => {
+        sqlsync_reducer::init_reducer!($fn);
+        sqlsync_reducer::init_reducer!(@codec $codec);
+    };
+
+    ($fn:ident, context, codec = $codec:ident) => {
+        sqlsync_reducer::init_reducer!($fn, context);
+        sqlsync_reducer::init_reducer!(@codec $codec);
+    };
+
+    (@codec $codec:ident) => {
+        #[no_mangle]
+        pub extern "C" fn ffi_mutation_codec() -> u32 {
+            sqlsync_reducer::mutation::MutationCodec::$codec as u32
+        }
+    };
+
+    (@ffi |$mutation:ident, $context:ident| $call:expr) => {
         /// ffi_reduce is called by the host to cause the reducer to start processing a new mutation.
         ///
         /// # Panics
         /// Panics if the host passes in an invalid pointer.
         /// # Safety
-        /// The host must pass in a valid pointer to a Mutation buffer.
+        /// The host must pass in valid pointers to a Mutation and a MutationContext buffer.
         #[no_mangle]
         pub unsafe fn ffi_reduce(
             mutation_ptr: sqlsync_reducer::guest_ffi::FFIBufPtr,
+            context_ptr: sqlsync_reducer::guest_ffi::FFIBufPtr,
         ) -> sqlsync_reducer::guest_ffi::FFIBufPtr {
             let reactor = sqlsync_reducer::guest_reactor::reactor();
             let fbm = sqlsync_reducer::guest_ffi::fbm();
-            let mutation = fbm.consume(mutation_ptr);
+            let $mutation = fbm.consume(mutation_ptr);
+            let $context: sqlsync_reducer::types::MutationContext =
+                fbm.decode(context_ptr).unwrap();
 
-            reactor.spawn(Box::pin(async move { $fn(mutation).await }));
+            reactor.spawn(Box::pin(async move {
+                $call
+                    .await
+                    .map(sqlsync_reducer::guest_reactor::ReducerOutput::into_output)
+            }));
 
             let requests = reactor.step(None);
             fbm.encode(&requests).unwrap()
@@ -183,9 +336,45 @@ macro_rules! init_reducer {
             sqlsync_reducer::guest_ffi::FFILogger;
 
         #[no_mangle]
-        pub extern "C" fn ffi_init_reducer() {
-            LOGGER.init(log::Level::Trace).unwrap();
+        pub extern "C" fn ffi_init_reducer() -> u32 {
+            LOGGER
+                .init(sqlsync_reducer::guest_ffi::host_max_log_level())
+                .unwrap();
             sqlsync_reducer::guest_ffi::install_panic_hook();
+            sqlsync_reducer::types::ABI_VERSION
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! init_migrations {
+    // version is the schema version this reducer expects
+    // fn should be (u32, u32) -> Future<Output = Result<(), ReducerError>>
+    // and is called with the document's current version and the new version
+    ($version:expr, $fn:ident) => {
+        #[no_mangle]
+        pub extern "C" fn ffi_schema_version() -> u32 {
+            $version
+        }
+
+        /// ffi_migrate is called by the host to upgrade the document's schema.
+        ///
+        /// # Panics
+        /// Panics if the requests can't be serialized.
+        #[no_mangle]
+        pub fn ffi_migrate(
+            from_version: u32,
+            to_version: u32,
+        ) -> sqlsync_reducer::guest_ffi::FFIBufPtr {
+            let reactor = sqlsync_reducer::guest_reactor::reactor();
+            let fbm = sqlsync_reducer::guest_ffi::fbm();
+
+            reactor.spawn(Box::pin(async move {
+                $fn(from_version, to_version).await.map(|()| None)
+            }));
+
+            let requests = reactor.step(None);
+            fbm.encode(&requests).unwrap()
         }
     };
 }
@@ -204,3 +393,63 @@ pub unsafe fn ffi_reactor_step(responses_ptr: FFIBufPtr) -> FFIBufPtr {
     let out = reactor().step(responses);
     fbm.encode(&out).unwrap()
 }
+
+/// ffi_reducer_output is called by the host once the reactor has no more
+/// requests, it returns the serialized output of the completed mutation
+///
+/// # Panics
+/// Panics if the output can't be serialized.
+#[no_mangle]
+pub fn ffi_reducer_output() -> FFIBufPtr {
+    fbm().encode(&reactor().output.take()).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq, FromRow)]
+    struct Task {
+        id: i64,
+        description: String,
+    }
+
+    #[derive(Debug, PartialEq, FromRow)]
+    struct Pair(i64, String);
+
+    // only used to check that decoding fails
+    #[allow(dead_code)]
+    #[derive(Debug, FromRow)]
+    struct Description(String);
+
+    #[test]
+    fn test_rows_as() -> Result<(), ReducerError> {
+        let response = QueryResponse {
+            columns: vec!["id".into(), "description".into()],
+            rows: vec![
+                vec![SqliteValue::Integer(1), SqliteValue::Text("a".into())].into(),
+                vec![SqliteValue::Integer(2), SqliteValue::Text("b".into())].into(),
+            ],
+        };
+
+        assert_eq!(
+            response.rows_as::<Task>()?,
+            vec![
+                Task { id: 1, description: "a".into() },
+                Task { id: 2, description: "b".into() },
+            ]
+        );
+        assert_eq!(
+            response.rows_as::<Pair>()?,
+            vec![Pair(1, "a".into()), Pair(2, "b".into())]
+        );
+
+        // columns which can't be converted fail to decode
+        assert!(matches!(
+            response.rows_as::<Description>(),
+            Err(ReducerError::ConversionError { .. })
+        ));
+
+        Ok(())
+    }
+}
@@ -1,12 +1,20 @@
-use serde::{de::DeserializeOwned, Serialize};
+use std::collections::BTreeMap;
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use thiserror::Error;
 use wasmi::{
     core::{HostError, Trap},
     errors::LinkerError,
-    AsContext, AsContextMut, Caller, Instance, Linker, Memory, TypedFunc,
+    AsContext, AsContextMut, Caller, Instance, Linker, Memory, TypedFunc, WasmParams, WasmResults,
 };
 
-use crate::types::{LogRecord, ReducerError, Requests, Responses};
+use crate::{
+    mutation::MutationCodec,
+    types::{
+        ErrorResponse, ExecResponse, LogRecord, MutationContext, PanicRecord, ReducerError,
+        Request, RequestId, Requests, Responses, SqliteValue,
+    },
+};
 
 pub type FFIBuf = Vec<u8>;
 pub type FFIBufPtr = u32;
@@ -20,12 +28,39 @@ pub enum WasmFFI {
         ffi_buf_allocate: TypedFunc<FFIBufLen, FFIBufPtr>,
         ffi_buf_deallocate: TypedFunc<FFIBufPtr, ()>,
         ffi_buf_len: TypedFunc<FFIBufPtr, FFIBufLen>,
-        ffi_init_reducer: TypedFunc<(), ()>,
-        ffi_reduce: TypedFunc<FFIBufPtr, FFIBufPtr>,
+        ffi_init_reducer: InitFunc,
+        // set by init_reducer, see ABI_VERSION
+        abi_version: u32,
+        ffi_reduce: ReduceFunc,
         ffi_reactor_step: TypedFunc<FFIBufPtr, FFIBufPtr>,
+        // reducers built before outputs were supported don't export this
+        ffi_reducer_output: Option<TypedFunc<(), FFIBufPtr>>,
+        // only exported by reducers which call init_migrations!
+        ffi_schema_version: Option<TypedFunc<(), u32>>,
+        ffi_mutation_codec: Option<TypedFunc<(), u32>>,
+        ffi_migrate: Option<TypedFunc<(u32, u32), FFIBufPtr>>,
+        // reducers built before panics were recorded don't export this
+        ffi_last_panic: Option<TypedFunc<(), FFIBufPtr>>,
     },
 }
 
+/// ffi_init_reducer returns the guest's ABI_VERSION
+#[derive(Debug, Copy, Clone)]
+pub enum InitFunc {
+    Versioned(TypedFunc<(), u32>),
+    // reducers built before the abi was versioned return nothing
+    Unversioned(TypedFunc<(), ()>),
+}
+
+/// ffi_reduce receives the mutation and its MutationContext
+#[derive(Debug, Copy, Clone)]
+pub enum ReduceFunc {
+    WithContext(TypedFunc<(FFIBufPtr, FFIBufPtr), FFIBufPtr>),
+    // reducers built before mutation contexts were supported only receive
+    // the mutation
+    WithoutContext(TypedFunc<FFIBufPtr, FFIBufPtr>),
+}
+
 impl WasmFFI {
     pub fn uninitialized() -> Self {
         Self::Uninitialized
@@ -34,16 +69,26 @@ impl WasmFFI {
     pub fn initialized(store: &impl AsContext, instance: &Instance) -> Result<Self, WasmFFIError> {
         let memory = instance
             .get_memory(store, "memory")
-            .ok_or(WasmFFIError::MemoryNotFound)?;
-        let ffi_buf_allocate =
-            instance.get_typed_func::<FFIBufLen, FFIBufPtr>(store, "ffi_buf_allocate")?;
-        let ffi_buf_deallocate =
-            instance.get_typed_func::<FFIBufPtr, ()>(store, "ffi_buf_deallocate")?;
-        let ffi_buf_len = instance.get_typed_func::<FFIBufPtr, FFIBufLen>(store, "ffi_buf_len")?;
-        let ffi_init_reducer = instance.get_typed_func::<(), ()>(store, "ffi_init_reducer")?;
-        let ffi_reduce = instance.get_typed_func::<FFIBufPtr, FFIBufPtr>(store, "ffi_reduce")?;
-        let ffi_reactor_step =
-            instance.get_typed_func::<FFIBufPtr, FFIBufPtr>(store, "ffi_reactor_step")?;
+            .ok_or_else(|| WasmFFIError::missing_export("memory"))?;
+        let ffi_buf_allocate = typed_export(store, instance, "ffi_buf_allocate")?;
+        let ffi_buf_deallocate = typed_export(store, instance, "ffi_buf_deallocate")?;
+        let ffi_buf_len = typed_export(store, instance, "ffi_buf_len")?;
+        let ffi_init_reducer = match typed_export(store, instance, "ffi_init_reducer") {
+            Ok(ffi_init_reducer) => InitFunc::Versioned(ffi_init_reducer),
+            Err(err @ WasmFFIError::MissingExport { .. }) => return Err(err),
+            Err(_) => InitFunc::Unversioned(typed_export(store, instance, "ffi_init_reducer")?),
+        };
+        let ffi_reduce = match typed_export(store, instance, "ffi_reduce") {
+            Ok(ffi_reduce) => ReduceFunc::WithContext(ffi_reduce),
+            Err(err @ WasmFFIError::MissingExport { .. }) => return Err(err),
+            Err(_) => ReduceFunc::WithoutContext(typed_export(store, instance, "ffi_reduce")?),
+        };
+        let ffi_reactor_step = typed_export(store, instance, "ffi_reactor_step")?;
+        let ffi_reducer_output = optional_typed_export(store, instance, "ffi_reducer_output")?;
+        let ffi_schema_version = optional_typed_export(store, instance, "ffi_schema_version")?;
+        let ffi_mutation_codec = optional_typed_export(store, instance, "ffi_mutation_codec")?;
+        let ffi_migrate = optional_typed_export(store, instance, "ffi_migrate")?;
+        let ffi_last_panic = optional_typed_export(store, instance, "ffi_last_panic")?;
 
         Ok(Self::Initialized {
             memory,
@@ -51,8 +96,14 @@ impl WasmFFI {
             ffi_buf_deallocate,
             ffi_buf_len,
             ffi_init_reducer,
+            abi_version: 0,
             ffi_reduce,
             ffi_reactor_step,
+            ffi_reducer_output,
+            ffi_schema_version,
+            ffi_mutation_codec,
+            ffi_migrate,
+            ffi_last_panic,
         })
     }
 
@@ -106,26 +157,91 @@ impl WasmFFI {
         self.persist(&mut store, &bytes)
     }
 
-    pub fn init_reducer(&self, mut ctx: impl AsContextMut) -> Result<(), WasmFFIError> {
+    /// initialize the reducer, recording the abi version it speaks
+    pub fn init_reducer(&mut self, mut ctx: impl AsContextMut) -> Result<(), WasmFFIError> {
         match self {
             Self::Uninitialized => Err(WasmFFIError::Uninitialized),
-            Self::Initialized { ffi_init_reducer, .. } => Ok(ffi_init_reducer.call(&mut ctx, ())?),
+            Self::Initialized { ffi_init_reducer, abi_version, .. } => {
+                *abi_version = match ffi_init_reducer {
+                    InitFunc::Versioned(ffi_init_reducer) => ffi_init_reducer.call(&mut ctx, ())?,
+                    InitFunc::Unversioned(ffi_init_reducer) => {
+                        ffi_init_reducer.call(&mut ctx, ())?;
+                        0
+                    }
+                };
+                Ok(())
+            }
+        }
+    }
+
+    /// returns the abi version of the reducer, which is 0 until init_reducer
+    /// is called
+    pub fn abi_version(&self) -> u32 {
+        match self {
+            Self::Uninitialized => 0,
+            Self::Initialized { abi_version, .. } => *abi_version,
+        }
+    }
+
+    fn decode_requests(
+        &self,
+        mut ctx: impl AsContextMut,
+        ptr: FFIBufPtr,
+    ) -> Result<Requests, WasmFFIError> {
+        let requests: Result<Requests, ReducerError> = if self.abi_version() == 0 {
+            let requests: Result<Option<BTreeMap<RequestId, LegacyRequest>>, ReducerError> =
+                self.decode(&mut ctx, ptr)?;
+            requests.map(|requests| {
+                requests.map(|requests| {
+                    requests
+                        .into_iter()
+                        .map(|(id, request)| (id, request.into()))
+                        .collect()
+                })
+            })
+        } else {
+            self.decode(&mut ctx, ptr)?
+        };
+        Ok(requests?)
+    }
+
+    /// encode the response to an Exec or ExecBatch request, in the layout
+    /// the reducer expects
+    pub fn encode_exec_response(
+        &self,
+        mut ctx: impl AsContextMut,
+        response: &Result<ExecResponse, ErrorResponse>,
+    ) -> Result<FFIBufPtr, WasmFFIError> {
+        if self.abi_version() == 0 {
+            let response = response
+                .as_ref()
+                .map(|response| LegacyExecResponse { changes: response.changes });
+            self.encode(&mut ctx, response)
+        } else {
+            self.encode(&mut ctx, response)
         }
     }
 
     pub fn reduce(
         &self,
         mut ctx: impl AsContextMut,
+        context: &MutationContext,
         mutation: &[u8],
     ) -> Result<Requests, WasmFFIError> {
         match self {
             Self::Uninitialized => Err(WasmFFIError::Uninitialized),
             Self::Initialized { ffi_reduce, .. } => {
                 let mutation_ptr = self.persist(&mut ctx, mutation)?;
-                let requests_ptr = ffi_reduce.call(&mut ctx, mutation_ptr)?;
-                let requests: Result<Requests, ReducerError> =
-                    self.decode(&mut ctx, requests_ptr)?;
-                Ok(requests?)
+                let requests_ptr = match ffi_reduce {
+                    ReduceFunc::WithContext(ffi_reduce) => {
+                        let context_ptr = self.encode(&mut ctx, context)?;
+                        ffi_reduce.call(&mut ctx, (mutation_ptr, context_ptr))?
+                    }
+                    ReduceFunc::WithoutContext(ffi_reduce) => {
+                        ffi_reduce.call(&mut ctx, mutation_ptr)?
+                    }
+                };
+                self.decode_requests(&mut ctx, requests_ptr)
             }
         }
     }
@@ -140,14 +256,166 @@ impl WasmFFI {
             Self::Initialized { ffi_reactor_step, .. } => {
                 let responses_ptr = self.encode(&mut ctx, responses)?;
                 let requests_ptr = ffi_reactor_step.call(&mut ctx, responses_ptr)?;
-                let requests: Result<Requests, ReducerError> =
-                    self.decode(&mut ctx, requests_ptr)?;
-                Ok(requests?)
+                self.decode_requests(&mut ctx, requests_ptr)
+            }
+        }
+    }
+
+    /// returns the schema version the reducer expects, reducers which don't
+    /// version their schema are at version 0
+    pub fn schema_version(&self, mut ctx: impl AsContextMut) -> Result<u32, WasmFFIError> {
+        match self {
+            Self::Uninitialized => Err(WasmFFIError::Uninitialized),
+            Self::Initialized { ffi_schema_version: None, .. } => Ok(0),
+            Self::Initialized {
+                ffi_schema_version: Some(ffi_schema_version),
+                ..
+            } => Ok(ffi_schema_version.call(&mut ctx, ())?),
+        }
+    }
+
+    /// returns the codec the reducer expects mutations to use, if it
+    /// declared one with init_reducer!(reducer, codec = ...)
+    pub fn mutation_codec(
+        &self,
+        mut ctx: impl AsContextMut,
+    ) -> Result<Option<MutationCodec>, WasmFFIError> {
+        match self {
+            Self::Uninitialized => Err(WasmFFIError::Uninitialized),
+            Self::Initialized { ffi_mutation_codec: None, .. } => Ok(None),
+            Self::Initialized {
+                ffi_mutation_codec: Some(ffi_mutation_codec),
+                ..
+            } => {
+                let codec = ffi_mutation_codec.call(&mut ctx, ())?;
+                MutationCodec::from_u32(codec)
+                    .map(Some)
+                    .ok_or(WasmFFIError::UnknownMutationCodec(codec))
+            }
+        }
+    }
+
+    /// start migrating the schema, the returned requests are handled like
+    /// those returned by reduce
+    pub fn migrate(
+        &self,
+        mut ctx: impl AsContextMut,
+        from_version: u32,
+        to_version: u32,
+    ) -> Result<Requests, WasmFFIError> {
+        match self {
+            Self::Uninitialized => Err(WasmFFIError::Uninitialized),
+            Self::Initialized { ffi_migrate: None, .. } => Ok(None),
+            Self::Initialized { ffi_migrate: Some(ffi_migrate), .. } => {
+                let requests_ptr = ffi_migrate.call(&mut ctx, (from_version, to_version))?;
+                self.decode_requests(&mut ctx, requests_ptr)
+            }
+        }
+    }
+
+    /// returns the panic which caused the reducer to trap, if it panicked.
+    /// the instance should be discarded afterwards
+    pub fn last_panic(
+        &self,
+        mut ctx: impl AsContextMut,
+    ) -> Result<Option<PanicRecord>, WasmFFIError> {
+        match self {
+            Self::Uninitialized => Err(WasmFFIError::Uninitialized),
+            Self::Initialized { ffi_last_panic: None, .. } => Ok(None),
+            Self::Initialized { ffi_last_panic: Some(ffi_last_panic), .. } => {
+                let record_ptr = ffi_last_panic.call(&mut ctx, ())?;
+                self.decode(&mut ctx, record_ptr)
+            }
+        }
+    }
+
+    /// returns the output of the last mutation, once the reactor has finished
+    pub fn reducer_output(
+        &self,
+        mut ctx: impl AsContextMut,
+    ) -> Result<Option<Vec<u8>>, WasmFFIError> {
+        match self {
+            Self::Uninitialized => Err(WasmFFIError::Uninitialized),
+            Self::Initialized { ffi_reducer_output: None, .. } => Ok(None),
+            Self::Initialized {
+                ffi_reducer_output: Some(ffi_reducer_output),
+                ..
+            } => {
+                let output_ptr = ffi_reducer_output.call(&mut ctx, ())?;
+                self.decode(&mut ctx, output_ptr)
             }
         }
     }
 }
 
+// the requests sent by abi version 0 reducers, which only bind positional
+// parameters
+#[derive(Serialize, Deserialize)]
+enum LegacyRequest {
+    Query {
+        sql: String,
+        params: Vec<SqliteValue>,
+    },
+    Exec {
+        sql: String,
+        params: Vec<SqliteValue>,
+    },
+}
+
+impl From<LegacyRequest> for Request {
+    fn from(request: LegacyRequest) -> Self {
+        match request {
+            LegacyRequest::Query { sql, params } => Request::Query {
+                sql,
+                params,
+                named_params: BTreeMap::new(),
+            },
+            LegacyRequest::Exec { sql, params } => Request::Exec {
+                sql,
+                params,
+                named_params: BTreeMap::new(),
+            },
+        }
+    }
+}
+
+// abi version 0 reducers don't expect last_insert_rowid
+#[derive(Serialize)]
+struct LegacyExecResponse {
+    changes: usize,
+}
+
+impl AsRef<WasmFFI> for WasmFFI {
+    fn as_ref(&self) -> &WasmFFI {
+        self
+    }
+}
+
+/// look up an exported function, checking that it has the expected signature
+fn typed_export<Params: WasmParams, Results: WasmResults>(
+    store: &impl AsContext,
+    instance: &Instance,
+    name: &'static str,
+) -> Result<TypedFunc<Params, Results>, WasmFFIError> {
+    instance
+        .get_func(store, name)
+        .ok_or_else(|| WasmFFIError::missing_export(name))?
+        .typed(store)
+        .map_err(|source| WasmFFIError::InvalidExport { name, source })
+}
+
+/// like typed_export, but returns None if the export doesn't exist
+fn optional_typed_export<Params: WasmParams, Results: WasmResults>(
+    store: &impl AsContext,
+    instance: &Instance,
+    name: &'static str,
+) -> Result<Option<TypedFunc<Params, Results>>, WasmFFIError> {
+    match instance.get_func(store, name) {
+        Some(_) => Ok(Some(typed_export(store, instance, name)?)),
+        None => Ok(None),
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum WasmFFIError {
     #[error("Bincode Error: {0}")]
@@ -159,11 +427,39 @@ pub enum WasmFFIError {
     #[error("ReducerError: {0}")]
     ReducerError(ReducerError),
 
-    #[error("No export named `memory` found in Wasm instance")]
-    MemoryNotFound,
+    #[error("Reducer is missing the `{name}` export; {hint}")]
+    MissingExport {
+        name: &'static str,
+        hint: &'static str,
+    },
+
+    #[error("Reducer export `{name}` has an unexpected signature: {source}")]
+    InvalidExport {
+        name: &'static str,
+        source: wasmi::Error,
+    },
 
     #[error("Wasm FFI must be initialized before use")]
     Uninitialized,
+
+    #[error("Reducer declared an unknown mutation codec: {0}")]
+    UnknownMutationCodec(u32),
+}
+
+impl WasmFFIError {
+    fn missing_export(name: &'static str) -> Self {
+        let hint = match name {
+            "ffi_init_reducer" | "ffi_reduce" => {
+                "did you call init_reducer! in your reducer crate?"
+            }
+            "memory" | "ffi_buf_allocate" | "ffi_buf_deallocate" | "ffi_buf_len"
+            | "ffi_reactor_step" => {
+                "is this module a reducer? reducers must depend on sqlsync-reducer and be compiled as a cdylib"
+            }
+            _ => "is this module a reducer?",
+        };
+        WasmFFIError::MissingExport { name, hint }
+    }
 }
 
 impl HostError for WasmFFIError {}
@@ -180,16 +476,254 @@ impl From<Trap> for WasmFFIError {
     }
 }
 
-pub fn register_log_handler(linker: &mut Linker<WasmFFI>) -> Result<(), LinkerError> {
+/// register the host functions guests use to log, records above max_level
+/// are dropped. guests read max_level when they are initialized, so they can
+/// skip sending those records in the first place
+///
+/// the store's data may be the WasmFFI itself, or any type which wraps it
+pub fn register_log_handler<T: AsRef<WasmFFI> + 'static>(
+    linker: &mut Linker<T>,
+    max_level: log::LevelFilter,
+) -> Result<(), LinkerError> {
     linker.func_wrap(
         "env",
         "host_log",
-        |mut ctx: Caller<'_, WasmFFI>, record_ptr: FFIBufPtr| {
-            let exports = *ctx.data();
+        move |mut ctx: Caller<'_, T>, record_ptr: FFIBufPtr| {
+            let exports = *ctx.data().as_ref();
             let record: LogRecord = exports.decode(&mut ctx, record_ptr)?;
-            record.log();
+            // guests built before the level was configurable log everything
+            if record.level() <= max_level {
+                record.log();
+            }
             Ok(())
         },
     )?;
+    linker.func_wrap("env", "host_log_level", move || max_level as u32)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use wasmi::{Engine, Module, Store};
+
+    use super::*;
+
+    // exports every reducer symbol other than ffi_reduce, as if the guest
+    // forgot to call init_reducer!
+    const WAT_WITHOUT_REDUCE: &str = r#"
+        (module
+            (memory (export "memory") 1)
+            (func (export "ffi_buf_allocate") (param i32) (result i32) local.get 0)
+            (func (export "ffi_buf_deallocate") (param i32))
+            (func (export "ffi_buf_len") (param i32) (result i32) local.get 0)
+            (func (export "ffi_init_reducer"))
+            (func (export "ffi_reactor_step") (param i32) (result i32) local.get 0)
+        )
+    "#;
+
+    fn instantiate(wat: &str) -> anyhow::Result<Result<WasmFFI, WasmFFIError>> {
+        let engine = Engine::default();
+        let module = Module::new(&engine, &wat::parse_str(wat)?[..])?;
+        let mut store = Store::new(&engine, WasmFFI::uninitialized());
+        let instance = Linker::new(&engine)
+            .instantiate(&mut store, &module)?
+            .start(&mut store)?;
+        Ok(WasmFFI::initialized(&store, &instance))
+    }
+
+    #[test]
+    fn test_missing_export() -> anyhow::Result<()> {
+        let err = instantiate(WAT_WITHOUT_REDUCE)?.unwrap_err();
+        assert!(matches!(
+            err,
+            WasmFFIError::MissingExport { name: "ffi_reduce", .. }
+        ));
+        let msg = err.to_string();
+        assert!(msg.contains("`ffi_reduce`"), "{}", msg);
+        assert!(msg.contains("init_reducer!"), "{}", msg);
+
+        // an export with the wrong signature is reported separately
+        let wat = WAT_WITHOUT_REDUCE.replace(
+            r#"(func (export "ffi_init_reducer"))"#,
+            r#"(func (export "ffi_init_reducer"))
+            (func (export "ffi_reduce") (param i64))"#,
+        );
+        let err = instantiate(&wat)?.unwrap_err();
+        assert!(matches!(
+            err,
+            WasmFFIError::InvalidExport { name: "ffi_reduce", .. }
+        ));
+
+        Ok(())
+    }
+
+    // a guest which requests requests_data from ffi_reduce, with the given
+    // ffi_init_reducer
+    fn request_guest(
+        init: &str,
+        requests_data: &[u8],
+    ) -> anyhow::Result<(Store<WasmFFI>, WasmFFI)> {
+        let data: String = requests_data
+            .iter()
+            .map(|b| format!("\\{:02x}", b))
+            .collect();
+        let wat = format!(
+            r#"
+            (module
+                (memory (export "memory") 1)
+                (data (i32.const 1024) "{data}")
+                (func (export "ffi_buf_allocate") (param i32) (result i32) i32.const 4096)
+                (func (export "ffi_buf_deallocate") (param i32))
+                (func (export "ffi_buf_len") (param i32) (result i32) i32.const {len})
+                {init}
+                (func (export "ffi_reduce") (param i32) (result i32) i32.const 1024)
+                (func (export "ffi_reactor_step") (param i32) (result i32) i32.const 0))
+            "#,
+            len = requests_data.len(),
+        );
+        let engine = Engine::default();
+        let module = Module::new(&engine, &wat::parse_str(wat)?[..])?;
+        let mut store = Store::new(&engine, WasmFFI::uninitialized());
+        let instance = Linker::new(&engine)
+            .instantiate(&mut store, &module)?
+            .start(&mut store)?;
+        let mut ffi = WasmFFI::initialized(&store, &instance)?;
+        ffi.init_reducer(&mut store)?;
+        Ok((store, ffi))
+    }
+
+    #[test]
+    fn test_legacy_abi() -> anyhow::Result<()> {
+        let requests = Ok::<_, ReducerError>(Some(BTreeMap::from([(
+            3,
+            LegacyRequest::Exec {
+                sql: "INSERT INTO tasks VALUES (?)".into(),
+                params: vec![SqliteValue::Integer(1)],
+            },
+        )])));
+        let (mut store, ffi) = request_guest(
+            r#"(func (export "ffi_init_reducer"))"#,
+            &bincode::serialize(&requests)?,
+        )?;
+        assert_eq!(ffi.abi_version(), 0);
+
+        // legacy requests are upgraded
+        let requests = ffi.reduce(&mut store, &MutationContext::default(), &[])?;
+        assert!(matches!(
+            requests.as_ref().and_then(|r| r.get(&3)),
+            Some(Request::Exec { params, named_params, .. }) if params.len() == 1 && named_params.is_empty()
+        ));
+
+        // and exec responses leave out last_insert_rowid
+        let response = Ok(ExecResponse { changes: 2, last_insert_rowid: 5 });
+        let ptr = ffi.encode_exec_response(&mut store, &response)?;
+        let expected =
+            bincode::serialize(&Ok::<_, ErrorResponse>(LegacyExecResponse { changes: 2 }))?;
+        let WasmFFI::Initialized { memory, .. } = ffi else {
+            unreachable!()
+        };
+        let written = &memory.data(&store)[ptr as usize..ptr as usize + expected.len()];
+        assert_eq!(written, &expected[..]);
+
+        // current reducers report their version
+        let requests = Ok::<Requests, ReducerError>(None);
+        let (_, ffi) = request_guest(
+            &format!(
+                r#"(func (export "ffi_init_reducer") (result i32) i32.const {})"#,
+                crate::types::ABI_VERSION
+            ),
+            &bincode::serialize(&requests)?,
+        )?;
+        assert_eq!(ffi.abi_version(), crate::types::ABI_VERSION);
+
+        Ok(())
+    }
+
+    // records every guest log message the host forwards to the log crate
+    struct CaptureLogger;
+    static CAPTURED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    impl log::Log for CaptureLogger {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            CAPTURED.lock().unwrap().push(record.args().to_string());
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[test]
+    fn test_log_level() -> anyhow::Result<()> {
+        let _ = log::set_logger(&CaptureLogger);
+        log::set_max_level(log::LevelFilter::Trace);
+
+        let encode = |level: log::Level, message: &str| {
+            let record = LogRecord::from(
+                &log::Record::builder()
+                    .level(level)
+                    .args(format_args!("{}", message))
+                    .build(),
+            );
+            bincode::serialize(&record)
+                .unwrap()
+                .iter()
+                .map(|b| format!("\\{:02x}", b))
+                .collect::<String>()
+        };
+        let debug = encode(log::Level::Debug, "guest debug record");
+        let warn = encode(log::Level::Warn, "guest warn record");
+
+        // a guest which logs both records on init, whether or not it
+        // respects host_log_level
+        let wat = format!(
+            r#"
+            (module
+                (import "env" "host_log" (func $host_log (param i32)))
+                (import "env" "host_log_level" (func $host_log_level (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 1024) "{debug}")
+                (data (i32.const 2048) "{warn}")
+                (func (export "ffi_buf_allocate") (param i32) (result i32) i32.const 4096)
+                (func (export "ffi_buf_deallocate") (param i32))
+                (func (export "ffi_buf_len") (param i32) (result i32)
+                    (if (i32.eq (local.get 0) (i32.const 1024))
+                        (then (return (i32.const {debug_len}))))
+                    i32.const {warn_len})
+                (func (export "ffi_init_reducer")
+                    (call $host_log (i32.const 1024))
+                    (call $host_log (i32.const 2048)))
+                (func (export "ffi_reduce") (param i32 i32) (result i32) i32.const 0)
+                (func (export "ffi_reactor_step") (param i32) (result i32) i32.const 0)
+                (func (export "max_log_level") (result i32) call $host_log_level))
+            "#,
+            debug_len = debug.len() / 3,
+            warn_len = warn.len() / 3,
+        );
+
+        let engine = Engine::default();
+        let module = Module::new(&engine, &wat::parse_str(wat)?[..])?;
+        let mut linker = Linker::new(&engine);
+        register_log_handler(&mut linker, log::LevelFilter::Warn)?;
+        let mut store = Store::new(&engine, WasmFFI::uninitialized());
+        let instance = linker.instantiate(&mut store, &module)?.start(&mut store)?;
+        let mut ffi = WasmFFI::initialized(&store, &instance)?;
+        (*store.data_mut()) = ffi;
+        ffi.init_reducer(&mut store)?;
+
+        let max_log_level = instance
+            .get_typed_func::<(), u32>(&store, "max_log_level")?
+            .call(&mut store, ())?;
+        assert_eq!(max_log_level, log::LevelFilter::Warn as u32);
+
+        let captured = CAPTURED.lock().unwrap();
+        assert!(captured.contains(&"guest warn record".to_string()));
+        assert!(!captured.contains(&"guest debug record".to_string()));
+
+        Ok(())
+    }
+}
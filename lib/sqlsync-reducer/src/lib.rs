@@ -1,3 +1,8 @@
+// lets code generated by sqlsync_mutation refer to this crate by name, even
+// from within it
+extern crate self as sqlsync_reducer;
+
+pub mod mutation;
 pub mod types;
 
 #[cfg(feature = "guest")]
@@ -8,3 +13,8 @@ pub mod guest_ffi;
 
 #[cfg(feature = "host")]
 pub mod host_ffi;
+
+pub use mutation::sqlsync_mutation;
+
+#[doc(hidden)]
+pub use serde;
@@ -0,0 +1,188 @@
+use serde::{
+    de::{DeserializeOwned, IgnoredAny},
+    Serialize,
+};
+use thiserror::Error;
+
+/// sqlsync_mutation derives serde's Serialize and Deserialize for a mutation
+/// enum, and generates encode and decode methods along with a constructor
+/// for each variant. define the enum in a crate shared by the reducer and
+/// its clients, so they can't disagree on how mutations are encoded
+///
+/// mutations are encoded with bincode unless another MutationCodec is
+/// chosen with #[sqlsync_mutation(codec = Json)], the codec is available as
+/// Mutation::CODEC
+///
+/// ```
+/// use sqlsync_reducer::sqlsync_mutation;
+///
+/// #[sqlsync_mutation]
+/// #[derive(Debug, PartialEq)]
+/// pub enum Mutation {
+///     InitSchema,
+///     CreateTask { id: String, description: String },
+///     DeleteTask(String),
+/// }
+///
+/// // the client encodes mutations
+/// let mutation = Mutation::create_task("1".into(), "write docs".into());
+/// let bytes = mutation.encode().unwrap();
+///
+/// // and the reducer decodes them
+/// assert_eq!(Mutation::decode(&bytes).unwrap(), mutation);
+/// ```
+pub use sqlsync_reducer_macros::sqlsync_mutation;
+
+/// MutationCodec is the encoding a reducer expects its mutations to use.
+/// reducers declare it with init_reducer!(reducer, codec = Json), which lets
+/// the host reject mutations that don't match before running the reducer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MutationCodec {
+    Bincode = 1,
+    Json = 2,
+}
+
+impl MutationCodec {
+    pub fn from_u32(codec: u32) -> Option<Self> {
+        match codec {
+            1 => Some(Self::Bincode),
+            2 => Some(Self::Json),
+            _ => None,
+        }
+    }
+
+    pub fn encode<M: Serialize>(self, mutation: &M) -> Result<Vec<u8>, MutationError> {
+        Ok(match self {
+            Self::Bincode => bincode::serialize(mutation)?,
+            Self::Json => serde_json::to_vec(mutation)?,
+        })
+    }
+
+    pub fn decode<M: DeserializeOwned>(self, mutation: &[u8]) -> Result<M, MutationError> {
+        Ok(match self {
+            Self::Bincode => bincode::deserialize(mutation)?,
+            Self::Json => serde_json::from_slice(mutation)?,
+        })
+    }
+
+    /// check that mutation is well formed without knowing its type. bincode
+    /// isn't self describing, so every mutation is assumed to be valid
+    pub fn validate(self, mutation: &[u8]) -> Result<(), MutationError> {
+        match self {
+            Self::Bincode => Ok(()),
+            Self::Json => {
+                serde_json::from_slice::<IgnoredAny>(mutation)?;
+                Ok(())
+            }
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum MutationError {
+    #[error("invalid bincode mutation: {0}")]
+    Bincode(#[from] bincode::Error),
+
+    #[error("invalid json mutation: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    // shared is a crate used by both the reducer and its clients
+    mod shared {
+        use crate::sqlsync_mutation;
+
+        #[sqlsync_mutation]
+        #[derive(Debug, Clone, PartialEq)]
+        pub enum Mutation {
+            InitSchema,
+            CreateTask { id: String, description: String },
+            SetCompleted(String, bool),
+            Type,
+        }
+
+        #[sqlsync_mutation(codec = Json)]
+        #[derive(Debug, PartialEq)]
+        #[serde(tag = "tag")]
+        pub enum JsonMutation {
+            CreateTask { id: String },
+        }
+    }
+
+    mod client {
+        use super::shared::Mutation;
+
+        pub fn create_task(id: &str) -> Vec<u8> {
+            Mutation::create_task(id.into(), format!("task {}", id))
+                .encode()
+                .unwrap()
+        }
+    }
+
+    mod guest {
+        use super::shared::Mutation;
+        use crate::types::ReducerError;
+
+        pub fn reducer(mutation: Vec<u8>) -> Result<Mutation, ReducerError> {
+            Ok(Mutation::decode(&mutation)?)
+        }
+    }
+
+    use super::*;
+    use shared::{JsonMutation, Mutation};
+
+    #[test]
+    fn test_mutation_roundtrip() {
+        assert_eq!(
+            guest::reducer(client::create_task("1")).unwrap(),
+            Mutation::CreateTask {
+                id: "1".into(),
+                description: "task 1".into()
+            }
+        );
+
+        for mutation in [
+            Mutation::init_schema(),
+            Mutation::set_completed("1".into(), true),
+            Mutation::r#type(),
+        ] {
+            let encoded = mutation.encode().unwrap();
+            assert_eq!(guest::reducer(encoded).unwrap(), mutation);
+        }
+
+        // garbage is reported to the reducer as an error
+        assert!(guest::reducer(vec![42, 0, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn test_codecs() {
+        assert_eq!(Mutation::CODEC, MutationCodec::Bincode);
+        assert_eq!(JsonMutation::CODEC, MutationCodec::Json);
+        for codec in [MutationCodec::Bincode, MutationCodec::Json] {
+            assert_eq!(MutationCodec::from_u32(codec as u32), Some(codec));
+        }
+        assert_eq!(MutationCodec::from_u32(0), None);
+
+        let mutation = JsonMutation::create_task("1".into());
+        let encoded = mutation.encode().unwrap();
+        assert_eq!(encoded, br#"{"tag":"CreateTask","id":"1"}"#);
+        assert_eq!(JsonMutation::decode(&encoded).unwrap(), mutation);
+        MutationCodec::Json.validate(&encoded).unwrap();
+
+        // bincode can't be validated without knowing the mutation's type, but
+        // a json reducer rejects it
+        let bincode = Mutation::create_task("1".into(), "a".into())
+            .encode()
+            .unwrap();
+        MutationCodec::Bincode.validate(&bincode).unwrap();
+        assert!(matches!(
+            MutationCodec::Json.validate(&bincode),
+            Err(MutationError::Json(_))
+        ));
+        assert!(matches!(
+            Mutation::decode(&encoded),
+            Err(MutationError::Bincode(_))
+        ));
+    }
+}
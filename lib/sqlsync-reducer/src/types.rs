@@ -11,6 +11,12 @@ use log::Level;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+/// ABI_VERSION is returned by ffi_init_reducer, so the host can speak to
+/// reducers built against older versions of this crate. reducers which
+/// return nothing from ffi_init_reducer are at version 0, which predates
+/// named parameters and last_insert_rowid
+pub const ABI_VERSION: u32 = 1;
+
 pub type RequestId = u32;
 
 pub type Requests = Option<BTreeMap<RequestId, Request>>;
@@ -40,15 +46,38 @@ impl FromIterator<SqliteValue> for Row {
     }
 }
 
+/// MutationContext describes where a mutation came from, reducers which
+/// call init_reducer!(reducer, context) receive it along with the mutation
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct MutationContext {
+    // the journal id of the timeline which authored the mutation, this is
+    // empty if the mutation wasn't applied from a timeline
+    pub timeline_id: Vec<u8>,
+    // the lsn of the mutation's timeline entry
+    pub lsn: u64,
+    // the time the mutation was created in unix milliseconds, this matches
+    // the time seen by datetime('now') while the mutation is applied
+    pub mutation_time: i64,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Request {
+    // named_params is empty unless the request binds parameters by name, in
+    // which case params is empty
     Query {
         sql: String,
         params: Vec<SqliteValue>,
+        named_params: BTreeMap<String, SqliteValue>,
     },
     Exec {
         sql: String,
         params: Vec<SqliteValue>,
+        named_params: BTreeMap<String, SqliteValue>,
+    },
+    // runs every statement in sql, responding with an ExecResponse which
+    // counts the changes made by the whole batch
+    ExecBatch {
+        sql: String,
     },
 }
 
@@ -61,16 +90,72 @@ pub struct QueryResponse {
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ExecResponse {
     pub changes: usize,
+    // the rowid of the most recent successful insert on the connection
+    pub last_insert_rowid: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Error)]
+#[error("SQLite Error({code}): {message}")]
+pub struct SqliteError {
+    // the extended result code, i.e. 2067 for SQLITE_CONSTRAINT_UNIQUE
+    pub code: i32,
+    pub message: String,
 }
 
 #[derive(Serialize, Deserialize, Debug, Error)]
 pub enum ErrorResponse {
-    #[error("SQLite Error({code}): {message}")]
-    SqliteError { code: i32, message: String },
+    #[error(transparent)]
+    SqliteError(SqliteError),
     #[error("Unknown: {0}")]
     Unknown(String),
 }
 
+/// PanicRecord describes a panic in the guest, the host retrieves it after
+/// the reducer traps so it can report where the reducer panicked
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PanicRecord {
+    pub message: String,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+    pub column: Option<u32>,
+}
+
+impl From<&panic::PanicInfo<'_>> for PanicRecord {
+    fn from(info: &panic::PanicInfo) -> Self {
+        let payload = info.payload();
+        let message = match payload.downcast_ref::<&str>() {
+            Some(message) => message.to_string(),
+            None => match payload.downcast_ref::<String>() {
+                Some(message) => message.clone(),
+                None => info.to_string(),
+            },
+        };
+        let loc = info.location();
+        PanicRecord {
+            message,
+            file: loc.map(|l| l.file().to_string()),
+            line: loc.map(|l| l.line()),
+            column: loc.map(|l| l.column()),
+        }
+    }
+}
+
+impl Display for PanicRecord {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        if let Some(file) = &self.file {
+            write!(f, " at {}", file)?;
+            if let Some(line) = self.line {
+                write!(f, ":{}", line)?;
+                if let Some(column) = self.column {
+                    write!(f, ":{}", column)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct LogRecord {
     level: String,
@@ -103,13 +188,18 @@ impl From<&log::Record<'_>> for LogRecord {
 }
 
 impl LogRecord {
+    pub fn level(&self) -> Level {
+        Level::from_str(&self.level).unwrap_or(Level::Error)
+    }
+
     pub fn log(&self) {
         log::logger().log(
             &log::Record::builder()
-                .level(Level::from_str(&self.level).unwrap_or(Level::Error))
+                .level(self.level())
                 .file(self.file.as_deref())
                 .line(self.line)
                 .module_path(Some("wasm guest"))
+                .target("sqlsync::reducer::guest")
                 .args(format_args!("{}", self.message))
                 .build(),
         );
@@ -124,6 +214,10 @@ pub enum ReducerError {
     },
 
     Unknown(String),
+
+    // appended so reducers built before sqlite errors were kept structured
+    // still encode Unknown the same way
+    Sqlite(SqliteError),
 }
 
 impl Display for ReducerError {
@@ -132,8 +226,16 @@ impl Display for ReducerError {
     }
 }
 
-impl<E: Error> From<E> for ReducerError {
+impl<E: Error + 'static> From<E> for ReducerError {
     fn from(e: E) -> Self {
+        // keep sqlite errors structured so reducers can match on their code
+        let err: &dyn Error = &e;
+        if let Some(ErrorResponse::SqliteError(err)) = err.downcast_ref() {
+            return Self::Sqlite(err.clone());
+        }
+        if let Some(err) = err.downcast_ref::<SqliteError>() {
+            return Self::Sqlite(err.clone());
+        }
         Self::Unknown(e.to_string())
     }
 }
@@ -1,7 +1,7 @@
 // this is needed due to an issue with Tsify emitting non-snake_case names without the correct annotations
 #![allow(non_snake_case)]
 
-use std::{collections::HashMap, fmt::Debug};
+use std::collections::HashMap;
 
 use anyhow::anyhow;
 use futures::{
@@ -9,7 +9,7 @@ use futures::{
     SinkExt,
 };
 use serde::{Deserialize, Serialize};
-use sqlsync::JournalId;
+use sqlsync::{JournalId, WasmReducer};
 use tsify::{declare, Tsify};
 use wasm_bindgen::{prelude::wasm_bindgen, JsValue};
 
@@ -18,7 +18,7 @@ use crate::{
     net::ConnectionStatus,
     reactive::QueryKey,
     sql::SqlValue,
-    utils::{fetch_reducer, WasmError, WasmResult},
+    utils::{resolve_reducer, WasmError, WasmResult},
 };
 
 #[wasm_bindgen(typescript_custom_section)]
@@ -35,6 +35,18 @@ interface WorkerApi {
 }
 "#;
 
+// by default storage changes are handled immediately
+const DEFAULT_STORAGE_DEBOUNCE_MS: u32 = 0;
+
+// by default each mutation is committed to the timeline immediately
+const DEFAULT_COMMIT_WINDOW_MS: u32 = 0;
+
+// by default streamed queries deliver this many rows per chunk
+pub const DEFAULT_QUERY_CHUNK_ROWS: u32 = 1000;
+
+// by default WaitForSync fails if the document hasn't synced within 30s
+pub const DEFAULT_WAIT_FOR_SYNC_TIMEOUT_MS: u32 = 30_000;
+
 pub type PortId = u32;
 pub type HandlerId = u32;
 
@@ -86,11 +98,8 @@ impl HostToWorkerMsg {
         WorkerToHostMsg::Reply { handler_id: self.handler_id, reply }
     }
 
-    pub fn reply_err<E: Debug>(&self, err: E) -> WorkerToHostMsg {
-        WorkerToHostMsg::Reply {
-            handler_id: self.handler_id,
-            reply: DocReply::Err { err: format!("{:?}", err) },
-        }
+    pub fn reply_err(&self, err: WasmError) -> WorkerToHostMsg {
+        self.reply(DocReply::err(err))
     }
 }
 
@@ -99,16 +108,73 @@ impl HostToWorkerMsg {
 #[tsify(from_wasm_abi)]
 pub enum DocRequest {
     Open {
-        reducer_url: String,
+        /// the url to fetch the reducer from, ignored if reducer_bytes is set
+        #[serde(default)]
+        #[tsify(optional)]
+        reducer_url: Option<String>,
+        /// the reducer's wasm module, allows the worker to skip fetching it
+        #[serde(default, with = "serde_bytes")]
+        #[tsify(optional, type = "Uint8Array")]
+        reducer_bytes: Option<Vec<u8>>,
+        /// coalesce storage changes which occur within this many milliseconds
+        /// of the first into a single subscription refresh, e.g. 16 refreshes
+        /// subscriptions at most once per frame
+        #[serde(default)]
+        #[tsify(optional)]
+        storage_debounce_ms: Option<u32>,
+        /// buffer mutations which occur within this many milliseconds of the
+        /// first and sync them to the coordinator together
+        #[serde(default)]
+        #[tsify(optional)]
+        commit_window_ms: Option<u32>,
+        /// keep the document and its timeline in OPFS so that the client
+        /// keeps its timeline id and unsent mutations across reloads,
+        /// defaults to false
+        #[serde(default)]
+        #[tsify(optional)]
+        persist: Option<bool>,
     },
     Query {
         sql: String,
         params: Vec<SqlValue>,
+        /// allows the query to be cancelled with CancelQuery, keyed queries
+        /// yield to other messages before they run
+        #[serde(default)]
+        #[tsify(optional)]
+        key: Option<QueryKey>,
+    },
+    /// cancel a keyed Query or a QueryStream sent from the same port. a
+    /// cancelled query fails with SQLITE_INTERRUPT, a cancelled stream stops
+    /// sending chunks
+    CancelQuery {
+        key: QueryKey,
+    },
+    /// return the plan sqlite would use to run a query without running it,
+    /// which is useful for checking whether a query uses an index
+    Explain {
+        sql: String,
+        params: Vec<SqlValue>,
+    },
+    /// run a query, delivering its results as a series of QueryChunk events
+    /// so that large result sets don't block the worker
+    QueryStream {
+        key: QueryKey,
+        sql: String,
+        params: Vec<SqlValue>,
+        #[serde(default)]
+        #[tsify(optional)]
+        chunk_rows: Option<u32>,
     },
     QuerySubscribe {
         key: QueryKey,
         sql: String,
         params: Vec<SqlValue>,
+        /// a column which uniquely and stably identifies each row, such as
+        /// a primary key. if set, changes after the first result are sent as
+        /// SubscriptionDelta events rather than re-sending every row
+        #[serde(default)]
+        #[tsify(optional)]
+        key_column: Option<String>,
     },
     QueryUnsubscribe {
         key: QueryKey,
@@ -119,6 +185,15 @@ pub enum DocRequest {
         mutation: Vec<u8>,
     },
     RefreshConnectionStatus,
+    /// reply once the coordinator has applied the mutation at lsn, as
+    /// returned by Mutate, and queries observe the result. fails if the
+    /// mutation is rejected, the connection is disabled, or timeout_ms elapses
+    WaitForSync {
+        lsn: u64,
+        #[serde(default)]
+        #[tsify(optional)]
+        timeout_ms: Option<u32>,
+    },
     SetConnectionEnabled {
         enabled: bool,
     },
@@ -143,15 +218,60 @@ pub enum WorkerToHostMsg {
 #[tsify(into_wasm_abi)]
 pub enum DocReply {
     Ack,
+    /// the lsn of the timeline entry holding the mutation, see WaitForSync
+    Mutated {
+        lsn: u64,
+    },
     RecordSet {
         columns: Vec<String>,
         rows: Vec<Vec<SqlValue>>,
     },
+    QueryPlan {
+        steps: Vec<QueryPlanStep>,
+    },
     Err {
-        err: String,
+        message: String,
+        /// the primary sqlite result code, if sqlite caused the error
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[tsify(optional)]
+        sqlite_code: Option<i32>,
+        /// the extended sqlite result code, if sqlite caused the error
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[tsify(optional)]
+        sqlite_extended_code: Option<i32>,
     },
 }
 
+impl DocReply {
+    pub fn err(err: WasmError) -> Self {
+        let sqlite_codes = err.sqlite_codes();
+        DocReply::Err {
+            message: format!("{:?}", err),
+            sqlite_code: sqlite_codes.map(|(code, _)| code),
+            sqlite_extended_code: sqlite_codes.map(|(_, extended_code)| extended_code),
+        }
+    }
+}
+
+/// a step of sqlite's plan for a query, see DocRequest::Explain
+#[derive(Debug, Serialize, Tsify)]
+pub struct QueryPlanStep {
+    pub id: i64,
+    /// the id of the step this step is nested under, or 0
+    pub parent: i64,
+    pub detail: String,
+}
+
+impl From<sqlsync::QueryPlanStep> for QueryPlanStep {
+    fn from(step: sqlsync::QueryPlanStep) -> Self {
+        Self {
+            id: step.id,
+            parent: step.parent,
+            detail: step.detail,
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Tsify, Clone)]
 #[serde(tag = "tag", rename_all_fields = "camelCase")]
 #[tsify(into_wasm_abi)]
@@ -164,10 +284,36 @@ pub enum DocEvent {
         columns: Vec<String>,
         rows: Vec<Vec<SqlValue>>,
     },
+    /// sent instead of SubscriptionChanged for subscriptions with a key
+    /// column, once subscribers have received the full result
+    SubscriptionDelta {
+        key: QueryKey,
+        columns: Vec<String>,
+        /// rows which were added or changed since the last result
+        upserts: Vec<Vec<SqlValue>>,
+        /// the key column of rows which were removed
+        deletes: Vec<SqlValue>,
+        /// the key column of every row in result order, omitted if the
+        /// remaining rows kept their order and none were added
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[tsify(optional)]
+        order: Option<Vec<SqlValue>>,
+    },
     SubscriptionErr {
         key: QueryKey,
         err: String,
     },
+    QueryChunk {
+        key: QueryKey,
+        columns: Vec<String>,
+        rows: Vec<Vec<SqlValue>>,
+        /// true for the last chunk of the stream
+        done: bool,
+    },
+    QueryStreamErr {
+        key: QueryKey,
+        err: String,
+    },
 }
 
 #[wasm_bindgen]
@@ -193,16 +339,32 @@ impl WorkerApi {
         let mut msg: HostToWorkerMsg = serde_wasm_bindgen::from_value(msg)?;
         log::info!("handle: {:?}", msg);
 
-        match &msg.req {
-            DocRequest::Open { reducer_url } => {
+        match &mut msg.req {
+            DocRequest::Open {
+                reducer_url,
+                reducer_bytes,
+                storage_debounce_ms,
+                commit_window_ms,
+                persist,
+            } => {
                 if let Some(inbox) = self.inboxes.get_mut(&msg.doc_id) {
                     // doc is already open
                     // request a connection status update from the doc
                     msg.req = DocRequest::RefreshConnectionStatus;
                     inbox.send(msg).await?;
                 } else {
-                    // open the doc
-                    self.spawn_doc_task(msg.doc_id, reducer_url).await?;
+                    // open the doc, only fetching the reducer if it wasn't provided
+                    let (reducer, digest) =
+                        resolve_reducer(reducer_bytes.take(), reducer_url.as_deref()).await?;
+                    self.spawn_doc_task(
+                        msg.doc_id,
+                        reducer,
+                        &digest,
+                        storage_debounce_ms.unwrap_or(DEFAULT_STORAGE_DEBOUNCE_MS),
+                        commit_window_ms.unwrap_or(DEFAULT_COMMIT_WINDOW_MS),
+                        persist.unwrap_or(false),
+                    )
+                    .await?;
                     let _ = self.ports.send_one(msg.port_id, msg.reply(DocReply::Ack));
                 }
             }
@@ -224,22 +386,34 @@ impl WorkerApi {
     async fn spawn_doc_task(
         &mut self,
         doc_id: JournalId,
-        reducer_url: &str,
+        reducer: WasmReducer,
+        digest: &[u8],
+        storage_debounce_ms: u32,
+        commit_window_ms: u32,
+        persist: bool,
     ) -> Result<(), WasmError> {
-        let (reducer, digest) = fetch_reducer(reducer_url).await?;
-
         let doc_url = self.coordinator_url.as_ref().map(|url| {
             format!(
                 "{}/doc/{}?reducer={}",
                 url,
                 doc_id.to_base58(),
-                bs58::encode(&digest).into_string()
+                bs58::encode(digest).into_string()
             )
         });
 
         let (tx, rx) = mpsc::unbounded();
 
-        let task = DocTask::new(doc_id, doc_url, reducer, rx, self.ports.clone())?;
+        let task = DocTask::new(
+            doc_id,
+            doc_url,
+            reducer,
+            storage_debounce_ms,
+            commit_window_ms,
+            persist,
+            rx,
+            self.ports.clone(),
+        )
+        .await?;
 
         wasm_bindgen_futures::spawn_local(task.into_task());
 
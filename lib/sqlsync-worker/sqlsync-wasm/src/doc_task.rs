@@ -1,64 +1,96 @@
+use std::collections::{HashMap, VecDeque};
+
 use anyhow::anyhow;
 use futures::{channel::mpsc, select, FutureExt, StreamExt};
-use rand::thread_rng;
 use sqlsync::{
-    local::LocalDocument, sqlite::params_from_iter, JournalId, MemoryJournal, WasmReducer,
+    explain_query_plan,
+    local::{DocumentSignals, LocalDocument, Signal as _, WaiterId},
+    logging,
+    sqlite::params_from_iter,
+    JournalId, QueryCancellation, QueryStream, WasmReducer,
 };
 
 use crate::{
-    api::{DocEvent, DocReply, DocRequest, HostToWorkerMsg, PortRouter, WorkerToHostMsg},
-    net::{ConnectionTask, CoordinatorClient},
-    reactive::ReactiveQueries,
+    api::{
+        DocEvent, DocReply, DocRequest, HandlerId, HostToWorkerMsg, PortId, PortRouter,
+        WorkerToHostMsg, DEFAULT_QUERY_CHUNK_ROWS, DEFAULT_WAIT_FOR_SYNC_TIMEOUT_MS,
+    },
+    net::{ConnectionStatus, ConnectionTask, CoordinatorClient},
+    opfs::{open_doc_journals, OpfsJournal},
+    reactive::{QueryStreams, ReactiveQueries},
     signal::{SignalEmitter, SignalRouter},
     sql::SqlValue,
-    utils::{WasmError, WasmResult},
+    utils::{Debounce, WasmError, WasmResult},
 };
 
+// how often pending WaitForSync requests are checked for timeouts
+const WAITER_TIMEOUT_CHECK_MS: u32 = 250;
+
 #[derive(Clone, Copy, Hash, PartialEq, Eq)]
 enum Signal {
     StorageChanged,
     TimelineChanged,
     CanRebase,
+    HasOutputs,
+    WaitersFinished,
     HasDirtyQueries,
+    HasPendingChunks,
+    HasPendingQueries,
     ConnectionStateChanged,
 }
 
 pub struct DocTask {
-    doc: LocalDocument<MemoryJournal, SignalEmitter<Signal>>,
+    doc: LocalDocument<OpfsJournal, SignalEmitter<Signal>>,
     inbox: mpsc::UnboundedReceiver<HostToWorkerMsg>,
     signals: SignalRouter<Signal>,
     ports: PortRouter,
     queries: ReactiveQueries<SignalEmitter<Signal>>,
+    streams: QueryStreams<SignalEmitter<Signal>>,
     coordinator_client: CoordinatorClient<SignalEmitter<Signal>>,
+
+    // coalesces bursts of storage changes into a single subscription refresh,
+    // the window starts at the first change so a continuous burst (such as
+    // dragging a slider) still refreshes once per window
+    storage_debounce: Debounce,
+
+    // bounds how long mutations are buffered before being committed to the
+    // timeline, the window starts at the first buffered mutation
+    commit_window: Debounce,
+
+    // WaitForSync requests by the doc's waiter id, they are answered once
+    // the waiter finishes. waiter_timeouts runs while any are pending
+    sync_waiters: HashMap<WaiterId, (PortId, HandlerId)>,
+    waiter_timeouts: Debounce,
+
+    // keyed Query requests are run from the loop rather than as they arrive,
+    // so that a CancelQuery sent after one can be received before it runs
+    pending_queries: VecDeque<(HostToWorkerMsg, QueryCancellation)>,
+    has_pending_queries: SignalEmitter<Signal>,
 }
 
 impl DocTask {
-    pub fn new(
+    pub async fn new(
         doc_id: JournalId,
         doc_url: Option<String>,
         reducer: WasmReducer,
+        storage_debounce_ms: u32,
+        commit_window_ms: u32,
+        persist: bool,
         inbox: mpsc::UnboundedReceiver<HostToWorkerMsg>,
         ports: PortRouter,
     ) -> WasmResult<Self> {
-        // TODO: use persisted timeline id when we start persisting the journal to OPFS
-        let timeline_id = JournalId::new128(&mut thread_rng());
-
         let signals = SignalRouter::new();
 
-        let storage = MemoryJournal::open(doc_id)?;
-        let timeline = MemoryJournal::open(timeline_id)?;
-        let doc = LocalDocument::open(
-            storage,
-            timeline,
-            reducer,
-            signals.emitter(Signal::StorageChanged),
-            signals.emitter(Signal::TimelineChanged),
-            signals.emitter(Signal::CanRebase),
-        )?;
+        let (storage, timeline) = open_doc_journals(doc_id, persist).await?;
+        let doc = open_doc(storage, timeline, reducer, &signals)?;
+
+        let commit_window = Debounce::new(commit_window_ms);
 
         let queries = ReactiveQueries::new(signals.emitter(Signal::HasDirtyQueries));
+        let streams = QueryStreams::new(signals.emitter(Signal::HasPendingChunks));
         let coordinator_client =
             CoordinatorClient::new(doc_url, signals.emitter(Signal::ConnectionStateChanged));
+        let has_pending_queries = signals.emitter(Signal::HasPendingQueries);
 
         Ok(Self {
             doc,
@@ -66,7 +98,14 @@ impl DocTask {
             signals,
             ports,
             queries,
+            streams,
             coordinator_client,
+            storage_debounce: Debounce::new(storage_debounce_ms),
+            commit_window,
+            sync_waiters: HashMap::new(),
+            waiter_timeouts: Debounce::new(WAITER_TIMEOUT_CHECK_MS),
+            pending_queries: VecDeque::new(),
+            has_pending_queries,
         })
     }
 
@@ -95,6 +134,20 @@ impl DocTask {
                 msg = self.inbox.select_next_some() => {
                     self.handle_message(msg).await;
                 },
+                _ = self.storage_debounce.wait().fuse() => {
+                    self.handle_storage_changed_or_panic();
+                },
+                _ = self.commit_window.wait().fuse() => {
+                    if let Err(e) = self.doc.commit_batch() {
+                        panic!("failed to commit mutations to the timeline: {:?}", e);
+                    }
+                },
+                _ = self.waiter_timeouts.wait().fuse() => {
+                    self.doc.expire_waiters();
+                    if self.doc.has_waiters() {
+                        self.waiter_timeouts.trigger();
+                    }
+                },
             }
         }
     }
@@ -103,12 +156,29 @@ impl DocTask {
         for signal in signals {
             match signal {
                 Signal::ConnectionStateChanged => self.handle_connection_state_changed(),
+                Signal::WaitersFinished => self.handle_finished_waiters(),
                 Signal::TimelineChanged => self.handle_timeline_changed().await,
                 Signal::HasDirtyQueries => self.handle_dirty_queries(),
+                Signal::HasPendingChunks => self.handle_pending_chunks(),
+                Signal::HasPendingQueries => self.handle_pending_query(),
 
                 Signal::StorageChanged => {
-                    if let Err(e) = self.handle_storage_changed() {
-                        panic!("failed to handle storage changes, the database is probably corrupted: {:?}", e);
+                    // storage accumulates changes until we ask for them, so
+                    // later changes in the window are picked up when it closes
+                    if self.storage_debounce.trigger() {
+                        self.handle_storage_changed_or_panic();
+                    }
+                }
+
+                Signal::HasOutputs => {
+                    // mutation outputs aren't exposed to the host yet, so
+                    // drain them to keep them from accumulating
+                    for output in self.doc.take_outputs() {
+                        log::debug!(
+                            "mutation at lsn {} returned {:?}",
+                            output.lsn,
+                            output.output
+                        );
                     }
                 }
 
@@ -122,15 +192,42 @@ impl DocTask {
     }
 
     fn handle_connection_state_changed(&mut self) {
+        // a disabled connection won't sync until it's enabled again
+        if self.coordinator_client.status() == ConnectionStatus::Disabled {
+            self.doc.cancel_waiters();
+        }
         self.ports.send_all(WorkerToHostMsg::Event {
             doc_id: self.doc.doc_id(),
             evt: DocEvent::ConnectionStatus { status: self.coordinator_client.status() },
         });
     }
 
+    fn handle_finished_waiters(&mut self) {
+        for (id, result) in self.doc.take_finished_waiters() {
+            if let Some((port_id, handler_id)) = self.sync_waiters.remove(&id) {
+                let reply = match result {
+                    Ok(()) => DocReply::Ack,
+                    Err(err) => DocReply::err(err.into()),
+                };
+                let _ = self
+                    .ports
+                    .send_one(port_id, WorkerToHostMsg::Reply { handler_id, reply });
+            }
+        }
+    }
+
+    fn handle_storage_changed_or_panic(&mut self) {
+        if let Err(e) = self.handle_storage_changed() {
+            panic!(
+                "failed to handle storage changes, the database is probably corrupted: {:?}",
+                e
+            );
+        }
+    }
+
     fn handle_storage_changed(&mut self) -> anyhow::Result<()> {
         let changes = self.doc.storage_changes()?;
-        log::debug!("storage changed: {:?}", changes);
+        log::debug!(target: logging::STORAGE, "storage changed: {:?}", changes);
         self.queries.handle_storage_change(&changes);
         Ok(())
     }
@@ -151,18 +248,27 @@ impl DocTask {
                 }
                 Ok::<_, WasmError>(out)
             });
+            let result = result.and_then(|(columns, rows)| query.result_event(columns, rows));
 
             let msg = match result {
-                Ok((columns, rows)) => WorkerToHostMsg::Event {
-                    doc_id: self.doc.doc_id(),
-                    evt: DocEvent::SubscriptionChanged {
-                        key: query.query_key().clone(),
-                        columns,
-                        rows,
-                    },
-                },
+                Ok(Some(evt)) => WorkerToHostMsg::Event { doc_id: self.doc.doc_id(), evt },
+                // a keyed subscription which didn't change
+                Ok(None) => return,
                 Err(err) => {
                     query.mark_error();
+
+                    // only report the first error in a streak, the query backs
+                    // off and will report again once it recovers
+                    if query.consecutive_errors() > 1 {
+                        log::debug!(
+                            "subscription {} failed {} times in a row: {}",
+                            query.query_key(),
+                            query.consecutive_errors(),
+                            err
+                        );
+                        return;
+                    }
+
                     WorkerToHostMsg::Event {
                         doc_id: self.doc.doc_id(),
                         evt: DocEvent::SubscriptionErr {
@@ -179,8 +285,90 @@ impl DocTask {
         }
     }
 
-    async fn handle_message(&mut self, msg: HostToWorkerMsg) {
-        match self.process_request(&msg).await {
+    /// send the next chunk of a single streamed query, the signal is emitted
+    /// again while chunks remain so that we yield to the loop in between
+    fn handle_pending_chunks(&mut self) {
+        if let Some(((port, key), mut stream)) = self.streams.next_stream() {
+            let result = stream.next_chunk(|columns, row| {
+                let mut out = Vec::with_capacity(columns.len());
+                for i in 0..columns.len() {
+                    let val: SqlValue = row.get_ref(i)?.into();
+                    out.push(val);
+                }
+                Ok::<_, WasmError>(out)
+            });
+
+            let evt = match result {
+                Ok((columns, rows, done)) => {
+                    DocEvent::QueryChunk { key: key.clone(), columns, rows, done }
+                }
+                Err(err) => {
+                    // the stream is dropped rather than resumed
+                    let evt = DocEvent::QueryStreamErr { key, err: err.to_string() };
+                    let msg = WorkerToHostMsg::Event { doc_id: self.doc.doc_id(), evt };
+                    if let Err(err) = self.ports.send_one(port, msg) {
+                        self.streams.cancel_all(&err.missing_ports());
+                    }
+                    return;
+                }
+            };
+
+            let msg = WorkerToHostMsg::Event { doc_id: self.doc.doc_id(), evt };
+            match self.ports.send_one(port, msg) {
+                Ok(()) => self.streams.resume((port, key), stream),
+                Err(err) => self.streams.cancel_all(&err.missing_ports()),
+            }
+        }
+    }
+
+    /// run the next keyed query, the signal is emitted again while queries
+    /// remain so that we yield to the loop in between
+    fn handle_pending_query(&mut self) {
+        if let Some((msg, cancellation)) = self.pending_queries.pop_front() {
+            if !self.pending_queries.is_empty() {
+                self.has_pending_queries.emit();
+            }
+            self.run_pending_query(msg, cancellation);
+        }
+    }
+
+    fn run_pending_query(&self, msg: HostToWorkerMsg, cancellation: QueryCancellation) {
+        let DocRequest::Query { sql, params, .. } = &msg.req else {
+            unreachable!("only queries are queued")
+        };
+        let result = self.query(sql, params, &cancellation);
+        self.reply(&msg, result);
+    }
+
+    fn query(
+        &self,
+        sql: &str,
+        params: &[SqlValue],
+        cancellation: &QueryCancellation,
+    ) -> WasmResult<DocReply> {
+        self.doc.query_cancellable(cancellation, |conn| {
+            let params = params_from_iter(params.iter());
+            let mut stmt = conn.prepare(sql)?;
+
+            let columns: Vec<_> = stmt.column_names().iter().map(|&s| s.to_owned()).collect();
+
+            let rows = stmt
+                .query_and_then(params, |row| {
+                    let mut out = Vec::with_capacity(columns.len());
+                    for i in 0..columns.len() {
+                        let val: SqlValue = row.get_ref(i)?.into();
+                        out.push(val);
+                    }
+                    Ok::<_, WasmError>(out)
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+
+            Ok::<_, WasmError>(DocReply::RecordSet { columns, rows })
+        })
+    }
+
+    fn reply(&self, msg: &HostToWorkerMsg, result: WasmResult<DocReply>) {
+        match result {
             Ok(reply) => {
                 log::info!("doc task reply: {:?}", reply);
                 let _ = self.ports.send_one(msg.port_id, msg.reply(reply));
@@ -192,34 +380,92 @@ impl DocTask {
         }
     }
 
+    async fn handle_message(&mut self, msg: HostToWorkerMsg) {
+        match msg.req {
+            DocRequest::Query { key: Some(_), .. } => {
+                let cancellation = self.doc.query_cancellation();
+                self.pending_queries.push_back((msg, cancellation));
+                self.has_pending_queries.emit();
+                return;
+            }
+            DocRequest::CancelQuery { .. } => {}
+            // queued queries run before any other request, so that they
+            // never observe the changes of a request sent after them
+            _ => {
+                while let Some((msg, cancellation)) = self.pending_queries.pop_front() {
+                    self.run_pending_query(msg, cancellation);
+                }
+            }
+        }
+
+        if let DocRequest::WaitForSync { lsn, timeout_ms } = msg.req {
+            // replied to by handle_finished_waiters once the waiter finishes
+            let timeout_ms = timeout_ms.unwrap_or(DEFAULT_WAIT_FOR_SYNC_TIMEOUT_MS);
+            match self.doc.wait_for_lsn(lsn, timeout_ms.into()) {
+                Ok(id) => {
+                    self.sync_waiters.insert(id, (msg.port_id, msg.handler_id));
+                    self.waiter_timeouts.trigger();
+                }
+                Err(err) => self.reply(&msg, Err(err.into())),
+            }
+            return;
+        }
+
+        let result = self.process_request(&msg).await;
+        self.reply(&msg, result);
+    }
+
     async fn process_request(&mut self, msg: &HostToWorkerMsg) -> WasmResult<DocReply> {
         log::info!("DocTask::process_request: {:?}", msg.req);
         match &msg.req {
             DocRequest::Open { .. } => Err(WasmError(anyhow!("doc is already open"))),
 
-            DocRequest::Query { sql, params } => self.doc.query(|conn| {
-                let params = params_from_iter(params.iter());
-                let mut stmt = conn.prepare(sql)?;
+            // keyed queries are queued by handle_message, the rest can't be
+            // cancelled and so run right away
+            DocRequest::Query { sql, params, .. } => {
+                self.query(sql, params, &self.doc.query_cancellation())
+            }
 
-                let columns: Vec<_> = stmt.column_names().iter().map(|&s| s.to_owned()).collect();
+            DocRequest::Explain { sql, params } => {
+                let steps = self
+                    .doc
+                    .query(|conn| explain_query_plan(conn, sql, params_from_iter(params.iter())))?;
+                Ok(DocReply::QueryPlan {
+                    steps: steps.into_iter().map(Into::into).collect(),
+                })
+            }
 
-                let rows = stmt
-                    .query_and_then(params, |row| {
-                        let mut out = Vec::with_capacity(columns.len());
-                        for i in 0..columns.len() {
-                            let val: SqlValue = row.get_ref(i)?.into();
-                            out.push(val);
-                        }
-                        Ok::<_, WasmError>(out)
-                    })?
-                    .collect::<Result<Vec<_>, _>>()?;
+            DocRequest::QueryStream { key, sql, params, chunk_rows } => {
+                let stream = QueryStream::new(
+                    self.doc.snapshot()?,
+                    sql.clone(),
+                    params.to_vec(),
+                    chunk_rows.unwrap_or(DEFAULT_QUERY_CHUNK_ROWS) as usize,
+                );
+                self.streams.start(msg.port_id, key, stream);
+                Ok(DocReply::Ack)
+            }
 
-                Ok::<_, WasmError>(DocReply::RecordSet { columns, rows })
-            }),
+            DocRequest::QuerySubscribe { key, sql, params, key_column } => {
+                self.queries.subscribe(
+                    msg.port_id,
+                    key,
+                    sql,
+                    params.to_vec(),
+                    key_column.as_deref(),
+                );
+                Ok(DocReply::Ack)
+            }
 
-            DocRequest::QuerySubscribe { key, sql, params } => {
-                self.queries
-                    .subscribe(msg.port_id, key, sql, params.to_vec());
+            DocRequest::CancelQuery { key } => {
+                let pending = self.pending_queries.iter().find(|(query, _)| {
+                    query.port_id == msg.port_id
+                        && matches!(&query.req, DocRequest::Query { key: Some(k), .. } if k == key)
+                });
+                if let Some((_, cancellation)) = pending {
+                    cancellation.cancel();
+                }
+                self.streams.cancel(msg.port_id, key);
                 Ok(DocReply::Ack)
             }
 
@@ -229,10 +475,26 @@ impl DocTask {
             }
 
             DocRequest::Mutate { mutation } => {
+                // mutations are batched until the commit window elapses
+                if !self.commit_window.is_disabled() {
+                    self.doc.begin_batch();
+                }
                 self.doc.mutate(&mutation.to_vec())?;
-                Ok(DocReply::Ack)
+                let lsn = self
+                    .doc
+                    .last_mutation_lsn()
+                    .expect("the mutation is in the timeline or the open batch");
+                // later mutations join the running window rather than
+                // extending it, so a steady stream still syncs regularly
+                if self.commit_window.trigger() {
+                    self.doc.commit_batch()?;
+                }
+                Ok(DocReply::Mutated { lsn })
             }
 
+            // handle_message registers a waiter instead
+            DocRequest::WaitForSync { .. } => unreachable!("handled by handle_message"),
+
             DocRequest::RefreshConnectionStatus => {
                 let _ = self.ports.send_one(
                     msg.port_id,
@@ -267,3 +529,142 @@ impl DocTask {
         }
     }
 }
+
+/// open a document on its journals, replaying any mutations in its timeline
+/// which the coordinator hasn't applied yet
+fn open_doc(
+    storage: OpfsJournal,
+    timeline: OpfsJournal,
+    reducer: WasmReducer,
+    signals: &SignalRouter<Signal>,
+) -> WasmResult<LocalDocument<OpfsJournal, SignalEmitter<Signal>>> {
+    let signals = DocumentSignals {
+        storage: signals.emitter(Signal::StorageChanged),
+        timeline: signals.emitter(Signal::TimelineChanged),
+        rebase: signals.emitter(Signal::CanRebase),
+        outputs: signals.emitter(Signal::HasOutputs),
+        waiters: signals.emitter(Signal::WaitersFinished),
+    };
+    Ok(LocalDocument::open(storage, timeline, reducer, signals)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use futures::executor::block_on;
+    use sqlsync::{
+        coordinator::CoordinatorDocument,
+        replication::{ReplicationProtocol, ReplicationSource},
+        Lsn, MemoryJournal, MemoryJournalFactory,
+    };
+
+    use super::*;
+    use crate::{
+        net::{coordinator_protocol, decode_msg, encode_msg},
+        opfs::{open_persisted_journals, JournalDir},
+        test_helpers::exec_batch_reducer,
+    };
+
+    type Doc = LocalDocument<OpfsJournal, SignalEmitter<Signal>>;
+    type Coordinator = CoordinatorDocument<MemoryJournal, WasmReducer>;
+
+    // every mutation adds a row to t
+    const REDUCER_SQL: &str = "CREATE TABLE IF NOT EXISTS t (x INTEGER); INSERT INTO t VALUES (1)";
+
+    // open the document as DocTask::new does with persistence enabled
+    fn open(dir: &JournalDir, doc_id: JournalId) -> anyhow::Result<Doc> {
+        let (storage, timeline) =
+            block_on(open_persisted_journals(dir, doc_id)).map_err(|e| e.0)?;
+        let reducer = exec_batch_reducer(REDUCER_SQL)?;
+        open_doc(storage, timeline, reducer, &SignalRouter::new()).map_err(|e| e.0)
+    }
+
+    // connect to the coordinator as CoordinatorConnection does, passing each
+    // websocket message across until neither side has anything left to send.
+    // returns the client's protocol, which outlives a real connection
+    fn connect(
+        doc: &mut Doc,
+        coordinator: &mut Coordinator,
+        last_acked: Option<Lsn>,
+    ) -> anyhow::Result<ReplicationProtocol> {
+        let mut client = coordinator_protocol(last_acked);
+        let mut server = ReplicationProtocol::new();
+        let mut to_server = vec![encode_msg(&client.start(doc), io::empty())?];
+        let mut to_client = vec![encode_msg(&server.start(coordinator), io::empty())?];
+        while !to_server.is_empty() || !to_client.is_empty() {
+            for msg in std::mem::take(&mut to_server) {
+                let (msg, mut frame) = decode_msg(msg)?;
+                if let Some(resp) = server.handle(coordinator, msg, &mut frame)? {
+                    to_client.push(encode_msg(&resp, io::empty())?);
+                }
+            }
+            for msg in std::mem::take(&mut to_client) {
+                let (msg, mut frame) = decode_msg(msg)?;
+                if let Some(resp) = client.handle(doc, msg, &mut frame)? {
+                    to_server.push(encode_msg(&resp, io::empty())?);
+                }
+            }
+            while let Some((msg, frame)) = client.sync(doc)? {
+                to_server.push(encode_msg(&msg, frame)?);
+            }
+            while let Some((msg, frame)) = server.sync(coordinator)? {
+                to_client.push(encode_msg(&msg, frame)?);
+            }
+        }
+        Ok(client)
+    }
+
+    fn count(doc: &Doc) -> anyhow::Result<i64> {
+        Ok(doc.query(|conn| conn.query_row("SELECT count(*) FROM t", [], |row| row.get(0)))?)
+    }
+
+    #[test]
+    fn test_reopen_and_reconnect() -> anyhow::Result<()> {
+        let dir = JournalDir::memory();
+        let doc_id = JournalId::new128(&mut rand::thread_rng());
+        let mut coordinator = CoordinatorDocument::open(
+            MemoryJournal::open(doc_id)?,
+            MemoryJournalFactory,
+            exec_batch_reducer(REDUCER_SQL)?,
+        )?;
+
+        // the first mutation is synced, and reconnecting within the session
+        // resumes after it
+        let mut doc = open(&dir, doc_id)?;
+        let timeline_id = doc.source_id();
+        doc.mutate(&[0])?;
+        let last_acked = connect(&mut doc, &mut coordinator, None)?.last_acked();
+        assert_eq!(last_acked, Some(0));
+        doc.mutate(&[1])?;
+        let last_acked = connect(&mut doc, &mut coordinator, last_acked)?.last_acked();
+        assert_eq!(last_acked, Some(1));
+
+        // the page is reloaded after a mutation made while offline
+        doc.mutate(&[2])?;
+        drop(doc);
+
+        // the reopened document has the same timeline, and replays the
+        // mutations the coordinator hasn't applied
+        let mut doc = open(&dir, doc_id)?;
+        assert_eq!(doc.source_id(), timeline_id);
+        assert_eq!(count(&doc)?, 3);
+
+        // reconnecting after the reload starts without last_acked, and
+        // sends the offline mutation to the coordinator
+        let last_acked = connect(&mut doc, &mut coordinator, None)?.last_acked();
+        assert_eq!(last_acked, Some(2));
+        while coordinator.has_pending_work() {
+            coordinator.step()?;
+        }
+
+        // once the coordinator's storage comes back, every mutation has been
+        // applied by the coordinator
+        connect(&mut doc, &mut coordinator, last_acked)?;
+        doc.rebase()?;
+        assert!(!doc.has_pending_mutations());
+        assert_eq!(count(&doc)?, 3);
+
+        Ok(())
+    }
+}
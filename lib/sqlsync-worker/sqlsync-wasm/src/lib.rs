@@ -1,11 +1,15 @@
 mod api;
 mod doc_task;
 mod net;
+mod opfs;
 mod reactive;
 mod signal;
 mod sql;
 mod utils;
 
+#[cfg(test)]
+mod test_helpers;
+
 use utils::ConsoleLogger;
 use wasm_bindgen::prelude::wasm_bindgen;
 
@@ -8,14 +8,24 @@ use std::{
 
 use anyhow::bail;
 use futures::{
+    select,
     stream::{Fuse, SplitSink, SplitStream},
-    SinkExt, StreamExt,
+    FutureExt, SinkExt, StreamExt,
+};
+use gloo::{
+    net::websocket::{futures::WebSocket, Message},
+    timers::future::TimeoutFuture,
 };
-use gloo::net::websocket::{futures::WebSocket, Message};
 use serde::Serialize;
 use sqlsync::{
     local::Signal,
-    replication::{ReplicationDestination, ReplicationMsg, ReplicationProtocol, ReplicationSource},
+    logging,
+    replication::{
+        Compression, Heartbeat, ReplicationDestination, ReplicationMsg, ReplicationProtocol,
+        ReplicationSource,
+    },
+    unixtime::unix_timestamp_milliseconds,
+    Lsn,
 };
 use tsify::Tsify;
 
@@ -25,6 +35,11 @@ use crate::utils::Backoff;
 const MIN_BACKOFF_MS: u32 = 10;
 const MAX_BACKOFF_MS: u32 = 5000;
 
+// ping the coordinator after 10s of silence, and reconnect if it doesn't
+// respond within another 10s
+const HEARTBEAT: Heartbeat = Heartbeat { interval_ms: 10_000, timeout_ms: 10_000 };
+const HEARTBEAT_TICK_MS: u32 = 1000;
+
 pub struct CoordinatorClient<S: Signal> {
     // while url is none, the state will always be disabled
     url: Option<String>,
@@ -33,6 +48,10 @@ pub struct CoordinatorClient<S: Signal> {
     // transitioning the state
     state: Option<ConnectionState>,
 
+    // the last lsn acknowledged by the coordinator, kept across reconnects so
+    // that replication resumes where it left off
+    last_acked: Option<Lsn>,
+
     state_changed: S,
 }
 
@@ -45,7 +64,12 @@ impl<S: Signal> CoordinatorClient<S> {
             },
         ));
 
-        Self { url: doc_url, state, state_changed }
+        Self {
+            url: doc_url,
+            state,
+            last_acked: None,
+            state_changed,
+        }
     }
 
     pub fn can_enable(&self) -> bool {
@@ -82,16 +106,20 @@ impl<S: Signal> CoordinatorClient<S> {
         let status = state.status();
 
         log::info!(
+            target: logging::REPLICATION,
             "coordinator client: state {:?} is handling task {:?}",
             status,
             task,
         );
 
         // handle the task
-        let state = state.handle(&self.url, doc, task).await;
+        let state = state.handle(&self.url, self.last_acked, doc, task).await;
 
         // get the new status and save the new state
         let new_status = state.status();
+        if let Some(last_acked) = state.last_acked() {
+            self.last_acked = Some(last_acked);
+        }
         self.state.replace(state);
 
         // if status changed, emit a signal
@@ -106,6 +134,7 @@ pub enum ConnectionTask {
     Connect,
     Recv(ReplicationMsg, Cursor<Vec<u8>>),
     Sync,
+    Heartbeat,
     Error(anyhow::Error),
 }
 
@@ -116,6 +145,7 @@ impl Debug for ConnectionTask {
             ConnectionTask::Connect => write!(f, "Connect"),
             ConnectionTask::Recv(_, _) => write!(f, "Recv"),
             ConnectionTask::Sync => write!(f, "Sync"),
+            ConnectionTask::Heartbeat => write!(f, "Heartbeat"),
             ConnectionTask::Error(e) => write!(f, "Error({:?})", e),
         }
     }
@@ -154,6 +184,13 @@ impl ConnectionState {
             Self::Connected { .. } => ConnectionStatus::Connected,
         }
     }
+
+    fn last_acked(&self) -> Option<Lsn> {
+        match self {
+            Self::Connecting { conn, .. } | Self::Connected { conn } => conn.protocol.last_acked(),
+            _ => None,
+        }
+    }
 }
 
 impl ConnectionState {
@@ -174,18 +211,14 @@ impl ConnectionState {
                 .map_or_else(ConnectionTask::Error, |(msg, buf)| {
                     ConnectionTask::Recv(msg, buf)
                 }),
-            ConnectionState::Connected { conn } => conn
-                .recv()
-                .await
-                .map_or_else(ConnectionTask::Error, |(msg, buf)| {
-                    ConnectionTask::Recv(msg, buf)
-                }),
+            ConnectionState::Connected { conn } => conn.recv_or_tick().await,
         }
     }
 
     async fn handle<'a, R, D>(
         self,
         url: &Option<String>,
+        last_acked: Option<Lsn>,
         doc: &'a mut D,
         task: ConnectionTask,
     ) -> ConnectionState
@@ -204,12 +237,12 @@ impl ConnectionState {
 
         macro_rules! handle_err {
             ($backoff:ident, $err:ident) => {{
-                log::error!("connection error: {:?}", $err);
+                log::error!(target: logging::REPLICATION, "connection error: {:?}", $err);
                 $backoff.step();
                 ConnectionState::Disconnected { $backoff }
             }};
             ($err:ident) => {{
-                log::error!("connection error: {:?}", $err);
+                log::error!(target: logging::REPLICATION, "connection error: {:?}", $err);
                 ConnectionState::Disconnected {
                     backoff: Backoff::new(MIN_BACKOFF_MS, MAX_BACKOFF_MS),
                 }
@@ -218,7 +251,7 @@ impl ConnectionState {
 
         match (self, task) {
             // disabled ignores all tasks except for Connect
-            (Disabled, Connect) => match CoordinatorConnection::open(url, doc).await {
+            (Disabled, Connect) => match CoordinatorConnection::open(url, last_acked, doc).await {
                 Ok(conn) => ConnectionState::Connecting {
                     conn,
                     backoff: Backoff::new(MIN_BACKOFF_MS, MAX_BACKOFF_MS),
@@ -231,7 +264,7 @@ impl ConnectionState {
             (_, Disable) => Disabled,
 
             (Disconnected { mut backoff }, Connect) => {
-                match CoordinatorConnection::open(url, doc).await {
+                match CoordinatorConnection::open(url, last_acked, doc).await {
                     Ok(conn) => ConnectionState::Connecting { conn, backoff },
                     Err(e) => handle_err!(backoff, e),
                 }
@@ -242,6 +275,7 @@ impl ConnectionState {
             // ignore sync/recv
             (s @ Disconnected { .. }, Sync) => s,
             (s @ Disconnected { .. }, Recv(_, _)) => s,
+            (s @ Disconnected { .. }, Heartbeat) => s,
 
             (s @ Connecting { .. }, Connect) => s,
 
@@ -263,6 +297,7 @@ impl ConnectionState {
 
             // can't sync until we have completed the connection
             (s @ Connecting { .. }, Sync) => s,
+            (s @ Connecting { .. }, Heartbeat) => s,
 
             (Connecting { mut backoff, .. }, Error(e)) => {
                 handle_err!(backoff, e)
@@ -280,6 +315,11 @@ impl ConnectionState {
                 Err(e) => handle_err!(e),
             },
 
+            (Connected { mut conn }, Heartbeat) => match conn.heartbeat().await {
+                Ok(()) => Connected { conn },
+                Err(e) => handle_err!(e),
+            },
+
             (Connected { .. }, Error(e)) => handle_err!(e),
         }
     }
@@ -289,24 +329,34 @@ struct CoordinatorConnection {
     reader: Fuse<SplitStream<WebSocket>>,
     writer: SplitSink<WebSocket, Message>,
     protocol: ReplicationProtocol,
+    heartbeat_tick: TimeoutFuture,
 }
 
 impl CoordinatorConnection {
-    async fn open<D>(url: &str, doc: &D) -> anyhow::Result<CoordinatorConnection>
+    async fn open<D>(
+        url: &str,
+        last_acked: Option<Lsn>,
+        doc: &D,
+    ) -> anyhow::Result<CoordinatorConnection>
     where
         D: ReplicationSource,
     {
-        log::info!("connecting to {}", url);
+        log::info!(target: logging::REPLICATION, "connecting to {}", url);
         let (mut writer, reader) = WebSocket::open(url)?.split();
         let reader = reader.fuse();
-        let protocol = ReplicationProtocol::new();
+        let protocol = coordinator_protocol(last_acked);
 
         let start_msg = protocol.start(doc);
-        log::info!("sending start message: {:?}", start_msg);
-        let start_msg = bincode::serialize(&start_msg)?;
+        log::info!(target: logging::REPLICATION, "sending start message: {:?}", start_msg);
+        let start_msg = encode_msg(&start_msg, io::empty())?;
         writer.send(Message::Bytes(start_msg)).await?;
 
-        Ok(CoordinatorConnection { reader, writer, protocol })
+        Ok(CoordinatorConnection {
+            reader,
+            writer,
+            protocol,
+            heartbeat_tick: TimeoutFuture::new(HEARTBEAT_TICK_MS),
+        })
     }
 
     fn initialized(&self) -> bool {
@@ -314,23 +364,48 @@ impl CoordinatorConnection {
     }
 
     async fn send(&mut self, msg: ReplicationMsg) -> anyhow::Result<()> {
-        let msg = bincode::serialize(&msg)?;
+        let msg = encode_msg(&msg, io::empty())?;
         Ok(self.writer.send(Message::Bytes(msg)).await?)
     }
 
     async fn recv(&mut self) -> anyhow::Result<(ReplicationMsg, Cursor<Vec<u8>>)> {
-        let msg = self.reader.select_next_some().await?;
-        match msg {
-            Message::Bytes(bytes) => {
-                let mut buf = io::Cursor::new(bytes);
-                Ok((bincode::deserialize_from(&mut buf)?, buf))
-            }
+        let msg = self.reader.select_next_some().await;
+        Self::decode(msg)
+    }
+
+    /// wait for the next message, or for the next heartbeat tick
+    async fn recv_or_tick(&mut self) -> ConnectionTask {
+        // both futures are safe to drop, see DocTask::into_task
+        let msg = select! {
+            msg = self.reader.select_next_some() => msg,
+            _ = (&mut self.heartbeat_tick).fuse() => return ConnectionTask::Heartbeat,
+        };
+        Self::decode(msg).map_or_else(ConnectionTask::Error, |(msg, buf)| {
+            ConnectionTask::Recv(msg, buf)
+        })
+    }
+
+    fn decode(
+        msg: Result<Message, gloo::net::websocket::WebSocketError>,
+    ) -> anyhow::Result<(ReplicationMsg, Cursor<Vec<u8>>)> {
+        match msg? {
+            Message::Bytes(bytes) => decode_msg(bytes),
             Message::Text(text) => {
                 bail!("received unexpected text message: {:?}", text)
             }
         }
     }
 
+    /// ping the coordinator if we have been idle, failing if it hasn't
+    /// answered our last ping
+    async fn heartbeat(&mut self) -> anyhow::Result<()> {
+        self.heartbeat_tick = TimeoutFuture::new(HEARTBEAT_TICK_MS);
+        if let Some(msg) = self.protocol.tick(unix_timestamp_milliseconds())? {
+            self.send(msg).await?;
+        }
+        Ok(())
+    }
+
     async fn handle<D>(
         &mut self,
         doc: &mut D,
@@ -340,9 +415,9 @@ impl CoordinatorConnection {
     where
         D: ReplicationDestination,
     {
-        log::info!("received message: {:?}", msg);
+        log::info!(target: logging::REPLICATION, "received message: {:?}", msg);
         if let Some(resp) = self.protocol.handle(doc, msg, &mut buf)? {
-            log::info!("sending response: {:?}", resp);
+            log::info!(target: logging::REPLICATION, "sending response: {:?}", resp);
             self.send(resp).await?;
         }
         Ok(())
@@ -353,15 +428,39 @@ impl CoordinatorConnection {
         R: io::Read,
         D: ReplicationSource<Reader<'a> = R>,
     {
-        while let Some((msg, mut reader)) = self.protocol.sync(doc)? {
-            log::info!("sending message: {:?}", msg);
-
-            let mut buf = io::Cursor::new(vec![]);
-            bincode::serialize_into(&mut buf, &msg)?;
-            io::copy(&mut reader, &mut buf)?;
-
-            self.writer.send(Message::Bytes(buf.into_inner())).await?;
+        while let Some((msg, reader)) = self.protocol.sync(doc)? {
+            log::info!(target: logging::REPLICATION, "sending message: {:?}", msg);
+            let msg = encode_msg(&msg, reader)?;
+            self.writer.send(Message::Bytes(msg)).await?;
         }
         Ok(())
     }
 }
+
+/// the protocol used to replicate a document's timeline to the coordinator,
+/// resuming from last_acked if a previous connection acknowledged frames
+pub fn coordinator_protocol(last_acked: Option<Lsn>) -> ReplicationProtocol {
+    // pages are frequently sparse, so compression saves a lot of bandwidth
+    let protocol = ReplicationProtocol::new()
+        .with_compression(Compression::Lz4)
+        .with_heartbeat(HEARTBEAT);
+    match last_acked {
+        Some(last_acked) => protocol.resume(last_acked),
+        None => protocol,
+    }
+}
+
+/// each websocket message holds a replication message followed by its frame,
+/// which is empty for every message other than a frame
+pub fn encode_msg(msg: &ReplicationMsg, mut frame: impl io::Read) -> anyhow::Result<Vec<u8>> {
+    let mut buf = io::Cursor::new(vec![]);
+    bincode::serialize_into(&mut buf, msg)?;
+    io::copy(&mut frame, &mut buf)?;
+    Ok(buf.into_inner())
+}
+
+/// the inverse of encode_msg, the frame is left in the returned cursor
+pub fn decode_msg(bytes: Vec<u8>) -> anyhow::Result<(ReplicationMsg, Cursor<Vec<u8>>)> {
+    let mut buf = io::Cursor::new(bytes);
+    Ok((bincode::deserialize_from(&mut buf)?, buf))
+}
@@ -0,0 +1,895 @@
+use std::{
+    cell::RefCell,
+    fmt::{Debug, Formatter},
+    io,
+    rc::Rc,
+};
+
+use anyhow::anyhow;
+use js_sys::Reflect;
+use rand::thread_rng;
+use sqlsync::{
+    positioned_io::{PositionedCursor, PositionedReader},
+    replication::{ReplicationDestination, ReplicationError, ReplicationSource},
+    Cursor, FileFrame, FrameChecksum, FrameEntry, FrameFile, FrameIndex, Journal, JournalError,
+    JournalFactory, JournalId, Lsn, LsnIter, LsnRange, PinnableJournal, PinnedJournal, Scannable,
+    Serializable,
+};
+use wasm_bindgen::{JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{
+    DedicatedWorkerGlobalScope, FileSystemDirectoryHandle, FileSystemFileHandle,
+    FileSystemGetDirectoryOptions, FileSystemGetFileOptions, FileSystemReadWriteOptions,
+    FileSystemSyncAccessHandle,
+};
+
+use crate::utils::{WasmError, WasmResult};
+
+/// the OPFS directory which holds every sqlsync journal
+const OPFS_DIR: &str = "sqlsync";
+
+/// every journal file starts with the first lsn of the journal, this is needed
+/// to remember where an empty journal starts after its prefix has been dropped.
+/// records before the first lsn have been dropped but not yet compacted away
+const HEADER_SIZE: u64 = 8;
+
+/// each record in a journal file is prefixed by (lsn: u64, len: u32, checksum: u32)
+const RECORD_HEADER_SIZE: u64 = 16;
+
+/// the scratch file starts with the length of the journal image it holds,
+/// which is only written once the image is complete
+const SCRATCH_HEADER_SIZE: u64 = 8;
+
+const COPY_CHUNK_SIZE: usize = 64 * 1024;
+
+fn js_io_err(err: JsValue) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("{:?}", err))
+}
+
+fn record_header(lsn: Lsn, len: u32, checksum: u32) -> [u8; RECORD_HEADER_SIZE as usize] {
+    let mut header = [0; RECORD_HEADER_SIZE as usize];
+    header[0..8].copy_from_slice(&lsn.to_le_bytes());
+    header[8..12].copy_from_slice(&len.to_le_bytes());
+    header[12..16].copy_from_slice(&checksum.to_le_bytes());
+    header
+}
+
+/// JournalFile is the file backing an OpfsJournal
+/// journals fall back to memory when OPFS sync access handles are unavailable
+pub enum JournalFile {
+    Opfs(FileSystemSyncAccessHandle),
+    // shared with the JournalDir it was opened from, if any
+    Memory(Rc<RefCell<Vec<u8>>>),
+}
+
+impl JournalFile {
+    fn size(&self) -> io::Result<u64> {
+        match self {
+            JournalFile::Opfs(handle) => Ok(handle.get_size().map_err(js_io_err)? as u64),
+            JournalFile::Memory(data) => Ok(data.borrow().len() as u64),
+        }
+    }
+
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            JournalFile::Opfs(handle) => {
+                let opts = FileSystemReadWriteOptions::new();
+                opts.set_at(pos as f64);
+                let n = handle
+                    .read_with_u8_array_and_options(buf, &opts)
+                    .map_err(js_io_err)?;
+                Ok(n as usize)
+            }
+            JournalFile::Memory(data) => {
+                let data = data.borrow();
+                let start = (pos as usize).min(data.len());
+                let end = (start + buf.len()).min(data.len());
+                buf[..end - start].copy_from_slice(&data[start..end]);
+                Ok(end - start)
+            }
+        }
+    }
+
+    fn read_exact_at(&self, pos: u64, buf: &mut [u8]) -> io::Result<()> {
+        if self.read_at(pos, buf)? != buf.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "failed to fill whole buffer",
+            ));
+        }
+        Ok(())
+    }
+
+    fn read_u64(&self, pos: u64) -> io::Result<u64> {
+        let mut buf = [0; 8];
+        self.read_exact_at(pos, &mut buf)?;
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    fn write_at(&self, pos: u64, buf: &[u8]) -> io::Result<()> {
+        match self {
+            JournalFile::Opfs(handle) => {
+                let opts = FileSystemReadWriteOptions::new();
+                opts.set_at(pos as f64);
+                let n = handle
+                    .write_with_u8_array_and_options(buf, &opts)
+                    .map_err(js_io_err)?;
+                if n as usize != buf.len() {
+                    return Err(io::ErrorKind::WriteZero.into());
+                }
+                Ok(())
+            }
+            JournalFile::Memory(data) => {
+                let mut data = data.borrow_mut();
+                let (start, end) = (pos as usize, pos as usize + buf.len());
+                if data.len() < end {
+                    data.resize(end, 0);
+                }
+                data[start..end].copy_from_slice(buf);
+                Ok(())
+            }
+        }
+    }
+
+    fn truncate(&self, len: u64) -> io::Result<()> {
+        match self {
+            JournalFile::Opfs(handle) => handle.truncate_with_f64(len as f64).map_err(js_io_err),
+            JournalFile::Memory(data) => {
+                data.borrow_mut().resize(len as usize, 0);
+                Ok(())
+            }
+        }
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        match self {
+            JournalFile::Opfs(handle) => handle.flush().map_err(js_io_err),
+            JournalFile::Memory(_) => Ok(()),
+        }
+    }
+
+    /// overwrite the contents of dest with the image stored in this scratch file
+    fn copy_image_into(&self, dest: &JournalFile, image_len: u64) -> io::Result<()> {
+        let mut buf = vec![0; COPY_CHUNK_SIZE];
+        let mut pos = 0;
+        while pos < image_len {
+            let n = COPY_CHUNK_SIZE.min((image_len - pos) as usize);
+            self.read_exact_at(SCRATCH_HEADER_SIZE + pos, &mut buf[..n])?;
+            dest.write_at(pos, &buf[..n])?;
+            pos += n as u64;
+        }
+        dest.truncate(image_len)?;
+        dest.flush()
+    }
+}
+
+impl FrameFile for JournalFile {
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        JournalFile::read_at(self, pos, buf)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        JournalFile::size(self)
+    }
+}
+
+impl Drop for JournalFile {
+    fn drop(&mut self) {
+        // release the exclusive lock OPFS holds for each sync access handle
+        if let JournalFile::Opfs(handle) = self {
+            handle.close();
+        }
+    }
+}
+
+/// OpfsJournal persists frames into the Origin Private File System, so that
+/// documents survive a page reload
+///
+/// each journal is a single file containing a header followed by a sequence
+/// of length-prefixed records, overwriting an lsn (which happens during
+/// replication) appends a new record which wins when the file is reloaded
+pub struct OpfsJournal {
+    id: JournalId,
+    // shared with pinned journals, which read their frames in place
+    file: Rc<JournalFile>,
+    // drop_prefix builds the compacted journal here before copying it back
+    // into file, so that a crash part way through can be recovered from
+    scratch: JournalFile,
+
+    len: u64,
+    // where each frame is stored in file
+    frames: FrameIndex,
+    // set when a prefix was dropped while the journal was pinned, the file
+    // is compacted once it's no longer pinned
+    compact_pending: bool,
+}
+
+impl Debug for OpfsJournal {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        let backing = match *self.file {
+            JournalFile::Opfs(_) => "opfs",
+            JournalFile::Memory(_) => "memory",
+        };
+        f.debug_tuple("OpfsJournal")
+            .field(&self.id)
+            .field(&self.frames.range())
+            .field(&backing)
+            .finish()
+    }
+}
+
+impl OpfsJournal {
+    /// open the journal with the given id in dir, creating it if needed
+    pub async fn open(dir: &JournalDir, id: JournalId) -> WasmResult<Self> {
+        let name = id.to_base58();
+        let file = dir.open_file(&format!("{}.journal", name)).await?;
+        let scratch = dir.open_file(&format!("{}.scratch", name)).await?;
+        Ok(Self::load(id, file, scratch)?)
+    }
+
+    /// open an empty journal which is only stored in memory
+    pub fn open_memory(id: JournalId) -> io::Result<Self> {
+        Self::load(
+            id,
+            JournalFile::Memory(Default::default()),
+            JournalFile::Memory(Default::default()),
+        )
+    }
+
+    fn load(id: JournalId, file: JournalFile, scratch: JournalFile) -> io::Result<Self> {
+        // finish copying a compacted image if drop_prefix was interrupted
+        if scratch.size()? >= SCRATCH_HEADER_SIZE {
+            let image_len = scratch.read_u64(0)?;
+            if image_len > 0 && scratch.size()? >= SCRATCH_HEADER_SIZE + image_len {
+                scratch.copy_image_into(&file, image_len)?;
+            }
+        }
+        scratch.truncate(0)?;
+        scratch.flush()?;
+
+        if file.size()? < HEADER_SIZE {
+            // new journal, or we crashed while creating it
+            file.truncate(0)?;
+            file.write_at(0, &0u64.to_le_bytes())?;
+            file.flush()?;
+        }
+
+        let mut journal = OpfsJournal {
+            id,
+            file: Rc::new(file),
+            scratch,
+            len: 0,
+            frames: FrameIndex::new(0),
+            compact_pending: false,
+        };
+        journal.reload()?;
+        Ok(journal)
+    }
+
+    /// rebuild the journal's index by scanning its file
+    fn reload(&mut self) -> io::Result<()> {
+        let size = self.file.size()?;
+        let first = self.file.read_u64(0)?;
+
+        let mut frames = FrameIndex::new(first);
+        let mut pos = HEADER_SIZE;
+        let mut header = [0; RECORD_HEADER_SIZE as usize];
+
+        // a crash may leave a partial record at the end of the file, we stop
+        // at the first record which isn't fully written
+        while pos + RECORD_HEADER_SIZE <= size {
+            self.file.read_exact_at(pos, &mut header)?;
+            let lsn = Lsn::from_le_bytes(header[0..8].try_into().unwrap());
+            let len = u32::from_le_bytes(header[8..12].try_into().unwrap());
+            let checksum = u32::from_le_bytes(header[12..16].try_into().unwrap());
+            let entry = FrameEntry {
+                offset: pos + RECORD_HEADER_SIZE,
+                len,
+                checksum: FrameChecksum::loaded(checksum),
+            };
+            if entry.end() > size {
+                break;
+            }
+            pos = entry.end();
+            if lsn >= first {
+                frames.insert(lsn, entry)?;
+            }
+        }
+
+        // discard anything past the last complete record
+        if pos < size {
+            self.file.truncate(pos)?;
+            self.file.flush()?;
+        }
+
+        self.len = pos;
+        self.frames = frames;
+        Ok(())
+    }
+
+    fn write_frame(&mut self, lsn: Lsn, frame: &[u8]) -> io::Result<()> {
+        let len: u32 = frame.len().try_into().map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidInput, "journal frame is too large")
+        })?;
+
+        if self.compact_pending && !self.is_pinned() {
+            self.compact(self.frames.range())?;
+        }
+        if self.frames.range().is_empty() {
+            // an empty journal restarts at lsn, which may precede its first
+            self.file.write_at(0, &lsn.to_le_bytes())?;
+        }
+
+        let checksum = FrameChecksum::of(frame);
+        let offset = self.len + RECORD_HEADER_SIZE;
+        self.file
+            .write_at(self.len, &record_header(lsn, len, checksum.value()))?;
+        self.file.write_at(offset, frame)?;
+        self.file.flush()?;
+        self.len = offset + len as u64;
+
+        self.frames
+            .insert(lsn, FrameEntry { offset, len, checksum })
+    }
+
+    /// returns true if a pinned journal is reading frames from file
+    fn is_pinned(&self) -> bool {
+        Rc::strong_count(&self.file) > 1
+    }
+
+    /// rewrite the journal so that it only contains remaining_range, which
+    /// must be a subset of the journal's range
+    fn compact(&mut self, remaining_range: LsnRange) -> io::Result<()> {
+        self.compact_pending = false;
+        // write the compacted journal into the scratch file
+        self.scratch.truncate(0)?;
+        let first = LsnRange::empty_preceeding(&remaining_range).next();
+        self.scratch
+            .write_at(SCRATCH_HEADER_SIZE, &first.to_le_bytes())?;
+
+        let mut image_len = HEADER_SIZE;
+        for (lsn, entry) in self.frames.entries(remaining_range) {
+            let buf = FileFrame::open(&*self.file, lsn, entry)?.read_all()?;
+            let pos = SCRATCH_HEADER_SIZE + image_len;
+            self.scratch
+                .write_at(pos, &record_header(lsn, entry.len, entry.checksum.value()))?;
+            self.scratch.write_at(pos + RECORD_HEADER_SIZE, &buf)?;
+            image_len += RECORD_HEADER_SIZE + entry.len as u64;
+        }
+        self.scratch.flush()?;
+
+        // once the image length is written the image will be used to recover
+        // from a crash, see OpfsJournal::load
+        self.scratch.write_at(0, &image_len.to_le_bytes())?;
+        self.scratch.flush()?;
+
+        self.scratch.copy_image_into(&self.file, image_len)?;
+        self.scratch.truncate(0)?;
+        self.scratch.flush()?;
+
+        self.reload()
+    }
+}
+
+/// OpfsJournalFactory exists to satisfy the Journal trait, OPFS files can only
+/// be opened asynchronously so use OpfsJournal::open instead
+pub struct OpfsJournalFactory;
+
+impl JournalFactory<OpfsJournal> for OpfsJournalFactory {
+    fn open(&self, _id: JournalId) -> io::Result<OpfsJournal> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "opfs journals must be opened with OpfsJournal::open",
+        ))
+    }
+}
+
+impl Journal for OpfsJournal {
+    type Factory = OpfsJournalFactory;
+
+    fn id(&self) -> JournalId {
+        self.id
+    }
+
+    fn range(&self) -> LsnRange {
+        self.frames.range()
+    }
+
+    fn append(&mut self, obj: impl Serializable) -> io::Result<()> {
+        let mut entry: Vec<u8> = Vec::with_capacity(obj.serialized_len().unwrap_or(0));
+        obj.serialize_into(&mut entry)?;
+        self.write_frame(self.frames.range().next(), &entry)
+    }
+
+    fn drop_prefix(&mut self, up_to: Lsn) -> io::Result<()> {
+        let remaining_range = self.frames.range().trim_prefix(up_to);
+        if !self.is_pinned() {
+            return self.compact(remaining_range);
+        }
+
+        // compacting would move the pinned frames, so instead the new first
+        // lsn is recorded and the dropped records are skipped on reload
+        let first = LsnRange::empty_preceeding(&remaining_range).next();
+        self.file.write_at(0, &first.to_le_bytes())?;
+        self.file.flush()?;
+        self.frames = self.frames.slice(remaining_range);
+        self.compact_pending = true;
+        Ok(())
+    }
+
+    fn drop_last(&mut self) -> io::Result<()> {
+        if self.is_pinned() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "can't drop the last frame of a pinned journal",
+            ));
+        }
+        self.compact(self.frames.range().trim_last())
+    }
+
+    fn verify(&self) -> Result<(), JournalError> {
+        self.frames.verify(self.len)
+    }
+}
+
+/// OpfsFrame reads a single frame out of a journal file
+pub type OpfsFrame<'a> = FileFrame<'a, JournalFile>;
+
+impl PinnableJournal for OpfsJournal {
+    type Pinned = PinnedJournal<Rc<JournalFile>>;
+
+    fn pin(&self, range: LsnRange) -> io::Result<Self::Pinned> {
+        Ok(PinnedJournal::new(
+            self.id,
+            self.frames.slice(range),
+            self.file.clone(),
+        ))
+    }
+}
+
+impl Scannable for OpfsJournal {
+    type Reader<'a> = OpfsFrame<'a>
+    where
+        Self: 'a;
+
+    fn scan(&self) -> Cursor<'_, Self, LsnIter> {
+        Cursor::new(self, self.frames.range().iter())
+    }
+
+    fn scan_range(&self, range: LsnRange) -> Cursor<'_, Self, LsnIter> {
+        let intersection = self.frames.range().intersect(&range);
+        Cursor::new(self, intersection.iter())
+    }
+
+    fn get(&self, lsn: Lsn) -> io::Result<Option<Self::Reader<'_>>> {
+        self.frames
+            .get(lsn)
+            .map(|entry| FileFrame::open(&*self.file, lsn, entry))
+            .transpose()
+    }
+}
+
+impl ReplicationSource for OpfsJournal {
+    // the coordinator client reads frames as a stream
+    type Reader<'a> = PositionedCursor<OpfsFrame<'a>>
+    where
+        Self: 'a;
+
+    fn source_id(&self) -> JournalId {
+        self.id()
+    }
+
+    fn source_range(&self) -> LsnRange {
+        self.range()
+    }
+
+    fn read_lsn(&self, lsn: Lsn) -> io::Result<Option<Self::Reader<'_>>> {
+        Ok(self.get(lsn)?.map(PositionedCursor::new))
+    }
+}
+
+impl ReplicationDestination for OpfsJournal {
+    fn range(&mut self, id: JournalId) -> Result<LsnRange, ReplicationError> {
+        if id != self.id {
+            return Err(ReplicationError::UnknownJournal(id));
+        }
+        Ok(self.frames.range())
+    }
+
+    fn write_lsn<R>(
+        &mut self,
+        id: JournalId,
+        lsn: Lsn,
+        reader: &mut R,
+    ) -> Result<(), ReplicationError>
+    where
+        R: io::Read,
+    {
+        if id != self.id() {
+            return Err(ReplicationError::UnknownJournal(id));
+        }
+
+        self.frames.check_replicated_lsn(lsn)?;
+        let mut frame_data = Vec::new();
+        reader.read_to_end(&mut frame_data)?;
+        self.write_frame(lsn, &frame_data)?;
+        Ok(())
+    }
+}
+
+/// JournalDir is the directory persisted journals are stored in, tests use a
+/// directory in memory whose files outlive the journals opened from it
+pub enum JournalDir {
+    Opfs(FileSystemDirectoryHandle),
+    #[cfg(test)]
+    Memory(RefCell<std::collections::HashMap<String, Rc<RefCell<Vec<u8>>>>>),
+}
+
+impl JournalDir {
+    #[cfg(test)]
+    pub fn memory() -> Self {
+        JournalDir::Memory(Default::default())
+    }
+
+    async fn open_file(&self, name: &str) -> WasmResult<JournalFile> {
+        match self {
+            JournalDir::Opfs(dir) => Ok(JournalFile::Opfs(open_sync_handle(dir, name).await?)),
+            #[cfg(test)]
+            JournalDir::Memory(files) => {
+                let file = files
+                    .borrow_mut()
+                    .entry(name.to_owned())
+                    .or_default()
+                    .clone();
+                Ok(JournalFile::Memory(file))
+            }
+        }
+    }
+}
+
+async fn opfs_dir() -> WasmResult<FileSystemDirectoryHandle> {
+    // sync access handles are only available in dedicated workers
+    let scope = js_sys::global()
+        .dyn_into::<DedicatedWorkerGlobalScope>()
+        .map_err(|_| anyhow!("OPFS persistence requires a dedicated worker"))?;
+    let root: FileSystemDirectoryHandle =
+        JsFuture::from(scope.navigator().storage().get_directory())
+            .await?
+            .unchecked_into();
+
+    let opts = FileSystemGetDirectoryOptions::new();
+    opts.set_create(true);
+    Ok(
+        JsFuture::from(root.get_directory_handle_with_options(OPFS_DIR, &opts))
+            .await?
+            .unchecked_into(),
+    )
+}
+
+async fn open_sync_handle(
+    dir: &FileSystemDirectoryHandle,
+    name: &str,
+) -> WasmResult<FileSystemSyncAccessHandle> {
+    let opts = FileSystemGetFileOptions::new();
+    opts.set_create(true);
+    let file: FileSystemFileHandle = JsFuture::from(dir.get_file_handle_with_options(name, &opts))
+        .await?
+        .unchecked_into();
+
+    if !Reflect::has(&file, &"createSyncAccessHandle".into())? {
+        return Err(WasmError(anyhow!(
+            "this browser does not support OPFS sync access handles"
+        )));
+    }
+
+    // this fails if another worker (i.e. another tab) has the file open
+    Ok(JsFuture::from(file.create_sync_access_handle())
+        .await?
+        .unchecked_into())
+}
+
+/// each document has a timeline per client, which must keep the same id for
+/// as long as its journal is persisted
+async fn load_timeline_id(dir: &JournalDir, doc_id: JournalId) -> WasmResult<JournalId> {
+    let file = dir
+        .open_file(&format!("{}.timeline-id", doc_id.to_base58()))
+        .await?;
+    read_or_create_timeline_id(&file)
+}
+
+fn read_or_create_timeline_id(file: &JournalFile) -> WasmResult<JournalId> {
+    let size = file.size()?;
+    if size > 0 {
+        let mut buf = vec![0; size as usize];
+        file.read_exact_at(0, &mut buf)?;
+        Ok(JournalId::try_from(buf)?)
+    } else {
+        let timeline_id = JournalId::new128(&mut thread_rng());
+        file.write_at(0, timeline_id.bytes())?;
+        file.flush()?;
+        Ok(timeline_id)
+    }
+}
+
+fn open_memory_journals(doc_id: JournalId) -> WasmResult<(OpfsJournal, OpfsJournal)> {
+    // without persistence every open looks like a new client to the coordinator
+    let timeline_id = JournalId::new128(&mut thread_rng());
+    Ok((
+        OpfsJournal::open_memory(doc_id)?,
+        OpfsJournal::open_memory(timeline_id)?,
+    ))
+}
+
+/// open the storage and timeline journals for a document from OPFS, falling
+/// back to in-memory journals if OPFS is unavailable or persist is false
+pub async fn open_doc_journals(
+    doc_id: JournalId,
+    persist: bool,
+) -> WasmResult<(OpfsJournal, OpfsJournal)> {
+    if !persist {
+        return open_memory_journals(doc_id);
+    }
+
+    let opened = async {
+        let dir = JournalDir::Opfs(opfs_dir().await?);
+        open_persisted_journals(&dir, doc_id).await
+    };
+
+    match opened.await {
+        Ok(journals) => Ok(journals),
+        Err(err) => {
+            log::warn!(
+                "unable to persist document {} to OPFS, it will only be stored in memory: {}",
+                doc_id,
+                err
+            );
+            open_memory_journals(doc_id)
+        }
+    }
+}
+
+/// open the storage and timeline journals for a document persisted in dir,
+/// the timeline keeps its id for as long as dir holds the document
+pub async fn open_persisted_journals(
+    dir: &JournalDir,
+    doc_id: JournalId,
+) -> WasmResult<(OpfsJournal, OpfsJournal)> {
+    // the storage journal's sync handle is exclusive, so opening it first
+    // ensures that a second worker (i.e. another tab without shared
+    // workers) falls back to memory rather than sharing our timeline id
+    let storage = OpfsJournal::open(dir, doc_id).await?;
+    let timeline_id = load_timeline_id(dir, doc_id).await?;
+    let timeline = OpfsJournal::open(dir, timeline_id).await?;
+    Ok((storage, timeline))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use futures::executor::block_on;
+
+    use super::*;
+
+    fn contents(file: &JournalFile) -> Vec<u8> {
+        match file {
+            JournalFile::Memory(data) => data.borrow().clone(),
+            JournalFile::Opfs(_) => unreachable!("tests only use memory files"),
+        }
+    }
+
+    fn memory_file(data: Vec<u8>) -> JournalFile {
+        JournalFile::Memory(Rc::new(RefCell::new(data)))
+    }
+
+    fn open(id: JournalId, file: Vec<u8>, scratch: Vec<u8>) -> OpfsJournal {
+        OpfsJournal::load(id, memory_file(file), memory_file(scratch)).unwrap()
+    }
+
+    // reopen a journal from a copy of its files, as happens after a reload
+    fn reopen(journal: &OpfsJournal) -> OpfsJournal {
+        open(
+            journal.id,
+            contents(&journal.file),
+            contents(&journal.scratch),
+        )
+    }
+
+    fn frames<S: Scannable>(journal: &S) -> Vec<Vec<u8>> {
+        let mut out = vec![];
+        let mut cursor = journal.scan();
+        while cursor.advance().unwrap() {
+            out.push(cursor.read_all().unwrap());
+        }
+        out
+    }
+
+    fn journal_with_frames(n: u8) -> OpfsJournal {
+        let mut journal = OpfsJournal::open_memory(JournalId::new128(&mut thread_rng())).unwrap();
+        for i in 0..n {
+            journal.append([i; 4].as_slice()).unwrap();
+        }
+        journal
+    }
+
+    #[test]
+    fn test_reload() {
+        let mut journal = journal_with_frames(3);
+        // replication overwrites an lsn by appending a new record
+        journal
+            .write_lsn(journal.id, 1, &mut [9u8; 4].as_slice())
+            .unwrap();
+        let expected = vec![vec![0; 4], vec![9; 4], vec![2; 4]];
+        assert_eq!(frames(&journal), expected);
+
+        let reopened = reopen(&journal);
+        assert_eq!(reopened.range(), LsnRange::new(0, 2));
+        assert_eq!(frames(&reopened), expected);
+        assert_eq!(reopened.len, journal.len);
+        reopened.verify().unwrap();
+    }
+
+    #[test]
+    fn test_compact() {
+        let mut journal = journal_with_frames(5);
+        let uncompacted = journal.file.size().unwrap();
+
+        journal.drop_prefix(2).unwrap();
+        assert!(!journal.compact_pending);
+        assert_eq!(journal.range(), LsnRange::new(3, 4));
+        assert_eq!(frames(&journal), vec![vec![3; 4], vec![4; 4]]);
+
+        // the dropped records were removed from the file by way of the
+        // scratch file, which is left empty
+        assert_eq!(journal.file.read_u64(0).unwrap(), 3);
+        assert_eq!(
+            journal.file.size().unwrap(),
+            uncompacted - 3 * (RECORD_HEADER_SIZE + 4)
+        );
+        assert_eq!(journal.scratch.size().unwrap(), 0);
+
+        let reopened = reopen(&journal);
+        assert_eq!(reopened.range(), LsnRange::new(3, 4));
+        assert_eq!(frames(&reopened), vec![vec![3; 4], vec![4; 4]]);
+
+        // dropping every frame leaves an empty journal which continues from
+        // where it left off
+        journal.drop_prefix(4).unwrap();
+        assert!(journal.range().is_empty());
+        let mut reopened = reopen(&journal);
+        assert!(reopened.range().is_empty());
+        reopened.append([5u8; 4].as_slice()).unwrap();
+        assert_eq!(reopened.range(), LsnRange::new(5, 5));
+    }
+
+    #[test]
+    fn test_drop_prefix_while_pinned() {
+        let mut journal = journal_with_frames(4);
+        let size = journal.file.size().unwrap();
+        let pinned = journal.pin(journal.range()).unwrap();
+
+        // the file can't be compacted while pinned, so the dropped records
+        // stay put and are skipped on reload
+        journal.drop_prefix(1).unwrap();
+        assert!(journal.compact_pending);
+        assert_eq!(journal.range(), LsnRange::new(2, 3));
+        assert_eq!(journal.file.size().unwrap(), size);
+        assert_eq!(
+            frames(&pinned),
+            (0..4).map(|i| vec![i; 4]).collect::<Vec<_>>()
+        );
+
+        let reopened = reopen(&journal);
+        assert_eq!(reopened.range(), LsnRange::new(2, 3));
+        assert_eq!(frames(&reopened), vec![vec![2; 4], vec![3; 4]]);
+
+        // dropping the last frame would move pinned frames
+        assert!(journal.drop_last().is_err());
+
+        // once unpinned, the next write compacts the file
+        drop(pinned);
+        journal.append([4u8; 4].as_slice()).unwrap();
+        assert!(!journal.compact_pending);
+        assert_eq!(
+            journal.file.size().unwrap(),
+            HEADER_SIZE + 3 * (RECORD_HEADER_SIZE + 4)
+        );
+        assert_eq!(frames(&journal), vec![vec![2; 4], vec![3; 4], vec![4; 4]]);
+        assert_eq!(frames(&reopen(&journal)), frames(&journal));
+    }
+
+    #[test]
+    fn test_crash_recovery() {
+        let journal = journal_with_frames(3);
+        let id = journal.id;
+        let file = contents(&journal.file);
+
+        // a crash part way through an append leaves a partial record, which
+        // is discarded
+        let mut partial = file.clone();
+        partial.extend_from_slice(&record_header(3, 4, 0));
+        partial.extend_from_slice(&[3, 3]);
+        let recovered = open(id, partial, vec![]);
+        assert_eq!(recovered.range(), LsnRange::new(0, 2));
+        assert_eq!(contents(&recovered.file), file);
+        recovered.verify().unwrap();
+
+        // build the image that compacting away lsn 0 produces
+        let mut compacted = open(id, file.clone(), vec![]);
+        compacted.drop_prefix(0).unwrap();
+        let image = contents(&compacted.file);
+
+        // a crash after the scratch image was completed finishes copying it
+        // into the journal file, even if the copy had already started
+        let mut scratch = (image.len() as u64).to_le_bytes().to_vec();
+        scratch.extend_from_slice(&image);
+        let mut half_copied = file.clone();
+        half_copied[..image.len() / 2].copy_from_slice(&image[..image.len() / 2]);
+        let recovered = open(id, half_copied, scratch);
+        assert_eq!(recovered.range(), LsnRange::new(1, 2));
+        assert_eq!(frames(&recovered), vec![vec![1; 4], vec![2; 4]]);
+        assert_eq!(contents(&recovered.file), image);
+        assert_eq!(recovered.scratch.size().unwrap(), 0);
+
+        // while a crash before the image length was written ignores the
+        // scratch file
+        let mut scratch = 0u64.to_le_bytes().to_vec();
+        scratch.extend_from_slice(&image);
+        let recovered = open(id, file.clone(), scratch);
+        assert_eq!(recovered.range(), LsnRange::new(0, 2));
+        assert_eq!(contents(&recovered.file), file);
+        assert_eq!(recovered.scratch.size().unwrap(), 0);
+
+        // and a journal file which is missing its header starts over
+        let recovered = open(id, vec![1, 2, 3], vec![]);
+        assert!(recovered.range().is_empty());
+        assert_eq!(contents(&recovered.file), 0u64.to_le_bytes());
+    }
+
+    #[test]
+    fn test_timeline_id_is_reused() -> anyhow::Result<()> {
+        let file = memory_file(vec![]);
+
+        let first = read_or_create_timeline_id(&file).map_err(|e| e.0)?;
+        let second = read_or_create_timeline_id(&file).map_err(|e| e.0)?;
+        assert_eq!(first, second);
+
+        // a different file gets a different timeline
+        let other = memory_file(vec![]);
+        assert_ne!(first, read_or_create_timeline_id(&other).map_err(|e| e.0)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_persisted_journals() -> anyhow::Result<()> {
+        let dir = JournalDir::memory();
+        let doc_id = JournalId::new128(&mut thread_rng());
+        let open = |doc_id| block_on(open_persisted_journals(&dir, doc_id)).map_err(|e| e.0);
+
+        let (mut storage, mut timeline) = open(doc_id)?;
+        assert_eq!(storage.id(), doc_id);
+        storage.append([1u8; 4].as_slice())?;
+        timeline.append([2u8; 4].as_slice())?;
+        let timeline_id = timeline.id();
+        drop((storage, timeline));
+
+        // reopening the document finds the same journals
+        let (storage, timeline) = open(doc_id)?;
+        assert_eq!(timeline.id(), timeline_id);
+        assert_eq!(frames(&storage), vec![vec![1; 4]]);
+        assert_eq!(frames(&timeline), vec![vec![2; 4]]);
+
+        // while other documents get their own timeline
+        let (_, other) = open(JournalId::new128(&mut thread_rng()))?;
+        assert_ne!(other.id(), timeline_id);
+
+        // without persistence, every open gets a new timeline
+        let memory = || block_on(open_doc_journals(doc_id, false)).map_err(|e| e.0);
+        let (first, second) = (memory()?.1, memory()?.1);
+        assert_ne!(first.id(), second.id());
+        assert!(first.range().is_empty());
+
+        Ok(())
+    }
+}
@@ -3,9 +3,13 @@ use std::{
     ops::{Deref, DerefMut},
 };
 
-use sqlsync::{local::Signal, ReactiveQuery, StorageChange};
+use sqlsync::{local::Signal, KeyedQuery, KeyedResult, QueryStream, ReactiveQuery, StorageChange};
 
-use crate::{api::PortId, sql::SqlValue};
+use crate::{
+    api::{DocEvent, PortId},
+    sql::SqlValue,
+    utils::WasmError,
+};
 
 pub type QueryKey = String;
 
@@ -14,6 +18,7 @@ pub struct QueryTracker {
     query_key: QueryKey,
     query: ReactiveQuery<SqlValue>,
     ports: Vec<PortId>,
+    keyed: Option<KeyedQuery<SqlValue>>,
 }
 
 impl QueryTracker {
@@ -24,6 +29,32 @@ impl QueryTracker {
     pub fn ports(&self) -> &Vec<PortId> {
         &self.ports
     }
+
+    /// turns a refreshed result into the event sent to subscribers. keyed
+    /// subscriptions receive a delta once they have the full result, and
+    /// nothing at all if no rows changed
+    pub fn result_event(
+        &mut self,
+        columns: Vec<String>,
+        rows: Vec<Vec<SqlValue>>,
+    ) -> Result<Option<DocEvent>, WasmError> {
+        let key = self.query_key.clone();
+        let Some(keyed) = &mut self.keyed else {
+            return Ok(Some(DocEvent::SubscriptionChanged { key, columns, rows }));
+        };
+
+        match keyed.update(&columns, &rows)? {
+            KeyedResult::Full => Ok(Some(DocEvent::SubscriptionChanged { key, columns, rows })),
+            KeyedResult::Delta(delta) => Ok(Some(DocEvent::SubscriptionDelta {
+                key,
+                columns,
+                upserts: delta.upserts,
+                deletes: delta.deletes,
+                order: delta.order,
+            })),
+            KeyedResult::Unchanged => Ok(None),
+        }
+    }
 }
 
 impl Deref for QueryTracker {
@@ -64,7 +95,14 @@ impl<S: Signal> ReactiveQueries<S> {
         }
     }
 
-    pub fn subscribe(&mut self, port: PortId, key: &QueryKey, sql: &str, params: Vec<SqlValue>) {
+    pub fn subscribe(
+        &mut self,
+        port: PortId,
+        key: &QueryKey,
+        sql: &str,
+        params: Vec<SqlValue>,
+        key_column: Option<&str>,
+    ) {
         let tracker = self
             .queries
             .entry(key.clone())
@@ -72,8 +110,14 @@ impl<S: Signal> ReactiveQueries<S> {
                 query_key: key.clone(),
                 query: ReactiveQuery::new(sql.to_owned(), params),
                 ports: Vec::new(),
+                keyed: key_column.map(|key_column| KeyedQuery::new(key_column.to_owned())),
             });
 
+        // the new port needs the full result rather than a delta
+        if let Some(keyed) = &mut tracker.keyed {
+            keyed.reset();
+        }
+
         // store the port, if it's not already subscribed
         if !tracker.ports.contains(&port) {
             tracker.ports.push(port);
@@ -116,3 +160,51 @@ impl<S: Signal> ReactiveQueries<S> {
         first
     }
 }
+
+pub struct QueryStreams<S: Signal> {
+    streams: BTreeMap<(PortId, QueryKey), QueryStream<SqlValue>>,
+    has_pending_chunks: S,
+}
+
+impl<S: Signal> QueryStreams<S> {
+    pub fn new(has_pending_chunks: S) -> Self {
+        Self {
+            streams: BTreeMap::new(),
+            has_pending_chunks,
+        }
+    }
+
+    /// start streaming results to port, replacing any stream the port
+    /// already has with the same key
+    pub fn start(&mut self, port: PortId, key: &QueryKey, stream: QueryStream<SqlValue>) {
+        self.streams.insert((port, key.clone()), stream);
+        self.has_pending_chunks.emit();
+    }
+
+    pub fn cancel(&mut self, port: PortId, key: &QueryKey) {
+        self.streams.remove(&(port, key.clone()));
+    }
+
+    pub fn cancel_all(&mut self, ports: &[PortId]) {
+        self.streams.retain(|(port, _), _| !ports.contains(port));
+    }
+
+    /// take the next stream with chunks remaining, it must be returned via
+    /// resume unless it has finished. sets self.has_pending_chunks if there
+    /// are more streams.
+    pub fn next_stream(&mut self) -> Option<((PortId, QueryKey), QueryStream<SqlValue>)> {
+        let next = self.streams.pop_first();
+        if !self.streams.is_empty() {
+            self.has_pending_chunks.emit();
+        }
+        next
+    }
+
+    /// return a stream taken by next_stream, dropping it if it has finished
+    pub fn resume(&mut self, id: (PortId, QueryKey), stream: QueryStream<SqlValue>) {
+        if !stream.is_done() {
+            self.streams.insert(id, stream);
+            self.has_pending_chunks.emit();
+        }
+    }
+}
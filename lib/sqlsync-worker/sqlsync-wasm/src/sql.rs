@@ -1,3 +1,5 @@
+use std::cmp::Ordering;
+
 use serde::{de::Visitor, Deserialize, Serialize};
 use sqlsync::sqlite::{
     self,
@@ -44,6 +46,46 @@ impl ToSql for SqlValue {
     }
 }
 
+impl SqlValue {
+    fn type_order(&self) -> u8 {
+        match self {
+            SqlValue::Null => 0,
+            SqlValue::Integer(_) => 1,
+            SqlValue::Real(_) => 2,
+            SqlValue::Text(_) => 3,
+            SqlValue::Blob(_) => 4,
+        }
+    }
+}
+
+// values are ordered by type and then by value, so they can be used as keys.
+// reals use their total order, which makes NaN equal to itself
+impl Ord for SqlValue {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (SqlValue::Integer(a), SqlValue::Integer(b)) => a.cmp(b),
+            (SqlValue::Real(a), SqlValue::Real(b)) => a.total_cmp(b),
+            (SqlValue::Text(a), SqlValue::Text(b)) => a.cmp(b),
+            (SqlValue::Blob(a), SqlValue::Blob(b)) => a.cmp(b),
+            _ => self.type_order().cmp(&other.type_order()),
+        }
+    }
+}
+
+impl PartialOrd for SqlValue {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for SqlValue {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for SqlValue {}
+
 impl From<ValueRef<'_>> for SqlValue {
     fn from(value: ValueRef<'_>) -> Self {
         match value {
@@ -0,0 +1,37 @@
+use std::collections::BTreeMap;
+
+use sqlsync::WasmReducer;
+use sqlsync_reducer::types::{ReducerError, Request, Requests};
+
+/// builds a reducer which answers every mutation with a single request,
+/// allowing documents to be tested without compiling a reducer
+pub fn reducer_wasm(request: Request) -> anyhow::Result<Vec<u8>> {
+    let requests: Result<Requests, ReducerError> = Ok(Some(BTreeMap::from([(0, request)])));
+    let requests = bincode::serialize(&requests)?;
+    let escaped: String = requests.iter().map(|b| format!("\\{:02x}", b)).collect();
+
+    Ok(wat::parse_str(format!(
+        r#"
+        (module
+            (memory (export "memory") 1)
+            (data (i32.const 1100) "{escaped}")
+            (func (export "ffi_buf_allocate") (param i32) (result i32) i32.const 2048)
+            (func (export "ffi_buf_deallocate") (param i32))
+            (func (export "ffi_buf_len") (param i32) (result i32)
+                (if (i32.eq (local.get 0) (i32.const 1100))
+                    (then (return (i32.const {len}))))
+                i32.const 5)
+            (func (export "ffi_init_reducer") (result i32) i32.const {abi_version})
+            (func (export "ffi_reduce") (param i32) (result i32) i32.const 1100)
+            (func (export "ffi_reactor_step") (param i32) (result i32) i32.const 1024))
+        "#,
+        len = requests.len(),
+        abi_version = sqlsync_reducer::types::ABI_VERSION,
+    ))?)
+}
+
+/// a reducer which runs sql as a batch for every mutation
+pub fn exec_batch_reducer(sql: &str) -> anyhow::Result<WasmReducer> {
+    let wasm = reducer_wasm(Request::ExecBatch { sql: sql.into() })?;
+    Ok(WasmReducer::new(wasm.as_slice())?)
+}
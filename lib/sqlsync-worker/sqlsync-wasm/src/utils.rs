@@ -2,12 +2,14 @@ use std::{convert::TryFrom, fmt::Display, io};
 
 use anyhow::anyhow;
 use gloo::{net::http::Request, timers::future::TimeoutFuture, utils::errors::JsError};
-use js_sys::{Reflect, Uint8Array};
 use log::Level;
+use rand::{rngs::StdRng, Rng, SeedableRng};
 use sha2::{Digest, Sha256};
-use sqlsync::WasmReducer;
-use wasm_bindgen::{JsCast, JsValue};
-use wasm_bindgen_futures::JsFuture;
+use sqlsync::{
+    debounce::{self, Trigger},
+    WasmReducer,
+};
+use wasm_bindgen::JsValue;
 use web_sys::console;
 
 pub fn set_panic_hook() {
@@ -49,6 +51,26 @@ impl Display for WasmError {
     }
 }
 
+impl WasmError {
+    /// returns the primary and extended sqlite result codes of the sqlite
+    /// failure which caused this error, if any
+    pub fn sqlite_codes(&self) -> Option<(i32, i32)> {
+        self.0.chain().find_map(|err| {
+            let err = match err.downcast_ref::<sqlsync::error::Error>() {
+                Some(err) => err.sqlite_error()?,
+                None => err.downcast_ref::<sqlsync::sqlite::Error>()?,
+            };
+            match err {
+                sqlsync::sqlite::Error::SqliteFailure(err, _) => {
+                    // the primary result code is the low byte of the extended code
+                    Some((err.extended_code & 0xff, err.extended_code))
+                }
+                _ => None,
+            }
+        })
+    }
+}
+
 impl From<JsValue> for WasmError {
     fn from(value: JsValue) -> Self {
         match JsError::try_from(value) {
@@ -92,9 +114,11 @@ impl_from_error!(
     bincode::Error,
     io::Error,
     sqlsync::error::Error,
+    sqlsync::local::WaitError,
     sqlsync::sqlite::Error,
     sqlsync::replication::ReplicationError,
     sqlsync::JournalIdParseError,
+    sqlsync::MissingKeyColumn,
     sqlsync::ReducerError,
     gloo::utils::errors::JsError,
     gloo::net::Error,
@@ -112,7 +136,42 @@ pub async fn fetch_reducer(reducer_url: &str) -> Result<(WasmReducer, Vec<u8>),
         )));
     }
 
-    let mut reducer_wasm_bytes = resp.binary().await?;
+    load_reducer(resp.binary().await?).await
+}
+
+/// resolve the reducer for a document, preferring inline wasm bytes over
+/// fetching reducer_url
+pub async fn resolve_reducer(
+    reducer_bytes: Option<Vec<u8>>,
+    reducer_url: Option<&str>,
+) -> Result<(WasmReducer, Vec<u8>), WasmError> {
+    match (reducer_bytes, reducer_url) {
+        (Some(bytes), _) => load_reducer(bytes).await,
+        (None, Some(url)) => fetch_reducer(url).await,
+        (None, None) => Err(WasmError(anyhow!(
+            "opening a document requires either reducer_url or reducer_bytes"
+        ))),
+    }
+}
+
+/// instantiate a reducer from wasm bytes, returning the reducer along with
+/// the sha256 digest of the bytes
+pub async fn load_reducer(
+    mut reducer_wasm_bytes: Vec<u8>,
+) -> Result<(WasmReducer, Vec<u8>), WasmError> {
+    let digest = sha256_digest(&mut reducer_wasm_bytes).await?;
+
+    let reducer = WasmReducer::new(reducer_wasm_bytes.as_slice())
+        .map_err(|err| anyhow!("failed to instantiate reducer from wasm: {}", err))?;
+
+    Ok((reducer, digest))
+}
+
+#[cfg(target_arch = "wasm32")]
+async fn sha256_digest(data: &mut [u8]) -> Result<Vec<u8>, WasmError> {
+    use js_sys::{Reflect, Uint8Array};
+    use wasm_bindgen::JsCast;
+    use wasm_bindgen_futures::JsFuture;
 
     let global = js_sys::global()
         .dyn_into::<js_sys::Object>()
@@ -122,38 +181,45 @@ pub async fn fetch_reducer(reducer_url: &str) -> Result<(WasmReducer, Vec<u8>),
         .expect("crypto not found")
         .subtle();
 
-    let digest: Vec<u8> = if subtle.is_undefined() {
-        let mut hasher = Sha256::new();
-        hasher.update(&reducer_wasm_bytes);
-        hasher.finalize().to_vec()
-    } else {
-        // sha256 sum the data
-        // TODO: it would be much better to stream the data through the hash function
-        // but afaik that's not doable with the crypto.subtle api
-        let digest = JsFuture::from(
-            subtle.digest_with_str_and_u8_array("SHA-256", &mut reducer_wasm_bytes)?,
-        )
-        .await?;
-        Uint8Array::new(&digest).to_vec()
-    };
+    if subtle.is_undefined() {
+        return Ok(Sha256::digest(data).to_vec());
+    }
 
-    let reducer = WasmReducer::new(reducer_wasm_bytes.as_slice())
-        .map_err(|err| anyhow!("failed to instantiate reducer from wasm: {}", err))?;
+    // TODO: it would be much better to stream the data through the hash function
+    // but afaik that's not doable with the crypto.subtle api
+    let digest = JsFuture::from(subtle.digest_with_str_and_u8_array("SHA-256", data)?).await?;
+    Ok(Uint8Array::new(&digest).to_vec())
+}
 
-    Ok((reducer, digest))
+// there is no crypto.subtle outside of a browser
+#[cfg(not(target_arch = "wasm32"))]
+async fn sha256_digest(data: &mut [u8]) -> Result<Vec<u8>, WasmError> {
+    Ok(Sha256::digest(data).to_vec())
 }
 
-pub struct Backoff {
+/// Backoff grows a delay exponentially between start_ms and max_ms. each wait
+/// is drawn uniformly from the upper half of the current delay, so that
+/// clients which were disconnected together don't all retry at the same time
+pub struct Backoff<R = StdRng> {
     current_ms: u32,
     max_ms: u32,
+    rng: R,
     future: Option<TimeoutFuture>,
 }
 
 impl Backoff {
     pub fn new(start_ms: u32, max_ms: u32) -> Self {
+        Self::with_rng(start_ms, max_ms, StdRng::from_entropy())
+    }
+}
+
+impl<R: Rng> Backoff<R> {
+    /// use a specific rng for jitter, a seeded rng makes the delays repeatable
+    pub fn with_rng(start_ms: u32, max_ms: u32, rng: R) -> Self {
         Self {
             current_ms: start_ms,
             max_ms,
+            rng,
             future: None,
         }
     }
@@ -165,11 +231,119 @@ impl Backoff {
         self.future = None;
     }
 
+    /// pick the next delay, between half of and the full current backoff
+    fn next_delay_ms(&mut self) -> u32 {
+        self.rng.gen_range(self.current_ms / 2..=self.current_ms)
+    }
+
     /// block until the current backoff time has elapsed
     pub async fn wait(&mut self) {
-        let current_ms = self.current_ms;
-        self.future
-            .get_or_insert_with(|| TimeoutFuture::new(current_ms))
-            .await;
+        if self.future.is_none() {
+            self.future = Some(TimeoutFuture::new(self.next_delay_ms()));
+        }
+        if let Some(future) = self.future.as_mut() {
+            future.await;
+        }
+    }
+}
+
+/// Debounce runs the timers for a sqlsync::debounce::Debounce, see there for
+/// how triggers are coalesced
+pub struct Debounce {
+    window: debounce::Debounce,
+    future: Option<TimeoutFuture>,
+}
+
+impl Debounce {
+    pub fn new(interval_ms: u32) -> Self {
+        Self {
+            window: debounce::Debounce::new(interval_ms),
+            future: None,
+        }
+    }
+
+    /// returns true if triggers should be handled immediately
+    pub fn is_disabled(&self) -> bool {
+        self.window.is_disabled()
+    }
+
+    /// record a trigger, returns true if it should be handled right away
+    /// rather than once the window elapses
+    pub fn trigger(&mut self) -> bool {
+        match self.window.trigger() {
+            Trigger::Now => true,
+            Trigger::StartTimer(interval_ms) => {
+                self.future = Some(TimeoutFuture::new(interval_ms));
+                false
+            }
+            Trigger::Coalesced => false,
+        }
+    }
+
+    /// block until the debounce window elapses
+    /// if the debounce has not been triggered, this will block forever
+    pub async fn wait(&mut self) {
+        match self.future.as_mut() {
+            Some(future) => {
+                future.await;
+                self.future = None;
+                self.window.elapsed();
+            }
+            None => futures::future::pending().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use futures::executor::block_on;
+    use sha2::{Digest, Sha256};
+    use sqlsync::{
+        local::{DocumentSignals, LocalDocument, NoopSignal},
+        JournalId, MemoryJournal,
+    };
+    use sqlsync_reducer::types::Request;
+
+    use super::resolve_reducer;
+    use crate::test_helpers::reducer_wasm;
+
+    #[test]
+    fn test_open_with_reducer_bytes() -> anyhow::Result<()> {
+        let wasm = reducer_wasm(Request::Exec {
+            sql: "CREATE TABLE IF NOT EXISTS t (x INTEGER)".into(),
+            params: vec![],
+            named_params: BTreeMap::new(),
+        })?;
+
+        // inline bytes win over the url, which is never fetched
+        let (reducer, digest) = block_on(resolve_reducer(
+            Some(wasm.clone()),
+            Some("http://unreachable"),
+        ))
+        .map_err(|e| e.0)?;
+        assert_eq!(digest, Sha256::digest(&wasm).to_vec());
+
+        let mut doc = LocalDocument::open(
+            MemoryJournal::open(JournalId::new128(&mut rand::thread_rng()))?,
+            MemoryJournal::open(JournalId::new128(&mut rand::thread_rng()))?,
+            reducer,
+            DocumentSignals::<NoopSignal>::default(),
+        )?;
+        doc.mutate(&[0])?;
+
+        let tables: i64 = doc.query(|conn| {
+            conn.query_row(
+                "SELECT count(*) FROM sqlite_master WHERE name = 't'",
+                [],
+                |row| row.get(0),
+            )
+        })?;
+        assert_eq!(tables, 1);
+
+        assert!(block_on(resolve_reducer(None, None)).is_err());
+
+        Ok(())
     }
 }
@@ -5,7 +5,7 @@ export {
   randomJournalId256,
 } from "./journal-id";
 export { normalizeQuery, sql } from "./sql";
-export { SQLSync } from "./sqlsync";
+export { SQLSync, SQLSyncError } from "./sqlsync";
 export { pendingPromise, serializeMutationAsJSON } from "./util";
 
 import type {
@@ -13,11 +13,12 @@ import type {
   DocId,
   DocRequest,
   HandlerId,
+  QueryPlanStep,
   SqlValue,
 } from "../sqlsync-wasm/pkg/sqlsync_wasm";
 import type { JournalId } from "./journal-id";
 import type { ParameterizedQuery } from "./sql";
-import type { DocType, QuerySubscription } from "./sqlsync";
+import type { DocType, QueryStreamHandler, QuerySubscription } from "./sqlsync";
 import type { Row } from "./types";
 
 export type {
@@ -28,6 +29,8 @@ export type {
   HandlerId,
   JournalId,
   ParameterizedQuery,
+  QueryPlanStep,
+  QueryStreamHandler,
   QuerySubscription,
   Row,
   SqlValue,
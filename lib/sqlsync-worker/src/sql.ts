@@ -7,6 +7,12 @@ const UTF8_ENCODER = new TextEncoder();
 export interface ParameterizedQuery {
   sql: string;
   params: SqlValue[];
+  /**
+   * A column which uniquely and stably identifies each row, such as an integer
+   * or text primary key. Subscriptions to queries with a key column receive
+   * only the rows which changed after the first result.
+   */
+  keyColumn?: string;
 }
 
 export function normalizeQuery(query: ParameterizedQuery | string): ParameterizedQuery {
@@ -35,7 +41,7 @@ export function sql(chunks: readonly string[], ...params: SqlValue[]): Parameter
 }
 
 export async function toQueryKey(query: ParameterizedQuery): Promise<QueryKey> {
-  const queryJson = JSON.stringify([query.sql, query.params]);
+  const queryJson = JSON.stringify([query.sql, query.params, query.keyColumn ?? null]);
   const encoded = UTF8_ENCODER.encode(queryJson);
   const hashed = await sha256Digest(encoded);
   return base58.encode(new Uint8Array(hashed));
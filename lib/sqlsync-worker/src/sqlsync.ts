@@ -5,17 +5,54 @@ import {
   DocReply,
   HandlerId,
   QueryKey,
+  QueryPlanStep,
   SqlValue,
   WorkerToHostMsg,
 } from "../sqlsync-wasm/pkg/sqlsync_wasm";
 import { journalIdToString } from "./journal-id";
 import { ParameterizedQuery, toQueryKey } from "./sql";
 import { Row, WorkerRequest } from "./types";
-import { NarrowTaggedEnum, OmitUnion, assertUnreachable, initWorker, toRows } from "./util";
+import {
+  NarrowTaggedEnum,
+  OmitUnion,
+  assertUnreachable,
+  initWorker,
+  toMapKey,
+  toRows,
+} from "./util";
 
 export interface DocType<Mutation> {
-  readonly reducerUrl: string | URL;
+  // where to fetch the reducer from, one of reducerUrl or reducerBytes is required
+  readonly reducerUrl?: string | URL;
+  // the reducer's wasm module, if set the worker won't fetch reducerUrl
+  readonly reducerBytes?: Uint8Array;
   readonly serializeMutation: (mutation: Mutation) => Uint8Array;
+
+  // coalesce storage changes which occur within this many milliseconds of the
+  // first into a single subscription refresh, so subscriptions refresh at most
+  // once per window during a burst of mutations; defaults to 0 (disabled)
+  readonly storageDebounceMs?: number;
+
+  // buffer mutations for this many milliseconds after the first one and sync
+  // them to the coordinator together; defaults to 0 (disabled)
+  readonly commitWindowMs?: number;
+
+  // store the document in OPFS so that it, along with the client's timeline id
+  // and any unsent mutations, survives a reload; defaults to false
+  readonly persist?: boolean;
+}
+
+// SQLSyncError is raised when the worker fails to handle a request; if sqlite
+// caused the failure, the primary and extended sqlite result codes are set
+export class SQLSyncError extends Error {
+  constructor(
+    message: string,
+    readonly sqliteCode?: number,
+    readonly sqliteExtendedCode?: number,
+  ) {
+    super(message);
+    this.name = "SQLSyncError";
+  }
 }
 
 type DocReplyTag = DocReply["tag"];
@@ -24,6 +61,16 @@ type SelectDocReply<T> = NarrowTaggedEnum<DocReply, T>;
 export interface QuerySubscription {
   handleRows: (rows: Row[]) => void;
   handleErr: (err: string) => void;
+  // called instead of handleRows with the rows which changed, for queries
+  // with a keyColumn. deletes contains the key of each removed row, and order
+  // the key of every row in result order if rows were added or moved
+  handleDelta?: (upserts: Row[], deletes: SqlValue[], order?: SqlValue[]) => void;
+}
+
+export interface QueryStreamHandler {
+  // called once per chunk of rows, done is true for the last chunk
+  handleChunk: (rows: Row[], done: boolean) => void;
+  handleErr: (err: string) => void;
 }
 
 const nextHandlerId = (() => {
@@ -37,6 +84,10 @@ export class SQLSync {
   #pendingOpens = new Map<DocId, Promise<{ tag: "Ack" }>>();
   #msgHandlers = new Map<HandlerId, (msg: DocReply) => void>();
   #querySubscriptions = new Map<QueryKey, QuerySubscription[]>();
+  // the latest rows of each subscribed query with a keyColumn in result
+  // order, by the toMapKey of their key
+  #keyedRows = new Map<QueryKey, { keyColumn: string; rows: Map<unknown, Row> }>();
+  #queryStreams = new Map<QueryKey, QueryStreamHandler>();
   #connectionStatus: ConnectionStatus = "disconnected";
   #connectionStatusListeners = new Set<(status: ConnectionStatus) => void>();
 
@@ -104,10 +155,49 @@ export class SQLSync {
         listener(evt.status);
       }
     } else if (evt.tag === "SubscriptionChanged") {
+      const rows = toRows(evt.columns, evt.rows);
+      const keyed = this.#keyedRows.get(evt.key);
+      if (keyed) {
+        keyed.rows = new Map(rows.map((row) => [toMapKey(row[keyed.keyColumn]), row]));
+      }
+      const subscriptions = this.#querySubscriptions.get(evt.key);
+      if (subscriptions) {
+        for (const subscription of subscriptions) {
+          subscription.handleRows(rows);
+        }
+      }
+    } else if (evt.tag === "SubscriptionDelta") {
+      const upserts = toRows(evt.columns, evt.upserts);
+      const keyed = this.#keyedRows.get(evt.key);
+      if (keyed) {
+        for (const key of evt.deletes) {
+          keyed.rows.delete(toMapKey(key));
+        }
+        for (const row of upserts) {
+          keyed.rows.set(toMapKey(row[keyed.keyColumn]), row);
+        }
+        // maps iterate in insertion order, so rebuild it if rows were added
+        // or moved
+        if (evt.order) {
+          const rows = keyed.rows;
+          keyed.rows = new Map();
+          for (const key of evt.order) {
+            const mapKey = toMapKey(key);
+            const row = rows.get(mapKey);
+            if (row) {
+              keyed.rows.set(mapKey, row);
+            }
+          }
+        }
+      }
       const subscriptions = this.#querySubscriptions.get(evt.key);
       if (subscriptions) {
         for (const subscription of subscriptions) {
-          subscription.handleRows(toRows(evt.columns, evt.rows));
+          if (subscription.handleDelta) {
+            subscription.handleDelta(upserts, evt.deletes, evt.order);
+          } else if (keyed) {
+            subscription.handleRows(Array.from(keyed.rows.values()));
+          }
         }
       }
     } else if (evt.tag === "SubscriptionErr") {
@@ -117,6 +207,16 @@ export class SQLSync {
           subscription.handleErr(evt.err);
         }
       }
+    } else if (evt.tag === "QueryChunk") {
+      const stream = this.#queryStreams.get(evt.key);
+      if (evt.done) {
+        this.#queryStreams.delete(evt.key);
+      }
+      stream?.handleChunk(toRows(evt.columns, evt.rows), evt.done);
+    } else if (evt.tag === "QueryStreamErr") {
+      const stream = this.#queryStreams.get(evt.key);
+      this.#queryStreams.delete(evt.key);
+      stream?.handleErr(evt.err);
     } else {
       assertUnreachable("unknown event", evt);
     }
@@ -135,7 +235,7 @@ export class SQLSync {
       this.#msgHandlers.set(handlerId, (msg: DocReply) => {
         this.#msgHandlers.delete(handlerId);
         if (msg.tag === "Err") {
-          reject(msg.err);
+          reject(new SQLSyncError(msg.message, msg.sqliteCode, msg.sqliteExtendedCode));
         } else if (msg.tag === expectedReplyTag) {
           // TODO: is it possible to get Typescript to infer this cast?
           resolve(msg as SelectDocReply<T>);
@@ -165,7 +265,11 @@ export class SQLSync {
         docId,
         req: {
           tag: "Open",
-          reducerUrl: docType.reducerUrl.toString(),
+          reducerUrl: docType.reducerUrl?.toString(),
+          reducerBytes: docType.reducerBytes,
+          storageDebounceMs: docType.storageDebounceMs,
+          commitWindowMs: docType.commitWindowMs,
+          persist: docType.persist,
         },
       });
       this.#pendingOpens.set(docId, openPromise);
@@ -175,23 +279,144 @@ export class SQLSync {
     this.#openDocs.add(docId);
   }
 
+  // aborting signal cancels the query, which then rejects with a
+  // SQLSyncError whose sqliteCode is SQLITE_INTERRUPT
   async query<M, T extends Row = Row>(
     docId: DocId,
     docType: DocType<M>,
     sql: string,
     params: SqlValue[],
+    signal?: AbortSignal,
   ): Promise<T[]> {
     if (!this.#openDocs.has(docId)) {
       await this.#open(docId, docType);
     }
 
-    const reply = await this.#send("RecordSet", {
+    const key = signal && `query-${nextHandlerId()}`;
+    const reply = await this.#cancellable(docId, key, signal, () =>
+      this.#send("RecordSet", {
+        tag: "Doc",
+        docId: docId,
+        req: { tag: "Query", sql, params, key },
+      }),
+    );
+
+    return toRows(reply.columns, reply.rows);
+  }
+
+  // returns the plan sqlite would use to run a query without running it, useful
+  // for checking whether a query uses an index
+  async explain<M>(
+    docId: DocId,
+    docType: DocType<M>,
+    sql: string,
+    params: SqlValue[],
+  ): Promise<QueryPlanStep[]> {
+    if (!this.#openDocs.has(docId)) {
+      await this.#open(docId, docType);
+    }
+
+    const reply = await this.#send("QueryPlan", {
       tag: "Doc",
       docId: docId,
-      req: { tag: "Query", sql, params },
+      req: { tag: "Explain", sql, params },
     });
 
-    return toRows(reply.columns, reply.rows);
+    return reply.steps;
+  }
+
+  // resolves once the coordinator has applied the mutation at lsn (as returned
+  // by mutate) and queries observe the result. rejects if the mutation is
+  // rejected, the connection is disabled, or timeoutMs elapses
+  async waitForSync<M>(
+    docId: DocId,
+    docType: DocType<M>,
+    lsn: number,
+    timeoutMs?: number,
+  ): Promise<void> {
+    if (!this.#openDocs.has(docId)) {
+      await this.#open(docId, docType);
+    }
+
+    await this.#send("Ack", {
+      tag: "Doc",
+      docId: docId,
+      req: { tag: "WaitForSync", lsn, timeoutMs },
+    });
+  }
+
+  // like query, but delivers the results in chunks of at most chunkRows rows so
+  // that large result sets don't block the worker
+  async queryStream<M>(
+    docId: DocId,
+    docType: DocType<M>,
+    sql: string,
+    params: SqlValue[],
+    handler: QueryStreamHandler,
+    chunkRows?: number,
+    signal?: AbortSignal,
+  ): Promise<void> {
+    if (!this.#openDocs.has(docId)) {
+      await this.#open(docId, docType);
+    }
+
+    const key = `stream-${nextHandlerId()}`;
+    this.#queryStreams.set(key, handler);
+    try {
+      await this.#send("Ack", {
+        tag: "Doc",
+        docId,
+        req: { tag: "QueryStream", key, sql, params, chunkRows },
+      });
+    } catch (err) {
+      this.#queryStreams.delete(key);
+      throw err;
+    }
+
+    // stop delivering chunks once the signal is aborted
+    signal?.addEventListener(
+      "abort",
+      () => {
+        if (this.#queryStreams.delete(key)) {
+          this.#cancelQuery(docId, key);
+        }
+      },
+      { once: true },
+    );
+  }
+
+  // run send, cancelling the keyed request if signal is aborted before it
+  // settles
+  async #cancellable<T>(
+    docId: DocId,
+    key: QueryKey | undefined,
+    signal: AbortSignal | undefined,
+    send: () => Promise<T>,
+  ): Promise<T> {
+    if (!signal || key === undefined) {
+      return send();
+    }
+    if (signal.aborted) {
+      throw new DOMException("the query was aborted", "AbortError");
+    }
+
+    const onAbort = () => this.#cancelQuery(docId, key);
+    signal.addEventListener("abort", onAbort, { once: true });
+    try {
+      return await send();
+    } finally {
+      signal.removeEventListener("abort", onAbort);
+    }
+  }
+
+  #cancelQuery(docId: DocId, key: QueryKey) {
+    this.#send("Ack", {
+      tag: "Doc",
+      docId,
+      req: { tag: "CancelQuery", key },
+    }).catch((err) => {
+      console.error("sqlsync: error cancelling query", err);
+    });
   }
 
   async subscribe<M>(
@@ -216,12 +441,21 @@ export class SQLSync {
     } else {
       throw new Error("sqlsync: duplicate subscription");
     }
+    if (query.keyColumn !== undefined && !this.#keyedRows.has(queryKey)) {
+      this.#keyedRows.set(queryKey, { keyColumn: query.keyColumn, rows: new Map() });
+    }
 
     // send subscribe request
     await this.#send("Ack", {
       tag: "Doc",
       docId,
-      req: { tag: "QuerySubscribe", key: queryKey, sql: query.sql, params: query.params },
+      req: {
+        tag: "QuerySubscribe",
+        key: queryKey,
+        sql: query.sql,
+        params: query.params,
+        keyColumn: query.keyColumn,
+      },
     });
 
     // return unsubscribe function
@@ -254,6 +488,7 @@ export class SQLSync {
       // query subscription is still registered but has no subscriptions on our side
       // inform the worker that we are no longer interested in this query
       this.#querySubscriptions.delete(queryKey);
+      this.#keyedRows.delete(queryKey);
 
       if (this.#openDocs.has(docId)) {
         await this.#send("Ack", {
@@ -265,15 +500,18 @@ export class SQLSync {
     }
   }
 
-  async mutate<M>(docId: DocId, docType: DocType<M>, mutation: M): Promise<void> {
+  // applies a mutation, resolving to the lsn it can be waited on at with
+  // waitForSync
+  async mutate<M>(docId: DocId, docType: DocType<M>, mutation: M): Promise<number> {
     if (!this.#openDocs.has(docId)) {
       await this.#open(docId, docType);
     }
-    await this.#send("Ack", {
+    const reply = await this.#send("Mutated", {
       tag: "Doc",
       docId,
       req: { tag: "Mutate", mutation: docType.serializeMutation(mutation) },
     });
+    return reply.lsn;
   }
 
   get connectionStatus(): ConnectionStatus {
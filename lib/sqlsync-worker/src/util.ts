@@ -44,6 +44,18 @@ export function toRows<R extends Row = Row>(columns: string[], rows: SqlValue[][
   return out;
 }
 
+// Map compares keys by identity, so blob keys are compared by their bytes
+// instead. text is prefixed so that it can't collide with an encoded blob
+export function toMapKey(value: SqlValue): unknown {
+  if (value instanceof Uint8Array) {
+    return `b:${Array.from(value, (b) => b.toString(16).padStart(2, "0")).join("")}`;
+  }
+  if (typeof value === "string") {
+    return `s:${value}`;
+  }
+  return value;
+}
+
 export const pendingPromise = <T = undefined>(): [Promise<T>, (v: T) => void] => {
   let resolve: (v: T) => void;
   const promise = new Promise<T>((r) => {
@@ -24,7 +24,7 @@ const MessageQueue = (() => {
           await handleMessage(m);
         } catch (e) {
           const err = e instanceof Error ? e.message : `error: ${JSON.stringify(e)}`;
-          reply(m.portId, m.req.handlerId, { tag: "Err", err });
+          reply(m.portId, m.req.handlerId, { tag: "Err", message: err });
         }
       });
     },
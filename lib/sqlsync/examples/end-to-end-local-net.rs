@@ -11,8 +11,10 @@ use rand::rngs::StdRng;
 use rand::thread_rng;
 use rand::Rng;
 use rand::SeedableRng;
+use sqlsync::local::DocumentSignals;
 use sqlsync::local::LocalDocument;
 use sqlsync::local::NoopSignal;
+use sqlsync::positioned_io::PositionedReader;
 use sqlsync::replication::ReplicationMsg;
 use sqlsync::replication::ReplicationProtocol;
 use sqlsync::JournalId;
@@ -186,7 +188,7 @@ fn handle_client(
             if let Some((msg, mut reader)) = protocol.sync(doc)? {
                 log::info!("server: syncing to client: {:?}", msg);
                 send_msg(socket_writer, &msg)?;
-                let frame_len = reader.len() as u64;
+                let frame_len = reader.size()? as u64;
                 // write the frame
                 let n = io::copy(&mut reader, &mut socket_writer)?;
                 assert!(
@@ -222,9 +224,7 @@ fn start_client(
         storage_journal,
         timeline_journal,
         WasmReducer::new(wasm_bytes.as_slice())?,
-        NoopSignal,
-        NoopSignal,
-        NoopSignal,
+        DocumentSignals::<NoopSignal>::default(),
     )?;
 
     // initialize schema
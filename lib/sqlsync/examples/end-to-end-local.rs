@@ -2,14 +2,12 @@
 //! and a server. There is no networking in this example so it's easy to follow
 //! the sync & rebase logic between the different nodes.
 
-use std::{collections::BTreeMap, format, io};
-
 use rand::{rngs::StdRng, Rng, SeedableRng};
 use serde::{Deserialize, Serialize};
 use sqlsync::{
     coordinator::CoordinatorDocument,
-    local::{LocalDocument, NoopSignal},
-    replication::ReplicationProtocol,
+    duplex::DuplexReplication,
+    local::{DocumentSignals, LocalDocument, NoopSignal},
     sqlite::Connection,
     JournalId, MemoryJournal, MemoryJournalFactory, WasmReducer,
 };
@@ -97,17 +95,13 @@ fn main() -> anyhow::Result<()> {
         MemoryJournal::open(doc_id)?,
         MemoryJournal::open(JournalId::new128(&mut rng))?,
         WasmReducer::new(wasm_bytes.as_slice())?,
-        NoopSignal,
-        NoopSignal,
-        NoopSignal,
+        DocumentSignals::<NoopSignal>::default(),
     )?;
     let mut local2 = LocalDocument::open(
         MemoryJournal::open(doc_id)?,
         MemoryJournal::open(JournalId::new128(&mut rng))?,
         WasmReducer::new(wasm_bytes.as_slice())?,
-        NoopSignal,
-        NoopSignal,
-        NoopSignal,
+        DocumentSignals::<NoopSignal>::default(),
     )?;
     let mut remote = CoordinatorDocument::open(
         MemoryJournal::open(doc_id)?,
@@ -115,20 +109,10 @@ fn main() -> anyhow::Result<()> {
         WasmReducer::new(wasm_bytes.as_slice())?,
     )?;
 
-    let mut protocols = BTreeMap::new();
-    protocols.insert("local->remote", ReplicationProtocol::new());
-    protocols.insert("remote->local", ReplicationProtocol::new());
-    protocols.insert("local2->remote", ReplicationProtocol::new());
-    protocols.insert("remote->local2", ReplicationProtocol::new());
-
-    macro_rules! protocol {
-        ($from:ident -> $to:ident) => {{
-            let key = format!("{}->{}", stringify!($from), stringify!($to));
-            protocols.get_mut(key.as_str()).unwrap()
-        }};
-    }
-
-    let mut empty_reader = io::empty();
+    // each client has a connection to remote which carries its timeline to
+    // remote and remote's storage back to the client
+    let mut local_conn = DuplexReplication::new();
+    let mut local2_conn = DuplexReplication::new();
 
     macro_rules! debug_state {
         (start $($log_args:tt)+) => {
@@ -217,57 +201,17 @@ fn main() -> anyhow::Result<()> {
         };
     }
 
-    macro_rules! send {
-        ($from:ident -> $to:ident, $msg:expr, $reader:expr) => {
+    macro_rules! sync {
+        ($client:ident, $conn:ident) => {
+            debug_state!(start "syncing: {} <-> remote", stringify!($client));
+
+            let exchanged = $conn.replicate(&mut $client, &mut remote)?;
             log::info!(
-                "sending: {:?} from {} to {}",
-                $msg,
-                stringify!($from),
-                stringify!($to)
+                "{}: exchanged {} messages with remote",
+                stringify!($client),
+                exchanged
             );
 
-            if let Some(resp) = protocol!($to -> $from).handle(&mut $to, $msg, $reader)? {
-                log::info!("received: {:?}", resp);
-
-                if let Some(resp) = protocol!($from -> $to).handle(&mut $from, resp, &mut empty_reader)? {
-                    panic!(
-                        "unexpected response, send! can only handle one round trip: {:?}",
-                        resp
-                    );
-                }
-            }
-        };
-    }
-
-    macro_rules! connect {
-        ($from:ident, $to:ident) => {
-            let msg = protocol!($from -> $to).start(&mut $from);
-            send!($from -> $to, msg, &mut empty_reader);
-
-            let msg = protocol!($to -> $from).start(&mut $to);
-            send!($to -> $from, msg, &mut empty_reader);
-        }
-    }
-
-    macro_rules! sync {
-        ($from:ident -> $to:ident) => {
-            debug_state!(start "syncing: {} -> {}", stringify!($from), stringify!($to));
-
-            let mut num_sent = 0;
-
-            while let Some((msg, reader)) = protocol!($from -> $to).sync(&$from)? {
-                // we copy here in order to release the mut borrow on protocols
-                // this is just for local testing without the network
-                let mut reader = &reader.to_owned()[..];
-                send!($from -> $to, msg, &mut reader);
-                num_sent += 1;
-            }
-
-            if num_sent > 0 {
-                log::info!("{}: synced {} frames", stringify!($from), num_sent);
-            }else {
-                log::info!("{}: nothing to sync", stringify!($from));
-            }
             debug_state!(finish);
         };
     }
@@ -279,10 +223,6 @@ fn main() -> anyhow::Result<()> {
         };
     }
 
-    // initialize replication protocols
-    connect!(local, remote);
-    connect!(local2, remote);
-
     // start the workload
     mutate!(local, InitSchema);
 
@@ -291,15 +231,17 @@ fn main() -> anyhow::Result<()> {
     // and let's say that before anything else happened local2 did some stuff
     mutate!(local2, AppendTask 4, "does this work?");
 
-    sync!(local -> remote);
+    sync!(local, local_conn);
 
     step_remote!();
 
-    sync!(remote -> local);
+    sync!(local, local_conn);
     rebase!(local);
     print_tasks!(local)?;
 
-    sync!(remote -> local2);
+    // this also sends local2's timeline to remote, but remote won't apply it
+    // until it's stepped
+    sync!(local2, local2_conn);
     rebase!(local2);
     print_tasks!(local2)?;
 
@@ -313,18 +255,18 @@ fn main() -> anyhow::Result<()> {
     print_tasks!(local)?;
     print_tasks!(local2)?;
 
-    sync!(local -> remote);
-    sync!(local2 -> remote);
+    sync!(local, local_conn);
+    sync!(local2, local2_conn);
 
-    // need to step twice to apply changes from both clients
-    // should be applied as local then local2
-    step_remote!();
-    step_remote!();
+    // each step applies changes from a single client
+    while remote.has_pending_work() {
+        step_remote!();
+    }
 
     // sync down changes
-    sync!(remote -> local);
+    sync!(local, local_conn);
     rebase!(local);
-    sync!(remote -> local2);
+    sync!(local2, local2_conn);
     rebase!(local2);
 
     print_tasks!(local)?;
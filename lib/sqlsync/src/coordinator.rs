@@ -5,18 +5,26 @@ use std::fmt::Debug;
 use std::io;
 use std::pin::Pin;
 
-use rusqlite::Transaction;
+use rusqlite::{Connection, Transaction};
 
-use crate::db::{open_with_vfs, run_in_tx, ConnectionPair};
+use crate::db::{open_with_vfs, run_in_tx, ConnectionPair, OpenConfig};
 use crate::error::Result;
-use crate::reducer::Reducer;
-use crate::replication::{ReplicationDestination, ReplicationError, ReplicationSource};
-use crate::timeline::{apply_timeline_range, run_timeline_migration};
+use crate::logging;
+use crate::page::DEFAULT_PAGESIZE;
+use crate::reducer::{validate_mutation, MutationContext, Reducer};
+use crate::replication::{
+    AppliedWatermark, Rejection, ReplicationDestination, ReplicationError, ReplicationSource,
+};
+use crate::timeline::{
+    applied_lsn, apply_timeline_range, prune_outputs, run_reducer_migration,
+    run_timeline_migration, Deadline, MutationValidator,
+};
+use crate::unixtime::unix_timestamp_milliseconds;
 use crate::Lsn;
 use crate::{
-    journal::{Journal, JournalFactory, JournalId},
+    journal::{Journal, JournalError, JournalFactory, JournalId},
     lsn::LsnRange,
-    storage::Storage,
+    storage::{DocumentStats, Storage},
 };
 
 struct ReceiveQueueEntry {
@@ -24,13 +32,44 @@ struct ReceiveQueueEntry {
     range: LsnRange,
 }
 
+/// CoordinatorMetrics receives events from the task which runs a
+/// coordinator, which lets operators export counters without changing the
+/// task. every method defaults to doing nothing
+pub trait CoordinatorMetrics {
+    /// called after each step, with the time spent stepping
+    fn on_step(&mut self, _elapsed_ms: i64) {}
+
+    /// called when a storage frame of the given size is sent to a client
+    fn on_frame_sent(&mut self, _bytes: usize) {}
+
+    /// called when a client connects, with the number of connected clients
+    fn on_client_connect(&mut self, _clients: usize) {}
+
+    /// called when a client is dropped, with the number of remaining clients
+    fn on_client_disconnect(&mut self, _clients: usize) {}
+
+    /// called when a storage frame has been durably persisted
+    fn on_persist(&mut self, _lsn: Lsn, _bytes: usize) {}
+}
+
+/// NoopMetrics ignores every event
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoopMetrics;
+
+impl CoordinatorMetrics for NoopMetrics {}
+
 pub struct CoordinatorDocument<J: Journal, R> {
     reducer: R,
-    storage: Pin<Box<Storage<J>>>,
+    // see open_with_storage for why this is declared before storage
     sqlite: ConnectionPair,
+    storage: Pin<Box<Storage<J>>>,
     timeline_factory: J::Factory,
     timelines: HashMap<JournalId, J>,
     timeline_receive_queue: VecDeque<ReceiveQueueEntry>,
+
+    validator: Option<Box<MutationValidator<'static>>>,
+    // rejections made while stepping which haven't been taken yet
+    rejections: Vec<Rejection>,
 }
 
 impl<J: Journal, R> Debug for CoordinatorDocument<J, R> {
@@ -44,10 +83,39 @@ impl<J: Journal, R> Debug for CoordinatorDocument<J, R> {
 
 impl<J: Journal, R: Reducer> CoordinatorDocument<J, R> {
     pub fn open(storage: J, timeline_factory: J::Factory, reducer: R) -> Result<Self> {
-        let (mut sqlite, mut storage) = open_with_vfs(storage)?;
+        Self::open_with_page_size(storage, timeline_factory, reducer, DEFAULT_PAGESIZE)
+    }
+
+    /// open a document whose database uses the given page size, every client
+    /// and the coordinator of a document must use the same page size
+    pub fn open_with_page_size(
+        storage: J,
+        timeline_factory: J::Factory,
+        reducer: R,
+        page_size: usize,
+    ) -> Result<Self> {
+        Self::open_with_config(
+            storage,
+            timeline_factory,
+            reducer,
+            page_size,
+            &OpenConfig::default(),
+        )
+    }
+
+    /// like open_with_page_size, but tunes the underlying sqlite connections
+    pub fn open_with_config(
+        storage: J,
+        timeline_factory: J::Factory,
+        mut reducer: R,
+        page_size: usize,
+        config: &OpenConfig,
+    ) -> Result<Self> {
+        let (mut sqlite, mut storage) = open_with_vfs(storage, page_size, config)?;
 
         // TODO: this feels awkward here
         run_timeline_migration(&mut sqlite.readwrite)?;
+        run_reducer_migration(&mut sqlite.readwrite, &mut reducer)?;
         storage.commit()?;
 
         Ok(Self {
@@ -57,6 +125,8 @@ impl<J: Journal, R: Reducer> CoordinatorDocument<J, R> {
             timeline_factory,
             timelines: HashMap::new(),
             timeline_receive_queue: VecDeque::new(),
+            validator: None,
+            rejections: Vec::new(),
         })
     }
 
@@ -67,10 +137,43 @@ impl<J: Journal, R: Reducer> CoordinatorDocument<J, R> {
         }
     }
 
+    /// validate every mutation before it is applied, mutations which fail
+    /// validation are skipped and reported by take_rejections. the client
+    /// drops a rejected mutation the next time it rebases
+    pub fn set_validator<F>(&mut self, validator: F)
+    where
+        F: FnMut(&Connection, &MutationContext, &[u8]) -> std::result::Result<(), String>
+            + Send
+            + 'static,
+    {
+        self.validator = Some(Box::new(validator));
+    }
+
+    /// returns the timeline entries rejected by the validator since the last
+    /// call, which should be sent to the client that owns each timeline as
+    /// a ReplicationMsg::Reject
+    pub fn take_rejections(&mut self) -> Vec<Rejection> {
+        std::mem::take(&mut self.rejections)
+    }
+
+    /// report the size of this document's storage
+    pub fn stats(&self) -> Result<DocumentStats> {
+        Ok(self.storage.stats()?)
+    }
+
     pub fn has_pending_work(&self) -> bool {
         !self.timeline_receive_queue.is_empty()
     }
 
+    /// check the consistency of storage and every client timeline
+    pub fn verify(&self) -> Result<(), JournalError> {
+        self.storage.verify()?;
+        for timeline in self.timelines.values() {
+            timeline.verify()?;
+        }
+        Ok(())
+    }
+
     fn mark_received(&mut self, id: JournalId, lsn: Lsn) {
         match self.timeline_receive_queue.back_mut() {
             // coalesce this update if the queue already ends with an entry for this journal
@@ -86,6 +189,8 @@ impl<J: Journal, R: Reducer> CoordinatorDocument<J, R> {
         }
     }
 
+    /// run f in a transaction and commit the result to storage. f may use
+    /// run_in_savepoint to roll back part of its changes without aborting
     pub fn mutate_direct<F, E>(&mut self, f: F) -> Result<(), E>
     where
         F: FnOnce(&mut Transaction) -> Result<(), E>,
@@ -96,38 +201,132 @@ impl<J: Journal, R: Reducer> CoordinatorDocument<J, R> {
         Ok(())
     }
 
-    pub fn step(&mut self) -> Result<()> {
-        // check to see if we have anything in the receive queue
-        let entry = self.timeline_receive_queue.pop_front();
-
-        if let Some(entry) = entry {
-            log::debug!("applying range {} to timeline {}", entry.range, entry.id);
-
-            // get the timeline
-            let timeline = self
-                .timelines
-                .get(&entry.id)
-                .expect("timeline missing in timelines but present in the receive queue");
-
-            // apply part of the timeline (per the receive queue entry) to the db
-            apply_timeline_range(
-                timeline,
-                &mut self.sqlite.readwrite,
-                &mut self.reducer,
-                entry.range,
-            )?;
-
-            // commit changes
+    /// delete the outputs recorded for the timeline with the given id in
+    /// storage frames up to and including up_to. clients read their outputs
+    /// when they rebase onto the frames they receive, so up_to should be the
+    /// last frame the timeline's client has received
+    pub fn prune_outputs(&mut self, id: JournalId, up_to: Lsn) -> Result<()> {
+        if prune_outputs(&self.sqlite.readwrite, id, up_to)? > 0 {
             self.storage.commit()?;
         }
+        Ok(())
+    }
+
+    /// write the latest version of every page in the document to writer,
+    /// which lets a new replica start from a snapshot rather than replaying
+    /// the whole storage journal. see ReplicaDocument::import_snapshot
+    pub fn export_snapshot(&self, mut writer: impl io::Write) -> Result<()> {
+        Ok(self.storage.export_snapshot(&mut writer)?)
+    }
 
+    /// apply the mutations in the next range of the receive queue
+    pub fn step(&mut self) -> Result<()> {
+        self.apply_next_range(None)?;
         Ok(())
     }
+
+    /// apply queued mutations until the queue is empty or budget_ms has
+    /// passed, any remaining work is left queued for the next call
+    ///
+    /// mutations applied before the budget runs out are committed, and a
+    /// mutation which can't be applied within the whole budget is rejected
+    /// like one which runs out of fuel, see take_rejections
+    pub fn step_with_budget(&mut self, budget_ms: i64) -> Result<()> {
+        let deadline = unix_timestamp_milliseconds() + budget_ms;
+        let mut first = true;
+        while self.has_pending_work() && (first || unix_timestamp_milliseconds() < deadline) {
+            // only the first mutation is guaranteed the whole budget
+            let finished =
+                self.apply_next_range(Some(Deadline { at: deadline, reject_first: first }))?;
+            if !finished {
+                break;
+            }
+            first = false;
+        }
+        Ok(())
+    }
+
+    /// apply the next range in the receive queue, returning false if the
+    /// deadline passed before the whole range was applied, in which case
+    /// the rest of the range stays at the front of the queue
+    ///
+    /// if applying the range fails it isn't requeued, so that an entry which
+    /// always fails can't wedge the document. apply_timeline_range retries
+    /// the range once the timeline receives another entry
+    fn apply_next_range(&mut self, deadline: Option<Deadline>) -> Result<bool> {
+        // check to see if we have anything in the receive queue
+        let entry = match self.timeline_receive_queue.pop_front() {
+            Some(entry) => entry,
+            None => return Ok(true),
+        };
+
+        log::debug!(
+            target: logging::TIMELINE,
+            "applying range {} to timeline {}",
+            entry.range,
+            entry.id
+        );
+
+        // get the timeline
+        let timeline = self
+            .timelines
+            .get(&entry.id)
+            .expect("timeline missing in timelines but present in the receive queue");
+
+        // mutations which don't match the reducer's codec are rejected
+        // along with those refused by the validator
+        let codec = self.reducer.mutation_codec();
+        let validator = &mut self.validator;
+        let mut validate = |sqlite: &Connection, context: &MutationContext, mutation: &[u8]| {
+            validate_mutation(codec, mutation).map_err(|err| err.to_string())?;
+            match validator {
+                Some(validator) => validator(sqlite, context, mutation),
+                None => Ok(()),
+            }
+        };
+
+        // apply part of the timeline (per the receive queue entry) to the db,
+        // any changes are committed to the next storage frame
+        let applied = apply_timeline_range(
+            timeline,
+            &mut self.sqlite.readwrite,
+            &mut self.reducer,
+            entry.range,
+            self.storage.next_commit_lsn(),
+            deadline,
+            Some(&mut validate),
+        )?;
+        self.rejections.extend(applied.rejections);
+
+        // pick up where we left off next time
+        let finished = applied.remaining.is_empty();
+        if !finished {
+            self.timeline_receive_queue
+                .push_front(ReceiveQueueEntry { id: entry.id, range: applied.remaining });
+        }
+
+        // commit changes
+        self.storage.commit()?;
+        Ok(finished)
+    }
+}
+
+impl<J: Journal + ReplicationDestination, R> CoordinatorDocument<J, R> {
+    /// drop storage frames before up_to, merging them into the frame at
+    /// up_to, to bound the size of the storage journal
+    ///
+    /// up_to should be persisted elsewhere and acknowledged by every
+    /// connected client. clients that are behind up_to (including new
+    /// clients) can still catch up, as they receive the frame at up_to as a
+    /// coalesced frame. see also export_snapshot
+    pub fn truncate_storage(&mut self, up_to: Lsn) -> Result<()> {
+        Ok(self.storage.truncate(up_to)?)
+    }
 }
 
 /// CoordinatorDocument knows how to replicate it's storage journal
 impl<J: Journal + ReplicationSource, R> ReplicationSource for CoordinatorDocument<J, R> {
-    type Reader<'a> = <J as ReplicationSource>::Reader<'a>
+    type Reader<'a> = <Storage<J> as ReplicationSource>::Reader<'a>
     where
         Self: 'a;
 
@@ -142,6 +341,10 @@ impl<J: Journal + ReplicationSource, R> ReplicationSource for CoordinatorDocumen
     fn read_lsn(&self, lsn: crate::Lsn) -> io::Result<Option<Self::Reader<'_>>> {
         self.storage.read_lsn(lsn)
     }
+
+    fn read_coalesced(&self, range: LsnRange) -> io::Result<Option<Self::Reader<'_>>> {
+        self.storage.read_coalesced(range)
+    }
 }
 
 /// CoordinatorDocument knows how to receive timeline journals from elsewhere
@@ -167,4 +370,328 @@ impl<J: Journal + ReplicationDestination, R: Reducer> ReplicationDestination
         self.mark_received(id, lsn);
         Ok(())
     }
+
+    fn applied_watermark(
+        &mut self,
+        id: JournalId,
+    ) -> std::result::Result<Option<AppliedWatermark>, ReplicationError> {
+        let lsn = applied_lsn(&self.sqlite.readonly, id)
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+        Ok(lsn
+            .zip(self.storage.last_committed_lsn())
+            .map(|(lsn, storage_lsn)| AppliedWatermark { id, lsn, storage_lsn }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        error::Error,
+        local::{DocumentSignals, LocalDocument, NoopSignal},
+        reducer::{MutationCodec, ReducerError, ReducerOutput},
+        replication::{ReplicationMsg, ReplicationProtocol},
+        test_helpers::{open_coordinator, open_local, replicate, SqlReducer, TestLocal},
+        MemoryJournal, MemoryJournalFactory,
+    };
+
+    use super::*;
+
+    fn count_rows(doc: &TestLocal) -> anyhow::Result<i64> {
+        Ok(doc.query(|conn| conn.query_row("SELECT COUNT(*) FROM t", [], |row| row.get(0)))?)
+    }
+
+    #[test]
+    fn test_truncate_storage() -> anyhow::Result<()> {
+        let doc_id = JournalId::new128(&mut rand::thread_rng());
+        let mut local = open_local(doc_id)?;
+        let mut coordinator = open_coordinator(doc_id)?;
+        let mut local_to_coordinator = ReplicationProtocol::new();
+        let mut coordinator_to_local = ReplicationProtocol::new();
+
+        local.mutate(b"CREATE TABLE t (x)")?;
+        for i in 0..50 {
+            local.mutate(format!("INSERT INTO t VALUES ({})", i).as_bytes())?;
+            replicate(&mut local_to_coordinator, &local, &mut coordinator)?;
+            while coordinator.has_pending_work() {
+                coordinator.step()?;
+            }
+            replicate(&mut coordinator_to_local, &coordinator, &mut local)?;
+            local.rebase()?;
+
+            // the storage journal stays bounded while the client keeps up
+            coordinator.truncate_storage(coordinator_to_local.last_acked().unwrap())?;
+            assert!(coordinator.source_range().len() <= 1);
+        }
+        coordinator.verify()?;
+        assert_eq!(coordinator.source_range().len(), 1);
+        assert_eq!(count_rows(&local)?, 50);
+
+        // a new client catches up from the truncated journal
+        let mut local2 = open_local(doc_id)?;
+        replicate(&mut ReplicationProtocol::new(), &coordinator, &mut local2)?;
+        local2.rebase()?;
+        assert_eq!(count_rows(&local2)?, 50);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reject_mutation() -> anyhow::Result<()> {
+        let doc_id = JournalId::new128(&mut rand::thread_rng());
+        let mut local = open_local(doc_id)?;
+        let mut coordinator = open_coordinator(doc_id)?;
+        coordinator.set_validator(|_, _, mutation| match std::str::from_utf8(mutation) {
+            Ok(sql) if sql.contains("666") => Err("666 is not allowed".into()),
+            _ => Ok(()),
+        });
+        let mut local_to_coordinator = ReplicationProtocol::new();
+        let mut coordinator_to_local = ReplicationProtocol::new();
+
+        local.mutate(b"CREATE TABLE t (x)")?;
+        local.mutate(b"INSERT INTO t VALUES (1)")?;
+        local.mutate(b"INSERT INTO t VALUES (666)")?;
+        local.mutate(b"INSERT INTO t VALUES (2)")?;
+        assert_eq!(count_rows(&local)?, 3);
+
+        replicate(&mut local_to_coordinator, &local, &mut coordinator)?;
+        while coordinator.has_pending_work() {
+            coordinator.step()?;
+        }
+        let rejections = coordinator.take_rejections();
+        assert_eq!(
+            rejections,
+            vec![Rejection {
+                timeline_id: local.source_id(),
+                lsn: 2,
+                reason: "666 is not allowed".into(),
+            }]
+        );
+        assert!(coordinator.take_rejections().is_empty());
+
+        // the client rolls back the rejected mutation as soon as it hears
+        // about it, even before it receives the coordinator's storage
+        for rejection in rejections {
+            let msg = ReplicationMsg::from(rejection);
+            coordinator_to_local.handle(&mut local, msg, &mut io::empty())?;
+        }
+        local.rebase()?;
+        assert_eq!(count_rows(&local)?, 2);
+        assert_eq!(local.take_rejections().len(), 1);
+
+        // and converges with the coordinator once it does
+        replicate(&mut coordinator_to_local, &coordinator, &mut local)?;
+        local.rebase()?;
+        assert_eq!(count_rows(&local)?, 2);
+        assert!(local.source_range().is_empty());
+        let mut local2 = open_local(doc_id)?;
+        replicate(&mut ReplicationProtocol::new(), &coordinator, &mut local2)?;
+        local2.rebase()?;
+        assert_eq!(count_rows(&local2)?, 2);
+
+        Ok(())
+    }
+
+    // like SqlReducer, but mutations mentioning "slow" run until their
+    // deadline and time out, mutations mentioning "spin" run out of fuel, and
+    // mutations mentioning "flaky" fail while flaky_failures is positive
+    #[derive(Default)]
+    struct ExpensiveReducer {
+        flaky_failures: u32,
+    }
+
+    impl Reducer for ExpensiveReducer {
+        fn apply(
+            &mut self,
+            tx: &mut rusqlite::Transaction,
+            mutation: &[u8],
+        ) -> std::result::Result<ReducerOutput, ReducerError> {
+            self.apply_with_deadline(tx, mutation, None)
+        }
+
+        fn apply_with_deadline(
+            &mut self,
+            tx: &mut rusqlite::Transaction,
+            mutation: &[u8],
+            deadline: Option<i64>,
+        ) -> std::result::Result<ReducerOutput, ReducerError> {
+            let sql = std::str::from_utf8(mutation).unwrap();
+            if let (true, Some(deadline)) = (sql.contains("slow"), deadline) {
+                let remaining = deadline - unix_timestamp_milliseconds();
+                std::thread::sleep(std::time::Duration::from_millis(remaining.max(0) as u64));
+                return Err(ReducerError::Timeout);
+            }
+            if sql.contains("spin") {
+                return Err(ReducerError::FuelExhausted);
+            }
+            if sql.contains("flaky") && self.flaky_failures > 0 {
+                self.flaky_failures -= 1;
+                return Err(ReducerError::External("flaky".into()));
+            }
+            SqlReducer.apply(tx, mutation)
+        }
+    }
+
+    #[test]
+    fn test_step_failures_keep_or_reject_entries() -> anyhow::Result<()> {
+        let doc_id = JournalId::new128(&mut rand::thread_rng());
+        let mut local = open_local(doc_id)?;
+        let mut coordinator = CoordinatorDocument::open(
+            MemoryJournal::open(doc_id)?,
+            MemoryJournalFactory,
+            ExpensiveReducer::default(),
+        )?;
+
+        local.mutate(b"CREATE TABLE t (x)")?;
+        local.mutate(b"INSERT INTO t VALUES (1)")?;
+        local.mutate(b"INSERT INTO t VALUES ('slow')")?;
+        local.mutate(b"INSERT INTO t VALUES ('spin')")?;
+        local.mutate(b"INSERT INTO t VALUES (2)")?;
+        replicate(&mut ReplicationProtocol::new(), &local, &mut coordinator)?;
+        let timeline_id = local.source_id();
+
+        // the budget runs out part way through the range, the mutations
+        // applied before the slow one are committed and the rest stay queued
+        coordinator.step_with_budget(20)?;
+        assert!(coordinator.has_pending_work());
+        assert_eq!(
+            applied_lsn(&coordinator.sqlite.readonly, timeline_id)?,
+            Some(1)
+        );
+        assert!(coordinator.take_rejections().is_empty());
+
+        // then the slow mutation has the whole budget to itself, which still
+        // isn't enough, so it's rejected
+        coordinator.step_with_budget(20)?;
+        let rejections = coordinator.take_rejections();
+        assert_eq!(rejections.len(), 1);
+        assert_eq!(rejections[0].lsn, 2);
+        assert_eq!(rejections[0].reason, ReducerError::Timeout.to_string());
+
+        // and running out of fuel rejects the entry too
+        while coordinator.has_pending_work() {
+            coordinator.step()?;
+        }
+        let rejections = coordinator.take_rejections();
+        assert_eq!(rejections.len(), 1);
+        assert_eq!(rejections[0].lsn, 3);
+
+        replicate(&mut ReplicationProtocol::new(), &coordinator, &mut local)?;
+        local.rebase()?;
+        assert_eq!(count_rows(&local)?, 2);
+        assert!(local.source_range().is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_failed_range_is_retried() -> anyhow::Result<()> {
+        let doc_id = JournalId::new128(&mut rand::thread_rng());
+        let mut local = open_local(doc_id)?;
+        let mut coordinator = CoordinatorDocument::open(
+            MemoryJournal::open(doc_id)?,
+            MemoryJournalFactory,
+            ExpensiveReducer { flaky_failures: 1 },
+        )?;
+        let mut local_to_coordinator = ReplicationProtocol::new();
+
+        local.mutate(b"CREATE TABLE t (x)")?;
+        local.mutate(b"INSERT INTO t VALUES ('flaky')")?;
+        replicate(&mut local_to_coordinator, &local, &mut coordinator)?;
+
+        // a range which fails isn't requeued, so it can't wedge the document
+        assert!(coordinator.step().is_err());
+        assert!(!coordinator.has_pending_work());
+        assert_eq!(
+            applied_lsn(&coordinator.sqlite.readonly, local.source_id())?,
+            None
+        );
+
+        // instead it's retried along with the next range from the timeline
+        local.mutate(b"INSERT INTO t VALUES (3)")?;
+        replicate(&mut local_to_coordinator, &local, &mut coordinator)?;
+        while coordinator.has_pending_work() {
+            coordinator.step()?;
+        }
+        assert!(coordinator.take_rejections().is_empty());
+
+        let mut local2 = open_local(doc_id)?;
+        replicate(&mut ReplicationProtocol::new(), &coordinator, &mut local2)?;
+        local2.rebase()?;
+        assert_eq!(count_rows(&local2)?, 2);
+
+        Ok(())
+    }
+
+    // executes each mutation as a json encoded string of sql
+    struct JsonSqlReducer;
+
+    impl Reducer for JsonSqlReducer {
+        fn apply(
+            &mut self,
+            tx: &mut rusqlite::Transaction,
+            mutation: &[u8],
+        ) -> std::result::Result<ReducerOutput, ReducerError> {
+            let sql: String = MutationCodec::Json
+                .decode(mutation)
+                .map_err(|err| ReducerError::External(err.into()))?;
+            SqlReducer.apply(tx, sql.as_bytes())
+        }
+
+        fn mutation_codec(&self) -> Option<MutationCodec> {
+            Some(MutationCodec::Json)
+        }
+    }
+
+    #[test]
+    fn test_mutation_codec_mismatch() -> anyhow::Result<()> {
+        let doc_id = JournalId::new128(&mut rand::thread_rng());
+        let mut local = LocalDocument::open(
+            MemoryJournal::open(doc_id)?,
+            MemoryJournal::open(JournalId::new128(&mut rand::thread_rng()))?,
+            JsonSqlReducer,
+            DocumentSignals::<NoopSignal>::default(),
+        )?;
+        let mut coordinator = CoordinatorDocument::open(
+            MemoryJournal::open(doc_id)?,
+            MemoryJournalFactory,
+            JsonSqlReducer,
+        )?;
+
+        // mutations which don't match the reducer's codec fail immediately,
+        // before they are applied or added to the timeline
+        local.mutate(br#""CREATE TABLE t (x)""#)?;
+        let err = local.mutate(b"INSERT INTO t VALUES (1)").unwrap_err();
+        assert!(
+            matches!(
+                err,
+                Error::ReducerError(ReducerError::InvalidMutation {
+                    codec: MutationCodec::Json,
+                    ..
+                })
+            ),
+            "unexpected error: {:?}",
+            err
+        );
+        assert_eq!(local.stats()?.timeline_lsn, Some(0));
+
+        // a client which doesn't know about the codec has its mutations
+        // rejected by the coordinator
+        let mut mismatched = open_local(doc_id)?;
+        mismatched.mutate(b"CREATE TABLE u (x)")?;
+        replicate(&mut ReplicationProtocol::new(), &local, &mut coordinator)?;
+        replicate(
+            &mut ReplicationProtocol::new(),
+            &mismatched,
+            &mut coordinator,
+        )?;
+        while coordinator.has_pending_work() {
+            coordinator.step()?;
+        }
+        let rejections = coordinator.take_rejections();
+        assert_eq!(rejections.len(), 1);
+        assert_eq!(rejections[0].timeline_id, mismatched.source_id());
+        assert!(rejections[0].reason.contains("Json codec"));
+
+        Ok(())
+    }
 }
@@ -1,13 +1,24 @@
-use std::{convert::From, pin::Pin};
+use std::{
+    convert::From,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
 
 use rusqlite::{
+    ffi,
     hooks::{AuthAction, AuthContext, Authorization},
-    Connection, OpenFlags, Transaction,
+    Connection, InterruptHandle, OpenFlags, Params, Transaction,
 };
+use sqlite_vfs::VfsHandle;
 
 use crate::{
+    error::Result,
     journal::Journal,
-    page::PAGESIZE,
+    random::register_deterministic_randomness,
     storage::Storage,
     vfs::{FilePtr, StorageVfs},
 };
@@ -15,12 +26,195 @@ use crate::{
 pub struct ConnectionPair {
     pub readwrite: Connection,
     pub readonly: Connection,
+    /// hands out cancellations for queries on the readonly connection
+    pub cancellable: CancellableQueries,
+
+    // fields drop in declaration order, so the vfs is unregistered once both
+    // connections have closed
+    _vfs: VfsHandle,
+}
+
+/// QueryCancellation cancels the single query it is passed to, see
+/// LocalDocument::query_cancellable. it can be cloned and cancelled from
+/// another thread: a running query fails with SQLITE_INTERRUPT, a query which
+/// hasn't started fails as soon as it does, and cancelling a query which has
+/// finished does nothing
+#[derive(Clone)]
+pub struct QueryCancellation {
+    id: u64,
+    cancelled: Arc<AtomicBool>,
+    running: Arc<Mutex<u64>>,
+    interrupt: Arc<InterruptHandle>,
+}
+
+impl QueryCancellation {
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+
+        // the lock is held while interrupting so that the query can't finish
+        // and another one start in between
+        let running = self.running.lock().unwrap();
+        if *running == self.id {
+            self.interrupt.interrupt();
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+impl std::fmt::Debug for QueryCancellation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("QueryCancellation")
+            .field("id", &self.id)
+            .field("cancelled", &self.is_cancelled())
+            .finish()
+    }
+}
+
+/// CancellableQueries runs queries on a connection which can be cancelled
+/// individually using the connection's interrupt handle
+pub struct CancellableQueries {
+    interrupt: Arc<InterruptHandle>,
+    // the id of the cancellable query which is running, or 0
+    running: Arc<Mutex<u64>>,
+    next_id: AtomicU64,
+}
+
+impl CancellableQueries {
+    fn new(conn: &Connection) -> Self {
+        Self {
+            interrupt: Arc::new(conn.get_interrupt_handle()),
+            running: Arc::new(Mutex::new(0)),
+            next_id: AtomicU64::new(1),
+        }
+    }
+
+    /// returns a cancellation for a query which hasn't started yet
+    pub fn cancellation(&self) -> QueryCancellation {
+        QueryCancellation {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            cancelled: Arc::new(AtomicBool::new(false)),
+            running: self.running.clone(),
+            interrupt: self.interrupt.clone(),
+        }
+    }
+
+    /// run f on conn, which must be the connection these queries were
+    /// created for, until it finishes or cancellation is cancelled
+    pub fn run<F, O, E>(
+        &self,
+        conn: &Connection,
+        cancellation: &QueryCancellation,
+        f: F,
+    ) -> std::result::Result<O, E>
+    where
+        F: FnOnce(&Connection) -> std::result::Result<O, E>,
+        E: From<rusqlite::Error>,
+    {
+        assert!(
+            Arc::ptr_eq(&self.running, &cancellation.running),
+            "query cancellation belongs to another connection"
+        );
+
+        {
+            let mut running = self.running.lock().unwrap();
+            if cancellation.is_cancelled() {
+                return Err(interrupted().into());
+            }
+            *running = cancellation.id;
+        }
+
+        let result = f(conn);
+        *self.running.lock().unwrap() = 0;
+        result
+    }
+}
+
+fn interrupted() -> rusqlite::Error {
+    rusqlite::Error::SqliteFailure(ffi::Error::new(ffi::SQLITE_INTERRUPT), None)
+}
+
+/// RegisterFunctions registers custom sql functions on a connection, see
+/// OpenConfig::register_functions
+pub type RegisterFunctions = fn(&Connection) -> rusqlite::Result<()>;
+
+/// OpenConfig tunes the sqlite connections used by a document, the defaults
+/// match sqlsync's behavior before it was configurable
+#[derive(Debug, Clone)]
+pub struct OpenConfig {
+    /// how long to wait for a lock before failing with SQLITE_BUSY
+    pub busy_timeout: Duration,
+    /// see PRAGMA cache_size, None keeps sqlite's default
+    pub cache_size: Option<i64>,
+    /// see PRAGMA journal_mode
+    pub journal_mode: JournalMode,
+    /// called with both connections after they are opened, e.g. to call
+    /// create_scalar_function. reducers run on every replica, so clients
+    /// and the coordinator must register identical, deterministic functions
+    pub register_functions: Option<RegisterFunctions>,
+}
+
+impl Default for OpenConfig {
+    fn default() -> Self {
+        Self {
+            busy_timeout: Duration::ZERO,
+            cache_size: None,
+            journal_mode: JournalMode::Memory,
+            register_functions: None,
+        }
+    }
+}
+
+/// the journal modes which work with sqlsync's storage, see PRAGMA
+/// journal_mode. wal needs shared memory, which the vfs doesn't provide,
+/// and the modes which keep a journal file need the vfs to truncate files
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JournalMode {
+    Memory,
+    Off,
+}
+
+impl JournalMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JournalMode::Memory => "memory",
+            JournalMode::Off => "off",
+        }
+    }
+}
+
+impl OpenConfig {
+    fn apply(&self, conn: &Connection) -> rusqlite::Result<()> {
+        conn.busy_timeout(self.busy_timeout)?;
+        if let Some(cache_size) = self.cache_size {
+            conn.pragma_update(None, "cache_size", cache_size)?;
+        }
+        conn.pragma_update(None, "journal_mode", self.journal_mode.as_str())?;
+        Ok(())
+    }
 }
 
 pub fn open_with_vfs<J: Journal>(
     journal: J,
-) -> rusqlite::Result<(ConnectionPair, Pin<Box<Storage<J>>>)> {
-    let mut storage = Box::pin(Storage::new(journal));
+    page_size: usize,
+    config: &OpenConfig,
+) -> Result<(ConnectionPair, Pin<Box<Storage<J>>>)> {
+    open_with_storage(Storage::new(journal, page_size)?, config)
+}
+
+/// like open_with_vfs, but opens the connections on an existing storage
+///
+/// the connections refer to the returned storage through the vfs, so the
+/// ConnectionPair must be dropped before the storage. structs which hold
+/// both should declare the pair first, as fields drop in declaration order
+pub fn open_with_storage<J: Journal>(
+    storage: Storage<J>,
+    config: &OpenConfig,
+) -> Result<(ConnectionPair, Pin<Box<Storage<J>>>)> {
+    let page_size = storage.page_size();
+    let mut storage = Box::pin(storage);
     let storage_ptr = FilePtr::new(&mut storage);
 
     // generate random vfs name
@@ -28,7 +222,8 @@ pub fn open_with_vfs<J: Journal>(
 
     // register the vfs globally
     let vfs = StorageVfs::new(storage_ptr);
-    sqlite_vfs::register(&vfs_name, vfs).expect("failed to register local-vfs with sqlite");
+    let vfs =
+        sqlite_vfs::register(&vfs_name, vfs).expect("failed to register local-vfs with sqlite");
 
     let sqlite = Connection::open_with_flags_and_vfs(
         "main.db",
@@ -36,9 +231,9 @@ pub fn open_with_vfs<J: Journal>(
         &vfs_name,
     )?;
 
-    sqlite.pragma_update(None, "page_size", PAGESIZE)?;
+    sqlite.pragma_update(None, "page_size", page_size)?;
     sqlite.pragma_update(None, "synchronous", "off")?;
-    sqlite.pragma_update(None, "journal_mode", "memory")?;
+    config.apply(&sqlite)?;
 
     // Enable incremental auto_vacuum support for query subscriptions
     // When SQLite is in incremental auto_vacuum mode, it will maintain
@@ -46,40 +241,365 @@ pub fn open_with_vfs<J: Journal>(
     // efficiently map changed pages back to their corresponding root.
     sqlite.pragma_update(None, "auto_vacuum", "incremental")?;
 
-    // TODO: benchmark with/without cache
-    // sqlite.pragma_update(None, "default_cache_size", 0).unwrap();
-    // sqlite.pragma_update(None, "cache_size", 0).unwrap();
-
     let sqlite_readonly = Connection::open_with_flags_and_vfs(
         "main.db",
         OpenFlags::SQLITE_OPEN_READ_ONLY,
         &vfs_name,
     )?;
+    config.apply(&sqlite_readonly)?;
 
     sqlite_readonly.authorizer(Some(|ctx: AuthContext| match ctx.action {
         AuthAction::Select => Authorization::Allow,
         AuthAction::Read { .. } => Authorization::Allow,
         AuthAction::Recursive => Authorization::Allow,
         AuthAction::Function { .. } => Authorization::Allow,
+        // reloads the schema without changing anything, see LocalDocument::rebase
+        AuthAction::Pragma { pragma_name, pragma_value: Some(value) }
+            if pragma_name.eq_ignore_ascii_case("writable_schema")
+                && value.eq_ignore_ascii_case("reset") =>
+        {
+            Authorization::Allow
+        }
         _ => Authorization::Deny,
     }));
 
+    let cancellable = CancellableQueries::new(&sqlite_readonly);
+
+    // reducers run on the readwrite connection, and must compute the same
+    // random values on every replica
+    register_deterministic_randomness(&sqlite)?;
+
+    #[cfg(feature = "regexp")]
+    {
+        register_regexp(&sqlite)?;
+        register_regexp(&sqlite_readonly)?;
+    }
+
+    if let Some(register_functions) = config.register_functions {
+        register_functions(&sqlite)?;
+        register_functions(&sqlite_readonly)?;
+    }
+
     Ok((
         ConnectionPair {
             readwrite: sqlite,
             readonly: sqlite_readonly,
+            cancellable,
+            _vfs: vfs,
         },
         storage,
     ))
 }
 
-pub fn run_in_tx<F, E>(sqlite: &mut Connection, f: F) -> Result<(), E>
+/// registers a deterministic regexp(pattern, value) function which backs
+/// SQLite's `value REGEXP pattern` operator. The regex crate is used on both
+/// the client and the server to ensure that reducers converge.
+#[cfg(feature = "regexp")]
+fn register_regexp(conn: &Connection) -> rusqlite::Result<()> {
+    use rusqlite::{functions::FunctionFlags, types::ValueRef};
+
+    conn.create_scalar_function(
+        "regexp",
+        2,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+        |ctx| {
+            if matches!(ctx.get_raw(0), ValueRef::Null) || matches!(ctx.get_raw(1), ValueRef::Null)
+            {
+                return Ok(None);
+            }
+
+            // sqlite caches the compiled pattern for as long as it stays constant
+            let re = ctx.get_or_create_aux(0, |pattern| {
+                regex::Regex::new(pattern.as_str()?)
+                    .map_err(|e| rusqlite::Error::UserFunctionError(e.into()))
+            })?;
+            let value = match ctx.get_raw(1) {
+                ValueRef::Text(t) | ValueRef::Blob(t) => String::from_utf8_lossy(t).into_owned(),
+                ValueRef::Integer(i) => i.to_string(),
+                ValueRef::Real(f) => f.to_string(),
+                ValueRef::Null => unreachable!("null values are handled above"),
+            };
+
+            Ok(Some(re.is_match(&value)))
+        },
+    )
+}
+
+/// a single step of the plan sqlite will use to run a query
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueryPlanStep {
+    pub id: i64,
+    /// the id of the step this step is nested under, or 0
+    pub parent: i64,
+    /// e.g. "SCAN tasks" or "SEARCH tasks USING INDEX tasks_completed (completed=?)"
+    pub detail: String,
+}
+
+/// returns the steps of sqlite's plan for running sql, which is useful for
+/// checking whether a query uses an index. the query itself is not run
+pub fn explain_query_plan<P: Params>(
+    conn: &Connection,
+    sql: &str,
+    params: P,
+) -> rusqlite::Result<Vec<QueryPlanStep>> {
+    // explaining a query doesn't check whether the connection's cached schema
+    // is stale, so read the schema first to pick up e.g. new indexes
+    conn.query_row("SELECT count(*) FROM sqlite_master", [], |_| Ok(()))?;
+
+    let mut stmt = conn.prepare(&format!("EXPLAIN QUERY PLAN {}", sql))?;
+    let steps = stmt.query_map(params, |row| {
+        Ok(QueryPlanStep {
+            id: row.get("id")?,
+            parent: row.get("parent")?,
+            detail: row.get("detail")?,
+        })
+    })?;
+    steps.collect()
+}
+
+pub fn run_in_tx<F, T, E>(sqlite: &mut Connection, f: F) -> Result<T, E>
 where
-    F: FnOnce(&mut Transaction) -> Result<(), E>,
+    F: FnOnce(&mut Transaction) -> Result<T, E>,
     E: From<rusqlite::Error>,
 {
     let mut txn = sqlite.transaction()?;
-    f(&mut txn)?; // will cause a rollback on failure
+    let out = f(&mut txn)?; // will cause a rollback on failure
     txn.commit()?;
-    Ok(())
+    Ok(out)
+}
+
+/// run f inside a savepoint on an open transaction. if f fails, only the
+/// changes made inside the savepoint are rolled back and the transaction
+/// remains usable. savepoints may be nested by calling this from within f.
+///
+/// nothing is persisted until the outermost transaction commits
+pub fn run_in_savepoint<F, T, E>(tx: &mut Transaction, f: F) -> Result<T, E>
+where
+    F: FnOnce(&mut Transaction) -> Result<T, E>,
+    E: From<rusqlite::Error>,
+{
+    // sqlite resolves savepoint names to the most recent match, so nested
+    // savepoints can share a name
+    tx.execute_batch("SAVEPOINT sqlsync_savepoint")?;
+    match f(tx) {
+        Ok(out) => {
+            tx.execute_batch("RELEASE sqlsync_savepoint")?;
+            Ok(out)
+        }
+        Err(err) => {
+            tx.execute_batch("ROLLBACK TO sqlsync_savepoint; RELEASE sqlsync_savepoint")?;
+            Err(err)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{page::DEFAULT_PAGESIZE, JournalId, MemoryJournal};
+
+    use super::*;
+
+    #[cfg(feature = "regexp")]
+    fn matching_names(conn: &Connection, pattern: &str) -> Vec<String> {
+        let mut stmt = conn
+            .prepare("SELECT name FROM people WHERE name REGEXP ? ORDER BY name")
+            .unwrap();
+        stmt.query_map([pattern], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap()
+    }
+
+    #[test]
+    #[cfg(feature = "regexp")]
+    fn test_regexp() {
+        let journal = MemoryJournal::open(JournalId::new128(&mut rand::thread_rng())).unwrap();
+        let (mut sqlite, mut storage) =
+            open_with_vfs(journal, DEFAULT_PAGESIZE, &OpenConfig::default()).unwrap();
+
+        run_in_tx(&mut sqlite.readwrite, |tx| {
+            tx.execute_batch(
+                "CREATE TABLE people (name TEXT);
+                INSERT INTO people VALUES ('alice'), ('bob'), ('carol'), (NULL);",
+            )
+        })
+        .unwrap();
+        storage.commit().unwrap();
+
+        // the readwrite connection is used by reducers, while the readonly
+        // connection is used by queries; both must agree
+        let expected = vec!["alice".to_string(), "carol".to_string()];
+        assert_eq!(matching_names(&sqlite.readwrite, "^[ac]"), expected);
+        assert_eq!(matching_names(&sqlite.readonly, "^[ac]"), expected);
+
+        // null patterns match nothing
+        let count: i64 = sqlite
+            .readonly
+            .query_row(
+                "SELECT count(*) FROM people WHERE name REGEXP NULL",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(count, 0);
+
+        // invalid patterns surface as an error
+        assert!(sqlite
+            .readonly
+            .query_row("SELECT 'a' REGEXP '('", [], |row| row.get::<_, bool>(0))
+            .is_err());
+    }
+
+    #[test]
+    fn test_query_cancellation() -> anyhow::Result<()> {
+        let journal = MemoryJournal::open(JournalId::new128(&mut rand::thread_rng()))?;
+        let (sqlite, _storage) = open_with_vfs(journal, DEFAULT_PAGESIZE, &OpenConfig::default())?;
+
+        // this query never finishes on its own
+        let slow_query = |conn: &Connection| {
+            conn.query_row(
+                "WITH RECURSIVE c(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM c)
+                SELECT count(*) FROM c",
+                [],
+                |row| row.get::<_, i64>(0),
+            )
+        };
+
+        // a running query is interrupted from another thread
+        let cancellation = sqlite.cancellable.cancellation();
+        let canceller = std::thread::spawn({
+            let cancellation = cancellation.clone();
+            move || {
+                std::thread::sleep(Duration::from_millis(50));
+                cancellation.cancel();
+            }
+        });
+        let result = sqlite
+            .cancellable
+            .run(&sqlite.readonly, &cancellation, slow_query);
+        canceller.join().unwrap();
+        assert_eq!(
+            result.unwrap_err().sqlite_error_code(),
+            Some(rusqlite::ErrorCode::OperationInterrupted)
+        );
+
+        let count = |conn: &Connection| {
+            conn.query_row(
+                "WITH RECURSIVE c(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM c WHERE x < 10000)
+                SELECT count(*) FROM c",
+                [],
+                |row| row.get::<_, i64>(0),
+            )
+        };
+
+        // cancelling only affects its own query
+        assert_eq!(count(&sqlite.readonly)?, 10000);
+        cancellation.cancel();
+        assert_eq!(count(&sqlite.readonly)?, 10000);
+        let next = sqlite.cancellable.cancellation();
+        assert_eq!(
+            sqlite.cancellable.run(&sqlite.readonly, &next, count)?,
+            10000
+        );
+
+        // a query cancelled before it starts never runs
+        let pending = sqlite.cancellable.cancellation();
+        pending.cancel();
+        let result =
+            sqlite
+                .cancellable
+                .run(&sqlite.readonly, &pending, |_| -> rusqlite::Result<()> {
+                    panic!("cancelled queries should not run")
+                });
+        assert_eq!(
+            result.unwrap_err().sqlite_error_code(),
+            Some(rusqlite::ErrorCode::OperationInterrupted)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_savepoints() -> anyhow::Result<()> {
+        let journal = MemoryJournal::open(JournalId::new128(&mut rand::thread_rng()))?;
+        let (mut sqlite, mut storage) =
+            open_with_vfs(journal, DEFAULT_PAGESIZE, &OpenConfig::default())?;
+
+        let names = |conn: &Connection| -> rusqlite::Result<Vec<String>> {
+            let mut stmt = conn.prepare("SELECT name FROM people ORDER BY name")?;
+            let rows = stmt.query_map([], |row| row.get(0))?;
+            rows.collect()
+        };
+
+        run_in_tx(&mut sqlite.readwrite, |tx| {
+            tx.execute_batch("CREATE TABLE people (name TEXT); INSERT INTO people VALUES ('a')")?;
+
+            // a failed savepoint only rolls back its own changes
+            let result = run_in_savepoint(tx, |tx| {
+                tx.execute("INSERT INTO people VALUES ('b')", [])?;
+                tx.execute("INSERT INTO missing VALUES ('b')", [])
+            });
+            assert!(result.is_err());
+
+            // nested savepoints roll back independently
+            run_in_savepoint(tx, |tx| {
+                tx.execute("INSERT INTO people VALUES ('c')", [])?;
+                let result = run_in_savepoint(tx, |tx| {
+                    tx.execute("INSERT INTO people VALUES ('d')", [])?;
+                    Err::<(), _>(rusqlite::Error::InvalidQuery)
+                });
+                assert!(result.is_err());
+                Ok::<_, rusqlite::Error>(())
+            })?;
+
+            assert_eq!(names(tx)?, vec!["a", "c"]);
+            Ok::<_, rusqlite::Error>(())
+        })?;
+        storage.commit()?;
+        assert_eq!(names(&sqlite.readonly)?, vec!["a", "c"]);
+
+        // released savepoints are discarded if the outer transaction fails
+        let result = run_in_tx(&mut sqlite.readwrite, |tx| {
+            run_in_savepoint(tx, |tx| tx.execute("INSERT INTO people VALUES ('e')", []))?;
+            Err::<(), _>(rusqlite::Error::InvalidQuery)
+        });
+        assert!(result.is_err());
+        assert_eq!(names(&sqlite.readonly)?, vec!["a", "c"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_config() -> anyhow::Result<()> {
+        let pragmas = |conn: &Connection| -> rusqlite::Result<(i64, i64, String)> {
+            // queries can't read pragmas on the readonly connection
+            conn.authorizer(None::<fn(AuthContext<'_>) -> Authorization>);
+            Ok((
+                conn.pragma_query_value(None, "busy_timeout", |row| row.get(0))?,
+                conn.pragma_query_value(None, "cache_size", |row| row.get(0))?,
+                conn.pragma_query_value(None, "journal_mode", |row| row.get(0))?,
+            ))
+        };
+
+        // the defaults preserve sqlite's busy_timeout and cache_size
+        let journal = MemoryJournal::open(JournalId::new128(&mut rand::thread_rng()))?;
+        let (sqlite, _storage) = open_with_vfs(journal, DEFAULT_PAGESIZE, &OpenConfig::default())?;
+        for conn in [&sqlite.readwrite, &sqlite.readonly] {
+            assert_eq!(pragmas(conn)?, (0, -2000, "memory".into()));
+        }
+
+        let config = OpenConfig {
+            busy_timeout: Duration::from_millis(250),
+            cache_size: Some(-512),
+            journal_mode: JournalMode::Off,
+            ..OpenConfig::default()
+        };
+        let journal = MemoryJournal::open(JournalId::new128(&mut rand::thread_rng()))?;
+        let (sqlite, _storage) = open_with_vfs(journal, DEFAULT_PAGESIZE, &config)?;
+        for conn in [&sqlite.readwrite, &sqlite.readonly] {
+            assert_eq!(pragmas(conn)?, (250, -512, "off".into()));
+        }
+
+        Ok(())
+    }
 }
@@ -0,0 +1,86 @@
+/// Debounce coalesces a burst of events into a single action. the window opens
+/// at the first event and later events join it rather than extending it, so a
+/// continuous burst (such as dragging a slider) is still handled once per
+/// window. Debounce doesn't keep time: the caller starts a timer when trigger
+/// returns Trigger::StartTimer and calls elapsed once it fires
+#[derive(Debug)]
+pub struct Debounce {
+    interval_ms: u32,
+    open: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    /// the debounce is disabled, handle the event right away
+    Now,
+    /// a window opened, handle the event once a timer for this many
+    /// milliseconds fires
+    StartTimer(u32),
+    /// the event joined the open window
+    Coalesced,
+}
+
+impl Debounce {
+    pub fn new(interval_ms: u32) -> Self {
+        Self { interval_ms, open: false }
+    }
+
+    /// returns true if events should be handled immediately
+    pub fn is_disabled(&self) -> bool {
+        self.interval_ms == 0
+    }
+
+    /// returns true if a window is waiting for its timer
+    pub fn is_open(&self) -> bool {
+        self.open
+    }
+
+    /// record an event, returning what the caller should do about it
+    pub fn trigger(&mut self) -> Trigger {
+        if self.is_disabled() {
+            Trigger::Now
+        } else if self.open {
+            Trigger::Coalesced
+        } else {
+            self.open = true;
+            Trigger::StartTimer(self.interval_ms)
+        }
+    }
+
+    /// close the window once its timer fires, returns true if the events in
+    /// it should be handled
+    pub fn elapsed(&mut self) -> bool {
+        std::mem::take(&mut self.open)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_debounce() {
+        let mut disabled = Debounce::new(0);
+        assert_eq!(disabled.trigger(), Trigger::Now);
+        assert_eq!(disabled.trigger(), Trigger::Now);
+        assert!(!disabled.is_open());
+        assert!(!disabled.elapsed());
+
+        // a burst opens a single window
+        let mut debounce = Debounce::new(16);
+        assert!(!debounce.elapsed());
+        assert_eq!(debounce.trigger(), Trigger::StartTimer(16));
+        for _ in 0..10 {
+            assert_eq!(debounce.trigger(), Trigger::Coalesced);
+        }
+        assert!(debounce.is_open());
+
+        // which is handled once
+        assert!(debounce.elapsed());
+        assert!(!debounce.elapsed());
+
+        // and the next event opens a new window
+        assert_eq!(debounce.trigger(), Trigger::StartTimer(16));
+        assert!(debounce.elapsed());
+    }
+}
@@ -0,0 +1,140 @@
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+use crate::{
+    positioned_io::PositionedReader,
+    replication::{
+        ReplicationDestination, ReplicationError, ReplicationMsg, ReplicationProtocol,
+        ReplicationSource,
+    },
+};
+
+/// a message along with the frame which follows it on the wire, which is
+/// empty for every message other than a frame
+type Packet = (ReplicationMsg, Vec<u8>);
+
+/// one side of an in-process connection, which replicates its own journal
+/// using protocol and handles everything the other side sends it
+pub(crate) struct Endpoint<'a> {
+    protocol: &'a mut ReplicationProtocol,
+    started: bool,
+    outbox: Sender<Packet>,
+    inbox: Receiver<Packet>,
+}
+
+/// connect two protocols over a pair of channels
+pub(crate) fn link<'a, 'b>(
+    a: &'a mut ReplicationProtocol,
+    b: &'b mut ReplicationProtocol,
+) -> (Endpoint<'a>, Endpoint<'b>) {
+    let (to_b, b_inbox) = channel();
+    let (to_a, a_inbox) = channel();
+    (
+        Endpoint {
+            started: a.initialized(),
+            protocol: a,
+            outbox: to_b,
+            inbox: a_inbox,
+        },
+        Endpoint {
+            started: b.initialized(),
+            protocol: b,
+            outbox: to_a,
+            inbox: b_inbox,
+        },
+    )
+}
+
+impl Endpoint<'_> {
+    /// ask the other side what it has of doc, unless the protocol has already
+    /// been started. returns the number of packets sent
+    pub(crate) fn start<D: ReplicationSource>(&mut self, doc: &D) -> usize {
+        if self.started {
+            return 0;
+        }
+        self.started = true;
+        self.push((self.protocol.start(doc), vec![]));
+        1
+    }
+
+    /// send every frame of doc the protocol is able to, returning the number
+    /// of frames sent
+    pub(crate) fn send_frames<D: ReplicationSource>(
+        &mut self,
+        doc: &D,
+    ) -> Result<usize, ReplicationError> {
+        let mut sent = 0;
+        while let Some((msg, reader)) = self.protocol.sync(doc)? {
+            let frame = reader.read_all()?;
+            self.push((msg, frame));
+            sent += 1;
+        }
+        Ok(sent)
+    }
+
+    /// handle every packet received from the other side, returning the
+    /// number of packets handled
+    pub(crate) fn receive<D: ReplicationDestination>(
+        &mut self,
+        doc: &mut D,
+    ) -> Result<usize, ReplicationError> {
+        let mut received = 0;
+        while let Ok((msg, frame)) = self.inbox.try_recv() {
+            if let Some(resp) = self.protocol.handle(doc, msg, &mut frame.as_slice())? {
+                self.push((resp, vec![]));
+            }
+            received += 1;
+        }
+        Ok(received)
+    }
+
+    fn push(&self, packet: Packet) {
+        self.outbox
+            .send(packet)
+            .expect("both endpoints of a link live as long as each other")
+    }
+}
+
+/// DuplexReplication connects a client and a server over a pair of
+/// in-process channels, carrying the same messages and frames as a network
+/// connection. both sides run a single ReplicationProtocol which replicates
+/// their own journal and receives the other side's, i.e. a local document
+/// sends its timeline and receives the coordinator's storage.
+/// the connection must be reused between calls.
+#[derive(Default)]
+pub struct DuplexReplication {
+    client: ReplicationProtocol,
+    server: ReplicationProtocol,
+}
+
+impl DuplexReplication {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// exchange messages in both directions until neither side has anything
+    /// left to send, returning the number of packets exchanged
+    pub fn replicate<C, S>(
+        &mut self,
+        client: &mut C,
+        server: &mut S,
+    ) -> Result<usize, ReplicationError>
+    where
+        C: ReplicationSource + ReplicationDestination,
+        S: ReplicationSource + ReplicationDestination,
+    {
+        let (mut client_end, mut server_end) = link(&mut self.client, &mut self.server);
+        let mut total = 0;
+        loop {
+            let exchanged = client_end.start(client)
+                + client_end.send_frames(client)?
+                + server_end.start(server)
+                + server_end.send_frames(server)?
+                + server_end.receive(server)?
+                + client_end.receive(client)?;
+            if exchanged == 0 {
+                return Ok(total);
+            }
+            total += exchanged;
+        }
+    }
+}
@@ -26,6 +26,64 @@ pub enum Error {
 
     #[error("io error: {0}")]
     IoError(#[from] io::Error),
+
+    /// returned by LocalDocument::mutate while the timeline holds the maximum
+    /// number of entries the coordinator hasn't acknowledged, see
+    /// LocalDocument::set_max_unacked_entries
+    #[error("the timeline has reached its limit of {max_entries} unacknowledged entries")]
+    TimelineFull { max_entries: usize },
+
+    /// returned by LocalDocument::set_meta for keys which sqlsync manages
+    #[error("meta key {0} is reserved")]
+    ReservedMetaKey(String),
+}
+
+impl Error {
+    /// returns the sqlite error which caused this error, if any
+    pub fn sqlite_error(&self) -> Option<&rusqlite::Error> {
+        match self {
+            Error::SqliteError(err)
+            | Error::ReducerError(ReducerError::Sqlite(err))
+            | Error::TimelineError(TimelineError::Sqlite(err))
+            | Error::TimelineError(TimelineError::ReducerError(ReducerError::Sqlite(err))) => {
+                Some(err)
+            }
+            _ => None,
+        }
+    }
 }
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::ErrorCode;
+
+    use crate::{test_helpers::open_local, JournalId};
+
+    #[test]
+    fn test_sqlite_error() -> anyhow::Result<()> {
+        let mut local = open_local(JournalId::new128(&mut rand::thread_rng()))?;
+        local.mutate(b"CREATE TABLE people (id INTEGER PRIMARY KEY)")?;
+        local.mutate(b"INSERT INTO people VALUES (1)")?;
+
+        let err = local.mutate(b"INSERT INTO people VALUES (1)").unwrap_err();
+        match err.sqlite_error() {
+            Some(rusqlite::Error::SqliteFailure(err, _)) => {
+                assert_eq!(err.code, ErrorCode::ConstraintViolation);
+                assert_eq!(
+                    err.extended_code,
+                    rusqlite::ffi::SQLITE_CONSTRAINT_PRIMARYKEY
+                );
+            }
+            other => panic!("expected a sqlite failure, got {:?}", other),
+        }
+
+        // errors which sqlite didn't cause have no sqlite error
+        let err =
+            crate::error::Error::from(std::io::Error::new(std::io::ErrorKind::Other, "not sqlite"));
+        assert!(err.sqlite_error().is_none());
+
+        Ok(())
+    }
+}
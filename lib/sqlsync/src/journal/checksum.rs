@@ -0,0 +1,156 @@
+use std::{cell::Cell, io};
+
+use crate::Lsn;
+
+use super::JournalError;
+
+pub const CHECKSUM_SIZE: usize = 4;
+
+/// compute the checksum journals store alongside each frame
+pub fn frame_checksum(frame: &[u8]) -> u32 {
+    crc32fast::hash(frame)
+}
+
+/// FrameChecksum is the checksum of a frame stored outside of memory, along
+/// with whether the frame has been checked against it since it was loaded.
+/// frames are only checked the first time they are read
+#[derive(Debug, Clone)]
+pub struct FrameChecksum {
+    checksum: u32,
+    verified: Cell<bool>,
+}
+
+impl FrameChecksum {
+    /// the checksum of a frame which is being written, which doesn't need
+    /// to be checked until it has been loaded again
+    pub fn of(frame: &[u8]) -> Self {
+        Self {
+            checksum: frame_checksum(frame),
+            verified: Cell::new(true),
+        }
+    }
+
+    /// the checksum computed by a ChecksumWriter while a frame was written
+    fn written(checksum: u32) -> Self {
+        Self { checksum, verified: Cell::new(true) }
+    }
+
+    /// a checksum loaded from storage, the frame is checked when it's read
+    pub fn loaded(checksum: u32) -> Self {
+        Self { checksum, verified: Cell::new(false) }
+    }
+
+    pub fn value(&self) -> u32 {
+        self.checksum
+    }
+
+    /// returns true if the frame still needs to be checked, which is never
+    /// the case when the verify-checksums feature is disabled
+    pub fn needs_verify(&self) -> bool {
+        cfg!(feature = "verify-checksums") && !self.verified.get()
+    }
+
+    /// check the frame at lsn against this checksum, if it still needs to be
+    pub fn verify(&self, lsn: Lsn, frame: &[u8]) -> io::Result<()> {
+        if self.needs_verify() {
+            if frame_checksum(frame) != self.checksum {
+                return Err(mismatch(lsn));
+            }
+            self.verified.set(true);
+        }
+        Ok(())
+    }
+}
+
+/// ChecksumWriter computes the checksum of a frame as it is written to
+/// inner, so that frames can be serialized straight into a journal's storage
+pub(super) struct ChecksumWriter<W> {
+    inner: W,
+    hasher: crc32fast::Hasher,
+    len: u64,
+}
+
+impl<W: io::Write> ChecksumWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: crc32fast::Hasher::new(),
+            len: 0,
+        }
+    }
+
+    /// flush inner, returning the checksum and length of everything written
+    pub fn finish(mut self) -> io::Result<(FrameChecksum, u64)> {
+        self.inner.flush()?;
+        Ok((FrameChecksum::written(self.hasher.finalize()), self.len))
+    }
+}
+
+impl<W: io::Write> io::Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.len += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// start a new entry, reserving room for the checksum
+pub(super) fn new_entry(capacity: usize) -> Vec<u8> {
+    let mut entry = Vec::with_capacity(CHECKSUM_SIZE + capacity);
+    entry.extend_from_slice(&[0; CHECKSUM_SIZE]);
+    entry
+}
+
+/// write the checksum of the frame stored in entry into its prefix
+pub(super) fn seal_entry(entry: &mut [u8]) {
+    let checksum = frame_checksum(&entry[CHECKSUM_SIZE..]);
+    entry[..CHECKSUM_SIZE].copy_from_slice(&checksum.to_le_bytes());
+}
+
+/// return the frame stored in entry
+pub(super) fn entry_frame(entry: &[u8]) -> &[u8] {
+    &entry[CHECKSUM_SIZE..]
+}
+
+/// check the frame stored in entry against its checksum
+pub(super) fn verify_entry(lsn: Lsn, entry: &[u8]) -> Result<(), JournalError> {
+    let (checksum, frame) = entry.split_at(CHECKSUM_SIZE);
+    if checksum != frame_checksum(frame).to_le_bytes() {
+        return Err(JournalError::ChecksumMismatch { lsn });
+    }
+    Ok(())
+}
+
+fn mismatch(lsn: Lsn) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        JournalError::ChecksumMismatch { lsn },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(feature = "verify-checksums")]
+    fn test_frame_checksum() {
+        let frame = b"123456789";
+        assert_eq!(frame_checksum(frame), 0xcbf43926);
+
+        // loaded checksums are checked once
+        let checksum = FrameChecksum::loaded(frame_checksum(frame));
+        assert!(checksum.needs_verify());
+        assert!(checksum.verify(3, b"123456780").is_err());
+        checksum.verify(3, frame).unwrap();
+        assert!(!checksum.needs_verify());
+
+        // while written frames don't need to be
+        assert!(!FrameChecksum::of(frame).needs_verify());
+    }
+}
@@ -0,0 +1,373 @@
+use std::fmt::{Debug, Formatter};
+use std::io;
+
+use crate::lsn::{Lsn, LsnIter, LsnRange};
+use crate::positioned_io::{PositionedCursor, PositionedReader};
+use crate::replication::{ReplicationDestination, ReplicationError, ReplicationSource};
+use crate::{JournalFactory, Serializable};
+
+use super::{Cursor, Journal, JournalError, JournalId, Scannable};
+
+/// Cipher encrypts journal frames at rest
+///
+/// the ciphertext must carry everything needed to decrypt it other than the
+/// key, such as a random nonce, since the same lsn may be encrypted more than
+/// once (e.g. when storage is truncated). the journal id and lsn should be
+/// authenticated so that frames can't be moved to another position.
+pub trait Cipher {
+    fn encrypt(&self, id: JournalId, lsn: Lsn, frame: &[u8]) -> io::Result<Vec<u8>>;
+
+    /// returns an error if the ciphertext can't be authenticated
+    fn decrypt(&self, id: JournalId, lsn: Lsn, ciphertext: &[u8]) -> io::Result<Vec<u8>>;
+}
+
+/// EncryptedJournal encrypts every frame appended to an inner journal, and
+/// transparently decrypts frames read via scan and get
+///
+/// replication sends and receives the ciphertext, so frames are never
+/// decrypted on their way to another peer and every peer replicating the
+/// journal must use the same key. received frames are authenticated before
+/// they are stored.
+pub struct EncryptedJournal<J, C> {
+    inner: J,
+    cipher: C,
+}
+
+impl<J: Debug, C> Debug for EncryptedJournal<J, C> {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        f.debug_tuple("EncryptedJournal")
+            .field(&self.inner)
+            .finish()
+    }
+}
+
+impl<J, C: Cipher> EncryptedJournal<J, C> {
+    /// wrap inner, which must only contain frames encrypted by cipher
+    pub fn open(inner: J, cipher: C) -> Self {
+        Self { inner, cipher }
+    }
+
+    pub fn inner(&self) -> &J {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> J {
+        self.inner
+    }
+}
+
+pub struct EncryptedJournalFactory<F, C> {
+    inner: F,
+    cipher: C,
+}
+
+impl<F, C> EncryptedJournalFactory<F, C> {
+    pub fn new(inner: F, cipher: C) -> Self {
+        Self { inner, cipher }
+    }
+}
+
+impl<J, F, C> JournalFactory<EncryptedJournal<J, C>> for EncryptedJournalFactory<F, C>
+where
+    F: JournalFactory<J>,
+    C: Cipher + Clone,
+{
+    fn open(&self, id: JournalId) -> io::Result<EncryptedJournal<J, C>> {
+        Ok(EncryptedJournal::open(
+            self.inner.open(id)?,
+            self.cipher.clone(),
+        ))
+    }
+}
+
+impl<J, C> Journal for EncryptedJournal<J, C>
+where
+    J: Journal,
+    C: Cipher + Clone,
+{
+    type Factory = EncryptedJournalFactory<J::Factory, C>;
+
+    fn id(&self) -> JournalId {
+        self.inner.id()
+    }
+
+    fn range(&self) -> LsnRange {
+        self.inner.range()
+    }
+
+    fn append(&mut self, obj: impl Serializable) -> io::Result<()> {
+        let mut frame: Vec<u8> = Vec::with_capacity(obj.serialized_len().unwrap_or(0));
+        obj.serialize_into(&mut frame)?;
+        let ciphertext = self
+            .cipher
+            .encrypt(self.id(), self.range().next(), &frame)?;
+        self.inner.append(ciphertext.as_slice())
+    }
+
+    fn drop_prefix(&mut self, up_to: Lsn) -> io::Result<()> {
+        self.inner.drop_prefix(up_to)
+    }
+
+    fn drop_last(&mut self) -> io::Result<()> {
+        self.inner.drop_last()
+    }
+
+    fn seal<'a, R: io::Read + 'a>(
+        &self,
+        lsn: Lsn,
+        mut frame: R,
+    ) -> io::Result<Box<dyn io::Read + 'a>> {
+        let mut plaintext = Vec::new();
+        frame.read_to_end(&mut plaintext)?;
+        let ciphertext = self.cipher.encrypt(self.id(), lsn, &plaintext)?;
+        Ok(Box::new(io::Cursor::new(ciphertext)))
+    }
+
+    fn verify(&self) -> Result<(), JournalError> {
+        self.inner.verify()
+    }
+}
+
+impl<J: Journal, C: Cipher> Scannable for EncryptedJournal<J, C> {
+    type Reader<'a> = PositionedCursor<Vec<u8>>
+    where
+        Self: 'a;
+
+    fn scan(&self) -> Cursor<'_, Self, LsnIter> {
+        Cursor::new(self, self.inner.range().iter())
+    }
+
+    fn scan_range(&self, range: LsnRange) -> Cursor<'_, Self, LsnIter> {
+        let intersection = self.inner.range().intersect(&range);
+        Cursor::new(self, intersection.iter())
+    }
+
+    fn get(&self, lsn: Lsn) -> io::Result<Option<Self::Reader<'_>>> {
+        match self.inner.get(lsn)? {
+            None => Ok(None),
+            Some(reader) => {
+                let ciphertext = reader.read_all()?;
+                let frame = self.cipher.decrypt(self.inner.id(), lsn, &ciphertext)?;
+                Ok(Some(PositionedCursor::new(frame)))
+            }
+        }
+    }
+}
+
+impl<J: ReplicationSource, C: Cipher> ReplicationSource for EncryptedJournal<J, C> {
+    type Reader<'a> = <J as ReplicationSource>::Reader<'a>
+    where
+        Self: 'a;
+
+    fn source_id(&self) -> JournalId {
+        self.inner.source_id()
+    }
+
+    fn source_range(&self) -> LsnRange {
+        self.inner.source_range()
+    }
+
+    fn read_lsn(&self, lsn: Lsn) -> io::Result<Option<Self::Reader<'_>>> {
+        self.inner.read_lsn(lsn)
+    }
+}
+
+impl<J: ReplicationDestination, C: Cipher> ReplicationDestination for EncryptedJournal<J, C> {
+    fn range(&mut self, id: JournalId) -> Result<LsnRange, ReplicationError> {
+        self.inner.range(id)
+    }
+
+    fn write_lsn<R>(
+        &mut self,
+        id: JournalId,
+        lsn: Lsn,
+        reader: &mut R,
+    ) -> Result<(), ReplicationError>
+    where
+        R: io::Read,
+    {
+        let mut ciphertext = Vec::new();
+        reader.read_to_end(&mut ciphertext)?;
+        // refuse frames encrypted with another key or for another lsn
+        self.cipher.decrypt(id, lsn, &ciphertext)?;
+        self.inner.write_lsn(id, lsn, &mut ciphertext.as_slice())
+    }
+}
+
+#[cfg(feature = "encryption")]
+pub use chacha::ChaChaCipher;
+
+#[cfg(feature = "encryption")]
+mod chacha {
+    use std::io;
+
+    use chacha20poly1305::{
+        aead::{Aead, KeyInit, Payload},
+        XChaCha20Poly1305, XNonce,
+    };
+    use rand::RngCore;
+
+    use crate::{JournalError, JournalId, Lsn};
+
+    use super::Cipher;
+
+    const NONCE_SIZE: usize = 24;
+
+    /// ChaChaCipher encrypts frames with XChaCha20-Poly1305
+    ///
+    /// every frame is encrypted with a random 24 byte nonce, which is stored
+    /// before the ciphertext. the journal id and lsn are authenticated as
+    /// associated data
+    #[derive(Clone)]
+    pub struct ChaChaCipher {
+        aead: XChaCha20Poly1305,
+    }
+
+    impl ChaChaCipher {
+        pub fn new(key: &[u8; 32]) -> Self {
+            Self { aead: XChaCha20Poly1305::new(key.into()) }
+        }
+
+        fn associated_data(id: JournalId, lsn: Lsn) -> Vec<u8> {
+            let mut aad = id.bytes().to_vec();
+            aad.extend_from_slice(&lsn.to_le_bytes());
+            aad
+        }
+    }
+
+    impl Cipher for ChaChaCipher {
+        fn encrypt(&self, id: JournalId, lsn: Lsn, frame: &[u8]) -> io::Result<Vec<u8>> {
+            let mut nonce = XNonce::default();
+            rand::thread_rng().fill_bytes(&mut nonce);
+            let aad = Self::associated_data(id, lsn);
+            let ciphertext = self
+                .aead
+                .encrypt(&nonce, Payload { msg: frame, aad: &aad })
+                .map_err(|_| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "failed to encrypt frame")
+                })?;
+
+            let mut out = Vec::with_capacity(NONCE_SIZE + ciphertext.len());
+            out.extend_from_slice(&nonce);
+            out.extend_from_slice(&ciphertext);
+            Ok(out)
+        }
+
+        fn decrypt(&self, id: JournalId, lsn: Lsn, ciphertext: &[u8]) -> io::Result<Vec<u8>> {
+            let failed = || {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    JournalError::DecryptionFailed { lsn },
+                )
+            };
+            if ciphertext.len() < NONCE_SIZE {
+                return Err(failed());
+            }
+            let (nonce, msg) = ciphertext.split_at(NONCE_SIZE);
+            let aad = Self::associated_data(id, lsn);
+            self.aead
+                .decrypt(XNonce::from_slice(nonce), Payload { msg, aad: &aad })
+                .map_err(|_| failed())
+        }
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "encryption")]
+mod tests {
+    use crate::MemoryJournal;
+
+    use super::*;
+
+    #[test]
+    fn test_encrypted_journal() {
+        let id = JournalId::new128(&mut rand::thread_rng());
+        let mut journal = EncryptedJournal::open(
+            MemoryJournal::open(id).unwrap(),
+            ChaChaCipher::new(&[1; 32]),
+        );
+
+        let frames: Vec<Vec<u8>> = (0..3u8).map(|i| vec![i; 64]).collect();
+        for frame in &frames {
+            journal.append(frame.as_slice()).unwrap();
+        }
+
+        // the inner journal only ever sees ciphertext
+        for (lsn, frame) in journal.range().iter().zip(&frames) {
+            let stored = journal.inner().get(lsn).unwrap().unwrap();
+            assert_ne!(stored, frame.as_slice());
+            assert!(!stored.windows(16).any(|w| w == &frame[..16]));
+            assert_eq!(
+                &journal.get(lsn).unwrap().unwrap().read_all().unwrap(),
+                frame
+            );
+        }
+
+        // replication sends the ciphertext, which a destination with another
+        // key refuses
+        let mut other = EncryptedJournal::open(
+            MemoryJournal::open(id).unwrap(),
+            ChaChaCipher::new(&[3; 32]),
+        );
+        let ciphertext = journal.read_lsn(0).unwrap().unwrap();
+        assert_eq!(ciphertext, journal.inner().get(0).unwrap().unwrap());
+        assert!(other
+            .write_lsn(id, 0, &mut ciphertext.read_all().unwrap().as_slice())
+            .is_err());
+        assert!(other.range().is_empty());
+
+        // but a destination with the same key stores it unchanged
+        let mut dest = EncryptedJournal::open(
+            MemoryJournal::open(id).unwrap(),
+            ChaChaCipher::new(&[1; 32]),
+        );
+        for lsn in journal.range().iter() {
+            let ciphertext = journal.read_lsn(lsn).unwrap().unwrap().read_all().unwrap();
+            dest.write_lsn(id, lsn, &mut ciphertext.as_slice()).unwrap();
+            let stored = dest.inner().get(lsn).unwrap().unwrap().read_all().unwrap();
+            assert_eq!(stored, ciphertext);
+        }
+        let mut cursor = dest.scan();
+        for frame in &frames {
+            assert!(cursor.advance().unwrap());
+            assert_eq!(&cursor.read_all().unwrap(), frame);
+        }
+
+        // frames written as another lsn are refused
+        let ciphertext = journal.read_lsn(1).unwrap().unwrap().read_all().unwrap();
+        assert!(dest.write_lsn(id, 3, &mut ciphertext.as_slice()).is_err());
+
+        // frames which don't come from a journal are sealed first
+        let mut sealed = dest.seal(3, &b"merged"[..]).unwrap();
+        dest.write_lsn(id, 3, &mut sealed).unwrap();
+        assert_eq!(dest.get(3).unwrap().unwrap().read_all().unwrap(), b"merged");
+
+        // an lsn can be encrypted again after it's dropped, with a new nonce
+        dest.drop_last().unwrap();
+        let before = dest.inner().get(2).unwrap().unwrap().read_all().unwrap();
+        dest.drop_last().unwrap();
+        dest.append(frames[2].as_slice()).unwrap();
+        let after = dest.inner().get(2).unwrap().unwrap().read_all().unwrap();
+        assert_ne!(before, after);
+        assert_eq!(
+            &dest.get(2).unwrap().unwrap().read_all().unwrap(),
+            &frames[2]
+        );
+
+        // frames can't be moved to another lsn
+        let mut moved = MemoryJournal::open(id).unwrap();
+        moved.append(after.as_slice()).unwrap();
+        let moved = EncryptedJournal::open(moved, ChaChaCipher::new(&[1; 32]));
+        assert!(moved.get(0).is_err());
+
+        // reading with the wrong key fails to authenticate
+        let journal = EncryptedJournal::open(journal.into_inner(), ChaChaCipher::new(&[2; 32]));
+        let err = journal.get(1).err().expect("decrypting with the wrong key");
+        assert!(matches!(
+            err.into_inner()
+                .and_then(|e| e.downcast::<JournalError>().ok())
+                .as_deref(),
+            Some(JournalError::DecryptionFailed { lsn: 1 })
+        ));
+    }
+}
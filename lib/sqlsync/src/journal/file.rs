@@ -0,0 +1,667 @@
+use std::fmt::{Debug, Formatter};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::lsn::{Lsn, LsnIter, LsnRange};
+use crate::positioned_io::PositionedReader;
+use crate::{JournalFactory, Serializable};
+
+use super::checksum::{ChecksumWriter, FrameChecksum};
+use super::frames::{FileFrame, FrameEntry, FrameFile, FrameIndex, PinnedJournal};
+use super::{Cursor, Journal, JournalError, JournalId, PinnableJournal, Scannable};
+use crate::replication::{ReplicationDestination, ReplicationError, ReplicationSource};
+
+/// the index file starts with the first lsn of the journal, this is needed to
+/// remember where an empty journal starts after its prefix has been dropped
+const INDEX_HEADER_SIZE: usize = 8;
+
+/// each index entry is (lsn: u64, offset: u64, len: u32, checksum: u32)
+const INDEX_ENTRY_SIZE: usize = 24;
+
+/// each record in the data file is prefixed by its length as a u32
+const RECORD_PREFIX_SIZE: u64 = 4;
+
+/// FileJournal stores each frame as a length-prefixed record in an append-only
+/// data file, along with an index sidecar which maps lsns to records
+///
+/// overwriting an lsn (which happens during replication) appends a new record
+/// and a new index entry, the later entry wins when the index is reloaded
+pub struct FileJournal {
+    id: JournalId,
+    data_path: PathBuf,
+    index_path: PathBuf,
+
+    data: File,
+    data_len: u64,
+    index: File,
+
+    // where each frame is stored in the data file
+    frames: FrameIndex,
+}
+
+impl Debug for FileJournal {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        f.debug_tuple("FileJournal")
+            .field(&self.id)
+            .field(&self.frames.range())
+            .field(&self.data_path)
+            .finish()
+    }
+}
+
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    tmp.into()
+}
+
+/// sync a directory so that renames within it are durable
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+/// directories can't be opened as files on other platforms
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+fn encode_index_entry(lsn: Lsn, offset: u64, len: u32, checksum: u32) -> [u8; INDEX_ENTRY_SIZE] {
+    let mut buf = [0; INDEX_ENTRY_SIZE];
+    buf[0..8].copy_from_slice(&lsn.to_le_bytes());
+    buf[8..16].copy_from_slice(&offset.to_le_bytes());
+    buf[16..20].copy_from_slice(&len.to_le_bytes());
+    buf[20..24].copy_from_slice(&checksum.to_le_bytes());
+    buf
+}
+
+fn decode_index_entry(buf: &[u8]) -> (Lsn, u64, u32, u32) {
+    (
+        Lsn::from_le_bytes(buf[0..8].try_into().unwrap()),
+        u64::from_le_bytes(buf[8..16].try_into().unwrap()),
+        u32::from_le_bytes(buf[16..20].try_into().unwrap()),
+        u32::from_le_bytes(buf[20..24].try_into().unwrap()),
+    )
+}
+
+impl FileJournal {
+    /// open the journal with the given id in dir, creating it if needed
+    pub fn open(dir: impl AsRef<Path>, id: JournalId) -> io::Result<Self> {
+        let name = id.to_base58();
+        let data_path = dir.as_ref().join(format!("{}.journal", name));
+        let index_path = dir.as_ref().join(format!("{}.index", name));
+
+        Self::recover_drop_prefix(&data_path, &index_path)?;
+
+        let data = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&data_path)?;
+        let mut index = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&index_path)?;
+
+        let mut index_buf = Vec::new();
+        index.read_to_end(&mut index_buf)?;
+        if index_buf.len() < INDEX_HEADER_SIZE {
+            // new journal, or we crashed while creating it
+            index_buf = 0u64.to_le_bytes().to_vec();
+            index.set_len(0)?;
+            index.write_all(&index_buf)?;
+        }
+        let first = Lsn::from_le_bytes(index_buf[..INDEX_HEADER_SIZE].try_into().unwrap());
+
+        let data_file_len = data.metadata()?.len();
+        let mut frames = FrameIndex::new(first);
+        let mut data_len = 0;
+        let mut index_len = INDEX_HEADER_SIZE;
+
+        // a crash may leave a partial record or index entry at the end of
+        // either file, we stop at the first entry which isn't fully written
+        for chunk in index_buf[INDEX_HEADER_SIZE..].chunks_exact(INDEX_ENTRY_SIZE) {
+            let (lsn, offset, len, checksum) = decode_index_entry(chunk);
+            let entry = FrameEntry {
+                offset,
+                len,
+                checksum: FrameChecksum::loaded(checksum),
+            };
+            let end = entry.end();
+            if end > data_file_len {
+                break;
+            }
+            frames.insert(lsn, entry)?;
+            data_len = data_len.max(end);
+            index_len += INDEX_ENTRY_SIZE;
+        }
+
+        // discard anything past the last complete entry
+        index.set_len(index_len as u64)?;
+        index.seek(SeekFrom::End(0))?;
+        data.set_len(data_len)?;
+
+        Ok(FileJournal {
+            id,
+            data_path,
+            index_path,
+            data,
+            data_len,
+            index,
+            frames,
+        })
+    }
+
+    /// drop_prefix writes the remaining frames to temporary files and then
+    /// renames them over the originals, data file first. If we crashed during
+    /// that process, either finish the rename or discard the temporary files.
+    fn recover_drop_prefix(data_path: &Path, index_path: &Path) -> io::Result<()> {
+        let data_tmp = tmp_path(data_path);
+        let index_tmp = tmp_path(index_path);
+        if index_tmp.exists() && !data_tmp.exists() {
+            // the new data file is in place, so the new index must follow
+            fs::rename(&index_tmp, index_path)?;
+        } else {
+            for path in [data_tmp, index_tmp] {
+                if path.exists() {
+                    fs::remove_file(path)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// rewrite the journal so that it only contains remaining_range, which
+    /// must be a subset of the journal's range
+    fn compact(&mut self, remaining_range: LsnRange) -> io::Result<()> {
+        let data_tmp = tmp_path(&self.data_path);
+        let index_tmp = tmp_path(&self.index_path);
+
+        // copy the remaining frames into fresh files
+        let mut data = File::create(&data_tmp)?;
+        let mut index = File::create(&index_tmp)?;
+        let first = LsnRange::empty_preceeding(&remaining_range).next();
+        index.write_all(&first.to_le_bytes())?;
+
+        let mut data_len = 0;
+        for (lsn, entry) in self.frames.entries(remaining_range) {
+            let buf = FileFrame::open(&self.data, lsn, entry)?.read_all()?;
+            data.write_all(&entry.len.to_le_bytes())?;
+            data.write_all(&buf)?;
+            index.write_all(&encode_index_entry(
+                lsn,
+                data_len + RECORD_PREFIX_SIZE,
+                entry.len,
+                entry.checksum.value(),
+            ))?;
+            data_len += RECORD_PREFIX_SIZE + entry.len as u64;
+        }
+        data.sync_all()?;
+        index.sync_all()?;
+        drop((data, index));
+
+        // see recover_drop_prefix for how a crash between these is handled
+        fs::rename(&data_tmp, &self.data_path)?;
+        fs::rename(&index_tmp, &self.index_path)?;
+        sync_dir(self.data_path.parent().unwrap_or(Path::new("")))?;
+
+        *self = Self::open(self.data_path.parent().unwrap_or(Path::new("")), self.id)?;
+        Ok(())
+    }
+
+    fn write_frame(&mut self, lsn: Lsn, frame: &[u8]) -> io::Result<()> {
+        self.write_frame_with(lsn, frame.len(), |writer| writer.write_all(frame))
+    }
+
+    /// write a frame of exactly len bytes by calling write, which writes the
+    /// frame straight into the data file
+    fn write_frame_with<F>(&mut self, lsn: Lsn, len: usize, write: F) -> io::Result<()>
+    where
+        F: FnOnce(&mut ChecksumWriter<BufWriter<&mut File>>) -> io::Result<()>,
+    {
+        let len: u32 = len.try_into().map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidInput, "journal frame is too large")
+        })?;
+
+        // write and sync the record before indexing it, so that the index
+        // never points at data which hasn't reached the disk
+        let offset = self.data_len + RECORD_PREFIX_SIZE;
+        let checksum = {
+            let data = &mut self.data;
+            data.seek(SeekFrom::Start(self.data_len))?;
+            data.write_all(&len.to_le_bytes())?;
+            let mut writer = ChecksumWriter::new(BufWriter::new(&mut *data));
+            write(&mut writer)?;
+            let (checksum, written) = writer.finish()?;
+            if written != len as u64 {
+                // the record is past data_len, so it's overwritten by the
+                // next write and discarded when the journal is reopened
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("journal frame was {} bytes, expected {}", written, len),
+                ));
+            }
+            data.sync_data()?;
+            checksum
+        };
+        self.index
+            .write_all(&encode_index_entry(lsn, offset, len, checksum.value()))?;
+        // the frame is only committed once its index entry is durable
+        self.index.sync_data()?;
+        self.data_len = offset + len as u64;
+
+        self.frames
+            .insert(lsn, FrameEntry { offset, len, checksum })
+    }
+}
+
+pub struct FileJournalFactory {
+    dir: PathBuf,
+}
+
+impl FileJournalFactory {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+}
+
+impl JournalFactory<FileJournal> for FileJournalFactory {
+    fn open(&self, id: JournalId) -> io::Result<FileJournal> {
+        FileJournal::open(&self.dir, id)
+    }
+}
+
+impl Journal for FileJournal {
+    type Factory = FileJournalFactory;
+
+    fn id(&self) -> JournalId {
+        self.id
+    }
+
+    fn range(&self) -> LsnRange {
+        self.frames.range()
+    }
+
+    fn append(&mut self, obj: impl Serializable) -> io::Result<()> {
+        let lsn = self.frames.range().next();
+        match obj.serialized_len() {
+            // the record is prefixed by its length, so the frame can only be
+            // serialized straight into the data file if its length is known
+            Some(len) => self.write_frame_with(lsn, len, |writer| obj.serialize_into(writer)),
+            None => {
+                let mut entry: Vec<u8> = Vec::new();
+                obj.serialize_into(&mut entry)?;
+                self.write_frame(lsn, &entry)
+            }
+        }
+    }
+
+    fn drop_prefix(&mut self, up_to: Lsn) -> io::Result<()> {
+        self.compact(self.frames.range().trim_prefix(up_to))
+    }
+
+    fn drop_last(&mut self) -> io::Result<()> {
+        self.compact(self.frames.range().trim_last())
+    }
+
+    fn verify(&self) -> Result<(), JournalError> {
+        self.frames.verify(self.data_len)
+    }
+}
+
+// the journal and its pinned copies share a file offset, so frames are read
+// with positional reads which never depend on it
+impl FrameFile for File {
+    #[cfg(unix)]
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        std::os::unix::fs::FileExt::read_at(self, buf, pos)
+    }
+
+    #[cfg(windows)]
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        std::os::windows::fs::FileExt::seek_read(self, buf, pos)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+}
+
+impl PinnableJournal for FileJournal {
+    type Pinned = PinnedJournal<File>;
+
+    fn pin(&self, range: LsnRange) -> io::Result<Self::Pinned> {
+        // records are never modified in place, and compaction replaces the
+        // data file rather than rewriting it, so a second handle to the data
+        // file keeps the pinned frames readable
+        let data = self.data.try_clone()?;
+        Ok(PinnedJournal::new(self.id, self.frames.slice(range), data))
+    }
+}
+
+impl Scannable for FileJournal {
+    type Reader<'a>
+        = FileFrame<'a, File>
+    where
+        Self: 'a;
+
+    fn scan(&self) -> Cursor<'_, Self, LsnIter> {
+        Cursor::new(self, self.frames.range().iter())
+    }
+
+    fn scan_range(&self, range: LsnRange) -> Cursor<'_, Self, LsnIter> {
+        let intersection = self.frames.range().intersect(&range);
+        Cursor::new(self, intersection.iter())
+    }
+
+    fn get(&self, lsn: Lsn) -> io::Result<Option<Self::Reader<'_>>> {
+        self.frames
+            .get(lsn)
+            .map(|entry| FileFrame::open(&self.data, lsn, entry))
+            .transpose()
+    }
+}
+
+impl ReplicationSource for FileJournal {
+    type Reader<'a>
+        = FileFrame<'a, File>
+    where
+        Self: 'a;
+
+    fn source_id(&self) -> JournalId {
+        self.id()
+    }
+
+    fn source_range(&self) -> LsnRange {
+        self.range()
+    }
+
+    fn read_lsn(&self, lsn: Lsn) -> io::Result<Option<Self::Reader<'_>>> {
+        self.get(lsn)
+    }
+}
+
+impl ReplicationDestination for FileJournal {
+    fn range(&mut self, id: JournalId) -> Result<LsnRange, ReplicationError> {
+        if id != self.id {
+            return Err(ReplicationError::UnknownJournal(id));
+        }
+        Ok(self.frames.range())
+    }
+
+    fn write_lsn<R>(
+        &mut self,
+        id: JournalId,
+        lsn: Lsn,
+        reader: &mut R,
+    ) -> Result<(), ReplicationError>
+    where
+        R: io::Read,
+    {
+        if id != self.id() {
+            return Err(ReplicationError::UnknownJournal(id));
+        }
+
+        self.frames.check_replicated_lsn(lsn)?;
+        let mut frame_data = Vec::new();
+        reader.read_to_end(&mut frame_data)?;
+        self.write_frame(lsn, &frame_data)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        journal::frame_checksum,
+        page::{SparsePages, DEFAULT_PAGESIZE},
+    };
+
+    use super::*;
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            let path = std::env::temp_dir().join(format!("sqlsync-{}", rand::random::<u64>()));
+            fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn frames(journal: &FileJournal, range: LsnRange) -> Vec<Vec<u8>> {
+        let mut out = vec![];
+        let mut cursor = journal.scan_range(range);
+        while cursor.advance().unwrap() {
+            out.push(cursor.read_all().unwrap());
+        }
+        out
+    }
+
+    #[test]
+    fn test_file_journal_round_trip() {
+        let dir = TempDir::new();
+        let id = JournalId::new128(&mut rand::thread_rng());
+
+        let mut journal = FileJournal::open(&dir.0, id).unwrap();
+        for i in 0..5u8 {
+            journal.append(vec![i; i as usize + 1].as_slice()).unwrap();
+        }
+        assert_eq!(journal.range(), LsnRange::new(0, 4));
+
+        // overwriting an lsn via replication replaces the frame
+        journal.write_lsn(id, 2, &mut [9u8; 3].as_slice()).unwrap();
+        drop(journal);
+
+        // reopening recovers the range and frames from the index
+        let mut journal = FileJournal::open(&dir.0, id).unwrap();
+        journal.verify().unwrap();
+        assert_eq!(journal.range(), LsnRange::new(0, 4));
+        assert_eq!(
+            frames(&journal, LsnRange::new(1, 3)),
+            vec![vec![1; 2], vec![9; 3], vec![3; 4]]
+        );
+
+        journal.drop_prefix(2).unwrap();
+        assert_eq!(journal.range(), LsnRange::new(3, 4));
+        journal.append([5u8; 6].as_slice()).unwrap();
+        drop(journal);
+
+        let mut journal = FileJournal::open(&dir.0, id).unwrap();
+        journal.verify().unwrap();
+        assert_eq!(journal.range(), LsnRange::new(3, 5));
+        assert_eq!(
+            frames(&journal, journal.range()),
+            vec![vec![3; 4], vec![4; 5], vec![5; 6]]
+        );
+
+        // dropping everything remembers where the journal resumes
+        journal.drop_prefix(5).unwrap();
+        drop(journal);
+        let journal = FileJournal::open(&dir.0, id).unwrap();
+        assert_eq!(journal.range(), LsnRange::Empty { nextlsn: 6 });
+    }
+
+    #[test]
+    fn test_append_streams_known_lengths() {
+        // a frame which may or may not report its length up front
+        struct Frame {
+            data: Vec<u8>,
+            len: Option<usize>,
+        }
+
+        impl Serializable for Frame {
+            fn serialize_into<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+                // small writes, like SparsePages
+                for chunk in self.data.chunks(7) {
+                    writer.write_all(chunk)?;
+                }
+                Ok(())
+            }
+
+            fn serialized_len(&self) -> Option<usize> {
+                self.len
+            }
+        }
+
+        let dir = TempDir::new();
+        let id = JournalId::new128(&mut rand::thread_rng());
+        let mut journal = FileJournal::open(&dir.0, id).unwrap();
+
+        let streamed: Vec<u8> = (0..20000).map(|i| i as u8).collect();
+        let buffered = vec![7u8; 100];
+        journal
+            .append(Frame {
+                data: streamed.clone(),
+                len: Some(streamed.len()),
+            })
+            .unwrap();
+        journal
+            .append(Frame { data: buffered.clone(), len: None })
+            .unwrap();
+
+        // a frame which doesn't match its length isn't stored
+        let err = journal
+            .append(Frame { data: vec![1; 10], len: Some(11) })
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(journal.range(), LsnRange::new(0, 1));
+
+        let mut pages = SparsePages::new(DEFAULT_PAGESIZE);
+        for page_idx in 1..=16 {
+            pages.write(page_idx, vec![page_idx as u8; DEFAULT_PAGESIZE].into());
+        }
+        let mut serialized_pages = Vec::new();
+        pages.serialize_into(&mut serialized_pages).unwrap();
+        journal.append(pages).unwrap();
+
+        // the streamed frames and their checksums match the serialized bytes,
+        // including after reloading them from disk
+        let expected = vec![streamed, buffered, serialized_pages];
+        assert_eq!(frames(&journal, journal.range()), expected);
+        for (lsn, frame) in expected.iter().enumerate() {
+            let checksum = journal.frames.get(lsn as Lsn).unwrap().checksum.value();
+            assert_eq!(checksum, frame_checksum(frame));
+        }
+        drop(journal);
+        let journal = FileJournal::open(&dir.0, id).unwrap();
+        journal.verify().unwrap();
+        assert_eq!(frames(&journal, journal.range()), expected);
+    }
+
+    #[test]
+    fn test_pin() {
+        let dir = TempDir::new();
+        let id = JournalId::new128(&mut rand::thread_rng());
+
+        let mut journal = FileJournal::open(&dir.0, id).unwrap();
+        for i in 0..4u8 {
+            journal.append([i; 4].as_slice()).unwrap();
+        }
+        let pinned = journal.pin(LsnRange::new(1, 5)).unwrap();
+        assert_eq!(pinned.range(), LsnRange::new(1, 3));
+
+        // replacing and dropping frames doesn't affect the pinned frames
+        journal.write_lsn(id, 2, &mut [9u8; 4].as_slice()).unwrap();
+        journal.drop_prefix(2).unwrap();
+        journal.append([4u8; 4].as_slice()).unwrap();
+        assert_eq!(
+            frames(&journal, journal.range()),
+            vec![vec![3; 4], vec![4; 4]]
+        );
+
+        pinned.verify().unwrap();
+        let mut cursor = pinned.scan();
+        let mut pinned_frames = vec![];
+        while cursor.advance().unwrap() {
+            pinned_frames.push(cursor.read_all().unwrap());
+        }
+        assert_eq!(pinned_frames, vec![vec![1; 4], vec![2; 4], vec![3; 4]]);
+    }
+
+    #[test]
+    fn test_pinned_reads_share_offset() {
+        let dir = TempDir::new();
+        let id = JournalId::new128(&mut rand::thread_rng());
+
+        let mut journal = FileJournal::open(&dir.0, id).unwrap();
+        for i in 0..3u8 {
+            journal.append([i; 4].as_slice()).unwrap();
+        }
+        let pinned = journal.pin(journal.range()).unwrap();
+
+        // the pinned handle shares the journal's file offset, reading from it
+        // in between appends must not move the offset (seek_read on windows
+        // does move it, but appends seek before writing)
+        let mut cursor = pinned.scan();
+        for i in 0..3u8 {
+            let offset = (&journal.data).stream_position().unwrap();
+            assert!(cursor.advance().unwrap());
+            assert_eq!(cursor.read_all().unwrap(), vec![i; 4]);
+            if cfg!(unix) {
+                assert_eq!((&journal.data).stream_position().unwrap(), offset);
+            }
+
+            journal.append([i + 10; 4].as_slice()).unwrap();
+        }
+
+        let expected: Vec<Vec<u8>> = [0, 1, 2, 10, 11, 12].map(|i| vec![i; 4]).into();
+        assert_eq!(frames(&journal, journal.range()), expected);
+        drop(journal);
+        let journal = FileJournal::open(&dir.0, id).unwrap();
+        assert_eq!(frames(&journal, journal.range()), expected);
+    }
+
+    #[test]
+    #[cfg(feature = "verify-checksums")]
+    fn test_checksum_mismatch() {
+        let is_mismatch = |err: io::Error, expected: Lsn| {
+            matches!(
+                err.into_inner().and_then(|e| e.downcast::<JournalError>().ok()).as_deref(),
+                Some(JournalError::ChecksumMismatch { lsn }) if *lsn == expected
+            )
+        };
+
+        let dir = TempDir::new();
+        let id = JournalId::new128(&mut rand::thread_rng());
+        let mut journal = FileJournal::open(&dir.0, id).unwrap();
+        for i in 0..3u8 {
+            journal.append([i; 16].as_slice()).unwrap();
+        }
+
+        // flip a byte in the middle of the second frame
+        let offset = journal.frames.get(1).unwrap().offset;
+        drop(journal);
+        let mut data = OpenOptions::new()
+            .write(true)
+            .open(dir.0.join(format!("{}.journal", id.to_base58())))
+            .unwrap();
+        data.seek(SeekFrom::Start(offset + 8)).unwrap();
+        data.write_all(&[0xff]).unwrap();
+        drop(data);
+
+        // the corrupt frame surfaces an error rather than its data
+        let journal = FileJournal::open(&dir.0, id).unwrap();
+        assert_eq!(frames(&journal, LsnRange::new(0, 0)), vec![vec![0; 16]]);
+        assert!(is_mismatch(journal.get(1).err().unwrap(), 1));
+        assert!(is_mismatch(journal.read_lsn(1).err().unwrap(), 1));
+        let mut cursor = journal.scan();
+        assert!(cursor.advance().unwrap());
+        assert!(is_mismatch(cursor.advance().unwrap_err(), 1));
+        match journal.verify_frames(|_| Ok(())) {
+            Err(JournalError::IoError(err)) => assert!(is_mismatch(err, 1)),
+            result => panic!("expected a checksum mismatch, got {:?}", result),
+        }
+    }
+}
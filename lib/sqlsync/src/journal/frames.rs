@@ -0,0 +1,355 @@
+use std::fmt::{Debug, Formatter};
+use std::io;
+use std::rc::Rc;
+
+use crate::lsn::{Lsn, LsnIter, LsnRange};
+use crate::positioned_io::PositionedReader;
+use crate::replication::ReplicationError;
+use crate::{JournalFactory, Serializable};
+
+use super::{Cursor, FrameChecksum, Journal, JournalError, JournalId, Scannable};
+
+/// FrameFile is a file which journal frames can be read from
+pub trait FrameFile {
+    /// read bytes starting at pos into buf, returning how many were read
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> io::Result<usize>;
+
+    /// the size of the file in bytes
+    fn size(&self) -> io::Result<u64>;
+}
+
+impl<F: FrameFile> FrameFile for Rc<F> {
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        F::read_at(self, pos, buf)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        F::size(self)
+    }
+}
+
+/// FrameEntry is where a frame is stored in its journal's file
+#[derive(Debug, Clone)]
+pub struct FrameEntry {
+    pub offset: u64,
+    pub len: u32,
+    pub checksum: FrameChecksum,
+}
+
+impl FrameEntry {
+    pub fn end(&self) -> u64 {
+        self.offset + self.len as u64
+    }
+}
+
+/// FrameIndex maps each lsn in a journal's range to the FrameEntry of its
+/// frame, it's shared by the journals which store frames in files
+#[derive(Debug)]
+pub struct FrameIndex {
+    range: LsnRange,
+    entries: Vec<FrameEntry>,
+}
+
+impl FrameIndex {
+    /// create an empty index, which starts at first
+    pub fn new(first: Lsn) -> Self {
+        Self {
+            range: LsnRange::Empty { nextlsn: first },
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn range(&self) -> LsnRange {
+        self.range
+    }
+
+    pub fn get(&self, lsn: Lsn) -> Option<&FrameEntry> {
+        self.range.offset(lsn).map(|idx| &self.entries[idx])
+    }
+
+    /// record where the frame at lsn is stored, replacing any earlier entry
+    /// for lsn. lsn must be in the index's range or immediately follow it,
+    /// unless the index is empty in which case its range restarts at lsn
+    pub fn insert(&mut self, lsn: Lsn, entry: FrameEntry) -> io::Result<()> {
+        if let Some(idx) = self.range.offset(lsn) {
+            self.entries[idx] = entry;
+        } else if self.range.is_empty() {
+            self.range = LsnRange::new(lsn, lsn);
+            self.entries = vec![entry];
+        } else if lsn == self.range.next() {
+            self.range = self.range.append(lsn);
+            self.entries.push(entry);
+        } else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("journal index contains non-contiguous lsn {}", lsn),
+            ));
+        }
+        Ok(())
+    }
+
+    /// check that a replicated frame can be written at lsn
+    pub fn check_replicated_lsn(&self, lsn: Lsn) -> Result<(), ReplicationError> {
+        let accepted_range = if self.range.is_empty() {
+            // if we have no range, then we reset to the incoming lsn
+            LsnRange::new(lsn, lsn)
+        } else {
+            // accept any lsn in our current range or immediately following
+            self.range.extend_by(1)
+        };
+        if accepted_range.contains(lsn) {
+            Ok(())
+        } else {
+            Err(ReplicationError::NonContiguousLsn { received: lsn, range: accepted_range })
+        }
+    }
+
+    /// copy the entries in range into a new index
+    pub fn slice(&self, range: LsnRange) -> FrameIndex {
+        let range = self.range.intersect(&range);
+        FrameIndex {
+            range,
+            entries: self.entries(range).map(|(_, e)| e.clone()).collect(),
+        }
+    }
+
+    /// iterate over the entries in range, which must be a subset of the
+    /// index's range
+    pub fn entries(&self, range: LsnRange) -> impl Iterator<Item = (Lsn, &FrameEntry)> {
+        let offsets = self.range.intersection_offsets(&range);
+        range.iter().zip(&self.entries[offsets])
+    }
+
+    /// check that every lsn in the index's range maps to exactly one entry,
+    /// and that every entry ends within file_len
+    pub fn verify(&self, file_len: u64) -> Result<(), JournalError> {
+        if self.range.len() != self.entries.len() {
+            return Err(JournalError::RangeMismatch {
+                range: self.range,
+                entries: self.entries.len(),
+            });
+        }
+        for (lsn, entry) in self.entries(self.range) {
+            if entry.end() > file_len {
+                return Err(JournalError::InvalidFrame {
+                    lsn,
+                    source: io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "journal frame extends past the end of its file",
+                    ),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// FileFrame reads a single frame out of a journal's file
+pub struct FileFrame<'a, F> {
+    file: &'a F,
+    offset: u64,
+    len: u32,
+}
+
+impl<'a, F: FrameFile> FileFrame<'a, F> {
+    /// open the frame at lsn, checking it against its checksum if it hasn't
+    /// been since it was loaded
+    pub fn open(file: &'a F, lsn: Lsn, entry: &FrameEntry) -> io::Result<Self> {
+        let frame = Self {
+            file,
+            offset: entry.offset,
+            len: entry.len,
+        };
+        if entry.checksum.needs_verify() {
+            entry.checksum.verify(lsn, &frame.read_all()?)?;
+        }
+        Ok(frame)
+    }
+}
+
+impl<F: FrameFile> PositionedReader for FileFrame<'_, F> {
+    fn read_at(&self, pos: usize, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.len as usize;
+        if pos >= len {
+            return Ok(0);
+        }
+        let end = buf.len().min(len - pos);
+        self.file.read_at(self.offset + pos as u64, &mut buf[..end])
+    }
+
+    fn size(&self) -> io::Result<usize> {
+        Ok(self.len as usize)
+    }
+}
+
+/// PinnedJournal is a read-only journal holding frames pinned from a journal
+/// which stores them in a file, see PinnableJournal
+pub struct PinnedJournal<F> {
+    id: JournalId,
+    frames: FrameIndex,
+    file: F,
+}
+
+impl<F> PinnedJournal<F> {
+    /// file must keep every frame in frames readable for as long as the
+    /// pinned journal exists
+    pub fn new(id: JournalId, frames: FrameIndex, file: F) -> Self {
+        Self { id, frames, file }
+    }
+}
+
+impl<F> Debug for PinnedJournal<F> {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        f.debug_tuple("PinnedJournal")
+            .field(&self.id)
+            .field(&self.frames.range())
+            .finish()
+    }
+}
+
+fn read_only() -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, "pinned journals are read-only")
+}
+
+/// PinnedJournalFactory exists to satisfy the Journal trait, pinned journals
+/// are created by PinnableJournal::pin
+pub struct PinnedJournalFactory;
+
+impl<F> JournalFactory<PinnedJournal<F>> for PinnedJournalFactory {
+    fn open(&self, _id: JournalId) -> io::Result<PinnedJournal<F>> {
+        Err(read_only())
+    }
+}
+
+impl<F: FrameFile> Journal for PinnedJournal<F> {
+    type Factory = PinnedJournalFactory;
+
+    fn id(&self) -> JournalId {
+        self.id
+    }
+
+    fn range(&self) -> LsnRange {
+        self.frames.range()
+    }
+
+    fn append(&mut self, _obj: impl Serializable) -> io::Result<()> {
+        Err(read_only())
+    }
+
+    fn drop_prefix(&mut self, _up_to: Lsn) -> io::Result<()> {
+        Err(read_only())
+    }
+
+    fn verify(&self) -> Result<(), JournalError> {
+        self.frames.verify(self.file.size()?)
+    }
+}
+
+impl<F: FrameFile> Scannable for PinnedJournal<F> {
+    type Reader<'a>
+        = FileFrame<'a, F>
+    where
+        Self: 'a;
+
+    fn scan(&self) -> Cursor<'_, Self, LsnIter> {
+        Cursor::new(self, self.frames.range().iter())
+    }
+
+    fn scan_range(&self, range: LsnRange) -> Cursor<'_, Self, LsnIter> {
+        let intersection = self.frames.range().intersect(&range);
+        Cursor::new(self, intersection.iter())
+    }
+
+    fn get(&self, lsn: Lsn) -> io::Result<Option<Self::Reader<'_>>> {
+        self.frames
+            .get(lsn)
+            .map(|entry| FileFrame::open(&self.file, lsn, entry))
+            .transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::journal::frame_checksum;
+
+    fn entry(offset: u64, len: u32) -> FrameEntry {
+        FrameEntry {
+            offset,
+            len,
+            checksum: FrameChecksum::loaded(0),
+        }
+    }
+
+    #[test]
+    fn test_frame_index() {
+        let mut index = FrameIndex::new(3);
+        assert_eq!(index.range(), LsnRange::Empty { nextlsn: 3 });
+        index.verify(0).unwrap();
+
+        // an empty index restarts at the first lsn written to it
+        index.check_replicated_lsn(7).unwrap();
+        index.insert(7, entry(0, 4)).unwrap();
+        index.insert(8, entry(4, 4)).unwrap();
+        assert_eq!(index.range(), LsnRange::new(7, 8));
+
+        // later entries replace earlier ones
+        index.insert(7, entry(8, 2)).unwrap();
+        assert_eq!(index.get(7).map(|e| e.offset), Some(8));
+        assert!(index.get(9).is_none());
+
+        // but lsns must be contiguous
+        assert!(index.insert(10, entry(10, 1)).is_err());
+        assert!(matches!(
+            index.check_replicated_lsn(10),
+            Err(ReplicationError::NonContiguousLsn { received: 10, .. })
+        ));
+        index.check_replicated_lsn(9).unwrap();
+
+        let offsets: Vec<_> = index
+            .entries(LsnRange::new(8, 8))
+            .map(|(lsn, e)| (lsn, e.offset))
+            .collect();
+        assert_eq!(offsets, vec![(8, 4)]);
+
+        // every entry must fit within the file
+        index.verify(10).unwrap();
+        assert!(matches!(
+            index.verify(9),
+            Err(JournalError::InvalidFrame { lsn: 7, .. })
+        ));
+    }
+
+    #[test]
+    fn test_file_frame() {
+        struct Bytes(Vec<u8>);
+
+        impl FrameFile for Bytes {
+            fn read_at(&self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+                let data = &self.0[(pos as usize).min(self.0.len())..];
+                let n = buf.len().min(data.len());
+                buf[..n].copy_from_slice(&data[..n]);
+                Ok(n)
+            }
+
+            fn size(&self) -> io::Result<u64> {
+                Ok(self.0.len() as u64)
+            }
+        }
+
+        let file = Bytes(b"xxhelloyy".to_vec());
+        let hello = FrameEntry {
+            offset: 2,
+            len: 5,
+            checksum: FrameChecksum::loaded(frame_checksum(b"hello")),
+        };
+        let frame = FileFrame::open(&file, 0, &hello).unwrap();
+        assert_eq!(frame.size().unwrap(), 5);
+        assert_eq!(frame.read_all().unwrap(), b"hello");
+
+        // reads stop at the end of the frame
+        let mut buf = [0; 8];
+        assert_eq!(frame.read_at(3, &mut buf).unwrap(), 2);
+        assert_eq!(&buf[..2], b"lo");
+    }
+}
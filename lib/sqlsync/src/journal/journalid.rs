@@ -1,4 +1,7 @@
-use std::fmt::{Debug, Display, Formatter};
+use std::{
+    fmt::{Debug, Display, Formatter},
+    str::FromStr,
+};
 
 use bs58::Alphabet;
 use rand::Rng;
@@ -6,7 +9,10 @@ use rusqlite::{
     types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, ValueRef},
     ToSql,
 };
-use serde::{de::Visitor, Deserialize, Serialize};
+use serde::{
+    de::{SeqAccess, Visitor},
+    Deserialize, Serialize,
+};
 
 const BS58_ALPHABET: &Alphabet = bs58::Alphabet::BITCOIN;
 
@@ -15,13 +21,36 @@ pub enum JournalIdParseError {
     #[error("failed to parse journal id; expected 16 or 32 bytes, got {0} instead")]
     InvalidByteLength(usize),
 
+    #[error(
+        "failed to parse journal id; expected a {expected}-bit id, got a {actual}-bit id instead"
+    )]
+    UnexpectedSize { expected: usize, actual: usize },
+
+    #[error("failed to convert from base58; invalid character {character:?} at index {index}")]
+    InvalidBase58Character { character: char, index: usize },
+
+    #[error("failed to convert from base58; non-ascii character at index {index}")]
+    NonAsciiCharacter { index: usize },
+
     #[error("failed to convert from base58; error: {0}")]
-    Base58Error(#[from] bs58::decode::Error),
+    Base58Error(bs58::decode::Error),
 
     #[error("failed to convert from hex; error: {0}")]
     HexError(#[from] hex::FromHexError),
 }
 
+impl From<bs58::decode::Error> for JournalIdParseError {
+    fn from(err: bs58::decode::Error) -> Self {
+        match err {
+            bs58::decode::Error::InvalidCharacter { character, index } => {
+                Self::InvalidBase58Character { character, index }
+            }
+            bs58::decode::Error::NonAsciiCharacter { index } => Self::NonAsciiCharacter { index },
+            err => Self::Base58Error(err),
+        }
+    }
+}
+
 type Bytes128 = [u8; 16];
 type Bytes256 = [u8; 32];
 
@@ -49,6 +78,20 @@ impl JournalId {
         data.as_slice().try_into()
     }
 
+    /// like from_base58, but fails with JournalIdParseError::UnexpectedSize
+    /// unless the id has expected_bits bits (128 or 256)
+    pub fn from_base58_checked(
+        str: &str,
+        expected_bits: usize,
+    ) -> Result<JournalId, JournalIdParseError> {
+        let id = Self::from_base58(str)?;
+        let actual = id.bytes().len() * 8;
+        if actual != expected_bits {
+            return Err(JournalIdParseError::UnexpectedSize { expected: expected_bits, actual });
+        }
+        Ok(id)
+    }
+
     pub fn to_base58(&self) -> String {
         bs58::encode(self.bytes())
             .with_alphabet(BS58_ALPHABET)
@@ -130,6 +173,16 @@ impl TryFrom<&str> for JournalId {
     }
 }
 
+impl FromStr for JournalId {
+    type Err = JournalIdParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::from_base58(s)
+    }
+}
+
+/// JournalId serializes as bytes, and may be deserialized from bytes or a
+/// base58 string
 impl Serialize for JournalId {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -197,6 +250,18 @@ impl<'a> Visitor<'a> for JournalIdVisitor {
     {
         self.visit_try_from(v)
     }
+
+    // formats without a bytes type, such as json, write bytes as a sequence
+    fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+    where
+        A: SeqAccess<'a>,
+    {
+        let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(32).min(32));
+        while let Some(byte) = seq.next_element::<u8>()? {
+            bytes.push(byte);
+        }
+        self.visit_try_from(bytes)
+    }
 }
 
 impl<'de> Deserialize<'de> for JournalId {
@@ -204,6 +269,92 @@ impl<'de> Deserialize<'de> for JournalId {
     where
         D: serde::Deserializer<'de>,
     {
-        deserializer.deserialize_bytes(JournalIdVisitor)
+        // self describing formats may contain a base58 string, which must
+        // not be mistaken for its utf8 bytes
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_any(JournalIdVisitor)
+        } else {
+            deserializer.deserialize_bytes(JournalIdVisitor)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() -> anyhow::Result<()> {
+        let ids = [
+            JournalId::Size128([0; 16]),
+            JournalId::Size128([0xff; 16]),
+            JournalId::Size256([0; 32]),
+            JournalId::Size256([0xff; 32]),
+            JournalId::new128(&mut rand::thread_rng()),
+            JournalId::new256(&mut rand::thread_rng()),
+        ];
+        for id in ids {
+            assert_eq!(JournalId::from_base58(&id.to_base58())?, id);
+            assert_eq!(JournalId::from_hex(&id.to_hex())?, id);
+            assert_eq!(id.to_string().parse::<JournalId>()?, id);
+
+            let bytes = bincode::serialize(&id)?;
+            assert_eq!(bincode::deserialize::<JournalId>(&bytes)?, id);
+            let json = serde_json::to_string(&id)?;
+            assert_eq!(serde_json::from_str::<JournalId>(&json)?, id);
+            let json = serde_json::to_string(&id.to_base58())?;
+            assert_eq!(serde_json::from_str::<JournalId>(&json)?, id);
+        }
+
+        // leading zero bytes are preserved by base58
+        assert_eq!(JournalId::Size128([0; 16]).to_base58(), "1".repeat(16));
+
+        assert!(matches!(
+            serde_json::from_str::<JournalId>("[1, 2, 3]"),
+            Err(err) if err.to_string().contains("16 or 32 bytes")
+        ));
+        assert!("".parse::<JournalId>().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_base58_validation() -> anyhow::Result<()> {
+        let id128 = JournalId::Size128([0xff; 16]).to_base58();
+        let id256 = JournalId::Size256([0xff; 32]).to_base58();
+
+        // truncated and overlong strings decode to the wrong number of bytes
+        assert!(matches!(
+            JournalId::from_base58(&id128[..id128.len() / 2]),
+            Err(JournalIdParseError::InvalidByteLength(8))
+        ));
+        assert!(matches!(
+            JournalId::from_base58(&format!("{}z", id256)),
+            Err(JournalIdParseError::InvalidByteLength(33))
+        ));
+
+        // 0, O, I and l are not in the bitcoin alphabet
+        assert!(matches!(
+            JournalId::from_base58(&format!("{}0", &id128[1..])),
+            Err(JournalIdParseError::InvalidBase58Character { character: '0', index: 21 })
+        ));
+        assert!(matches!(
+            JournalId::from_base58("l"),
+            Err(JournalIdParseError::InvalidBase58Character { character: 'l', index: 0 })
+        ));
+        assert!(matches!(
+            JournalId::from_base58("1é"),
+            Err(JournalIdParseError::NonAsciiCharacter { index: 1 })
+        ));
+
+        // the checked variant also validates the size of the id
+        assert!(JournalId::from_base58_checked(&id128, 128).is_ok());
+        assert!(JournalId::from_base58_checked(&id256, 256).is_ok());
+        assert!(matches!(
+            JournalId::from_base58_checked(&id128, 256),
+            Err(JournalIdParseError::UnexpectedSize { expected: 256, actual: 128 })
+        ));
+
+        Ok(())
     }
 }
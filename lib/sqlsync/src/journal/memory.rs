@@ -1,16 +1,21 @@
 use std::fmt::{Debug, Formatter};
 use std::io;
+use std::sync::Arc;
 
 use crate::lsn::{Lsn, LsnIter, LsnRange};
 use crate::{JournalFactory, Serializable};
 
-use super::{Cursor, Journal, JournalId, Scannable};
+use super::checksum;
+use super::{Cursor, Journal, JournalError, JournalId, PinnableJournal, Scannable};
 use crate::replication::{ReplicationDestination, ReplicationError, ReplicationSource};
 
 pub struct MemoryJournal {
     id: JournalId,
     range: LsnRange,
-    data: Vec<Vec<u8>>,
+    // each entry is a frame prefixed by its checksum, frames never leave
+    // memory so the checksums are only checked by verify. entries are
+    // shared with pinned journals
+    data: Vec<Arc<Vec<u8>>>,
 }
 
 impl Debug for MemoryJournal {
@@ -30,6 +35,12 @@ impl MemoryJournal {
             data: vec![],
         })
     }
+
+    fn frame(&self, lsn: Lsn) -> Option<&[u8]> {
+        self.range
+            .offset(lsn)
+            .map(|offset| checksum::entry_frame(&self.data[offset]))
+    }
 }
 
 pub struct MemoryJournalFactory;
@@ -52,12 +63,13 @@ impl Journal for MemoryJournal {
     }
 
     fn append(&mut self, obj: impl Serializable) -> io::Result<()> {
-        // serialize the entry
-        let mut entry: Vec<u8> = Vec::new();
+        // serialize the entry, allocating the entry once if the size is known
+        let mut entry = checksum::new_entry(obj.serialized_len().unwrap_or(0));
         obj.serialize_into(&mut entry)?;
+        checksum::seal_entry(&mut entry);
 
         // update the journal
-        self.data.push(entry);
+        self.data.push(Arc::new(entry));
         self.range = self.range.extend_by(1);
 
         Ok(())
@@ -70,10 +82,49 @@ impl Journal for MemoryJournal {
         self.range = remaining_range;
         Ok(())
     }
+
+    fn drop_last(&mut self) -> io::Result<()> {
+        if self.range.is_non_empty() {
+            self.data.pop();
+            self.range = self.range.trim_last();
+        }
+        Ok(())
+    }
+
+    fn verify(&self) -> Result<(), JournalError> {
+        // every lsn in our range must map to exactly one entry
+        if self.range.len() != self.data.len() {
+            return Err(JournalError::RangeMismatch {
+                range: self.range,
+                entries: self.data.len(),
+            });
+        }
+
+        // and every frame must match its checksum
+        for (lsn, entry) in self.range.iter().zip(&self.data) {
+            checksum::verify_entry(lsn, entry)?;
+        }
+        Ok(())
+    }
+}
+
+impl PinnableJournal for MemoryJournal {
+    type Pinned = MemoryJournal;
+
+    fn pin(&self, range: LsnRange) -> io::Result<Self::Pinned> {
+        let range = self.range.intersect(&range);
+        let offsets = self.range.intersection_offsets(&range);
+        Ok(MemoryJournal {
+            id: self.id,
+            range,
+            data: self.data[offsets].to_vec(),
+        })
+    }
 }
 
 impl Scannable for MemoryJournal {
-    type Reader<'a> = &'a [u8]
+    type Reader<'a>
+        = &'a [u8]
     where
         Self: 'a;
 
@@ -87,15 +138,13 @@ impl Scannable for MemoryJournal {
     }
 
     fn get(&self, lsn: Lsn) -> io::Result<Option<Self::Reader<'_>>> {
-        Ok(self
-            .range
-            .offset(lsn)
-            .map(|offset| self.data[offset].as_slice()))
+        Ok(self.frame(lsn))
     }
 }
 
 impl ReplicationSource for MemoryJournal {
-    type Reader<'a> = &'a [u8]
+    type Reader<'a>
+        = &'a [u8]
     where
         Self: 'a;
 
@@ -108,10 +157,7 @@ impl ReplicationSource for MemoryJournal {
     }
 
     fn read_lsn(&self, lsn: Lsn) -> io::Result<Option<Self::Reader<'_>>> {
-        match self.range.offset(lsn) {
-            None => Ok(None),
-            Some(offset) => Ok(Some(&self.data[offset][..])),
-        }
+        Ok(self.frame(lsn))
     }
 }
 
@@ -145,17 +191,18 @@ impl ReplicationDestination for MemoryJournal {
         };
 
         if accepted_range.contains(lsn) {
-            let mut frame_data = Vec::new();
+            let mut frame_data = checksum::new_entry(0);
             reader.read_to_end(&mut frame_data)?;
+            checksum::seal_entry(&mut frame_data);
 
             // store frame into self.data
             match self.range.offset(lsn) {
                 Some(offset) => {
-                    self.data[offset] = frame_data
+                    self.data[offset] = Arc::new(frame_data)
                     // no need to update range since this was an intersection
                 }
                 None => {
-                    self.data.push(frame_data);
+                    self.data.push(Arc::new(frame_data));
                     // update our range to include the new lsn
                     self.range = accepted_range;
                 }
@@ -167,3 +214,187 @@ impl ReplicationDestination for MemoryJournal {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::page::{SerializedPagesReader, SparsePages, DEFAULT_PAGESIZE};
+
+    use super::checksum::CHECKSUM_SIZE;
+    use super::*;
+
+    #[test]
+    fn test_append_preallocates() {
+        let mut pages = SparsePages::new(DEFAULT_PAGESIZE);
+        for page_idx in 1..=256 {
+            pages.write(page_idx, vec![page_idx as u8; DEFAULT_PAGESIZE].into());
+        }
+        let expected_len = pages.serialized_len().unwrap();
+
+        let mut journal = MemoryJournal::open(JournalId::new128(&mut rand::thread_rng())).unwrap();
+        journal.append(pages).unwrap();
+
+        // the entry should have been allocated exactly once, at the right size
+        let entry = &journal.data[0];
+        assert_eq!(entry.len(), CHECKSUM_SIZE + expected_len);
+        assert_eq!(entry.capacity(), CHECKSUM_SIZE + expected_len);
+
+        // without a size hint the entry grows as it is written
+        let mut unhinted: Vec<u8> = Vec::new();
+        let mut pages = SparsePages::new(DEFAULT_PAGESIZE);
+        for page_idx in 1..=256 {
+            pages.write(page_idx, vec![page_idx as u8; DEFAULT_PAGESIZE].into());
+        }
+        pages.serialize_into(&mut unhinted).unwrap();
+        assert_eq!(&unhinted[..], &entry[CHECKSUM_SIZE..]);
+        assert!(unhinted.capacity() >= expected_len);
+    }
+
+    #[test]
+    fn test_verify() {
+        let validate =
+            |frame: &&[u8]| SerializedPagesReader::new(*frame, DEFAULT_PAGESIZE).validate();
+
+        let mut journal = MemoryJournal::open(JournalId::new128(&mut rand::thread_rng())).unwrap();
+        journal.verify().unwrap();
+        for page_idx in 1..=4 {
+            let mut pages = SparsePages::new(DEFAULT_PAGESIZE);
+            pages.write(page_idx, vec![page_idx as u8; DEFAULT_PAGESIZE].into());
+            journal.append(pages).unwrap();
+        }
+        journal.drop_prefix(1).unwrap();
+        journal.verify().unwrap();
+        journal.verify_frames(validate).unwrap();
+
+        // a truncated frame is structurally fine, but does not decode
+        journal.append(&[0u8; DEFAULT_PAGESIZE][..]).unwrap();
+        journal.verify().unwrap();
+        let err = journal.verify_frames(validate).unwrap_err();
+        assert!(
+            matches!(err, JournalError::InvalidFrame { lsn: 4, .. }),
+            "unexpected error: {}",
+            err
+        );
+        assert_eq!(
+            err.to_string(),
+            "journal frame at lsn 4 is invalid: serialized pages contain a partial page"
+        );
+
+        // losing an entry breaks the journal's range
+        journal.data.pop();
+        let err = journal.verify().unwrap_err();
+        assert!(matches!(
+            err,
+            JournalError::RangeMismatch { entries: 2, .. }
+        ));
+        assert!(journal.verify_frames(validate).is_err());
+    }
+
+    #[test]
+    fn test_default_verify() {
+        // a journal which claims one more lsn than it stores, and relies on
+        // the default verify
+        #[derive(Debug)]
+        struct Overlong(MemoryJournal);
+
+        impl Scannable for Overlong {
+            type Reader<'a>
+                = &'a [u8]
+            where
+                Self: 'a;
+
+            fn scan(&self) -> Cursor<'_, Self, LsnIter> {
+                Cursor::new(self, self.range().iter())
+            }
+
+            fn scan_range(&self, range: LsnRange) -> Cursor<'_, Self, LsnIter> {
+                Cursor::new(self, self.range().intersect(&range).iter())
+            }
+
+            fn get(&self, lsn: Lsn) -> io::Result<Option<Self::Reader<'_>>> {
+                self.0.get(lsn)
+            }
+        }
+
+        struct OverlongFactory;
+
+        impl JournalFactory<Overlong> for OverlongFactory {
+            fn open(&self, id: JournalId) -> io::Result<Overlong> {
+                MemoryJournal::open(id).map(Overlong)
+            }
+        }
+
+        impl Journal for Overlong {
+            type Factory = OverlongFactory;
+
+            fn id(&self) -> JournalId {
+                self.0.id()
+            }
+
+            fn range(&self) -> LsnRange {
+                self.0.range().extend_by(1)
+            }
+
+            fn append(&mut self, obj: impl Serializable) -> io::Result<()> {
+                self.0.append(obj)
+            }
+
+            fn drop_prefix(&mut self, up_to: Lsn) -> io::Result<()> {
+                self.0.drop_prefix(up_to)
+            }
+        }
+
+        let mut journal =
+            Overlong(MemoryJournal::open(JournalId::new128(&mut rand::thread_rng())).unwrap());
+        journal.append(&[1u8; 4][..]).unwrap();
+        assert!(matches!(
+            journal.verify(),
+            Err(JournalError::MissingFrame(1))
+        ));
+        assert!(matches!(
+            journal.verify_frames(|_| Ok(())),
+            Err(JournalError::MissingFrame(1))
+        ));
+    }
+
+    #[test]
+    fn test_pin() {
+        let mut journal = MemoryJournal::open(JournalId::new128(&mut rand::thread_rng())).unwrap();
+        for i in 0..4u8 {
+            journal.append(&[i; 4][..]).unwrap();
+        }
+        let pinned = journal.pin(LsnRange::new(1, 2)).unwrap();
+
+        // the pinned journal shares frames rather than copying them
+        assert!(Arc::ptr_eq(&pinned.data[0], &journal.data[1]));
+
+        let id = journal.id();
+        journal.write_lsn(id, 1, &mut [9u8; 4].as_slice()).unwrap();
+        journal.drop_prefix(2).unwrap();
+        assert_eq!(pinned.range(), LsnRange::new(1, 2));
+        assert_eq!(pinned.get(1).unwrap().unwrap(), &[1; 4]);
+        assert_eq!(pinned.get(2).unwrap().unwrap(), &[2; 4]);
+        pinned.verify().unwrap();
+    }
+
+    #[test]
+    fn test_checksum_mismatch() {
+        let mut journal = MemoryJournal::open(JournalId::new128(&mut rand::thread_rng())).unwrap();
+        for i in 0..3u8 {
+            journal.append(&[i; 16][..]).unwrap();
+        }
+        journal.verify().unwrap();
+
+        // flip a byte in the middle of a stored frame
+        Arc::make_mut(&mut journal.data[1])[CHECKSUM_SIZE + 8] ^= 0xff;
+        assert!(matches!(
+            journal.verify().unwrap_err(),
+            JournalError::ChecksumMismatch { lsn: 1 }
+        ));
+
+        // frames received via replication are checksummed on arrival
+        let id = journal.id();
+        journal.write_lsn(id, 1, &mut [7u8; 16].as_slice()).unwrap();
+        journal.verify().unwrap();
+        assert_eq!(journal.get(1).unwrap().unwrap(), &[7; 16]);
+    }
+}
@@ -1,18 +1,54 @@
+mod checksum;
 mod cursor;
+mod encrypted;
+mod file;
+mod frames;
 mod journalid;
 mod memory;
 
+pub use checksum::{frame_checksum, FrameChecksum};
 pub use cursor::{Cursor, Scannable};
+pub use encrypted::{Cipher, EncryptedJournal, EncryptedJournalFactory};
+
+#[cfg(feature = "encryption")]
+pub use encrypted::ChaChaCipher;
 pub use journalid::{JournalId, JournalIdParseError};
 
+pub use file::{FileJournal, FileJournalFactory};
+pub use frames::{
+    FileFrame, FrameEntry, FrameFile, FrameIndex, PinnedJournal, PinnedJournalFactory,
+};
 pub use memory::{MemoryJournal, MemoryJournalFactory};
 
 use std::fmt::Debug;
 use std::io;
 
+use thiserror::Error;
+
 use crate::lsn::{Lsn, LsnRange};
 use crate::Serializable;
 
+#[derive(Error, Debug)]
+pub enum JournalError {
+    #[error("journal range {range} does not match the {entries} entries it contains")]
+    RangeMismatch { range: LsnRange, entries: usize },
+
+    #[error("journal is missing lsn {0} which is inside its range")]
+    MissingFrame(Lsn),
+
+    #[error("journal frame at lsn {lsn} is invalid: {source}")]
+    InvalidFrame { lsn: Lsn, source: io::Error },
+
+    #[error("journal frame at lsn {lsn} does not match its checksum")]
+    ChecksumMismatch { lsn: Lsn },
+
+    #[error("journal frame at lsn {lsn} could not be decrypted")]
+    DecryptionFailed { lsn: Lsn },
+
+    #[error("io error: {0}")]
+    IoError(#[from] io::Error),
+}
+
 pub trait Journal: Scannable + Debug + Sized {
     type Factory: JournalFactory<Self>;
 
@@ -27,6 +63,68 @@ pub trait Journal: Scannable + Debug + Sized {
 
     /// drop the journal's prefix
     fn drop_prefix(&mut self, up_to: Lsn) -> io::Result<()>;
+
+    /// drop the journal's last entry, its lsn will be reused by the next
+    /// append. journals which can't safely reuse lsns don't support this.
+    fn drop_last(&mut self) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("{:?} does not support dropping entries", self),
+        ))
+    }
+
+    /// convert a frame into the form this journal stores and replicates it
+    /// in, which is what ReplicationDestination::write_lsn expects. frames
+    /// which didn't come from another journal, such as merged or imported
+    /// frames, must be sealed before they are written. most journals store
+    /// frames unchanged
+    fn seal<'a, R: io::Read + 'a>(
+        &self,
+        _lsn: Lsn,
+        frame: R,
+    ) -> io::Result<Box<dyn io::Read + 'a>> {
+        Ok(Box::new(frame))
+    }
+
+    /// check the journal's internal invariants
+    /// this only checks the journal's structure, use verify_frames to also
+    /// check that each frame can be decoded. by default this checks that every
+    /// lsn in the journal's range has a frame which can be read
+    fn verify(&self) -> Result<(), JournalError> {
+        check_frames(self, |_| Ok(()))
+    }
+
+    /// verify the journal and then run check against every frame
+    /// check should return an error if the frame can not be decoded
+    fn verify_frames<F>(&self, check: F) -> Result<(), JournalError>
+    where
+        F: FnMut(&Self::Reader<'_>) -> io::Result<()>,
+    {
+        self.verify()?;
+        check_frames(self, check)
+    }
+}
+
+/// run check against the frame at every lsn in the journal's range
+fn check_frames<J, F>(journal: &J, mut check: F) -> Result<(), JournalError>
+where
+    J: Journal,
+    F: FnMut(&J::Reader<'_>) -> io::Result<()>,
+{
+    for lsn in journal.range().iter() {
+        let frame = journal.get(lsn)?.ok_or(JournalError::MissingFrame(lsn))?;
+        check(&frame).map_err(|source| JournalError::InvalidFrame { lsn, source })?;
+    }
+    Ok(())
+}
+
+/// PinnableJournal can pin a range of its frames, which remain readable
+/// through the returned read-only journal even after they are replaced or
+/// dropped from this journal
+pub trait PinnableJournal: Journal {
+    type Pinned: Journal + 'static;
+
+    fn pin(&self, range: LsnRange) -> io::Result<Self::Pinned>;
 }
 
 pub trait JournalFactory<J> {
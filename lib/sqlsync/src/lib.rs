@@ -2,29 +2,55 @@ mod db;
 mod iter;
 mod journal;
 mod lsn;
+mod meta;
 mod page;
+mod query_stream;
+mod random;
 mod reactive_query;
+mod schema;
 mod serialization;
 mod storage;
 mod vfs;
 
+#[cfg(test)]
+mod test_helpers;
+
 pub mod coordinator;
+pub mod debounce;
+pub mod duplex;
 pub mod error;
 pub mod local;
+pub mod logging;
+pub mod persistence;
 pub mod positioned_io;
 pub mod reducer;
+pub mod replica;
 pub mod replication;
+pub mod server;
+pub mod snapshot;
 pub mod timeline;
 pub mod unixtime;
 
+pub use db::{
+    explain_query_plan, run_in_savepoint, JournalMode, OpenConfig, QueryCancellation,
+    QueryPlanStep, RegisterFunctions,
+};
 pub use journal::*;
-pub use reactive_query::ReactiveQuery;
-pub use reducer::{ReducerError, WasmReducer};
+pub use meta::decode_set_meta;
+pub use query_stream::QueryStream;
+pub use reactive_query::{
+    KeyedQuery, KeyedResult, KeyedRows, MissingKeyColumn, ReactiveQuery, RowDelta,
+};
+pub use reducer::{MutationCodec, ReducerError, WasmModule, WasmReducer, WasmReducerConfig};
+pub use snapshot::Snapshot;
 pub use serialization::{Deserializable, Serializable};
-pub use storage::StorageChange;
+pub use storage::{import_sqlite_file, DocumentStats, StorageChange, SNAPSHOT_HEADER_SIZE};
+
+pub use lsn::{Lsn, LsnIter, LsnRange, LsnSet};
+pub use page::{NoHasher, Page, PageHasher, PageIdx, SparsePages, Xxh3Hasher};
 
-pub use lsn::{Lsn, LsnRange};
-pub use page::PageIdx;
+#[cfg(feature = "blake3")]
+pub use page::Blake3Hasher;
 
 pub mod sqlite {
     pub use rusqlite::*;
@@ -1,16 +1,37 @@
-use std::{fmt::Debug, io, pin::Pin};
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    fmt::Debug,
+    io,
+    pin::Pin,
+};
 
 use rusqlite::Connection;
+use thiserror::Error;
 
 use crate::{
-    db::{open_with_vfs, ConnectionPair},
-    error::Result,
-    journal::{Journal, JournalId},
-    lsn::LsnRange,
-    reducer::WasmReducer,
-    replication::{ReplicationDestination, ReplicationError, ReplicationSource},
-    storage::{Storage, StorageChange},
-    timeline::{apply_mutation, rebase_timeline, run_timeline_migration},
+    db::{open_with_vfs, ConnectionPair, OpenConfig, QueryCancellation, RegisterFunctions},
+    error::{Error, Result},
+    journal::{Journal, JournalError, JournalId, PinnableJournal},
+    logging,
+    lsn::{LsnRange, LsnSet},
+    meta::{encode_set_meta, get_meta, is_reserved_meta_key},
+    page::{PageIdx, DEFAULT_PAGESIZE},
+    positioned_io::PositionedReader,
+    random::seed_randomness,
+    reducer::{validate_mutation, Reducer, ReducerOutput, WasmReducer},
+    replication::{
+        AppliedWatermark, Rejection, ReplicationDestination, ReplicationError, ReplicationSource,
+    },
+    schema::{root_page_tables, SchemaTracker},
+    snapshot::Snapshot,
+    storage::{DocumentStats, Storage, StorageChange},
+    timeline::{
+        applied_lsn, apply_mutation, apply_pending_mutation, apply_pending_mutations,
+        bookkeeping_root_pages, read_outputs, rebase_timeline, run_reducer_migration,
+        run_timeline_migration, MutationOutput,
+    },
+    unixtime::unix_timestamp_milliseconds,
     Lsn,
 };
 
@@ -18,24 +39,123 @@ pub trait Signal {
     fn emit(&mut self);
 }
 
+#[derive(Debug, Default, Clone, Copy)]
 pub struct NoopSignal;
 impl Signal for NoopSignal {
     fn emit(&mut self) {}
 }
 
-pub struct LocalDocument<J, S> {
-    reducer: WasmReducer,
+/// DocumentSignals are emitted by a LocalDocument to tell its host that
+/// there is work to do
+#[derive(Debug, Default, Clone)]
+pub struct DocumentSignals<S> {
+    /// storage changed, see storage_changes
+    pub storage: S,
+    /// the timeline has entries to sync to the coordinator
+    pub timeline: S,
+    /// storage received from the coordinator can be rebased onto
+    pub rebase: S,
+    /// mutation outputs are available, see take_outputs
+    pub outputs: S,
+    /// calls to wait_for_lsn have finished, see take_finished_waiters
+    pub waiters: S,
+}
+
+/// RebaseOutcome describes how a rebase changed the document
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RebaseOutcome {
+    /// true if the document differs from what was shown before the rebase,
+    /// because the coordinator computed different results for our mutations
+    /// or applied changes from other clients
+    pub diverged: bool,
+    /// the sorted root pages of every table and index which changed. this
+    /// may be empty when the document diverged in ways which couldn't be
+    /// traced to individual tables
+    pub changed_tables: Vec<PageIdx>,
+}
+
+/// WaiterId identifies a call to LocalDocument::wait_for_lsn
+pub type WaiterId = u64;
+
+/// WaitError is why a call to LocalDocument::wait_for_lsn failed
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum WaitError {
+    #[error("timed out waiting for lsn {0} to sync")]
+    Timeout(Lsn),
+
+    #[error("the coordinator rejected lsn {lsn}: {reason}")]
+    Rejected { lsn: Lsn, reason: String },
+
+    #[error("stopped waiting for lsn {0} to sync")]
+    Cancelled(Lsn),
+}
+
+struct LsnWaiter {
+    id: WaiterId,
+    lsn: Lsn,
+    // in unix milliseconds
+    deadline: i64,
+}
+
+pub struct LocalDocument<J, S, R = WasmReducer> {
+    reducer: R,
     timeline: J,
-    storage: Pin<Box<Storage<J>>>,
+    // see open_with_storage for why this is declared before storage
     sqlite: ConnectionPair,
+    storage: Pin<Box<Storage<J>>>,
+
+    // the latest applied watermark reported by the coordinator for our
+    // timeline, which we can act on once our storage contains storage_lsn
+    applied_watermark: Option<AppliedWatermark>,
+
+    // set between begin_batch and commit_batch. mutations in a batch are
+    // applied to the database immediately but buffered here until the batch
+    // is appended to the timeline, where each occupies its own lsn
+    batching: bool,
+    pending_mutations: Vec<Vec<u8>>,
 
-    // signals
-    storage_changed: S,
-    timeline_changed: S,
-    rebase_available: S,
+    // authoritative outputs of our mutations which haven't been taken yet,
+    // along with the next timeline lsn to look for outputs at
+    outputs: Vec<MutationOutput>,
+    next_output_lsn: Lsn,
+
+    // timeline entries the coordinator refused to apply, which are skipped
+    // when rebasing. rollback_pending is set until the next rebase
+    rejected: LsnSet,
+    rejections: Vec<Rejection>,
+    rollback_pending: bool,
+
+    // timeline entries removed by undo, most recently undone last
+    redo_stack: Vec<Vec<u8>>,
+
+    // calls to wait_for_lsn which haven't finished, and the results of those
+    // which have but haven't been taken yet
+    waiters: Vec<LsnWaiter>,
+    next_waiter_id: WaiterId,
+    finished_waiters: Vec<(WaiterId, std::result::Result<(), WaitError>)>,
+
+    // mutate fails with Error::TimelineFull once the timeline holds this many
+    // entries the coordinator hasn't acknowledged
+    max_unacked_entries: Option<usize>,
+
+    // the last timeline lsn which may have been sent to the coordinator,
+    // entries up to and including it can't be undone
+    last_sent_lsn: Cell<Option<Lsn>>,
+
+    // used to report schema changes which only dropped tables
+    schema: SchemaTracker,
+
+    // custom sql functions, which are also registered on snapshots
+    register_functions: Option<RegisterFunctions>,
+
+    // the root page to table mapping, along with the schema cookie it was
+    // read at
+    root_page_tables: RefCell<Option<(u32, HashMap<PageIdx, String>)>>,
+
+    signals: DocumentSignals<S>,
 }
 
-impl<J: Journal, S> Debug for LocalDocument<J, S> {
+impl<J: Journal, S, R> Debug for LocalDocument<J, S, R> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_tuple("LocalDocument")
             .field(&("timeline", &self.timeline))
@@ -44,39 +164,142 @@ impl<J: Journal, S> Debug for LocalDocument<J, S> {
     }
 }
 
-impl<J, S> LocalDocument<J, S>
+impl<J, S, R> LocalDocument<J, S, R>
 where
     J: Journal + ReplicationSource,
     S: Signal,
+    R: Reducer,
 {
-    pub fn open(
+    pub fn open(storage: J, timeline: J, reducer: R, signals: DocumentSignals<S>) -> Result<Self> {
+        Self::open_with_page_size(storage, timeline, reducer, signals, DEFAULT_PAGESIZE)
+    }
+
+    /// open a document whose database uses the given page size, every client
+    /// and the coordinator of a document must use the same page size
+    pub fn open_with_page_size(
         storage: J,
         timeline: J,
-        reducer: WasmReducer,
-        storage_changed: S,
-        timeline_changed: S,
-        rebase_available: S,
+        reducer: R,
+        signals: DocumentSignals<S>,
+        page_size: usize,
     ) -> Result<Self> {
-        let (mut sqlite, storage) = open_with_vfs(storage)?;
+        Self::open_with_config(
+            storage,
+            timeline,
+            reducer,
+            signals,
+            page_size,
+            &OpenConfig::default(),
+        )
+    }
+
+    /// like open_with_page_size, but tunes the underlying sqlite connections
+    pub fn open_with_config(
+        storage: J,
+        mut timeline: J,
+        mut reducer: R,
+        signals: DocumentSignals<S>,
+        page_size: usize,
+        config: &OpenConfig,
+    ) -> Result<Self> {
+        let (mut sqlite, storage) = open_with_vfs(storage, page_size, config)?;
 
         // TODO: this feels awkward here
         run_timeline_migration(&mut sqlite.readwrite)?;
 
+        // migrating locally lets us use the new schema before we sync, the
+        // coordinator runs the same migration when it opens the document
+        run_reducer_migration(&mut sqlite.readwrite, &mut reducer)?;
+
+        // only outputs for mutations applied after we opened are delivered
+        let next_output_lsn = applied_lsn(&sqlite.readonly, timeline.id())?
+            .map(|lsn| lsn + 1)
+            .unwrap_or(0);
+
+        // mutations made before we were closed may not have reached the
+        // coordinator yet, replay them so they stay visible while offline
+        if timeline.range().last().is_some() {
+            rebase_timeline(
+                &mut timeline,
+                &mut sqlite.readwrite,
+                &mut reducer,
+                &LsnSet::new(),
+            )?;
+        }
+
+        // we don't know which entries were sent before we were opened
+        let last_sent_lsn = Cell::new(timeline.range().last());
+
         Ok(Self {
             reducer,
             timeline,
             storage,
             sqlite,
-            storage_changed,
-            timeline_changed,
-            rebase_available,
+            applied_watermark: None,
+            pending_mutations: Vec::new(),
+            batching: false,
+            outputs: Vec::new(),
+            next_output_lsn,
+            rejected: LsnSet::new(),
+            rejections: Vec::new(),
+            rollback_pending: false,
+            redo_stack: Vec::new(),
+            waiters: Vec::new(),
+            next_waiter_id: 0,
+            finished_waiters: Vec::new(),
+            max_unacked_entries: None,
+            last_sent_lsn,
+            schema: SchemaTracker::default(),
+            register_functions: config.register_functions,
+            root_page_tables: RefCell::new(None),
+            signals,
         })
     }
 
     fn signal_storage_change(&mut self) {
         if self.storage.has_changes() {
-            self.storage_changed.emit()
+            self.signals.storage.emit()
+        }
+    }
+
+    /// start a batch, which buffers mutations until commit_batch appends them
+    /// to the timeline together. each mutation still occupies its own lsn, but
+    /// a burst of mutations is synced to the coordinator at once rather than
+    /// one at a time. mutations are still applied and visible to queries
+    /// immediately. starting a batch while one is open does nothing
+    pub fn begin_batch(&mut self) {
+        self.batching = true;
+    }
+
+    /// end the batch started by begin_batch, appending its mutations to the
+    /// timeline
+    pub fn commit_batch(&mut self) -> Result<()> {
+        self.batching = false;
+        self.flush_batch()
+    }
+
+    /// returns true if the open batch contains mutations
+    pub fn has_pending_mutations(&self) -> bool {
+        !self.pending_mutations.is_empty()
+    }
+
+    /// append the mutations buffered by the open batch to the timeline,
+    /// leaving the batch open
+    fn flush_batch(&mut self) -> Result<()> {
+        if self.pending_mutations.is_empty() {
+            return Ok(());
         }
+        for mutation in std::mem::take(&mut self.pending_mutations) {
+            self.timeline.append(mutation.as_slice())?;
+        }
+        self.signals.timeline.emit();
+        Ok(())
+    }
+
+    /// the timeline lsn the next mutation will occupy, after the mutations
+    /// in the open batch
+    fn next_mutation_lsn(&self) -> Lsn {
+        self.timeline.range().next() + self.pending_mutations.len() as Lsn
     }
 
     pub fn doc_id(&self) -> JournalId {
@@ -91,47 +314,413 @@ where
         f(&self.sqlite.readonly)
     }
 
+    /// capture the document's current state, including local mutations, so
+    /// that several queries can be run against it consistently. the snapshot
+    /// is unaffected by later mutations and rebases.
+    pub fn snapshot(&self) -> Result<Snapshot>
+    where
+        J: PinnableJournal,
+    {
+        Snapshot::new(&self.storage, self.register_functions)
+    }
+
+    /// write the document's current state, including local mutations, to
+    /// writer as a standalone SQLite database file, e.g. for backups or
+    /// inspecting the document with other tools
+    pub fn export_sqlite(&self, mut writer: impl io::Write) -> Result<()> {
+        Ok(self.storage.export_sqlite(&mut writer)?)
+    }
+
+    /// map the root page of every table and index to the name of its table,
+    /// which allows the root pages in a StorageChange to be resolved to tables.
+    /// the mapping is cached until the schema changes
+    pub fn root_page_to_table(&self) -> Result<HashMap<PageIdx, String>> {
+        let schema_cookie = self.storage.schema_cookie()?;
+        let mut cache = self.root_page_tables.borrow_mut();
+        match &*cache {
+            Some((cookie, tables)) if *cookie == schema_cookie => Ok(tables.clone()),
+            _ => {
+                let tables = root_page_tables(&self.sqlite.readonly)?;
+                *cache = Some((schema_cookie, tables.clone()));
+                Ok(tables)
+            }
+        }
+    }
+
     #[inline]
     pub fn sqlite_readonly(&self) -> &Connection {
         &self.sqlite.readonly
     }
 
-    pub fn mutate(&mut self, m: &[u8]) -> Result<()> {
-        apply_mutation(
-            &mut self.timeline,
+    /// returns a handle which cancels a single query passed to
+    /// query_cancellable, see QueryCancellation
+    pub fn query_cancellation(&self) -> QueryCancellation {
+        self.sqlite.cancellable.cancellation()
+    }
+
+    /// like query, but the query fails with SQLITE_INTERRUPT if cancellation
+    /// is cancelled before or while it runs
+    pub fn query_cancellable<F, O, E>(
+        &self,
+        cancellation: &QueryCancellation,
+        f: F,
+    ) -> std::result::Result<O, E>
+    where
+        F: FnOnce(&Connection) -> std::result::Result<O, E>,
+        E: std::convert::From<rusqlite::Error>,
+    {
+        self.sqlite
+            .cancellable
+            .run(&self.sqlite.readonly, cancellation, f)
+    }
+
+    /// apply a mutation, returning the reducer's output. the output is
+    /// optimistic: the coordinator may compute a different output once the
+    /// mutation is rebased onto other clients' changes, which is delivered
+    /// through the outputs signal, see take_outputs
+    pub fn mutate(&mut self, m: &[u8]) -> Result<ReducerOutput> {
+        validate_mutation(self.reducer.mutation_codec(), m)?;
+        self.check_timeline_capacity(1)?;
+        self.redo_stack.clear();
+        let output = if self.batching {
+            let (id, lsn) = (self.timeline.id(), self.next_mutation_lsn());
+            seed_randomness(&self.sqlite.readwrite, id, lsn)?;
+            let output = apply_pending_mutation(
+                &mut self.sqlite.readwrite,
+                &mut self.reducer,
+                (id, lsn),
+                m,
+            )?;
+            self.pending_mutations.push(m.to_vec());
+            output
+        } else {
+            let output = apply_mutation(
+                &mut self.timeline,
+                &mut self.sqlite.readwrite,
+                &mut self.reducer,
+                m,
+            )?;
+            self.signals.timeline.emit();
+            output
+        };
+        self.signal_storage_change();
+        Ok(output)
+    }
+
+    /// apply several mutations in a single transaction, which is much faster
+    /// than calling mutate for each of them and signals a single storage
+    /// change. each mutation still occupies its own timeline lsn, and they
+    /// join the open batch or are appended right away if there isn't one.
+    /// if any of them fail none are applied
+    pub fn mutate_batch(&mut self, mutations: &[Vec<u8>]) -> Result<Vec<ReducerOutput>> {
+        if mutations.is_empty() {
+            return Ok(Vec::new());
+        }
+        for m in mutations {
+            validate_mutation(self.reducer.mutation_codec(), m)?;
+        }
+        self.check_timeline_capacity(mutations.len())?;
+        self.redo_stack.clear();
+        let lsn = self.next_mutation_lsn();
+        let outputs = apply_pending_mutations(
             &mut self.sqlite.readwrite,
             &mut self.reducer,
-            m,
+            (self.timeline.id(), lsn),
+            mutations,
         )?;
-        self.timeline_changed.emit();
+        self.pending_mutations.extend_from_slice(mutations);
+        if !self.batching {
+            self.flush_batch()?;
+        }
         self.signal_storage_change();
+        Ok(outputs)
+    }
+
+    /// returns the outputs the coordinator computed for our mutations since
+    /// the last call, in timeline order
+    pub fn take_outputs(&mut self) -> Vec<MutationOutput> {
+        std::mem::take(&mut self.outputs)
+    }
+
+    /// returns the timeline entries rejected by the coordinator since the
+    /// last call, each entry is dropped by the next rebase
+    pub fn take_rejections(&mut self) -> Vec<Rejection> {
+        std::mem::take(&mut self.rejections)
+    }
+
+    /// the lsn of the timeline entry holding the latest mutation, which can
+    /// be passed to wait_for_lsn. None once the entry has been acknowledged
+    pub fn last_mutation_lsn(&self) -> Option<Lsn> {
+        if self.pending_mutations.is_empty() {
+            self.timeline.range().last()
+        } else {
+            Some(self.next_mutation_lsn() - 1)
+        }
+    }
+
+    /// wait for the coordinator to apply the timeline entry at lsn, and for
+    /// this document to rebase onto the result so that queries observe it.
+    /// the waiter fails if the entry is rejected, or once timeout_ms has
+    /// elapsed and expire_waiters is called. finished waiters are announced
+    /// through the waiters signal, see take_finished_waiters
+    pub fn wait_for_lsn(&mut self, lsn: Lsn, timeout_ms: i64) -> Result<WaiterId> {
+        let id = self.next_waiter_id;
+        self.next_waiter_id += 1;
+        let deadline = unix_timestamp_milliseconds() + timeout_ms;
+        self.waiters.push(LsnWaiter { id, lsn, deadline });
+        self.finish_synced_waiters()?;
+        Ok(id)
+    }
+
+    /// true if any waiters haven't finished
+    pub fn has_waiters(&self) -> bool {
+        !self.waiters.is_empty()
+    }
+
+    /// fail the waiters whose timeout has elapsed
+    pub fn expire_waiters(&mut self) {
+        let now = unix_timestamp_milliseconds();
+        self.finish_waiters(|w| (w.deadline <= now).then_some(Err(WaitError::Timeout(w.lsn))));
+    }
+
+    /// fail every waiter, e.g. once the document stops syncing
+    pub fn cancel_waiters(&mut self) {
+        self.finish_waiters(|w| Some(Err(WaitError::Cancelled(w.lsn))));
+    }
+
+    /// returns the waiters which finished since the last call
+    pub fn take_finished_waiters(&mut self) -> Vec<(WaiterId, std::result::Result<(), WaitError>)> {
+        std::mem::take(&mut self.finished_waiters)
+    }
+
+    fn finish_synced_waiters(&mut self) -> Result<()> {
+        if self.waiters.is_empty() {
+            return Ok(());
+        }
+        let applied = applied_lsn(&self.sqlite.readonly, self.timeline.id())?;
+        self.finish_waiters(|w| (applied >= Some(w.lsn)).then_some(Ok(())));
         Ok(())
     }
 
-    pub fn rebase(&mut self) -> Result<()> {
-        if self.storage.has_committed_pages() && self.storage.has_invisible_pages() {
-            self.storage.reset()?;
-            rebase_timeline(
-                &mut self.timeline,
-                &mut self.sqlite.readwrite,
-                &mut self.reducer,
-            )?;
-            self.signal_storage_change();
+    fn receive_outputs(&mut self) -> Result<()> {
+        let outputs = read_outputs(
+            &self.sqlite.readonly,
+            self.timeline.id(),
+            self.next_output_lsn,
+        )?;
+        if let Some(last) = outputs.last() {
+            self.next_output_lsn = last.lsn + 1;
+            self.outputs.extend(outputs);
+            self.signals.outputs.emit();
+        }
+        Ok(())
+    }
+
+    /// bound the memory used by local changes by spilling them into journal
+    /// once more than max_pending_pages pages have changed since the last
+    /// rebase
+    pub fn set_spill(&mut self, journal: J, max_pending_pages: usize) -> Result<()> {
+        self.storage.set_spill(journal, max_pending_pages)?;
+        Ok(())
+    }
+
+    /// returns the number of timeline entries the coordinator hasn't
+    /// acknowledged, including the mutations in the open batch. entries are
+    /// acknowledged by rebasing once the coordinator has applied them
+    pub fn unacked_entries(&self) -> usize {
+        self.timeline.range().len() + self.pending_mutations.len()
+    }
+
+    /// returns the size in bytes of the entries counted by unacked_entries,
+    /// counting batched mutations by their own size. unlike unacked_entries
+    /// this reads every entry in the timeline
+    pub fn unacked_bytes(&self) -> Result<usize> {
+        let mut size: usize = self.pending_mutations.iter().map(Vec::len).sum();
+        let mut cursor = self.timeline.scan();
+        while cursor.advance()? {
+            size += cursor.size()?;
+        }
+        Ok(size)
+    }
+
+    /// limit the number of entries the coordinator hasn't acknowledged, once
+    /// the limit is reached mutate fails with Error::TimelineFull until the
+    /// coordinator catches up. this bounds the size of the timeline while
+    /// offline
+    pub fn set_max_unacked_entries(&mut self, max_entries: Option<usize>) {
+        self.max_unacked_entries = max_entries;
+    }
+
+    /// fail with Error::TimelineFull if adding new_entries mutations would
+    /// exceed max_unacked_entries
+    fn check_timeline_capacity(&self, new_entries: usize) -> Result<()> {
+        let Some(max_entries) = self.max_unacked_entries else {
+            return Ok(());
+        };
+        if self.unacked_entries() + new_entries > max_entries {
+            return Err(Error::TimelineFull { max_entries });
+        }
+        Ok(())
+    }
+
+    /// set a document metadata key, the change is replicated like any other
+    /// mutation and conflicts resolve last-writer-wins. keys starting with
+    /// __sqlsync are reserved
+    pub fn set_meta(&mut self, key: &str, value: &str) -> Result<()> {
+        if is_reserved_meta_key(key) {
+            return Err(Error::ReservedMetaKey(key.to_owned()));
         }
+        self.mutate(&encode_set_meta(key, value))?;
         Ok(())
     }
 
+    pub fn get_meta(&self, key: &str) -> Result<Option<String>> {
+        Ok(get_meta(&self.sqlite.readonly, key)?)
+    }
+
+    /// rebase local changes onto storage received from the coordinator. when
+    /// the coordinator computed the same changes we applied optimistically,
+    /// only its bookkeeping changes, the outcome isn't diverged and
+    /// subscriptions aren't invalidated
+    pub fn rebase(&mut self) -> Result<RebaseOutcome> {
+        let storage_changed =
+            self.storage.has_committed_pages() && self.storage.has_invisible_pages();
+        if storage_changed || self.rollback_pending {
+            // batched mutations only exist in the database until they are
+            // committed, so they must be in the timeline before we reset storage
+            self.flush_batch()?;
+            return self.reapply_timeline();
+        }
+        Ok(RebaseOutcome::default())
+    }
+
+    /// reset storage to its committed state and reapply the timeline on top,
+    /// ignoring changes to the coordinator's bookkeeping in the outcome
+    fn reapply_timeline(&mut self) -> Result<RebaseOutcome> {
+        self.storage.begin_rebase()?;
+        // sqlite only reloads its schema when the schema cookie changes, but
+        // our optimistic schema changes may have left it at the same value
+        // as the coordinator's different schema
+        for conn in [&self.sqlite.readwrite, &self.sqlite.readonly] {
+            conn.execute_batch("PRAGMA writable_schema = RESET")?;
+        }
+        // before anything is committed, reset also reverts our own migrations
+        run_timeline_migration(&mut self.sqlite.readwrite)?;
+        // the coordinator may be running an older reducer, and pending
+        // mutations expect the schema they were applied against
+        run_reducer_migration(&mut self.sqlite.readwrite, &mut self.reducer)?;
+        rebase_timeline(
+            &mut self.timeline,
+            &mut self.sqlite.readwrite,
+            &mut self.reducer,
+            &self.rejected,
+        )?;
+        // rejections only need to be remembered while their entry is in the
+        // timeline
+        let range = self.timeline.range();
+        self.rejected
+            .remove_before(range.first().unwrap_or(range.next()));
+        self.rollback_pending = false;
+        let outcome = match self.storage.finish_rebase()? {
+            Some(mut root_pages) => {
+                let bookkeeping = bookkeeping_root_pages(&self.sqlite.readonly)?;
+                root_pages.retain(|root| !bookkeeping.contains(root));
+                RebaseOutcome {
+                    diverged: !root_pages.is_empty(),
+                    changed_tables: root_pages,
+                }
+            }
+            None => RebaseOutcome {
+                diverged: true,
+                changed_tables: Vec::new(),
+            },
+        };
+        self.signal_storage_change();
+        self.receive_outputs()?;
+        self.finish_synced_waiters()?;
+        Ok(outcome)
+    }
+
+    /// revert the most recent mutation, returning false if there is nothing
+    /// to undo. only mutations which haven't been sent to the coordinator can
+    /// be undone, as the coordinator would otherwise still apply them.
+    /// an undone mutation can be reapplied with redo until the next mutation.
+    pub fn undo(&mut self) -> Result<bool> {
+        self.flush_batch()?;
+        let lsn = match self.timeline.range().last() {
+            Some(lsn) if self.last_sent_lsn.get() < Some(lsn) => lsn,
+            _ => return Ok(false),
+        };
+        let entry = match self.timeline.get(lsn)? {
+            Some(reader) => reader.read_all()?,
+            None => return Ok(false),
+        };
+        self.timeline.drop_last()?;
+        self.redo_stack.push(entry);
+        self.signals.timeline.emit();
+        self.reapply_timeline()?;
+        Ok(true)
+    }
+
+    /// reapply the most recently undone mutation, returning false if there is
+    /// nothing to redo. like mutate, this fails with Error::TimelineFull if
+    /// the timeline has no room, in which case the mutation can still be
+    /// redone later
+    pub fn redo(&mut self) -> Result<bool> {
+        if self.redo_stack.is_empty() {
+            return Ok(false);
+        }
+        self.check_timeline_capacity(1)?;
+        let entry = self.redo_stack.pop().expect("redo stack is not empty");
+        self.flush_batch()?;
+        apply_mutation(
+            &mut self.timeline,
+            &mut self.sqlite.readwrite,
+            &mut self.reducer,
+            &entry,
+        )?;
+        self.signals.timeline.emit();
+        self.signal_storage_change();
+        Ok(true)
+    }
+
     pub fn storage_changes(&mut self) -> Result<StorageChange> {
-        Ok(self.storage.changes()?)
+        let dropped_root_pages = if self.storage.schema_changed()? {
+            self.schema.update(&self.sqlite.readonly)?
+        } else {
+            None
+        };
+        Ok(self.storage.changes(dropped_root_pages)?)
     }
 
     pub fn storage_lsn(&mut self) -> Option<Lsn> {
         self.storage.last_committed_lsn()
     }
+
+    /// report the size of this document's storage and timeline
+    pub fn stats(&self) -> Result<DocumentStats> {
+        Ok(DocumentStats {
+            timeline_lsn: self.timeline.range().last(),
+            ..self.storage.stats()?
+        })
+    }
+
+    /// storage_source allows this document's storage journal to be replicated
+    /// to other clients. The storage journal only contains frames received from
+    /// the coordinator, so it's a valid source for seeding a new client.
+    pub fn storage_source(&self) -> &impl ReplicationSource {
+        &*self.storage
+    }
+
+    /// check the consistency of this document's storage and timeline journals
+    pub fn verify(&self) -> Result<(), JournalError> {
+        self.storage.verify()?;
+        self.timeline.verify()
+    }
 }
 
 /// LocalDocument knows how to send it's timeline journal elsewhere
-impl<J: ReplicationSource, S> ReplicationSource for LocalDocument<J, S> {
+impl<J: ReplicationSource, S, R> ReplicationSource for LocalDocument<J, S, R> {
     type Reader<'a> = <J as ReplicationSource>::Reader<'a>
     where
         Self: 'a;
@@ -147,25 +736,1543 @@ impl<J: ReplicationSource, S> ReplicationSource for LocalDocument<J, S> {
     fn read_lsn(&self, lsn: crate::Lsn) -> io::Result<Option<Self::Reader<'_>>> {
         self.timeline.read_lsn(lsn)
     }
+
+    fn frames_sent(&self, range: LsnRange) {
+        if self.last_sent_lsn.get() < range.last() {
+            self.last_sent_lsn.set(range.last());
+        }
+    }
+}
+
+impl<J, S: Signal, R> LocalDocument<J, S, R> {
+    /// finish the waiters for which f returns a result
+    fn finish_waiters<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&LsnWaiter) -> Option<std::result::Result<(), WaitError>>,
+    {
+        let finished = &mut self.finished_waiters;
+        let before = finished.len();
+        self.waiters.retain(|waiter| match f(waiter) {
+            Some(result) => {
+                finished.push((waiter.id, result));
+                false
+            }
+            None => true,
+        });
+        if self.finished_waiters.len() > before {
+            self.signals.waiters.emit();
+        }
+    }
 }
 
 /// LocalDocument knows how to receive a storage journal from elsewhere
-impl<J: ReplicationDestination, S: Signal> ReplicationDestination for LocalDocument<J, S> {
+impl<J: Journal + ReplicationDestination, S, R> LocalDocument<J, S, R> {
+    /// drop timeline entries which the coordinator has applied, as soon as
+    /// our storage contains the result. dropping them any earlier would cause
+    /// a rebase to lose the mutations until the storage caught up
+    fn drop_applied_timeline(&mut self) -> io::Result<()> {
+        if let Some(applied) = self.applied_watermark {
+            if self.storage.last_committed_lsn() >= Some(applied.storage_lsn) {
+                if self.timeline.range().contains(applied.lsn) {
+                    log::debug!(
+                        target: logging::TIMELINE,
+                        "dropping timeline entries up to applied lsn {}",
+                        applied.lsn
+                    );
+                    self.timeline.drop_prefix(applied.lsn)?;
+                    self.rejected.remove_before(applied.lsn + 1);
+                }
+                self.applied_watermark = None;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<J: Journal + ReplicationDestination, S: Signal, R> ReplicationDestination
+    for LocalDocument<J, S, R>
+{
     fn range(&mut self, id: JournalId) -> std::result::Result<LsnRange, ReplicationError> {
         self.storage.range(id)
     }
 
-    fn write_lsn<R>(
+    fn write_lsn<Reader>(
         &mut self,
         id: JournalId,
         lsn: crate::Lsn,
-        reader: &mut R,
+        reader: &mut Reader,
+    ) -> std::result::Result<(), ReplicationError>
+    where
+        Reader: io::Read,
+    {
+        self.storage.write_lsn(id, lsn, reader)?;
+        self.signals.rebase.emit();
+        self.drop_applied_timeline()?;
+        Ok(())
+    }
+
+    fn write_coalesced<Reader>(
+        &mut self,
+        id: JournalId,
+        range: LsnRange,
+        reader: &mut Reader,
     ) -> std::result::Result<(), ReplicationError>
     where
-        R: io::Read,
+        Reader: io::Read,
     {
-        let out = self.storage.write_lsn(id, lsn, reader);
-        self.rebase_available.emit();
-        out
+        self.storage.write_coalesced(id, range, reader)?;
+        self.signals.rebase.emit();
+        self.drop_applied_timeline()?;
+        Ok(())
+    }
+
+    fn acknowledge_applied(
+        &mut self,
+        applied: AppliedWatermark,
+    ) -> std::result::Result<(), ReplicationError> {
+        if applied.id != self.timeline.id() {
+            return Err(ReplicationError::UnknownJournal(applied.id));
+        }
+        // ignore watermarks which are older than the one we are waiting on
+        let stale = matches!(self.applied_watermark, Some(current) if current.lsn >= applied.lsn);
+        if !stale {
+            self.applied_watermark = Some(applied);
+            self.drop_applied_timeline()?;
+        }
+        Ok(())
+    }
+
+    fn reject(&mut self, rejection: Rejection) -> std::result::Result<(), ReplicationError> {
+        if rejection.timeline_id != self.timeline.id() {
+            return Err(ReplicationError::UnknownJournal(rejection.timeline_id));
+        }
+        if !self.rejected.contains(rejection.lsn) {
+            log::info!(
+                target: logging::TIMELINE,
+                "timeline entry {} was rejected: {}",
+                rejection.lsn,
+                rejection.reason
+            );
+            self.rejected
+                .insert(LsnRange::new(rejection.lsn, rejection.lsn));
+            self.finish_waiters(|w| {
+                (w.lsn == rejection.lsn).then(|| {
+                    Err(WaitError::Rejected {
+                        lsn: rejection.lsn,
+                        reason: rejection.reason.clone(),
+                    })
+                })
+            });
+            self.rejections.push(rejection);
+            self.rollback_pending = true;
+            self.signals.rebase.emit();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, io, rc::Rc};
+
+    use rusqlite::{functions::FunctionFlags, Transaction};
+
+    use crate::{
+        coordinator::CoordinatorDocument,
+        debounce::{Debounce, Trigger},
+        error::Error,
+        explain_query_plan, import_sqlite_file,
+        meta::encode_set_meta,
+        page::DEFAULT_PAGESIZE,
+        reducer::{Reducer, ReducerError, ReducerOutput},
+        replication::{
+            Rejection, ReplicationDestination, ReplicationMsg, ReplicationProtocol,
+            ReplicationSource,
+        },
+        test_helpers::{
+            open_coordinator, open_local, replicate, replicate_acked, Acks, SqlReducer, TestLocal,
+        },
+        timeline::MutationOutput,
+        unixtime::with_mutation_time,
+        FileJournal, Journal, JournalId, LsnRange, MemoryJournal, MemoryJournalFactory, OpenConfig,
+        PageIdx, ReactiveQuery, Scannable,
+    };
+
+    use super::{DocumentSignals, LocalDocument, NoopSignal, RebaseOutcome, Signal, WaitError};
+
+    #[test]
+    fn test_meta_replicates() -> anyhow::Result<()> {
+        let doc_id = JournalId::new128(&mut rand::thread_rng());
+        let mut local = open_local(doc_id)?;
+        let mut local2 = open_local(doc_id)?;
+        let mut coordinator = open_coordinator(doc_id)?;
+
+        let mut local_to_coordinator = ReplicationProtocol::new();
+        let mut local2_to_coordinator = ReplicationProtocol::new();
+        let mut coordinator_to_local = ReplicationProtocol::new();
+        let mut coordinator_to_local2 = ReplicationProtocol::new();
+
+        assert_eq!(local.get_meta("title")?, None);
+
+        local.set_meta("title", "groceries")?;
+        local.set_meta("title", "shopping list")?;
+        assert_eq!(local.get_meta("title")?, Some("shopping list".into()));
+
+        replicate(&mut local_to_coordinator, &local, &mut coordinator)?;
+        coordinator.step()?;
+        replicate(&mut coordinator_to_local2, &coordinator, &mut local2)?;
+        local2.rebase()?;
+        assert_eq!(local2.get_meta("title")?, Some("shopping list".into()));
+
+        // concurrent writes resolve in the order the coordinator applies them
+        local2.set_meta("title", "todo")?;
+        local.set_meta("title", "chores")?;
+        replicate(&mut local2_to_coordinator, &local2, &mut coordinator)?;
+        replicate(&mut local_to_coordinator, &local, &mut coordinator)?;
+        while coordinator.has_pending_work() {
+            coordinator.step()?;
+        }
+        replicate(&mut coordinator_to_local, &coordinator, &mut local)?;
+        replicate(&mut coordinator_to_local2, &coordinator, &mut local2)?;
+        local.rebase()?;
+        local2.rebase()?;
+
+        assert_eq!(local.get_meta("title")?, Some("chores".into()));
+        assert_eq!(local2.get_meta("title")?, Some("chores".into()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reserved_meta_rejected() -> anyhow::Result<()> {
+        let doc_id = JournalId::new128(&mut rand::thread_rng());
+        let mut local = open_local(doc_id)?;
+        let mut coordinator = open_coordinator(doc_id)?;
+
+        let err = local
+            .set_meta("__sqlsync_schema_version", "99")
+            .unwrap_err();
+        assert!(matches!(err, Error::ReservedMetaKey(_)));
+
+        // a client which bypasses set_meta is rejected by the coordinator
+        local.mutate(&encode_set_meta("__sqlsync_schema_version", "99"))?;
+        local.set_meta("title", "groceries")?;
+        replicate(&mut ReplicationProtocol::new(), &local, &mut coordinator)?;
+        while coordinator.has_pending_work() {
+            coordinator.step()?;
+        }
+        let rejections = coordinator.take_rejections();
+        assert_eq!(rejections.len(), 1);
+        assert_eq!(rejections[0].lsn, 0);
+        for rejection in rejections {
+            let msg = ReplicationMsg::from(rejection);
+            ReplicationProtocol::new().handle(&mut local, msg, &mut std::io::empty())?;
+        }
+        local.rebase()?;
+        assert_eq!(local.get_meta("__sqlsync_schema_version")?, None);
+        assert!(!local.rejected.is_empty());
+
+        // and forgets the rejection once the coordinator's storage arrives
+        replicate(&mut ReplicationProtocol::new(), &coordinator, &mut local)?;
+        local.rebase()?;
+        assert_eq!(local.get_meta("__sqlsync_schema_version")?, None);
+        assert_eq!(local.get_meta("title")?, Some("groceries".into()));
+        assert!(local.rejected.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_mutation_time_converges() -> anyhow::Result<()> {
+        let doc_id = JournalId::new128(&mut rand::thread_rng());
+        let mut local = open_local(doc_id)?;
+        let mut local2 = open_local(doc_id)?;
+        let mut coordinator = open_coordinator(doc_id)?;
+
+        // both clients have clocks which are far in the past
+        let stamp = b"INSERT INTO events VALUES (strftime('%Y-%m-%d %H:%M:%f', 'now'))";
+        with_mutation_time(1000, || {
+            local.mutate(b"CREATE TABLE events (at TEXT)")?;
+            local.mutate(stamp)
+        })?;
+        with_mutation_time(2000, || {
+            local2.mutate(b"CREATE TABLE IF NOT EXISTS events (at TEXT)")?;
+            local2.mutate(stamp)
+        })?;
+        assert_eq!(query_events(&local)?, ["1970-01-01 00:00:01.000"]);
+
+        // the coordinator applies both mutations at its own time
+        replicate(&mut ReplicationProtocol::new(), &local, &mut coordinator)?;
+        replicate(&mut ReplicationProtocol::new(), &local2, &mut coordinator)?;
+        while coordinator.has_pending_work() {
+            coordinator.step()?;
+        }
+        for doc in [&mut local, &mut local2] {
+            replicate(&mut ReplicationProtocol::new(), &coordinator, doc)?;
+            doc.rebase()?;
+        }
+
+        let applied = query_events(&local)?;
+        assert_eq!(applied.len(), 2);
+        assert!(applied.iter().all(|at| !at.starts_with("1970")));
+        assert_eq!(query_events(&local2)?, applied);
+
+        Ok(())
+    }
+
+    fn query_events(doc: &TestLocal) -> anyhow::Result<Vec<String>> {
+        Ok(doc.query(|conn| {
+            let mut stmt = conn.prepare("SELECT at FROM events ORDER BY rowid")?;
+            let rows = stmt.query_map([], |row| row.get(0))?;
+            rows.collect::<rusqlite::Result<Vec<String>>>()
+        })?)
+    }
+
+    #[test]
+    fn test_coalesce_mutations() -> anyhow::Result<()> {
+        let doc_id = JournalId::new128(&mut rand::thread_rng());
+        let mut local = open_local(doc_id)?;
+        let mut coalesced = open_local(doc_id)?;
+        coalesced.begin_batch();
+
+        let mut coordinator = open_coordinator(doc_id)?;
+        let mut coalesced_coordinator = open_coordinator(doc_id)?;
+        let mut local_to_coordinator = ReplicationProtocol::new();
+        let mut coalesced_to_coordinator = ReplicationProtocol::new();
+
+        let mutations = ["CREATE TABLE people (name TEXT)"]
+            .into_iter()
+            .map(String::from)
+            .chain(
+                ["a", "al", "ali", "alic", "alice"]
+                    .map(|name| format!("INSERT INTO people VALUES ('{}')", name)),
+            );
+        for mutation in mutations {
+            local.mutate(mutation.as_bytes())?;
+            coalesced.mutate(mutation.as_bytes())?;
+        }
+
+        // batched mutations are visible locally but not synced
+        assert_eq!(query_names(&coalesced)?, query_names(&local)?);
+        assert_eq!(
+            replicate(
+                &mut coalesced_to_coordinator,
+                &coalesced,
+                &mut coalesced_coordinator
+            )?,
+            0
+        );
+
+        // until the batch is committed, at which point each mutation is
+        // synced as its own frame
+        coalesced.commit_batch()?;
+        assert_eq!(
+            replicate(&mut local_to_coordinator, &local, &mut coordinator)?,
+            6
+        );
+        assert_eq!(
+            replicate(
+                &mut coalesced_to_coordinator,
+                &coalesced,
+                &mut coalesced_coordinator
+            )?,
+            6
+        );
+
+        // the coordinators and clients end up in the same state
+        for (doc, coordinator) in [
+            (&mut local, &mut coordinator),
+            (&mut coalesced, &mut coalesced_coordinator),
+        ] {
+            while coordinator.has_pending_work() {
+                coordinator.step()?;
+            }
+            replicate(&mut ReplicationProtocol::new(), &*coordinator, doc)?;
+            doc.rebase()?;
+        }
+        assert_eq!(query_names(&coalesced)?, query_names(&local)?);
+        assert_eq!(
+            query_names(&coalesced)?,
+            vec!["a", "al", "ali", "alic", "alice"]
+        );
+
+        Ok(())
+    }
+
+    fn query_names(doc: &TestLocal) -> anyhow::Result<Vec<String>> {
+        Ok(doc.query(|conn| {
+            let mut stmt = conn.prepare("SELECT name FROM people ORDER BY name")?;
+            let rows = stmt.query_map([], |row| row.get(0))?;
+            rows.collect::<rusqlite::Result<Vec<String>>>()
+        })?)
+    }
+
+    #[derive(Clone, Default)]
+    struct CountingSignal(Rc<Cell<usize>>);
+    impl Signal for CountingSignal {
+        fn emit(&mut self) {
+            self.0.set(self.0.get() + 1)
+        }
+    }
+
+    #[test]
+    fn test_coalesce_storage_changes() -> anyhow::Result<()> {
+        // like the worker, storage change signals trigger a debounce and
+        // storage changes are only handled once its window elapses. returns
+        // the number of timers started for the new signals
+        fn trigger(signal: &CountingSignal, seen: &mut usize, debounce: &mut Debounce) -> usize {
+            let mut timers = 0;
+            while *seen < signal.0.get() {
+                *seen += 1;
+                match debounce.trigger() {
+                    Trigger::StartTimer(_) => timers += 1,
+                    Trigger::Coalesced => {}
+                    Trigger::Now => panic!("the debounce is enabled"),
+                }
+            }
+            timers
+        }
+
+        let storage_changed = CountingSignal::default();
+        let mut local = LocalDocument::open(
+            MemoryJournal::open(JournalId::new128(&mut rand::thread_rng()))?,
+            MemoryJournal::open(JournalId::new128(&mut rand::thread_rng()))?,
+            SqlReducer,
+            DocumentSignals {
+                storage: storage_changed.clone(),
+                ..DocumentSignals::default()
+            },
+        )?;
+        let mut debounce = Debounce::new(16);
+        let mut seen = 0;
+
+        local.mutate(b"CREATE TABLE sliders (id INTEGER PRIMARY KEY, value INTEGER)")?;
+        local.mutate(b"INSERT INTO sliders VALUES (1, 0)")?;
+        assert_eq!(trigger(&storage_changed, &mut seen, &mut debounce), 1);
+        assert!(debounce.elapsed());
+        local.storage_changes()?;
+
+        let mut query = ReactiveQuery::new("SELECT value FROM sliders".into(), Vec::<i64>::new());
+        query.refresh(local.sqlite_readonly(), |_, row| row.get::<_, i64>(0))?;
+
+        // a burst of mutations signals every change, but starts one window
+        for value in 1..=10 {
+            local.mutate(format!("UPDATE sliders SET value = {}", value).as_bytes())?;
+        }
+        let before = seen;
+        assert_eq!(trigger(&storage_changed, &mut seen, &mut debounce), 1);
+        assert_eq!(seen - before, 10);
+
+        // which dirties the query once when it elapses
+        assert!(debounce.elapsed());
+        assert!(query.handle_storage_change(&local.storage_changes()?));
+        let (_, rows) = query.refresh(local.sqlite_readonly(), |_, row| row.get::<_, i64>(0))?;
+        assert_eq!(rows, vec![10]);
+        assert!(!query.handle_storage_change(&local.storage_changes()?));
+
+        // and the next change opens a new window
+        local.mutate(b"UPDATE sliders SET value = 11")?;
+        assert_eq!(trigger(&storage_changed, &mut seen, &mut debounce), 1);
+        assert!(debounce.elapsed());
+        assert!(query.handle_storage_change(&local.storage_changes()?));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_batch() -> anyhow::Result<()> {
+        let open = |storage_changed: &CountingSignal| {
+            LocalDocument::open(
+                MemoryJournal::open(JournalId::new128(&mut rand::thread_rng()))?,
+                MemoryJournal::open(JournalId::new128(&mut rand::thread_rng()))?,
+                SqlReducer,
+                DocumentSignals {
+                    storage: storage_changed.clone(),
+                    ..DocumentSignals::default()
+                },
+            )
+        };
+        let mutations = [
+            "CREATE TABLE tasks (id INTEGER PRIMARY KEY)",
+            "INSERT INTO tasks VALUES (1)",
+            "INSERT INTO tasks VALUES (2)",
+            "INSERT INTO tasks VALUES (3)",
+        ];
+
+        let unbatched_changes = CountingSignal::default();
+        let mut unbatched = open(&unbatched_changes)?;
+        for m in mutations {
+            unbatched.mutate(m.as_bytes())?;
+        }
+
+        let batched_changes = CountingSignal::default();
+        let mut batched = open(&batched_changes)?;
+        batched.begin_batch();
+        for m in mutations {
+            batched.mutate(m.as_bytes())?;
+        }
+        // mutations are visible and signalled right away
+        assert_eq!(batched_changes.0.get(), 4);
+        assert_eq!(batched.stats()?.timeline_lsn, None);
+        batched.commit_batch()?;
+
+        // and each mutation occupies its own lsn once the batch is committed
+        assert_eq!(unbatched.stats()?.timeline_lsn, Some(3));
+        assert_eq!(batched.stats()?.timeline_lsn, Some(3));
+        assert_eq!(unbatched_changes.0.get(), 4);
+        for lsn in 0..4 {
+            assert_eq!(
+                batched.timeline.get(lsn)?,
+                Some(mutations[lsn as usize].as_bytes())
+            );
+        }
+
+        // mutate_batch signals a single storage change
+        let mutations = [
+            b"INSERT INTO tasks VALUES (4)".to_vec(),
+            b"INSERT INTO tasks VALUES (5)".to_vec(),
+        ];
+        batched.mutate_batch(&mutations)?;
+        assert_eq!(batched_changes.0.get(), 5);
+        assert_eq!(batched.stats()?.timeline_lsn, Some(5));
+
+        // an empty batch doesn't signal or append anything
+        batched.begin_batch();
+        batched.commit_batch()?;
+        assert_eq!(batched_changes.0.get(), 5);
+        assert_eq!(batched.stats()?.timeline_lsn, Some(5));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_explain_query_plan() -> anyhow::Result<()> {
+        let mut local = open_local(JournalId::new128(&mut rand::thread_rng()))?;
+        local.mutate(b"CREATE TABLE tasks (id INTEGER PRIMARY KEY, completed BOOLEAN)")?;
+
+        let sql = "SELECT id FROM tasks WHERE completed = ?";
+        let plan = local.query(|conn| explain_query_plan(conn, sql, [true]))?;
+        assert_eq!(plan.len(), 1);
+        assert_eq!(plan[0].detail, "SCAN tasks");
+
+        local.mutate(b"CREATE INDEX tasks_completed ON tasks (completed)")?;
+        let plan = local.query(|conn| explain_query_plan(conn, sql, [true]))?;
+        assert_eq!(plan.len(), 1);
+        assert_eq!(
+            plan[0].detail,
+            "SEARCH tasks USING COVERING INDEX tasks_completed (completed=?)"
+        );
+
+        // explaining doesn't get around the readonly connection's authorizer
+        assert!(local
+            .query(|conn| explain_query_plan(conn, "DELETE FROM tasks", []))
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rebase_without_changes() -> anyhow::Result<()> {
+        let doc_id = JournalId::new128(&mut rand::thread_rng());
+        let mut client_a = open_local(doc_id)?;
+        let mut client_b = open_local(doc_id)?;
+        let mut coordinator = open_coordinator(doc_id)?;
+
+        let mut a_to_coordinator = ReplicationProtocol::new();
+        let mut b_to_coordinator = ReplicationProtocol::new();
+        let mut coordinator_to_a = ReplicationProtocol::new();
+
+        client_a.mutate(b"CREATE TABLE tasks (id INTEGER PRIMARY KEY, done BOOLEAN)")?;
+        replicate(&mut a_to_coordinator, &client_a, &mut coordinator)?;
+        coordinator.step()?;
+        replicate(&mut coordinator_to_a, &coordinator, &mut client_a)?;
+        client_a.rebase()?;
+
+        let mut query = ReactiveQuery::new("SELECT id FROM tasks".into(), Vec::<i64>::new());
+        query.handle_storage_change(&client_a.storage_changes()?);
+        query.refresh(client_a.sqlite_readonly(), |_, row| row.get::<_, i64>(0))?;
+
+        // the coordinator computes exactly what we applied optimistically, so
+        // the subscription isn't invalidated
+        client_a.mutate(b"INSERT INTO tasks VALUES (1, false)")?;
+        assert!(query.handle_storage_change(&client_a.storage_changes()?));
+        query.refresh(client_a.sqlite_readonly(), |_, row| row.get::<_, i64>(0))?;
+        replicate(&mut a_to_coordinator, &client_a, &mut coordinator)?;
+        coordinator.step()?;
+        replicate(&mut coordinator_to_a, &coordinator, &mut client_a)?;
+        assert!(!client_a.rebase()?.diverged);
+        assert!(!query.handle_storage_change(&client_a.storage_changes()?));
+
+        // changes which hadn't been picked up before the rebase are kept
+        client_a.mutate(b"INSERT INTO tasks VALUES (2, false)")?;
+        replicate(&mut a_to_coordinator, &client_a, &mut coordinator)?;
+        coordinator.step()?;
+        replicate(&mut coordinator_to_a, &coordinator, &mut client_a)?;
+        assert!(!client_a.rebase()?.diverged);
+        assert!(query.handle_storage_change(&client_a.storage_changes()?));
+        query.refresh(client_a.sqlite_readonly(), |_, row| row.get::<_, i64>(0))?;
+
+        // another client's change is a real change
+        replicate(&mut ReplicationProtocol::new(), &coordinator, &mut client_b)?;
+        client_b.rebase()?;
+        client_b.mutate(b"INSERT INTO tasks VALUES (3, false)")?;
+        replicate(&mut b_to_coordinator, &client_b, &mut coordinator)?;
+        coordinator.step()?;
+        replicate(&mut coordinator_to_a, &coordinator, &mut client_a)?;
+        assert!(client_a.rebase()?.diverged);
+        assert!(query.handle_storage_change(&client_a.storage_changes()?));
+        let (_, ids) = query.refresh(client_a.sqlite_readonly(), |_, row| row.get::<_, i64>(0))?;
+        assert_eq!(ids, vec![1, 2, 3]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rebase_divergence() -> anyhow::Result<()> {
+        let doc_id = JournalId::new128(&mut rand::thread_rng());
+        let mut client_a = open_local(doc_id)?;
+        let mut client_b = open_local(doc_id)?;
+        let mut coordinator = open_coordinator(doc_id)?;
+
+        let mut a_to_coordinator = ReplicationProtocol::new();
+        let mut b_to_coordinator = ReplicationProtocol::new();
+        let mut coordinator_to_a = ReplicationProtocol::new();
+        let mut coordinator_to_b = ReplicationProtocol::new();
+
+        client_a.mutate(b"CREATE TABLE counters (id INTEGER PRIMARY KEY, value INTEGER)")?;
+        client_a.mutate(b"CREATE TABLE notes (body TEXT)")?;
+        client_a.mutate(b"INSERT INTO counters VALUES (1, 0)")?;
+        replicate(&mut a_to_coordinator, &client_a, &mut coordinator)?;
+        coordinator.step()?;
+        replicate(&mut coordinator_to_a, &coordinator, &mut client_a)?;
+        replicate(&mut coordinator_to_b, &coordinator, &mut client_b)?;
+        client_a.rebase()?;
+        assert!(client_b.rebase()?.diverged);
+
+        // both clients increment the counter and optimistically see 1
+        let increment = b"UPDATE counters SET value = value + 1";
+        client_a.mutate(increment)?;
+        client_a.mutate(b"INSERT INTO notes VALUES ('a was here')")?;
+        client_b.mutate(increment)?;
+
+        // b's increment reaches the coordinator first, so b saw the right value
+        replicate(&mut b_to_coordinator, &client_b, &mut coordinator)?;
+        coordinator.step()?;
+        replicate(&mut coordinator_to_b, &coordinator, &mut client_b)?;
+        assert_eq!(client_b.rebase()?, RebaseOutcome::default());
+
+        // but a's increment is applied on top of it
+        replicate(&mut a_to_coordinator, &client_a, &mut coordinator)?;
+        coordinator.step()?;
+        replicate(&mut coordinator_to_a, &coordinator, &mut client_a)?;
+        let outcome = client_a.rebase()?;
+        assert!(outcome.diverged);
+        let counters_root: PageIdx = client_a.query(|conn| {
+            conn.query_row(
+                "SELECT rootpage FROM sqlite_master WHERE name = 'counters'",
+                [],
+                |row| row.get(0),
+            )
+        })?;
+        assert_eq!(outcome.changed_tables, vec![counters_root]);
+        let value: i64 = client_a
+            .query(|conn| conn.query_row("SELECT value FROM counters", [], |row| row.get(0)))?;
+        assert_eq!(value, 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_unacked_entries() -> anyhow::Result<()> {
+        let doc_id = JournalId::new128(&mut rand::thread_rng());
+        let mut local = open_local(doc_id)?;
+        let mut coordinator = open_coordinator(doc_id)?;
+        local.set_max_unacked_entries(Some(10));
+
+        // while disconnected, mutations pile up in the timeline until the cap
+        let mut mutations = vec![b"CREATE TABLE items (id INTEGER PRIMARY KEY)".to_vec()];
+        for id in 1..10 {
+            mutations.push(format!("INSERT INTO items VALUES ({})", id).into_bytes());
+        }
+        for mutation in &mutations {
+            local.mutate(mutation)?;
+        }
+        assert_eq!(local.unacked_entries(), 10);
+        let size: usize = mutations.iter().map(Vec::len).sum();
+        assert_eq!(local.unacked_bytes()?, size);
+        let full = |result: crate::error::Result<()>| {
+            matches!(result, Err(Error::TimelineFull { max_entries: 10 }))
+        };
+        let insert = b"INSERT INTO items VALUES (10)";
+        assert!(full(local.mutate(insert).map(drop)));
+        assert!(full(local.mutate_batch(&[insert.to_vec()]).map(drop)));
+        assert_eq!(local.unacked_entries(), 10);
+
+        // batched mutations each count as an entry, so they don't fit either
+        local.begin_batch();
+        assert!(full(local.mutate(insert).map(drop)));
+        local.commit_batch()?;
+
+        // redo adds an entry too, and keeps the mutation if it doesn't fit
+        assert!(local.undo()?);
+        local.set_max_unacked_entries(Some(9));
+        assert!(matches!(
+            local.redo(),
+            Err(Error::TimelineFull { max_entries: 9 })
+        ));
+        assert_eq!(local.unacked_entries(), 9);
+        local.set_max_unacked_entries(Some(10));
+        assert!(local.redo()?);
+        assert_eq!(local.unacked_entries(), 10);
+
+        // once the coordinator catches up, mutations are accepted again
+        replicate(&mut ReplicationProtocol::new(), &local, &mut coordinator)?;
+        coordinator.step()?;
+        replicate(&mut ReplicationProtocol::new(), &coordinator, &mut local)?;
+        local.rebase()?;
+        assert_eq!(local.unacked_entries(), 0);
+        assert_eq!(local.unacked_bytes()?, 0);
+        local.mutate(insert)?;
+        let count: i64 = local
+            .query(|conn| conn.query_row("SELECT count(*) FROM items", [], |row| row.get(0)))?;
+        assert_eq!(count, 10);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reopen_replays_timeline() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("sqlsync-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir)?;
+        let doc_id = JournalId::new128(&mut rand::thread_rng());
+        let timeline_id = JournalId::new128(&mut rand::thread_rng());
+        let open = || -> anyhow::Result<LocalDocument<FileJournal, NoopSignal, SqlReducer>> {
+            Ok(LocalDocument::open(
+                FileJournal::open(&dir, doc_id)?,
+                FileJournal::open(&dir, timeline_id)?,
+                SqlReducer,
+                DocumentSignals::default(),
+            )?)
+        };
+        let names = |local: &LocalDocument<_, _, _>| -> anyhow::Result<Vec<String>> {
+            Ok(local.query(|conn| {
+                let mut stmt = conn.prepare("SELECT name FROM people ORDER BY name")?;
+                let rows = stmt.query_map([], |row| row.get(0))?;
+                rows.collect::<rusqlite::Result<Vec<String>>>()
+            })?)
+        };
+
+        // mutations made while offline only exist in the timeline
+        let mut local = open()?;
+        local.mutate(b"CREATE TABLE people (name TEXT)")?;
+        local.mutate(b"INSERT INTO people VALUES ('alice')")?;
+        drop(local);
+
+        // and are still visible after the document is reopened
+        let mut local = open()?;
+        assert_eq!(names(&local)?, vec!["alice"]);
+        assert_eq!(local.unacked_entries(), 2);
+        local.mutate(b"INSERT INTO people VALUES ('bob')")?;
+
+        // once we reconnect, the coordinator receives all of them
+        let mut coordinator = open_coordinator(doc_id)?;
+        replicate(&mut ReplicationProtocol::new(), &local, &mut coordinator)?;
+        while coordinator.has_pending_work() {
+            coordinator.step()?;
+        }
+        replicate(&mut ReplicationProtocol::new(), &coordinator, &mut local)?;
+        local.rebase()?;
+        assert_eq!(names(&local)?, vec!["alice", "bob"]);
+        assert_eq!(local.unacked_entries(), 0);
+
+        // reopening after the coordinator caught up replays nothing
+        drop(local);
+        let local = open()?;
+        assert_eq!(names(&local)?, vec!["alice", "bob"]);
+        assert_eq!(local.unacked_entries(), 0);
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    /// TasksReducer is a SqlReducer whose migration creates a tasks table
+    struct TasksReducer;
+
+    impl Reducer for TasksReducer {
+        fn apply(
+            &mut self,
+            tx: &mut Transaction,
+            mutation: &[u8],
+        ) -> Result<ReducerOutput, ReducerError> {
+            SqlReducer.apply(tx, mutation)
+        }
+
+        fn schema_version(&self) -> u32 {
+            1
+        }
+
+        fn migrate(&mut self, tx: &mut Transaction, _: u32, _: u32) -> Result<(), ReducerError> {
+            tx.execute_batch("CREATE TABLE tasks (title TEXT)")?;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_rebase_migrates_before_replay() -> anyhow::Result<()> {
+        let doc_id = JournalId::new128(&mut rand::thread_rng());
+        let mut local = LocalDocument::open(
+            MemoryJournal::open(doc_id)?,
+            MemoryJournal::open(JournalId::new128(&mut rand::thread_rng()))?,
+            TasksReducer,
+            DocumentSignals::<NoopSignal>::default(),
+        )?;
+        local.mutate(b"INSERT INTO tasks VALUES ('write tests')")?;
+
+        // the coordinator runs an older reducer which never created tasks
+        let mut coordinator = open_coordinator(doc_id)?;
+        let mut other = open_local(doc_id)?;
+        other.mutate(b"CREATE TABLE t (x)")?;
+        replicate(&mut ReplicationProtocol::new(), &other, &mut coordinator)?;
+        while coordinator.has_pending_work() {
+            coordinator.step()?;
+        }
+
+        // rebasing onto its storage must migrate before replaying
+        replicate(&mut ReplicationProtocol::new(), &coordinator, &mut local)?;
+        local.rebase()?;
+        let count: i64 = local
+            .query(|conn| conn.query_row("SELECT COUNT(*) FROM tasks", [], |row| row.get(0)))?;
+        assert_eq!(count, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_wait_for_lsn() -> anyhow::Result<()> {
+        let doc_id = JournalId::new128(&mut rand::thread_rng());
+        let mut local = open_local(doc_id)?;
+        let mut coordinator = open_coordinator(doc_id)?;
+
+        local.mutate(b"CREATE TABLE people (name TEXT)")?;
+        local.mutate(b"INSERT INTO people VALUES ('alice')")?;
+        let lsn = local.last_mutation_lsn().unwrap();
+        let waiter = local.wait_for_lsn(lsn, 60_000)?;
+        let expired = local.wait_for_lsn(lsn, 0)?;
+        let other = local.wait_for_lsn(lsn, 60_000)?;
+        assert!(local.take_finished_waiters().is_empty());
+
+        // waiters time out
+        local.expire_waiters();
+        assert_eq!(
+            local.take_finished_waiters(),
+            vec![(expired, Err(WaitError::Timeout(lsn)))]
+        );
+
+        replicate(&mut ReplicationProtocol::new(), &local, &mut coordinator)?;
+        while coordinator.has_pending_work() {
+            coordinator.step()?;
+        }
+
+        // receiving the storage isn't enough, it must be rebased onto
+        replicate(&mut ReplicationProtocol::new(), &coordinator, &mut local)?;
+        assert!(local.take_finished_waiters().is_empty());
+        local.rebase()?;
+        assert_eq!(
+            local.take_finished_waiters(),
+            vec![(waiter, Ok(())), (other, Ok(()))]
+        );
+        assert_eq!(query_names(&local)?, vec!["alice"]);
+
+        // lsns which have already synced finish right away
+        let synced = local.wait_for_lsn(lsn, 60_000)?;
+        assert_eq!(local.take_finished_waiters(), vec![(synced, Ok(()))]);
+
+        // waiters can be cancelled
+        local.mutate(b"INSERT INTO people VALUES ('bob')")?;
+        let next = local.last_mutation_lsn().unwrap();
+        let cancelled = local.wait_for_lsn(next, 60_000)?;
+        local.cancel_waiters();
+        assert_eq!(
+            local.take_finished_waiters(),
+            vec![(cancelled, Err(WaitError::Cancelled(next)))]
+        );
+
+        // and fail if the coordinator rejects their entry
+        let rejected = local.wait_for_lsn(next, 60_000)?;
+        let reason = "no bobs".to_string();
+        local.reject(Rejection {
+            timeline_id: local.timeline.id(),
+            lsn: next,
+            reason,
+        })?;
+        assert_eq!(
+            local.take_finished_waiters(),
+            vec![(
+                rejected,
+                Err(WaitError::Rejected { lsn: next, reason: "no bobs".into() })
+            )]
+        );
+        assert!(!local.has_waiters());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_import_sqlite_file() -> anyhow::Result<()> {
+        // a legacy database created without sqlsync
+        let path = std::env::temp_dir().join(format!("sqlsync-{}.db", rand::random::<u64>()));
+        let conn = rusqlite::Connection::open(&path)?;
+        conn.pragma_update(None, "journal_mode", "wal")?;
+        conn.execute_batch(
+            "CREATE TABLE people (name TEXT);
+            INSERT INTO people VALUES ('alice'), ('bob');",
+        )?;
+        conn.close().map_err(|(_, err)| err)?;
+        let mut file = std::fs::read(&path)?;
+        std::fs::remove_file(&path)?;
+        let pages = import_sqlite_file(file.as_slice(), DEFAULT_PAGESIZE)?;
+
+        // the pages become the first frame of the coordinator's storage
+        let doc_id = JournalId::new128(&mut rand::thread_rng());
+        let mut storage = MemoryJournal::open(doc_id)?;
+        storage.append(pages)?;
+        let mut coordinator = CoordinatorDocument::open(storage, MemoryJournalFactory, SqlReducer)?;
+
+        let mut local = open_local(doc_id)?;
+        replicate(&mut ReplicationProtocol::new(), &coordinator, &mut local)?;
+        local.rebase()?;
+        assert_eq!(query_names(&local)?, vec!["alice", "bob"]);
+
+        // and the document can be mutated as usual
+        local.mutate(b"INSERT INTO people VALUES ('carol')")?;
+        replicate(&mut ReplicationProtocol::new(), &local, &mut coordinator)?;
+        coordinator.step()?;
+        replicate(&mut ReplicationProtocol::new(), &coordinator, &mut local)?;
+        local.rebase()?;
+        assert_eq!(query_names(&local)?, vec!["alice", "bob", "carol"]);
+
+        // the page size must match the document's
+        let err = import_sqlite_file(file.as_slice(), 8192).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        file[16..18].copy_from_slice(&8192u16.to_be_bytes());
+        assert!(import_sqlite_file(file.as_slice(), DEFAULT_PAGESIZE).is_err());
+        assert!(import_sqlite_file(&[0u8; 4096][..], DEFAULT_PAGESIZE).is_err());
+        file[16..18].copy_from_slice(&(DEFAULT_PAGESIZE as u16).to_be_bytes());
+        let err = import_sqlite_file(&file[..file.len() - 1], DEFAULT_PAGESIZE).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_sqlite() -> anyhow::Result<()> {
+        let doc_id = JournalId::new128(&mut rand::thread_rng());
+        let mut local = open_local(doc_id)?;
+        let mut coordinator = open_coordinator(doc_id)?;
+
+        // the export includes both synced and local mutations
+        local.mutate(b"CREATE TABLE people (name TEXT)")?;
+        local.mutate(b"INSERT INTO people VALUES ('alice')")?;
+        replicate(&mut ReplicationProtocol::new(), &local, &mut coordinator)?;
+        coordinator.step()?;
+        replicate(&mut ReplicationProtocol::new(), &coordinator, &mut local)?;
+        local.rebase()?;
+        local.mutate(b"INSERT INTO people VALUES ('bob')")?;
+
+        let mut file = Vec::new();
+        local.export_sqlite(&mut file)?;
+        assert_eq!(file.len() % DEFAULT_PAGESIZE, 0);
+
+        let path = std::env::temp_dir().join(format!("sqlsync-{}.db", rand::random::<u64>()));
+        std::fs::write(&path, &file)?;
+        let conn = rusqlite::Connection::open(&path)?;
+        let check: String = conn.query_row("PRAGMA integrity_check", [], |row| row.get(0))?;
+        assert_eq!(check, "ok");
+        let mut stmt = conn.prepare("SELECT name FROM people ORDER BY name")?;
+        let names = stmt
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        assert_eq!(names, vec!["alice", "bob"]);
+        drop(stmt);
+        conn.close().map_err(|(_, err)| err)?;
+        std::fs::remove_file(&path)?;
+
+        // the export can be imported into a new document
+        let mut storage = MemoryJournal::open(doc_id)?;
+        storage.append(import_sqlite_file(file.as_slice(), DEFAULT_PAGESIZE)?)?;
+        let imported = CoordinatorDocument::open(storage, MemoryJournalFactory, SqlReducer)?;
+        let mut copy = open_local(doc_id)?;
+        replicate(&mut ReplicationProtocol::new(), &imported, &mut copy)?;
+        copy.rebase()?;
+        assert_eq!(query_names(&copy)?, vec!["alice", "bob"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_seed_from_peer() -> anyhow::Result<()> {
+        let doc_id = JournalId::new128(&mut rand::thread_rng());
+        let mut client_a = open_local(doc_id)?;
+        let mut coordinator = open_coordinator(doc_id)?;
+
+        let mut a_to_coordinator = ReplicationProtocol::new();
+        let mut coordinator_to_a = ReplicationProtocol::new();
+
+        client_a.mutate(b"CREATE TABLE people (name TEXT)")?;
+        client_a.mutate(b"INSERT INTO people VALUES ('alice'), ('bob')")?;
+        replicate(&mut a_to_coordinator, &client_a, &mut coordinator)?;
+        coordinator.step()?;
+        replicate(&mut coordinator_to_a, &coordinator, &mut client_a)?;
+        client_a.rebase()?;
+
+        // client b seeds entirely from client a
+        let mut client_b = open_local(doc_id)?;
+        let mut a_to_b = ReplicationProtocol::new();
+        let num_frames = replicate(&mut a_to_b, client_a.storage_source(), &mut client_b)?;
+        assert!(num_frames > 0);
+        client_b.rebase()?;
+
+        assert_eq!(client_b.storage_lsn(), client_a.storage_lsn());
+        assert_eq!(query_names(&client_b)?, vec!["alice", "bob"]);
+
+        // client b can then continue from the coordinator without resending frames
+        let mut coordinator_to_b = ReplicationProtocol::new();
+        assert_eq!(
+            replicate(&mut coordinator_to_b, &coordinator, &mut client_b)?,
+            0
+        );
+
+        client_a.mutate(b"INSERT INTO people VALUES ('carol')")?;
+        replicate(&mut a_to_coordinator, &client_a, &mut coordinator)?;
+        coordinator.step()?;
+        assert_eq!(
+            replicate(&mut coordinator_to_b, &coordinator, &mut client_b)?,
+            1
+        );
+        client_b.rebase()?;
+        assert_eq!(query_names(&client_b)?, vec!["alice", "bob", "carol"]);
+
+        client_a.verify()?;
+        client_b.verify()?;
+        coordinator.verify()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_stats() -> anyhow::Result<()> {
+        let doc_id = JournalId::new128(&mut rand::thread_rng());
+        let mut local = open_local(doc_id)?;
+        let mut coordinator = open_coordinator(doc_id)?;
+
+        // local changes are pending until they are replaced by storage from
+        // the coordinator
+        let empty = local.stats()?;
+        local.mutate(b"CREATE TABLE people (name TEXT)")?;
+        local.mutate(b"INSERT INTO people VALUES ('alice')")?;
+        let stats = local.stats()?;
+        assert_eq!(stats.storage_lsn, None);
+        assert_eq!(stats.journal_frames, 0);
+        assert_eq!(stats.timeline_lsn, Some(1));
+        assert!(stats.pending_pages > empty.pending_pages);
+        assert_eq!(stats.live_pages, empty.live_pages + 1);
+
+        // the coordinator commits everything it applies
+        let before = coordinator.stats()?;
+        replicate(&mut ReplicationProtocol::new(), &local, &mut coordinator)?;
+        while coordinator.has_pending_work() {
+            coordinator.step()?;
+        }
+        let after = coordinator.stats()?;
+        assert_eq!(after.pending_pages, 0);
+        assert_eq!(after.timeline_lsn, None);
+        assert!(after.journal_frames > before.journal_frames);
+        assert!(after.storage_lsn > before.storage_lsn);
+        assert_eq!(after.live_pages, stats.live_pages);
+
+        // once rebased, the local document matches the coordinator
+        replicate(&mut ReplicationProtocol::new(), &coordinator, &mut local)?;
+        local.rebase()?;
+        assert_eq!(local.stats()?, after);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_drop_applied_timeline() -> anyhow::Result<()> {
+        let doc_id = JournalId::new128(&mut rand::thread_rng());
+        let mut local = open_local(doc_id)?;
+        let mut coordinator = open_coordinator(doc_id)?;
+
+        let mut local_to_coordinator = ReplicationProtocol::new();
+        let mut coordinator_to_local = ReplicationProtocol::new();
+
+        local.mutate(b"CREATE TABLE people (name TEXT)")?;
+        for name in ["alice", "bob", "carol", "dave"] {
+            local.mutate(format!("INSERT INTO people VALUES ('{}')", name).as_bytes())?;
+        }
+
+        // the coordinator has received but not applied the timeline
+        replicate_acked(&mut local_to_coordinator, &mut local, &mut coordinator)?;
+        assert_eq!(local.source_range(), LsnRange::new(0, 4));
+
+        while coordinator.has_pending_work() {
+            coordinator.step()?;
+        }
+
+        // the coordinator reports what it has applied in the next ack, but we
+        // keep the timeline until our storage contains the applied mutations
+        local.mutate(b"INSERT INTO people VALUES ('eve')")?;
+        replicate_acked(&mut local_to_coordinator, &mut local, &mut coordinator)?;
+        assert_eq!(local.source_range(), LsnRange::new(0, 5));
+
+        // the timeline shrinks as soon as storage catches up, before rebase
+        replicate(&mut coordinator_to_local, &coordinator, &mut local)?;
+        assert_eq!(local.source_range(), LsnRange::new(5, 5));
+
+        // the unapplied mutation is still rebased on top of the coordinator
+        local.rebase()?;
+        assert_eq!(
+            query_names(&local)?,
+            vec!["alice", "bob", "carol", "dave", "eve"]
+        );
+
+        // and continues to replicate from where it left off
+        coordinator.step()?;
+        replicate_acked(&mut local_to_coordinator, &mut local, &mut coordinator)?;
+        local.mutate(b"INSERT INTO people VALUES ('frank')")?;
+        replicate_acked(&mut local_to_coordinator, &mut local, &mut coordinator)?;
+        replicate(&mut coordinator_to_local, &coordinator, &mut local)?;
+        assert_eq!(local.source_range(), LsnRange::new(6, 6));
+
+        Ok(())
+    }
+
+    /// RowIdReducer executes each mutation as sql, returning the rowid of the
+    /// last inserted row for inserts
+    struct RowIdReducer;
+
+    impl Reducer for RowIdReducer {
+        fn apply(
+            &mut self,
+            tx: &mut Transaction,
+            mutation: &[u8],
+        ) -> Result<ReducerOutput, ReducerError> {
+            let sql =
+                std::str::from_utf8(mutation).map_err(|e| ReducerError::External(e.into()))?;
+            tx.execute_batch(sql)?;
+            Ok(sql
+                .starts_with("INSERT")
+                .then(|| tx.last_insert_rowid().to_le_bytes().to_vec()))
+        }
+    }
+
+    fn open_rowid_local(
+        doc_id: JournalId,
+    ) -> anyhow::Result<LocalDocument<MemoryJournal, NoopSignal, RowIdReducer>> {
+        Ok(LocalDocument::open(
+            MemoryJournal::open(doc_id)?,
+            MemoryJournal::open(JournalId::new128(&mut rand::thread_rng()))?,
+            RowIdReducer,
+            DocumentSignals::default(),
+        )?)
+    }
+
+    #[test]
+    fn test_mutation_outputs() -> anyhow::Result<()> {
+        let doc_id = JournalId::new128(&mut rand::thread_rng());
+        let mut local = open_rowid_local(doc_id)?;
+        let mut local2 = open_rowid_local(doc_id)?;
+        let mut coordinator = CoordinatorDocument::open(
+            MemoryJournal::open(doc_id)?,
+            MemoryJournalFactory,
+            RowIdReducer,
+        )?;
+
+        let mut local_to_coordinator = ReplicationProtocol::new();
+        let mut local2_to_coordinator = ReplicationProtocol::new();
+        let mut coordinator_to_local = ReplicationProtocol::new();
+        let mut coordinator_to_local2 = ReplicationProtocol::new();
+
+        let row_id = |output: Vec<u8>| i64::from_le_bytes(output.try_into().unwrap());
+
+        assert_eq!(local.mutate(b"CREATE TABLE people (name TEXT)")?, None);
+        replicate(&mut local_to_coordinator, &local, &mut coordinator)?;
+        coordinator.step()?;
+        replicate(&mut coordinator_to_local2, &coordinator, &mut local2)?;
+        local2.rebase()?;
+
+        // both clients optimistically insert the first row
+        let output = local.mutate(b"INSERT INTO people VALUES ('alice')")?;
+        assert_eq!(output.map(row_id), Some(1));
+        let output = local2.mutate(b"INSERT INTO people VALUES ('bob')")?;
+        assert_eq!(output.map(row_id), Some(1));
+
+        // but the coordinator applies local2's mutation first
+        replicate(&mut local2_to_coordinator, &local2, &mut coordinator)?;
+        replicate(&mut local_to_coordinator, &local, &mut coordinator)?;
+        while coordinator.has_pending_work() {
+            coordinator.step()?;
+        }
+        replicate(&mut coordinator_to_local, &coordinator, &mut local)?;
+        replicate(&mut coordinator_to_local2, &coordinator, &mut local2)?;
+        local.rebase()?;
+        local2.rebase()?;
+
+        // so each client learns the authoritative row id of its insert
+        let outputs = local.take_outputs();
+        assert_eq!(
+            outputs,
+            vec![MutationOutput {
+                lsn: 1,
+                index: 0,
+                output: 2i64.to_le_bytes().to_vec()
+            }]
+        );
+        let outputs = local2.take_outputs();
+        assert_eq!(
+            outputs,
+            vec![MutationOutput {
+                lsn: 0,
+                index: 0,
+                output: 1i64.to_le_bytes().to_vec()
+            }]
+        );
+
+        // outputs are only delivered once
+        local.rebase()?;
+        assert!(local.take_outputs().is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_prune_outputs() -> anyhow::Result<()> {
+        let doc_id = JournalId::new128(&mut rand::thread_rng());
+        let mut local = open_rowid_local(doc_id)?;
+        let mut coordinator = CoordinatorDocument::open(
+            MemoryJournal::open(doc_id)?,
+            MemoryJournalFactory,
+            RowIdReducer,
+        )?;
+        let mut local_to_coordinator = ReplicationProtocol::new();
+        let mut coordinator_to_local = ReplicationProtocol::new();
+        let count_outputs = |local: &LocalDocument<_, _, _>| {
+            local.query(|conn| {
+                conn.query_row("SELECT COUNT(*) FROM __sqlsync_outputs", [], |row| {
+                    row.get::<_, i64>(0)
+                })
+            })
+        };
+
+        local.mutate(b"CREATE TABLE people (name TEXT)")?;
+        local.mutate(b"INSERT INTO people VALUES ('alice')")?;
+        replicate(&mut local_to_coordinator, &local, &mut coordinator)?;
+        while coordinator.has_pending_work() {
+            coordinator.step()?;
+        }
+
+        // outputs the client hasn't received are kept
+        let timeline_id = local.timeline.id();
+        let received = coordinator.source_range().last().unwrap();
+        coordinator.prune_outputs(timeline_id, received - 1)?;
+        replicate(&mut coordinator_to_local, &coordinator, &mut local)?;
+        local.rebase()?;
+        assert_eq!(local.take_outputs().len(), 1);
+        assert_eq!(count_outputs(&local)?, 1);
+
+        // and pruned once it has received them
+        assert_eq!(coordinator_to_local.last_acked(), Some(received));
+        coordinator.prune_outputs(timeline_id, received)?;
+        replicate(&mut coordinator_to_local, &coordinator, &mut local)?;
+        local.rebase()?;
+        assert_eq!(count_outputs(&local)?, 0);
+        assert!(local.take_outputs().is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_mutate_batch() -> anyhow::Result<()> {
+        let doc_id = JournalId::new128(&mut rand::thread_rng());
+        let mut looped = open_local(doc_id)?;
+        let mut batched = open_local(doc_id)?;
+
+        let schema = b"CREATE TABLE people (name TEXT)";
+        looped.mutate(schema)?;
+        batched.mutate(schema)?;
+
+        let mutations: Vec<Vec<u8>> = (0..1000)
+            .map(|i| format!("INSERT INTO people VALUES ('person {}')", i).into_bytes())
+            .collect();
+
+        let start = std::time::Instant::now();
+        for mutation in &mutations {
+            looped.mutate(mutation)?;
+        }
+        let looped_elapsed = start.elapsed();
+        let start = std::time::Instant::now();
+        batched.mutate_batch(&mutations)?;
+        let batched_elapsed = start.elapsed();
+        log::info!(
+            "1000 inserts: looped {:?}, batched {:?}",
+            looped_elapsed,
+            batched_elapsed
+        );
+        assert!(batched_elapsed < looped_elapsed);
+
+        // both documents end up in the same state and timeline
+        assert_eq!(query_names(&batched)?, query_names(&looped)?);
+        assert_eq!(looped.source_range(), LsnRange::new(0, 1000));
+        assert_eq!(batched.source_range(), LsnRange::new(0, 1000));
+
+        // each mutation is seeded with the lsn it occupies, so rebasing the
+        // batch draws the same random values
+        let random = b"INSERT INTO people VALUES (random())".to_vec();
+        batched.mutate_batch(&[random.clone(), random])?;
+        let names = query_names(&batched)?;
+        batched.reapply_timeline()?;
+        assert_eq!(query_names(&batched)?, names);
+
+        // a failing mutation rolls back the whole batch
+        let result = batched.mutate_batch(&[
+            b"INSERT INTO people VALUES ('zed')".to_vec(),
+            b"INSERT INTO missing VALUES (1)".to_vec(),
+        ]);
+        assert!(result.is_err());
+        assert_eq!(query_names(&batched)?.len(), 1002);
+        assert_eq!(batched.source_range(), LsnRange::new(0, 1002));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_undo_redo() -> anyhow::Result<()> {
+        let doc_id = JournalId::new128(&mut rand::thread_rng());
+        let mut local = open_local(doc_id)?;
+        let mut coordinator = open_coordinator(doc_id)?;
+        let mut local_to_coordinator = ReplicationProtocol::new();
+
+        local.mutate(b"CREATE TABLE people (name TEXT)")?;
+        for name in ["alice", "bob", "carol"] {
+            local.mutate(format!("INSERT INTO people VALUES ('{}')", name).as_bytes())?;
+        }
+
+        assert!(local.undo()?);
+        assert!(local.undo()?);
+        assert_eq!(query_names(&local)?, vec!["alice"]);
+        assert!(local.redo()?);
+        assert_eq!(query_names(&local)?, vec!["alice", "bob"]);
+        assert_eq!(local.source_range(), LsnRange::new(0, 2));
+
+        // a new mutation clears the redo stack
+        local.mutate(b"INSERT INTO people VALUES ('dave')")?;
+        assert!(!local.redo()?);
+
+        // planning a sync doesn't send anything
+        let msg = local_to_coordinator.start(&local);
+        let resp = ReplicationProtocol::new()
+            .handle(&mut coordinator, msg, &mut io::empty())?
+            .unwrap();
+        local_to_coordinator.handle(&mut Acks, resp, &mut io::empty())?;
+        assert_eq!(local_to_coordinator.sync_plan(&local)?.frames, 4);
+        assert!(local.undo()?);
+        assert!(local.redo()?);
+
+        // sent mutations can't be undone
+        replicate(&mut local_to_coordinator, &local, &mut coordinator)?;
+        assert!(!local.undo()?);
+        assert_eq!(query_names(&local)?, vec!["alice", "bob", "dave"]);
+
+        // the coordinator sees the same timeline
+        while coordinator.has_pending_work() {
+            coordinator.step()?;
+        }
+        replicate(&mut ReplicationProtocol::new(), &coordinator, &mut local)?;
+        local.rebase()?;
+        assert_eq!(query_names(&local)?, vec!["alice", "bob", "dave"]);
+
+        Ok(())
+    }
+
+    fn root_page(doc: &TestLocal, name: &str) -> anyhow::Result<PageIdx> {
+        Ok(doc.query(|conn| {
+            conn.query_row(
+                "SELECT rootpage FROM sqlite_master WHERE name = ?",
+                [name],
+                |row| row.get(0),
+            )
+        })?)
+    }
+
+    #[test]
+    fn test_root_page_to_table() -> anyhow::Result<()> {
+        let mut local = open_local(JournalId::new128(&mut rand::thread_rng()))?;
+        local.mutate(
+            b"CREATE TABLE people (name TEXT);
+            CREATE TABLE pets (name TEXT, owner TEXT);
+            CREATE INDEX pets_owner ON pets (owner);",
+        )?;
+
+        let tables = local.root_page_to_table()?;
+        assert_eq!(tables[&root_page(&local, "people")?], "people");
+        assert_eq!(tables[&root_page(&local, "pets")?], "pets");
+        assert_eq!(tables[&root_page(&local, "pets_owner")?], "pets");
+
+        // the mapping is refreshed when the schema changes
+        local.mutate(b"CREATE TABLE toys (name TEXT)")?;
+        let toys = root_page(&local, "toys")?;
+        assert!(!tables.contains_key(&toys));
+        assert_eq!(local.root_page_to_table()?[&toys], "toys");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshot() -> anyhow::Result<()> {
+        let doc_id = JournalId::new128(&mut rand::thread_rng());
+        let mut local = open_local(doc_id)?;
+        let mut coordinator = open_coordinator(doc_id)?;
+        let mut local_to_coordinator = ReplicationProtocol::new();
+        let mut coordinator_to_local = ReplicationProtocol::new();
+
+        local.mutate(b"CREATE TABLE people (name TEXT)")?;
+        local.mutate(b"INSERT INTO people VALUES ('alice')")?;
+        let snapshot = local.snapshot()?;
+
+        // mutating and rebasing the document doesn't affect the snapshot
+        local.mutate(b"INSERT INTO people VALUES ('bob')")?;
+        replicate(&mut local_to_coordinator, &local, &mut coordinator)?;
+        while coordinator.has_pending_work() {
+            coordinator.step()?;
+        }
+        replicate(&mut coordinator_to_local, &coordinator, &mut local)?;
+        local.rebase()?;
+        local.mutate(b"INSERT INTO people VALUES ('carol')")?;
+
+        let snapshot_names = |snapshot: &crate::Snapshot| -> anyhow::Result<Vec<String>> {
+            Ok(snapshot.query(|conn| {
+                let mut stmt = conn.prepare("SELECT name FROM people ORDER BY name")?;
+                let rows = stmt.query_map([], |row| row.get(0))?;
+                rows.collect::<rusqlite::Result<_>>()
+            })?)
+        };
+        assert_eq!(snapshot_names(&snapshot)?, vec!["alice"]);
+        assert_eq!(query_names(&local)?, vec!["alice", "bob", "carol"]);
+
+        // repeated queries see the same state, and new snapshots see the latest
+        assert_eq!(snapshot_names(&snapshot)?, vec!["alice"]);
+        assert_eq!(snapshot.range(), LsnRange::empty());
+        let latest = local.snapshot()?;
+        assert_eq!(snapshot_names(&latest)?, vec!["alice", "bob", "carol"]);
+        assert!(latest.range().is_non_empty());
+
+        Ok(())
+    }
+    #[test]
+    fn test_register_functions() -> anyhow::Result<()> {
+        fn register(conn: &rusqlite::Connection) -> rusqlite::Result<()> {
+            conn.create_scalar_function(
+                "double",
+                1,
+                FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+                |ctx| Ok(ctx.get::<i64>(0)? * 2),
+            )
+        }
+        let config = OpenConfig {
+            register_functions: Some(register),
+            ..OpenConfig::default()
+        };
+
+        let doc_id = JournalId::new128(&mut rand::thread_rng());
+        let mut local = LocalDocument::open_with_config(
+            MemoryJournal::open(doc_id)?,
+            MemoryJournal::open(JournalId::new128(&mut rand::thread_rng()))?,
+            SqlReducer,
+            DocumentSignals::<NoopSignal>::default(),
+            DEFAULT_PAGESIZE,
+            &config,
+        )?;
+        let mut coordinator = CoordinatorDocument::open_with_config(
+            MemoryJournal::open(doc_id)?,
+            MemoryJournalFactory,
+            SqlReducer,
+            DEFAULT_PAGESIZE,
+            &config,
+        )?;
+
+        local.mutate(b"CREATE TABLE t (n INTEGER)")?;
+        local.mutate(b"INSERT INTO t VALUES (double(21))")?;
+
+        // the coordinator can run the reducer, and the local keeps its result
+        // after rebasing
+        replicate(&mut ReplicationProtocol::new(), &local, &mut coordinator)?;
+        while coordinator.has_pending_work() {
+            coordinator.step()?;
+        }
+        replicate(&mut ReplicationProtocol::new(), &coordinator, &mut local)?;
+        local.rebase()?;
+
+        assert!(local.storage_lsn().is_some());
+        assert!(!local.has_pending_mutations());
+
+        // queries can use the function too
+        let n: (i64, i64) = local.query(|conn| {
+            conn.query_row("SELECT n, double(n) FROM t", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+        })?;
+        assert_eq!(n, (42, 84));
+        let n: i64 = local
+            .snapshot()?
+            .query(|conn| conn.query_row("SELECT double(n) FROM t", [], |row| row.get(0)))?;
+        assert_eq!(n, 84);
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "regexp")]
+    fn test_regexp_converges() -> anyhow::Result<()> {
+        let doc_id = JournalId::new128(&mut rand::thread_rng());
+        let mut local = open_local(doc_id)?;
+        let mut local2 = open_local(doc_id)?;
+        let mut coordinator = open_coordinator(doc_id)?;
+
+        local.mutate(
+            b"CREATE TABLE people (name TEXT);
+            CREATE TABLE matches (name TEXT);
+            INSERT INTO people VALUES ('alice'), ('bob'), ('carol'), ('Anne'), (NULL);",
+        )?;
+        local
+            .mutate(b"INSERT INTO matches SELECT name FROM people WHERE name REGEXP '^[aA]|ol$'")?;
+
+        let matches = |doc: &TestLocal| -> anyhow::Result<Vec<String>> {
+            Ok(doc.query(|conn| {
+                let mut stmt = conn.prepare("SELECT name FROM matches ORDER BY name")?;
+                let rows = stmt.query_map([], |row| row.get(0))?;
+                rows.collect::<rusqlite::Result<Vec<String>>>()
+            })?)
+        };
+        let expected = vec!["Anne", "alice", "carol"];
+        assert_eq!(matches(&local)?, expected);
+
+        // the coordinator runs the same mutation, and both clients end up
+        // with its result
+        replicate(&mut ReplicationProtocol::new(), &local, &mut coordinator)?;
+        while coordinator.has_pending_work() {
+            coordinator.step()?;
+        }
+        replicate(&mut ReplicationProtocol::new(), &coordinator, &mut local)?;
+        replicate(&mut ReplicationProtocol::new(), &coordinator, &mut local2)?;
+        local.rebase()?;
+        local2.rebase()?;
+
+        assert!(!local.has_pending_mutations());
+        assert_eq!(local.storage_lsn(), local2.storage_lsn());
+        assert_eq!(matches(&local)?, expected);
+        assert_eq!(matches(&local2)?, expected);
+
+        Ok(())
     }
 }
@@ -0,0 +1,77 @@
+//! SQLSync logs each subsystem to a distinct target, allowing them to be
+//! filtered independently. For example: `RUST_LOG=sqlsync::replication=trace`
+
+pub const REDUCER: &str = "sqlsync::reducer";
+pub const REPLICATION: &str = "sqlsync::replication";
+pub const STORAGE: &str = "sqlsync::storage";
+pub const TIMELINE: &str = "sqlsync::timeline";
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use crate::{
+        replication::ReplicationProtocol,
+        test_helpers::{open_coordinator, open_local, replicate},
+        JournalId,
+    };
+
+    use super::*;
+
+    struct CapturingLogger {
+        targets: Mutex<Vec<String>>,
+    }
+
+    impl log::Log for CapturingLogger {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            // other tests may log concurrently, so tolerate a poisoned lock
+            let mut targets = self.targets.lock().unwrap_or_else(|e| e.into_inner());
+            targets.push(record.target().to_owned());
+        }
+
+        fn flush(&self) {}
+    }
+
+    static LOGGER: CapturingLogger = CapturingLogger { targets: Mutex::new(Vec::new()) };
+
+    #[test]
+    fn test_log_targets() -> anyhow::Result<()> {
+        log::set_logger(&LOGGER).unwrap();
+        log::set_max_level(log::LevelFilter::Trace);
+
+        let doc_id = JournalId::new128(&mut rand::thread_rng());
+        let mut local = open_local(doc_id)?;
+        let mut coordinator = open_coordinator(doc_id)?;
+
+        local.mutate(b"CREATE TABLE people (name TEXT)")?;
+        replicate(&mut ReplicationProtocol::new(), &local, &mut coordinator)?;
+        coordinator.step()?;
+        replicate(&mut ReplicationProtocol::new(), &coordinator, &mut local)?;
+        local.rebase()?;
+
+        let targets = LOGGER.targets.lock().unwrap();
+        for target in [REPLICATION, STORAGE, TIMELINE] {
+            assert!(
+                targets.iter().any(|t| t == target),
+                "missing target {}",
+                target
+            );
+        }
+
+        // every record logged by sqlsync should use one of the subsystem targets
+        let subsystems = [REDUCER, REPLICATION, STORAGE, TIMELINE];
+        for target in targets.iter().filter(|t| t.starts_with("sqlsync")) {
+            assert!(
+                subsystems.contains(&target.as_str()),
+                "unexpected target {}",
+                target
+            );
+        }
+
+        Ok(())
+    }
+}
@@ -3,12 +3,19 @@ use std::{
     ops::Range,
 };
 
-use serde::{Deserialize, Serialize};
+use serde::{
+    de::{self, SeqAccess, Visitor},
+    ser::SerializeTuple,
+    Deserialize, Deserializer, Serialize, Serializer,
+};
 
 /// Log Sequence Number
 pub type Lsn = u64;
 
+// human readable formats keep the derived representation (it's persisted by
+// some backends), while binary formats use the compact encoding below
 #[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(remote = "Self")]
 #[must_use]
 pub enum LsnRange {
     Empty {
@@ -65,6 +72,13 @@ impl LsnRange {
         }
     }
 
+    pub fn first(&self) -> Option<Lsn> {
+        match self {
+            LsnRange::Empty { .. } => None,
+            LsnRange::NonEmpty { first, .. } => Some(*first),
+        }
+    }
+
     pub fn last(&self) -> Option<Lsn> {
         match self {
             LsnRange::Empty { .. } => None,
@@ -160,6 +174,21 @@ impl LsnRange {
         }
     }
 
+    /// returns a new LsnRange with the last lsn removed, such that it will be
+    /// the next lsn in the range
+    pub fn trim_last(&self) -> LsnRange {
+        match self {
+            LsnRange::Empty { .. } => *self,
+            &LsnRange::NonEmpty { first, last } => {
+                if first == last {
+                    LsnRange::Empty { nextlsn: first }
+                } else {
+                    LsnRange::new(first, last - 1)
+                }
+            }
+        }
+    }
+
     /// advance_first increments first
     /// returns self if already empty
     fn advance_first(&self) -> LsnRange {
@@ -269,6 +298,35 @@ impl LsnRange {
         }
     }
 
+    /// union returns the range containing every lsn in self and other
+    /// an empty range doesn't contribute any lsns, so the union of an empty
+    /// range and a non-empty range is the non-empty range
+    /// panics if self and other are disjoint, as the union can't be
+    /// represented without including lsns which are in neither range
+    pub fn union(&self, other: &Self) -> Self {
+        match (self, other) {
+            (&LsnRange::Empty { nextlsn }, &LsnRange::Empty { nextlsn: other_nextlsn }) => {
+                LsnRange::Empty {
+                    nextlsn: std::cmp::max(nextlsn, other_nextlsn),
+                }
+            }
+            (LsnRange::Empty { .. }, _) => *other,
+            (_, LsnRange::Empty { .. }) => *self,
+            (
+                &LsnRange::NonEmpty { first, last },
+                &LsnRange::NonEmpty { first: ofirst, last: olast },
+            ) => {
+                assert!(
+                    self.intersects(other)
+                        || self.immediately_preceeds(other)
+                        || self.immediately_follows(other),
+                    "union resulted in disjointed lsnrange"
+                );
+                LsnRange::new(std::cmp::min(first, ofirst), std::cmp::max(last, olast))
+            }
+        }
+    }
+
     pub fn iter(&self) -> LsnIter {
         LsnIter { range: *self }
     }
@@ -319,10 +377,199 @@ impl DoubleEndedIterator for LsnIter {
     }
 }
 
+/// LsnSet is a set of lsns stored as sorted ranges, which allows a
+/// destination receiving frames out of order to find the lsns it's missing
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LsnSet {
+    // sorted by first lsn, every range is non-empty and no two ranges
+    // intersect or are adjacent
+    ranges: Vec<LsnRange>,
+}
+
+impl LsnSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    pub fn ranges(&self) -> &[LsnRange] {
+        &self.ranges
+    }
+
+    /// add every lsn in range to the set
+    pub fn insert(&mut self, range: LsnRange) {
+        if range.is_empty() {
+            return;
+        }
+
+        // merge every range which range overlaps or touches
+        let mut merged = range;
+        self.ranges.retain(|r| {
+            let touches = r.intersects(&range)
+                || r.immediately_preceeds(&range)
+                || r.immediately_follows(&range);
+            if touches {
+                merged = merged.union(r);
+            }
+            !touches
+        });
+
+        let idx = self.ranges.partition_point(|r| r.first() < merged.first());
+        self.ranges.insert(idx, merged);
+    }
+
+    pub fn contains(&self, lsn: Lsn) -> bool {
+        let idx = self.ranges.partition_point(|r| r.next() <= lsn);
+        self.ranges.get(idx).is_some_and(|r| r.contains(lsn))
+    }
+
+    /// remove every lsn before lsn from the set
+    pub fn remove_before(&mut self, lsn: Lsn) {
+        let Some(up_to) = lsn.checked_sub(1) else {
+            return;
+        };
+        self.ranges.retain_mut(|r| {
+            *r = r.trim_prefix(up_to);
+            !r.is_empty()
+        });
+    }
+
+    /// returns the sub-ranges of bounds which are missing from the set, in
+    /// order
+    pub fn gaps_within(&self, bounds: &LsnRange) -> Vec<LsnRange> {
+        let (first, last) = match *bounds {
+            LsnRange::Empty { .. } => return Vec::new(),
+            LsnRange::NonEmpty { first, last } => (first, last),
+        };
+
+        let mut gaps = Vec::new();
+        let mut next = first;
+        for range in &self.ranges {
+            let (rfirst, rlast) = match *range {
+                LsnRange::NonEmpty { first, last } => (first, last),
+                LsnRange::Empty { .. } => unreachable!("LsnSet only contains non-empty ranges"),
+            };
+            if rlast < next {
+                continue;
+            }
+            if rfirst > last {
+                break;
+            }
+            if rfirst > next {
+                gaps.push(LsnRange::new(next, rfirst - 1));
+            }
+            next = rlast + 1;
+        }
+        if next <= last {
+            gaps.push(LsnRange::new(next, last));
+        }
+        gaps
+    }
+}
+
+// the longest LEB128 encoding we accept, which holds 70 bits and so fits
+// an lsn along with its tag bit
+const MAX_VARINT_LEN: usize = 10;
+
+fn write_varint(buf: &mut Vec<u8>, mut v: u128) {
+    while v >= 0x80 {
+        buf.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+fn read_varint<'de, A: SeqAccess<'de>>(seq: &mut A, max: u128) -> Result<u128, A::Error> {
+    let mut v: u128 = 0;
+    for i in 0..MAX_VARINT_LEN {
+        let byte: u8 = seq
+            .next_element()?
+            .ok_or_else(|| de::Error::custom("truncated varint"))?;
+        v |= ((byte & 0x7f) as u128) << (7 * i);
+        if v > max {
+            return Err(de::Error::custom("varint is out of range"));
+        }
+        if byte & 0x80 == 0 {
+            return Ok(v);
+        }
+    }
+    Err(de::Error::custom("varint is too long"))
+}
+
+/// Binary formats encode LsnRange as one or two varints:
+///  - Empty: nextlsn << 1
+///  - NonEmpty: (first << 1) | 1, followed by last - first
+impl Serialize for LsnRange {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            return LsnRange::serialize(self, serializer);
+        }
+
+        let tagged = |lsn: Lsn, tag: u128| ((lsn as u128) << 1) | tag;
+
+        let mut buf = Vec::with_capacity(MAX_VARINT_LEN * 2);
+        match *self {
+            LsnRange::Empty { nextlsn } => write_varint(&mut buf, tagged(nextlsn, 0)),
+            LsnRange::NonEmpty { first, last } => {
+                write_varint(&mut buf, tagged(first, 1));
+                write_varint(&mut buf, (last - first) as u128);
+            }
+        }
+
+        // a tuple of bytes is written without a length prefix by bincode
+        let mut tuple = serializer.serialize_tuple(buf.len())?;
+        for byte in buf {
+            tuple.serialize_element(&byte)?;
+        }
+        tuple.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for LsnRange {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            return LsnRange::deserialize(deserializer);
+        }
+
+        struct CompactVisitor;
+
+        impl<'de> Visitor<'de> for CompactVisitor {
+            type Value = LsnRange;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a varint encoded LsnRange")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<LsnRange, A::Error> {
+                let head = read_varint(&mut seq, ((Lsn::MAX as u128) << 1) | 1)?;
+                let lsn = (head >> 1) as Lsn;
+                if head & 1 == 0 {
+                    return Ok(LsnRange::Empty { nextlsn: lsn });
+                }
+                let last = lsn
+                    .checked_add(read_varint(&mut seq, Lsn::MAX as u128)? as Lsn)
+                    .ok_or_else(|| de::Error::custom("LsnRange overflows u64"))?;
+                Ok(LsnRange::NonEmpty { first: lsn, last })
+            }
+        }
+
+        // the visitor stops reading once both varints are complete, so the
+        // tuple length is only an upper bound
+        deserializer.deserialize_tuple(MAX_VARINT_LEN * 2, CompactVisitor)
+    }
+}
+
 // write some tests for LsnRange
 #[cfg(test)]
 mod tests {
-    use super::LsnRange;
+    use std::{collections::BTreeSet, panic::AssertUnwindSafe};
+
+    use proptest::prelude::*;
+
+    use super::{Lsn, LsnRange, LsnSet, MAX_VARINT_LEN};
 
     #[test]
     #[should_panic(expected = "first must be <= last")]
@@ -452,6 +699,19 @@ mod tests {
         assert_eq!(range.trim_prefix(20), LsnRange::Empty { nextlsn: 21 });
     }
 
+    #[test]
+    fn lsnrange_trim_last() {
+        assert_eq!(LsnRange::new(5, 10).trim_last(), LsnRange::new(5, 9));
+        assert_eq!(
+            LsnRange::new(5, 5).trim_last(),
+            LsnRange::Empty { nextlsn: 5 }
+        );
+        assert_eq!(
+            LsnRange::Empty { nextlsn: 5 }.trim_last(),
+            LsnRange::Empty { nextlsn: 5 }
+        );
+    }
+
     #[test]
     #[should_panic(expected = "len must be > 0")]
     fn lsnrange_extend_invariant() {
@@ -549,6 +809,116 @@ mod tests {
         let _ = LsnRange::new(5, 10).difference(&LsnRange::new(6, 9));
     }
 
+    #[test]
+    fn lsnrange_union() {
+        macro_rules! t {
+            ($self:expr, $other:expr, $union:expr) => {
+                assert_eq!(
+                    $self.union(&$other),
+                    $union,
+                    "checking union: {:?}, {:?}",
+                    $self,
+                    $other
+                );
+            };
+        }
+
+        macro_rules! r {
+            ($first:expr, $last:expr) => {
+                LsnRange::new($first, $last)
+            };
+            (empty $nextlsn:expr) => {
+                LsnRange::Empty { nextlsn: $nextlsn }
+            };
+        }
+
+        // empty ranges preserve the other range
+        t!(r!(empty 0), r!(empty 5), r!(empty 5));
+        t!(r!(empty 20), r!(5, 10), r!(5, 10));
+        t!(r!(5, 10), r!(empty 0), r!(5, 10));
+
+        // adjacent ranges
+        t!(r!(0, 4), r!(5, 10), r!(0, 10));
+        t!(r!(5, 10), r!(0, 4), r!(0, 10));
+        t!(r!(5, 10), r!(11, 11), r!(5, 11));
+
+        // overlapping ranges
+        t!(r!(0, 5), r!(5, 10), r!(0, 10));
+        t!(r!(0, 10), r!(3, 7), r!(0, 10));
+        t!(r!(3, 7), r!(0, 10), r!(0, 10));
+        t!(r!(5, 10), r!(5, 10), r!(5, 10));
+    }
+
+    #[test]
+    #[should_panic(expected = "union resulted in disjointed lsnrange")]
+    fn lsnrange_union_disjoint_panics() {
+        let _ = LsnRange::new(0, 4).union(&LsnRange::new(6, 10));
+    }
+
+    #[test]
+    fn lsnset_gaps() {
+        let mut set = LsnSet::new();
+        assert!(set.is_empty());
+        assert_eq!(
+            set.gaps_within(&LsnRange::new(0, 10)),
+            vec![LsnRange::new(0, 10)]
+        );
+
+        // frames arrive out of order
+        set.insert(LsnRange::new(20, 25));
+        set.insert(LsnRange::new(5, 7));
+        set.insert(LsnRange::new(12, 12));
+        set.insert(LsnRange::empty());
+        assert_eq!(
+            set.ranges(),
+            &[
+                LsnRange::new(5, 7),
+                LsnRange::new(12, 12),
+                LsnRange::new(20, 25)
+            ]
+        );
+        assert!(set.contains(5) && set.contains(12) && set.contains(25));
+        assert!(!set.contains(4) && !set.contains(8) && !set.contains(26));
+
+        assert_eq!(
+            set.gaps_within(&LsnRange::new(0, 30)),
+            vec![
+                LsnRange::new(0, 4),
+                LsnRange::new(8, 11),
+                LsnRange::new(13, 19),
+                LsnRange::new(26, 30)
+            ]
+        );
+        assert_eq!(
+            set.gaps_within(&LsnRange::new(6, 21)),
+            vec![LsnRange::new(8, 11), LsnRange::new(13, 19)]
+        );
+        assert_eq!(set.gaps_within(&LsnRange::new(20, 25)), vec![]);
+        assert_eq!(set.gaps_within(&LsnRange::empty()), vec![]);
+
+        // adjacent and overlapping ranges are merged
+        set.insert(LsnRange::new(8, 11));
+        set.insert(LsnRange::new(10, 22));
+        assert_eq!(set.ranges(), &[LsnRange::new(5, 25)]);
+        assert_eq!(
+            set.gaps_within(&LsnRange::new(0, 30)),
+            vec![LsnRange::new(0, 4), LsnRange::new(26, 30)]
+        );
+
+        set.insert(LsnRange::new(30, 30));
+        set.remove_before(0);
+        assert_eq!(set.ranges(), &[LsnRange::new(5, 25), LsnRange::new(30, 30)]);
+        set.remove_before(20);
+        assert_eq!(
+            set.ranges(),
+            &[LsnRange::new(20, 25), LsnRange::new(30, 30)]
+        );
+        set.remove_before(26);
+        assert_eq!(set.ranges(), &[LsnRange::new(30, 30)]);
+        set.remove_before(31);
+        assert!(set.is_empty());
+    }
+
     #[test]
     fn lsnrange_advance_first() {
         assert_eq!(
@@ -612,4 +982,171 @@ mod tests {
         let mut iter = range.iter().rev();
         assert_eq!(iter.next(), None);
     }
+
+    fn encoded_len(range: LsnRange) -> usize {
+        bincode::serialize(&range).unwrap().len()
+    }
+
+    #[test]
+    fn lsnrange_compact_size() {
+        assert_eq!(encoded_len(LsnRange::empty()), 1);
+        assert_eq!(encoded_len(LsnRange::new(0, 0)), 2);
+        // a typical ack once a document has seen a few thousand mutations
+        assert_eq!(encoded_len(LsnRange::new(5000, 5010)), 3);
+        assert_eq!(encoded_len(LsnRange::new(Lsn::MAX >> 1, Lsn::MAX)), 20);
+        assert_eq!(encoded_len(LsnRange::new(0, Lsn::MAX)), 11);
+        assert_eq!(encoded_len(LsnRange::Empty { nextlsn: Lsn::MAX }), 10);
+
+        // values past the end of a u64 are refused
+        let mut head = vec![0xff; MAX_VARINT_LEN - 1];
+        head.push(0x04);
+        assert!(bincode::deserialize::<LsnRange>(&head).is_err());
+        let mut delta = vec![0x03, 0xff];
+        delta.extend([0xff; MAX_VARINT_LEN - 2]);
+        delta.push(0x02);
+        assert!(bincode::deserialize::<LsnRange>(&delta).is_err());
+    }
+
+    fn arb_lsnrange() -> impl Strategy<Value = LsnRange> {
+        prop_oneof![
+            any::<u64>().prop_map(|nextlsn| LsnRange::Empty { nextlsn }),
+            (any::<u64>(), any::<u64>())
+                .prop_map(|(first, len)| LsnRange::new(first, first.saturating_add(len))),
+            (any::<u64>(), 0..1000u64)
+                .prop_map(|(first, len)| LsnRange::new(first, first.saturating_add(len))),
+        ]
+    }
+
+    // small ranges which frequently overlap or are adjacent
+    fn arb_small_lsnrange() -> impl Strategy<Value = LsnRange> {
+        prop_oneof![
+            (0..150u64).prop_map(|nextlsn| LsnRange::Empty { nextlsn }),
+            (0..100u64, 0..50u64).prop_map(|(first, len)| LsnRange::new(first, first + len)),
+        ]
+    }
+
+    // the set of lsns in range, which the algebra is checked against
+    fn model(range: &LsnRange) -> BTreeSet<Lsn> {
+        range.iter().collect()
+    }
+
+    // run f, returning None if it panics
+    fn try_op<T>(f: impl FnOnce() -> T) -> Option<T> {
+        std::panic::catch_unwind(AssertUnwindSafe(f)).ok()
+    }
+
+    proptest! {
+        #[test]
+        fn lsnrange_model_props(a in arb_small_lsnrange()) {
+            let set = model(&a);
+            prop_assert_eq!(a.len(), set.len());
+            prop_assert_eq!(a.is_empty(), set.is_empty());
+            prop_assert_eq!(a.first(), set.first().copied());
+            prop_assert_eq!(a.last(), set.last().copied());
+            for lsn in 0..200 {
+                prop_assert_eq!(a.contains(lsn), set.contains(&lsn));
+            }
+            if let Some(last) = a.last() {
+                prop_assert_eq!(a.next(), last + 1);
+            }
+            prop_assert_eq!(LsnRange::empty_following(&a).next(), a.next());
+            prop_assert_eq!(
+                LsnRange::empty_preceeding(&a).next(),
+                a.first().unwrap_or(a.next())
+            );
+        }
+
+        #[test]
+        fn lsnrange_intersect_props(a in arb_small_lsnrange(), b in arb_small_lsnrange()) {
+            let result = a.intersect(&b);
+            let expected: BTreeSet<_> = model(&a).intersection(&model(&b)).copied().collect();
+            prop_assert_eq!(model(&result), expected);
+            prop_assert_eq!(a.intersects(&b), result.is_non_empty());
+            if result.is_empty() {
+                prop_assert_eq!(result.next(), a.next());
+            }
+        }
+
+        #[test]
+        fn lsnrange_difference_props(a in arb_small_lsnrange(), b in arb_small_lsnrange()) {
+            let expected: BTreeSet<_> = model(&a).difference(&model(&b)).copied().collect();
+            // the difference panics exactly when b splits a in two
+            let splits = match (a.first(), a.last(), b.first(), b.last()) {
+                (Some(first), Some(last), Some(ofirst), Some(olast)) => {
+                    first < ofirst && olast < last
+                }
+                _ => false,
+            };
+            match try_op(|| a.difference(&b)) {
+                None => prop_assert!(splits, "{} - {} panicked", a, b),
+                Some(result) => {
+                    prop_assert!(!splits, "{} - {} should panic", a, b);
+                    prop_assert_eq!(model(&result), expected);
+                    if result.is_empty() {
+                        prop_assert_eq!(result.next(), a.next());
+                    }
+                }
+            }
+        }
+
+        #[test]
+        fn lsnrange_trim_prefix_props(a in arb_small_lsnrange(), up_to in 0..200u64) {
+            let expected: BTreeSet<_> = model(&a).into_iter().filter(|&lsn| lsn > up_to).collect();
+            // an empty range can't be trimmed to before its nextlsn
+            let invalid = a.is_empty() && up_to + 1 < a.next();
+            match try_op(|| a.trim_prefix(up_to)) {
+                None => prop_assert!(invalid, "{}.trim_prefix({}) panicked", a, up_to),
+                Some(result) => {
+                    prop_assert!(!invalid, "{}.trim_prefix({}) should panic", a, up_to);
+                    prop_assert_eq!(model(&result), expected);
+                    prop_assert_eq!(result.next(), std::cmp::max(a.next(), up_to + 1));
+                }
+            }
+        }
+
+        #[test]
+        fn lsnrange_union_model_props(a in arb_small_lsnrange(), b in arb_small_lsnrange()) {
+            let disjoint = a.is_non_empty()
+                && b.is_non_empty()
+                && !a.intersects(&b)
+                && !a.immediately_preceeds(&b)
+                && !a.immediately_follows(&b);
+            match try_op(|| a.union(&b)) {
+                None => prop_assert!(disjoint, "{} | {} panicked", a, b),
+                Some(result) => {
+                    prop_assert!(!disjoint, "{} | {} should panic", a, b);
+                    let expected: BTreeSet<_> = model(&a).union(&model(&b)).copied().collect();
+                    prop_assert_eq!(model(&result), expected);
+                }
+            }
+        }
+
+        #[test]
+        fn lsnrange_union_props(a in arb_small_lsnrange(), b in arb_small_lsnrange()) {
+            let disjoint = a.is_non_empty()
+                && b.is_non_empty()
+                && !a.intersects(&b)
+                && !a.immediately_preceeds(&b)
+                && !a.immediately_follows(&b);
+            prop_assume!(!disjoint);
+
+            let union = a.union(&b);
+            prop_assert_eq!(union, b.union(&a));
+
+            // the union contains exactly the lsns in either input
+            for lsn in 0..200 {
+                prop_assert_eq!(union.contains(lsn), a.contains(lsn) || b.contains(lsn));
+            }
+        }
+
+        #[test]
+        fn lsnrange_compact_round_trip(range in arb_lsnrange()) {
+            let buf = bincode::serialize(&range).unwrap();
+            prop_assert_eq!(bincode::deserialize::<LsnRange>(&buf).unwrap(), range);
+
+            // trailing data must be left for the next field
+            let pair = bincode::serialize(&(range, 7u8)).unwrap();
+            prop_assert_eq!(bincode::deserialize::<(LsnRange, u8)>(&pair).unwrap(), (range, 7));
+        }
+    }
 }
@@ -0,0 +1,93 @@
+use std::io;
+
+use rusqlite::{named_params, Connection, OptionalExtension};
+
+// document metadata is stored in a builtin table which is updated by meta
+// mutations. meta mutations are replicated through the timeline like any
+// other mutation, but are applied by sqlsync rather than by the reducer.
+const META_TABLE_SQL: &str = "
+    CREATE TABLE IF NOT EXISTS __sqlsync_meta (
+        key TEXT PRIMARY KEY NOT NULL,
+        value TEXT NOT NULL
+    ) STRICT
+";
+
+const META_READ_SQL: &str = "
+    SELECT value
+    FROM __sqlsync_meta
+    WHERE key = :key
+";
+
+// meta conflicts resolve last-writer-wins in the order that mutations are
+// applied to storage
+const META_WRITE_SQL: &str = "
+    INSERT INTO __sqlsync_meta (key, value)
+    VALUES (:key, :value)
+    ON CONFLICT (key) DO UPDATE SET value = :value
+";
+
+/// Binary layout of a meta mutation is:
+/// META_MUTATION_TAG
+/// key_len: u32
+/// key: [u8; key_len]
+/// value: [u8; remaining]
+const META_MUTATION_TAG: &[u8] = b"\0__sqlsync_meta\0";
+const KEY_LEN_SIZE: usize = std::mem::size_of::<u32>();
+
+// keys with this prefix are managed by sqlsync, clients can't set them
+const RESERVED_KEY_PREFIX: &str = "__sqlsync";
+
+pub fn is_reserved_meta_key(key: &str) -> bool {
+    key.starts_with(RESERVED_KEY_PREFIX)
+}
+
+pub fn run_meta_migration(sqlite: &Connection) -> rusqlite::Result<()> {
+    sqlite.execute(META_TABLE_SQL, [])?;
+    Ok(())
+}
+
+pub fn encode_set_meta(key: &str, value: &str) -> Vec<u8> {
+    let mut out =
+        Vec::with_capacity(META_MUTATION_TAG.len() + KEY_LEN_SIZE + key.len() + value.len());
+    out.extend_from_slice(META_MUTATION_TAG);
+    out.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    out.extend_from_slice(key.as_bytes());
+    out.extend_from_slice(value.as_bytes());
+    out
+}
+
+/// decode a meta mutation, returns None if the mutation should be passed to the reducer
+pub fn decode_set_meta(mutation: &[u8]) -> io::Result<Option<(&str, &str)>> {
+    let body = match mutation.strip_prefix(META_MUTATION_TAG) {
+        Some(body) => body,
+        None => return Ok(None),
+    };
+
+    let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+
+    if body.len() < KEY_LEN_SIZE {
+        return Err(invalid("meta mutation is missing the key length"));
+    }
+    let (key_len, body) = body.split_at(KEY_LEN_SIZE);
+    let key_len = u32::from_le_bytes(key_len.try_into().unwrap()) as usize;
+
+    if body.len() < key_len {
+        return Err(invalid("meta mutation key is truncated"));
+    }
+    let (key, value) = body.split_at(key_len);
+
+    let key = std::str::from_utf8(key).map_err(|_| invalid("meta key must be utf-8"))?;
+    let value = std::str::from_utf8(value).map_err(|_| invalid("meta value must be utf-8"))?;
+    Ok(Some((key, value)))
+}
+
+pub fn set_meta(sqlite: &Connection, key: &str, value: &str) -> rusqlite::Result<()> {
+    sqlite.execute(META_WRITE_SQL, named_params! {":key": key, ":value": value})?;
+    Ok(())
+}
+
+pub fn get_meta(sqlite: &Connection, key: &str) -> rusqlite::Result<Option<String>> {
+    sqlite
+        .query_row(META_READ_SQL, named_params! {":key": key}, |row| row.get(0))
+        .optional()
+}
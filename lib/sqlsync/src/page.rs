@@ -1,29 +1,106 @@
 use std::{
     collections::BTreeMap,
+    fmt::Debug,
+    hash::Hash,
     io::{self, Write},
     mem::size_of,
 };
 
+use xxhash_rust::xxh3::xxh3_128;
+
 use crate::{positioned_io::PositionedReader, Serializable};
 
 // TODO: profile both bandwidth usage and general perf for different page sizes on various workloads
 // TODO: research OPFS block sizes and whether we should use that as a guide for page size
-pub const PAGESIZE: usize = 4096;
+pub const DEFAULT_PAGESIZE: usize = 4096;
+
+/// returns true if SQLite supports databases with the given page size
+pub fn is_valid_page_size(page_size: usize) -> bool {
+    page_size.is_power_of_two() && (512..=65536).contains(&page_size)
+}
 
 /// PageIdx is the 1-based index of a page in a SQLite database file
 pub type PageIdx = u32;
 const PAGE_IDX_SIZE: usize = size_of::<PageIdx>();
 
-pub type Page = [u8; PAGESIZE];
+pub type Page = Box<[u8]>;
+
+/// PageHasher computes a digest of a page's contents, which is the
+/// foundation for addressing pages by content rather than by index
+pub trait PageHasher {
+    type Hash: AsRef<[u8]> + Eq + Hash + Clone + Debug;
+
+    fn hash(&self, page: &Page) -> Self::Hash;
+}
+
+/// NoHasher is used by SparsePages which don't hash their pages
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoHasher;
+
+impl PageHasher for NoHasher {
+    type Hash = [u8; 0];
 
-#[derive(Default, Debug, Clone)]
-pub struct SparsePages {
+    fn hash(&self, _page: &Page) -> Self::Hash {
+        []
+    }
+}
+
+/// Xxh3Hasher is a fast non-cryptographic PageHasher, suitable for
+/// detecting changed pages but not for trusting pages from a peer
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Xxh3Hasher;
+
+impl PageHasher for Xxh3Hasher {
+    type Hash = [u8; 16];
+
+    fn hash(&self, page: &Page) -> Self::Hash {
+        xxh3_128(page).to_le_bytes()
+    }
+}
+
+/// Blake3Hasher is a cryptographic PageHasher
+#[cfg(feature = "blake3")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Blake3Hasher;
+
+#[cfg(feature = "blake3")]
+impl PageHasher for Blake3Hasher {
+    type Hash = [u8; 32];
+
+    fn hash(&self, page: &Page) -> Self::Hash {
+        blake3::hash(page).into()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SparsePages<H: PageHasher = NoHasher> {
+    page_size: usize,
     pages: BTreeMap<PageIdx, Page>,
+    hasher: H,
+    // hashes computed by page_hash, removed when their page is written
+    hashes: BTreeMap<PageIdx, H::Hash>,
 }
 
 impl SparsePages {
-    pub fn new() -> SparsePages {
-        Self { pages: BTreeMap::new() }
+    pub fn new(page_size: usize) -> SparsePages {
+        Self::with_hasher(page_size, NoHasher)
+    }
+}
+
+impl<H: PageHasher> SparsePages<H> {
+    /// create an empty set of pages which can hash its pages with hasher,
+    /// see page_hash
+    pub fn with_hasher(page_size: usize, hasher: H) -> Self {
+        Self {
+            page_size,
+            pages: BTreeMap::new(),
+            hasher,
+            hashes: BTreeMap::new(),
+        }
+    }
+
+    pub fn page_size(&self) -> usize {
+        self.page_size
     }
 
     pub fn num_pages(&self) -> usize {
@@ -32,10 +109,24 @@ impl SparsePages {
 
     pub fn clear(&mut self) {
         self.pages.clear();
+        self.hashes.clear();
     }
 
     pub fn write(&mut self, page_idx: PageIdx, page: Page) {
+        assert_eq!(page.len(), self.page_size, "page has the wrong size");
         self.pages.insert(page_idx, page);
+        self.hashes.remove(&page_idx);
+    }
+
+    /// returns the hash of a page, which is cached until the page is
+    /// written again
+    pub fn page_hash(&mut self, page_idx: PageIdx) -> Option<H::Hash> {
+        let page = self.pages.get(&page_idx)?;
+        let hash = self
+            .hashes
+            .entry(page_idx)
+            .or_insert_with(|| self.hasher.hash(page));
+        Some(hash.clone())
     }
 
     pub fn page_idxs(&self) -> impl Iterator<Item = &PageIdx> {
@@ -52,7 +143,7 @@ impl SparsePages {
             .get(&page_idx)
             .map(|page| {
                 let end = page_offset + buf.len();
-                assert!(end <= PAGESIZE, "page offset out of bounds");
+                assert!(end <= self.page_size, "page offset out of bounds");
                 buf.copy_from_slice(&page[page_offset..end]);
                 buf.len()
             })
@@ -61,7 +152,7 @@ impl SparsePages {
 }
 
 /// The serialized form of SparsePages can be read using the SerializedPagesReader object below
-impl Serializable for SparsePages {
+impl<H: PageHasher> Serializable for SparsePages<H> {
     fn serialize_into<W: Write>(&self, writer: &mut W) -> io::Result<()> {
         assert!(
             !self.pages.is_empty(),
@@ -80,6 +171,10 @@ impl Serializable for SparsePages {
 
         Ok(())
     }
+
+    fn serialized_len(&self) -> Option<usize> {
+        Some(self.pages.len() * (PAGE_IDX_SIZE + self.page_size))
+    }
 }
 
 /// Binary layout of Serialized Page objects is:
@@ -87,21 +182,33 @@ impl Serializable for SparsePages {
 ///   page_idx: u32
 /// ]
 /// for each page (sorted by page_idx desc) [
-///   page: [u8; PAGESIZE]
+///   page: [u8; page_size]
 /// ]
-pub struct SerializedPagesReader<R: PositionedReader>(pub R);
+/// the page size is not serialized, so readers must be told what it is
+pub struct SerializedPagesReader<R: PositionedReader> {
+    reader: R,
+    page_size: usize,
+}
 
 impl<R: PositionedReader> SerializedPagesReader<R> {
+    pub fn new(reader: R, page_size: usize) -> Self {
+        Self { reader, page_size }
+    }
+
     pub fn num_pages(&self) -> io::Result<usize> {
-        let file_size = self.0.size()?;
-        let num_pages = file_size / (PAGE_IDX_SIZE + PAGESIZE);
+        let file_size = self.reader.size()?;
+        let num_pages = file_size / (PAGE_IDX_SIZE + self.page_size);
         Ok(num_pages)
     }
 
-    pub fn max_page_idx(&self) -> io::Result<PageIdx> {
+    /// returns None if this object contains no pages
+    pub fn max_page_idx(&self) -> io::Result<Option<PageIdx>> {
+        if self.num_pages()? == 0 {
+            return Ok(None);
+        }
         let mut buf = [0; PAGE_IDX_SIZE];
-        self.0.read_exact_at(0, &mut buf)?;
-        Ok(PageIdx::from_le_bytes(buf))
+        self.reader.read_exact_at(0, &mut buf)?;
+        Ok(Some(PageIdx::from_le_bytes(buf)))
     }
 
     // returns a list of page indexes contained by this serialized pages object
@@ -109,7 +216,7 @@ impl<R: PositionedReader> SerializedPagesReader<R> {
     pub fn page_idxs(&self) -> io::Result<Vec<PageIdx>> {
         let num_pages = self.num_pages()?;
         let mut buf = vec![0u8; PAGE_IDX_SIZE * num_pages];
-        self.0.read_exact_at(0, &mut buf)?;
+        self.reader.read_exact_at(0, &mut buf)?;
 
         Ok(buf
             .chunks_exact(PAGE_IDX_SIZE)
@@ -117,6 +224,29 @@ impl<R: PositionedReader> SerializedPagesReader<R> {
             .collect())
     }
 
+    /// check that this object is a well formed set of serialized pages
+    pub fn validate(&self) -> io::Result<()> {
+        let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+
+        // empty frames are written in place of lsns which were coalesced
+        // into a later frame, see ReplicationSource::read_coalesced
+        let file_size = self.reader.size()?;
+        if file_size % (PAGE_IDX_SIZE + self.page_size) != 0 {
+            return Err(invalid("serialized pages contain a partial page"));
+        }
+
+        // page indexes are 1-based and must be unique and sorted desc
+        let page_idxs = self.page_idxs()?;
+        if page_idxs.last() == Some(&0) {
+            return Err(invalid("serialized pages contain page index 0"));
+        }
+        if page_idxs.windows(2).any(|pair| pair[0] <= pair[1]) {
+            return Err(invalid("serialized page indexes are not sorted desc"));
+        }
+
+        Ok(())
+    }
+
     // binary searches for the page at the given page_idx, returning the offset
     // of the page in this file
     fn find_page_start(&self, page_idx: PageIdx) -> io::Result<Option<usize>> {
@@ -128,13 +258,13 @@ impl<R: PositionedReader> SerializedPagesReader<R> {
         while left < right {
             let mid = left + (right - left) / 2;
             let mid_offset = mid * PAGE_IDX_SIZE;
-            self.0.read_exact_at(mid_offset, &mut page_idx_buf)?;
+            self.reader.read_exact_at(mid_offset, &mut page_idx_buf)?;
 
             let mid_idx = PageIdx::from_le_bytes(page_idx_buf);
 
             match mid_idx.cmp(&page_idx) {
                 std::cmp::Ordering::Equal => {
-                    let page_offset = (num_pages * PAGE_IDX_SIZE) + (mid * PAGESIZE);
+                    let page_offset = (num_pages * PAGE_IDX_SIZE) + (mid * self.page_size);
                     return Ok(Some(page_offset));
                 }
                 std::cmp::Ordering::Less => {
@@ -152,18 +282,63 @@ impl<R: PositionedReader> SerializedPagesReader<R> {
     }
 
     pub fn read(&self, page_idx: PageIdx, page_offset: usize, buf: &mut [u8]) -> io::Result<usize> {
-        assert!(page_offset < PAGESIZE, "page_offset must be < PAGESIZE");
         assert!(
-            page_offset + buf.len() <= PAGESIZE,
+            page_offset < self.page_size,
+            "page_offset must be < page_size"
+        );
+        assert!(
+            page_offset + buf.len() <= self.page_size,
             "refusing to read more than one page"
         );
 
         if let Some(page_start) = self.find_page_start(page_idx)? {
             let read_start = page_start + page_offset;
-            self.0.read_exact_at(read_start, buf)?;
+            self.reader.read_exact_at(read_start, buf)?;
             Ok(buf.len())
         } else {
             Ok(0)
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check_hasher<H: PageHasher>(hasher: H) -> io::Result<()> {
+        let page_size = 512;
+        let mut pages = SparsePages::with_hasher(page_size, hasher);
+        pages.write(1, vec![1; page_size].into());
+        pages.write(2, vec![1; page_size].into());
+        pages.write(3, vec![2; page_size].into());
+
+        // identical pages hash equal
+        let hash = pages.page_hash(1).unwrap();
+        assert_eq!(pages.page_hash(2).unwrap(), hash);
+        assert_ne!(pages.page_hash(3).unwrap(), hash);
+        assert_eq!(pages.page_hash(4), None);
+
+        // writing a page invalidates its cached hash
+        pages.write(2, vec![2; page_size].into());
+        assert_eq!(pages.page_hash(2), pages.page_hash(3));
+
+        // hashes are stable across serialization
+        let mut data = Vec::new();
+        pages.serialize_into(&mut data)?;
+        let reader = SerializedPagesReader::new(data.as_slice(), page_size);
+        for page_idx in reader.page_idxs()? {
+            let mut page: Page = vec![0; page_size].into();
+            reader.read(page_idx, 0, &mut page)?;
+            assert_eq!(pages.hasher.hash(&page), pages.page_hash(page_idx).unwrap());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_page_hashers() -> io::Result<()> {
+        check_hasher(Xxh3Hasher)?;
+        #[cfg(feature = "blake3")]
+        check_hasher(Blake3Hasher)?;
+        Ok(())
+    }
+}
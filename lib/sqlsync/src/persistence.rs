@@ -0,0 +1,161 @@
+use std::fmt;
+
+use crate::{replication::ReplicationError, Lsn, LsnRange};
+
+/// FramePersistence durably stores a coordinator's storage frames, so that
+/// its storage can be rebuilt with replication::replay_frames after a
+/// restart. implement it to run the coordinator on top of any durable store
+///
+/// futures returned by this trait are not required to be Send, as
+/// coordinators usually run on a single threaded runtime
+#[allow(async_fn_in_trait)]
+pub trait FramePersistence {
+    type Error: fmt::Debug + fmt::Display;
+
+    /// the range of lsns which have been persisted
+    fn range(&self) -> LsnRange;
+
+    /// the next lsn that should be written
+    fn expected_lsn(&self) -> Lsn {
+        self.range().next()
+    }
+
+    /// durably write the frame at lsn, which must be the expected lsn.
+    /// writing any other lsn must fail without changing the range
+    async fn write_lsn(&mut self, lsn: Lsn, frame: Vec<u8>) -> Result<(), Self::Error>;
+
+    /// read every persisted frame in range, in order. lsns in range which
+    /// have not been persisted are skipped
+    async fn read_range(&self, range: LsnRange) -> Result<Vec<(Lsn, Vec<u8>)>, Self::Error>;
+}
+
+/// MemoryPersistence keeps frames in memory, it's useful for tests and as a
+/// reference implementation of FramePersistence
+#[derive(Debug)]
+pub struct MemoryPersistence {
+    range: LsnRange,
+    frames: Vec<Vec<u8>>,
+}
+
+impl MemoryPersistence {
+    pub fn new() -> Self {
+        Self {
+            range: LsnRange::empty(),
+            frames: Vec::new(),
+        }
+    }
+}
+
+impl Default for MemoryPersistence {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FramePersistence for MemoryPersistence {
+    type Error = ReplicationError;
+
+    fn range(&self) -> LsnRange {
+        self.range
+    }
+
+    async fn write_lsn(&mut self, lsn: Lsn, frame: Vec<u8>) -> Result<(), Self::Error> {
+        let expected = self.expected_lsn();
+        if lsn != expected {
+            return Err(ReplicationError::NonContiguousLsn {
+                received: lsn,
+                range: LsnRange::new(expected, expected),
+            });
+        }
+        self.range = self.range.append(lsn);
+        self.frames.push(frame);
+        Ok(())
+    }
+
+    async fn read_range(&self, range: LsnRange) -> Result<Vec<(Lsn, Vec<u8>)>, Self::Error> {
+        Ok(self
+            .range
+            .intersect(&range)
+            .iter()
+            .map(|lsn| {
+                let offset = self.range.offset(lsn).expect("lsn is in range");
+                (lsn, self.frames[offset].clone())
+            })
+            .collect())
+    }
+}
+
+/// check that persistence behaves as FramePersistence requires, panicking
+/// if it doesn't. persistence must be empty, and is left containing a few
+/// frames. run this from the tests of each FramePersistence implementation
+pub async fn check_conformance<P: FramePersistence>(persistence: &mut P) {
+    assert!(
+        persistence.range().is_empty(),
+        "persistence must start empty"
+    );
+    let first = persistence.expected_lsn();
+    assert_eq!(
+        persistence
+            .read_range(LsnRange::new(first, first + 10))
+            .await
+            .unwrap(),
+        vec![],
+        "empty persistence returned frames"
+    );
+
+    let frames: Vec<Vec<u8>> = vec![b"first".to_vec(), vec![], vec![7; 4096]];
+    for (i, frame) in frames.iter().enumerate() {
+        let lsn = first + i as Lsn;
+        assert_eq!(persistence.expected_lsn(), lsn);
+        persistence.write_lsn(lsn, frame.clone()).await.unwrap();
+        assert_eq!(persistence.range(), LsnRange::new(first, lsn));
+    }
+    let last = persistence.range().last().unwrap();
+
+    // lsns other than the expected lsn are rejected
+    for lsn in [first, last, last + 2] {
+        assert!(
+            persistence.write_lsn(lsn, b"bad".to_vec()).await.is_err(),
+            "write to lsn {} should fail",
+            lsn
+        );
+        assert_eq!(persistence.range(), LsnRange::new(first, last));
+    }
+
+    let expected: Vec<_> = frames
+        .into_iter()
+        .enumerate()
+        .map(|(i, frame)| (first + i as Lsn, frame))
+        .collect();
+    assert_eq!(
+        persistence.read_range(persistence.range()).await.unwrap(),
+        expected
+    );
+    assert_eq!(
+        persistence
+            .read_range(LsnRange::new(first + 1, first + 1))
+            .await
+            .unwrap(),
+        expected[1..2]
+    );
+    assert_eq!(
+        persistence
+            .read_range(LsnRange::new(last, last + 10))
+            .await
+            .unwrap(),
+        expected[2..],
+        "read_range should skip lsns which haven't been persisted"
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+
+    #[test]
+    fn test_memory_persistence_conformance() {
+        block_on(check_conformance(&mut MemoryPersistence::new()));
+    }
+}
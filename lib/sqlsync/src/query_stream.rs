@@ -0,0 +1,155 @@
+use std::{convert, fmt::Debug};
+
+use rusqlite::{params_from_iter, Row, ToSql};
+
+use crate::snapshot::Snapshot;
+
+/// QueryStream reads the results of a query in chunks, allowing the caller
+/// to yield between chunks rather than materializing a large result set in
+/// one go. The query runs against a snapshot, so every chunk reflects the
+/// same state of the document.
+///
+/// Each chunk reruns the statement and skips the rows returned by earlier
+/// chunks, so no cursor is held open between chunks. As the snapshot
+/// doesn't change, the query must be deterministic (i.e. not use random())
+/// for the chunks to line up.
+pub struct QueryStream<P: ToSql> {
+    snapshot: Snapshot,
+    sql: String,
+    params: Vec<P>,
+    chunk_rows: usize,
+    // the number of rows returned by earlier chunks
+    offset: usize,
+    done: bool,
+}
+
+impl<P: ToSql> Debug for QueryStream<P> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("QueryStream")
+            .field("chunk_rows", &self.chunk_rows)
+            .field("offset", &self.offset)
+            .field("done", &self.done)
+            .finish()
+    }
+}
+
+impl<P: ToSql> QueryStream<P> {
+    pub fn new(snapshot: Snapshot, sql: String, params: Vec<P>, chunk_rows: usize) -> Self {
+        Self {
+            snapshot,
+            sql,
+            params,
+            chunk_rows: chunk_rows.max(1),
+            offset: 0,
+            done: false,
+        }
+    }
+
+    /// true once the last chunk has been returned
+    #[inline]
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    /// returns the columns and up to chunk_rows rows, along with whether
+    /// this is the last chunk
+    pub fn next_chunk<T, E, F>(&mut self, mut f: F) -> Result<(Vec<String>, Vec<T>, bool), E>
+    where
+        E: convert::From<rusqlite::Error>,
+        F: FnMut(&[String], &Row<'_>) -> Result<T, E>,
+    {
+        if self.done {
+            return Ok((vec![], vec![], true));
+        }
+
+        let mut stmt = self.snapshot.connection().prepare_cached(&self.sql)?;
+        let columns: Vec<String> = stmt.column_names().iter().map(|&s| s.to_owned()).collect();
+        let mut rows = stmt.query(params_from_iter(&self.params))?;
+
+        // skip the rows returned by earlier chunks
+        for _ in 0..self.offset {
+            if rows.next()?.is_none() {
+                break;
+            }
+        }
+
+        let mut out = Vec::with_capacity(self.chunk_rows);
+        while out.len() < self.chunk_rows {
+            match rows.next()? {
+                Some(row) => out.push(f(&columns, row)?),
+                None => break,
+            }
+        }
+        self.offset += out.len();
+
+        // peek at the next row so the last chunk is marked as done, rather
+        // than following it with an empty chunk
+        self.done = out.len() < self.chunk_rows || rows.next()?.is_none();
+        Ok((columns, out, self.done))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{test_helpers::open_local, JournalId};
+
+    use super::*;
+
+    #[test]
+    fn test_query_stream() -> anyhow::Result<()> {
+        let mut local = open_local(JournalId::new128(&mut rand::thread_rng()))?;
+        local.mutate(
+            b"CREATE TABLE nums (n INTEGER);
+            WITH RECURSIVE seq(n) AS (SELECT 0 UNION ALL SELECT n + 1 FROM seq WHERE n < 9999)
+            INSERT INTO nums SELECT n FROM seq;",
+        )?;
+
+        let mut stream = QueryStream::new(
+            local.snapshot()?,
+            "SELECT n FROM nums WHERE n >= ? ORDER BY n".into(),
+            vec![0],
+            3000,
+        );
+
+        // later changes don't affect the stream
+        local.mutate(b"DELETE FROM nums")?;
+
+        let mut chunks = vec![];
+        while !stream.is_done() {
+            let (columns, rows, done) =
+                stream.next_chunk(|_, row| row.get::<_, i64>(0).map_err(anyhow::Error::from))?;
+            assert_eq!(columns, vec!["n"]);
+            chunks.push((rows, done));
+        }
+
+        let sizes: Vec<_> = chunks.iter().map(|(rows, _)| rows.len()).collect();
+        assert_eq!(sizes, vec![3000, 3000, 3000, 1000]);
+        let done: Vec<_> = chunks.iter().map(|(_, done)| *done).collect();
+        assert_eq!(done, vec![false, false, false, true]);
+
+        let all: Vec<i64> = chunks.into_iter().flat_map(|(rows, _)| rows).collect();
+        assert_eq!(all, (0..10000).collect::<Vec<_>>());
+
+        // a stream can be dropped part way through, and errors surface from
+        // the first chunk
+        let mut partial = QueryStream::new(
+            local.snapshot()?,
+            "SELECT 1 UNION SELECT 2".into(),
+            Vec::<i64>::new(),
+            1,
+        );
+        let (_, rows, done) = partial.next_chunk(|_, row| row.get::<_, i64>(0))?;
+        assert_eq!((rows, done), (vec![1], false));
+        drop(partial);
+
+        let mut invalid = QueryStream::new(
+            local.snapshot()?,
+            "SELECT * FROM missing".into(),
+            Vec::<i64>::new(),
+            1,
+        );
+        assert!(invalid.next_chunk(|_, row| row.get::<_, i64>(0)).is_err());
+
+        Ok(())
+    }
+}
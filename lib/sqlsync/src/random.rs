@@ -0,0 +1,117 @@
+use std::sync::{Arc, Mutex};
+
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use rusqlite::{functions::FunctionFlags, named_params, Connection};
+
+use crate::{JournalId, Lsn};
+
+const SEED_RANDOMNESS_SQL: &str = "SELECT __sqlsync_seed_randomness(:id, :lsn)";
+
+/// replaces random() and randomblob() on a connection used by reducers with
+/// versions that draw from a prng seeded by seed_randomness, which allows
+/// every replica to compute identical "random" values for a mutation
+///
+/// sqlite's builtin prng is shared by the whole process and seeded once from
+/// the default vfs, so overriding StorageVfs::randomness is not enough
+pub fn register_deterministic_randomness(conn: &Connection) -> rusqlite::Result<()> {
+    // until the first mutation is applied (i.e. during migrations) we use a
+    // fixed seed
+    let rng = Arc::new(Mutex::new(ChaCha20Rng::from_seed([0; 32])));
+
+    let seed_rng = rng.clone();
+    conn.create_scalar_function(
+        "__sqlsync_seed_randomness",
+        2,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DIRECTONLY,
+        move |ctx| {
+            let id: JournalId = ctx.get(0)?;
+            let lsn: Lsn = ctx.get(1)?;
+            *seed_rng.lock().unwrap() = rng_for(id, lsn);
+            Ok(None::<i64>)
+        },
+    )?;
+
+    let random_rng = rng.clone();
+    conn.create_scalar_function("random", 0, FunctionFlags::SQLITE_UTF8, move |_| {
+        Ok(random_rng.lock().unwrap().gen::<i64>())
+    })?;
+
+    conn.create_scalar_function("randomblob", 1, FunctionFlags::SQLITE_UTF8, move |ctx| {
+        // matches sqlite, which returns a single byte if n is less than 1
+        let len = ctx.get::<i64>(0)?.max(1) as usize;
+        let mut blob = vec![0u8; len];
+        rng.lock().unwrap().fill(blob.as_mut_slice());
+        Ok(blob)
+    })
+}
+
+/// reseed random() and randomblob() before applying the timeline entry at
+/// lsn in journal id, mutations in a batch entry share a seed
+pub fn seed_randomness(conn: &Connection, id: JournalId, lsn: Lsn) -> rusqlite::Result<()> {
+    conn.query_row(
+        SEED_RANDOMNESS_SQL,
+        named_params! {":id": id, ":lsn": lsn},
+        |_| Ok(()),
+    )
+}
+
+fn rng_for(id: JournalId, lsn: Lsn) -> ChaCha20Rng {
+    // the journal id is the key, and the lsn selects the stream
+    let mut seed = [0u8; 32];
+    seed[..id.bytes().len()].copy_from_slice(id.bytes());
+    let mut rng = ChaCha20Rng::from_seed(seed);
+    rng.set_stream(lsn);
+    rng
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        replication::ReplicationProtocol,
+        test_helpers::{open_coordinator, open_local, replicate, TestLocal},
+    };
+
+    use super::*;
+
+    fn read_rows(doc: &TestLocal) -> anyhow::Result<Vec<(i64, Vec<u8>)>> {
+        Ok(doc.query(|conn| {
+            let mut stmt = conn.prepare("SELECT n, b FROM r ORDER BY rowid")?;
+            let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+            rows.collect::<rusqlite::Result<Vec<_>>>()
+        })?)
+    }
+
+    #[test]
+    fn test_random_converges() -> anyhow::Result<()> {
+        let doc_id = JournalId::new128(&mut rand::thread_rng());
+        let mut local = open_local(doc_id)?;
+        let mut local2 = open_local(doc_id)?;
+        let mut coordinator = open_coordinator(doc_id)?;
+
+        local.mutate(b"CREATE TABLE r (n INTEGER, b BLOB)")?;
+        local.mutate(
+            b"INSERT INTO r VALUES (random(), randomblob(16));
+            INSERT INTO r VALUES (random(), randomblob(0));",
+        )?;
+        local.mutate_batch(&[b"INSERT INTO r VALUES (random(), randomblob(16))".to_vec()])?;
+        let optimistic = read_rows(&local)?;
+        assert_eq!(optimistic.len(), 3);
+        assert_eq!(optimistic[1].1.len(), 1);
+        assert_ne!(optimistic[0], optimistic[2]);
+
+        // the coordinator computes the same values when it applies the
+        // mutations, which local2 receives and local keeps after rebasing
+        replicate(&mut ReplicationProtocol::new(), &local, &mut coordinator)?;
+        while coordinator.has_pending_work() {
+            coordinator.step()?;
+        }
+        for doc in [&mut local, &mut local2] {
+            replicate(&mut ReplicationProtocol::new(), &coordinator, doc)?;
+            doc.rebase()?;
+            assert_eq!(read_rows(doc)?, optimistic);
+        }
+
+        Ok(())
+    }
+}
@@ -1,9 +1,17 @@
-use std::convert;
+use std::{
+    collections::{BTreeMap, HashSet},
+    convert,
+};
 
 use rusqlite::{params_from_iter, Connection, Row, ToSql};
+use thiserror::Error;
 
 use crate::{iter::has_sorted_intersection, PageIdx, StorageChange};
 
+// the maximum number of storage changes an erroring query will skip before
+// it's retried
+const MAX_ERROR_BACKOFF: u32 = 64;
+
 #[derive(Debug)]
 enum State {
     // The query is pending refresh
@@ -13,9 +21,9 @@ enum State {
     // The query is monitoring for changes to the root pages
     Monitoring { root_pages_sorted: Vec<PageIdx> },
 
-    // The query failed last time it was run, we will only rerun the query if
-    // the storage changes
-    Error,
+    // The query failed last time it was run, we will only rerun the query
+    // once the storage has changed more than skip_changes times
+    Error { skip_changes: u32 },
 }
 
 #[derive(Debug)]
@@ -24,6 +32,7 @@ pub struct ReactiveQuery<P: ToSql> {
     explain_sql: String,
     params: Vec<P>,
     state: State,
+    consecutive_errors: u32,
 }
 
 impl<P: ToSql> ReactiveQuery<P> {
@@ -34,6 +43,7 @@ impl<P: ToSql> ReactiveQuery<P> {
             explain_sql,
             params,
             state: State::Dirty,
+            consecutive_errors: 0,
         }
     }
 
@@ -52,8 +62,19 @@ impl<P: ToSql> ReactiveQuery<P> {
                         self.state = State::Dirty;
                     }
                 }
+                StorageChange::Schema {
+                    ref dropped_root_pages,
+                    root_pages_sorted: ref changed_root_pages,
+                } => {
+                    if has_sorted_intersection(root_pages, dropped_root_pages)
+                        || has_sorted_intersection(root_pages, changed_root_pages)
+                    {
+                        self.state = State::Dirty;
+                    }
+                }
             },
-            State::Error => self.state = State::Dirty,
+            State::Error { skip_changes: 0 } => self.state = State::Dirty,
+            State::Error { ref mut skip_changes } => *skip_changes -= 1,
         }
         self.is_dirty()
     }
@@ -68,9 +89,21 @@ impl<P: ToSql> ReactiveQuery<P> {
         self.state = State::Dirty;
     }
 
+    /// mark_error backs off the query, each consecutive error doubles the
+    /// number of storage changes which must occur before the query is retried
     #[inline]
     pub fn mark_error(&mut self) {
-        self.state = State::Error;
+        self.consecutive_errors = self.consecutive_errors.saturating_add(1);
+        let skip_changes = 1u32
+            .checked_shl(self.consecutive_errors - 1)
+            .map_or(MAX_ERROR_BACKOFF, |n| (n - 1).min(MAX_ERROR_BACKOFF));
+        self.state = State::Error { skip_changes };
+    }
+
+    /// the number of times this query has failed since it last succeeded
+    #[inline]
+    pub fn consecutive_errors(&self) -> u32 {
+        self.consecutive_errors
     }
 
     pub fn refresh<T, E, F>(
@@ -93,30 +126,425 @@ impl<P: ToSql> ReactiveQuery<P> {
             out.push(mapped);
         }
 
+        self.consecutive_errors = 0;
         Ok((columns, out))
     }
 
-    fn refresh_state(&mut self, conn: &Connection) -> rusqlite::Result<()> {
+    /// returns the root pages of every table and index this query reads.
+    /// sqlite inlines views and subqueries when compiling a query, so they
+    /// are expanded to the tables they read from. returns InvalidQuery if the
+    /// query isn't read only.
+    pub fn dependencies(&self, conn: &Connection) -> rusqlite::Result<HashSet<PageIdx>> {
+        if !conn.prepare_cached(&self.sql)?.readonly() {
+            return Err(rusqlite::Error::InvalidQuery);
+        }
+
         let mut explain = conn.prepare_cached(&self.explain_sql)?;
         let mut rows = explain.query(params_from_iter(&self.params))?;
 
-        let mut root_pages_sorted = Vec::new();
+        let mut root_pages = HashSet::new();
         while let Some(row) = rows.next()? {
             // explain rows have the schema:
             // addr, opcode, p1, p2, p3, p4, p5, comment
             // to find root pages, we need to find the OpenRead opcodes
             // and then look at the p2 column which contains the root page id
+            // p3 is the database, only the main database (0) is stored
             let opcode: String = row.get(1)?;
-            if opcode == "OpenRead" {
-                let root_page: PageIdx = row.get(3)?;
-                root_pages_sorted.push(root_page);
+            let db: i64 = row.get(4)?;
+            if opcode == "OpenRead" && db == 0 {
+                root_pages.insert(row.get(3)?);
             }
         }
+        Ok(root_pages)
+    }
 
+    fn refresh_state(&mut self, conn: &Connection) -> rusqlite::Result<()> {
+        let mut root_pages_sorted: Vec<_> = self.dependencies(conn)?.into_iter().collect();
         root_pages_sorted.sort();
-        root_pages_sorted.dedup();
 
         self.state = State::Monitoring { root_pages_sorted };
         Ok(())
     }
 }
+
+/// RowDelta is the difference between two results of a keyed query
+#[derive(Debug, Clone, PartialEq)]
+pub struct RowDelta<K, R> {
+    /// rows which were added or changed, in the order the query returned them
+    pub upserts: Vec<R>,
+    /// the keys of rows which are no longer in the result
+    pub deletes: Vec<K>,
+    /// the key of every row in the order the query returned them, None if
+    /// the remaining rows are in the same order as before. rows are added or
+    /// moved by ORDER BY, so deltas which do either include it
+    pub order: Option<Vec<K>>,
+}
+
+impl<K, R> RowDelta<K, R> {
+    pub fn is_empty(&self) -> bool {
+        self.upserts.is_empty() && self.deletes.is_empty() && self.order.is_none()
+    }
+}
+
+/// KeyedRows remembers the last result of a query by each row's key, so that
+/// later results can be sent as a RowDelta rather than in full. the key must
+/// be unique and stable, such as the primary key of the table being queried
+#[derive(Debug)]
+pub struct KeyedRows<K, R> {
+    rows: BTreeMap<K, R>,
+    order: Vec<K>,
+}
+
+impl<K, R> Default for KeyedRows<K, R> {
+    fn default() -> Self {
+        Self { rows: BTreeMap::new(), order: Vec::new() }
+    }
+}
+
+impl<K: Ord + Clone, R: PartialEq + Clone> KeyedRows<K, R> {
+    /// forget the remembered rows, the next diff will upsert every row
+    pub fn clear(&mut self) {
+        self.rows.clear();
+        self.order.clear();
+    }
+
+    /// remember rows as the latest result, returning how it differs from the
+    /// previous one
+    pub fn diff(&mut self, rows: impl IntoIterator<Item = (K, R)>) -> RowDelta<K, R> {
+        let mut previous = std::mem::take(&mut self.rows);
+        let previous_order = std::mem::take(&mut self.order);
+        let mut upserts = Vec::new();
+        for (key, row) in rows {
+            if previous.remove(&key).as_ref() != Some(&row) {
+                upserts.push(row.clone());
+            }
+            self.order.push(key.clone());
+            self.rows.insert(key, row);
+        }
+
+        // the order is unchanged if the rows which remain are in the same
+        // order as before, and no rows were added
+        let remaining = previous_order
+            .iter()
+            .filter(|key| !previous.contains_key(key));
+        let order = (!remaining.eq(self.order.iter())).then(|| self.order.clone());
+
+        RowDelta {
+            upserts,
+            deletes: previous.into_keys().collect(),
+            order,
+        }
+    }
+}
+
+/// KeyedResult is how a new result of a keyed query should be sent to
+/// subscribers, see KeyedQuery::update
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeyedResult<V> {
+    /// send every row, subscribers don't have a result to apply a delta to
+    Full,
+    /// send the rows which changed
+    Delta(RowDelta<V, Vec<V>>),
+    /// nothing changed
+    Unchanged,
+}
+
+#[derive(Debug, Error)]
+#[error("key column {0} is not in the query result")]
+pub struct MissingKeyColumn(pub String);
+
+/// KeyedQuery tracks the results of a query subscribed to with a key column,
+/// so that subscribers which have received a full result are sent deltas
+#[derive(Debug)]
+pub struct KeyedQuery<V> {
+    key_column: String,
+    // None until subscribers have received a full result
+    columns: Option<Vec<String>>,
+    rows: KeyedRows<V, Vec<V>>,
+}
+
+impl<V: Ord + Clone> KeyedQuery<V> {
+    pub fn new(key_column: String) -> Self {
+        Self {
+            key_column,
+            columns: None,
+            rows: KeyedRows::default(),
+        }
+    }
+
+    /// the next result is sent in full, e.g. for a new subscriber
+    pub fn reset(&mut self) {
+        self.columns = None;
+    }
+
+    /// remember a new result, returning how to send it to subscribers
+    pub fn update(
+        &mut self,
+        columns: &[String],
+        rows: &[Vec<V>],
+    ) -> Result<KeyedResult<V>, MissingKeyColumn> {
+        let idx = columns
+            .iter()
+            .position(|c| c == &self.key_column)
+            .ok_or_else(|| MissingKeyColumn(self.key_column.clone()))?;
+        let keyed_rows = rows.iter().map(|row| (row[idx].clone(), row.clone()));
+
+        if self.columns.as_deref() != Some(columns) {
+            // new subscribers, or the columns changed
+            self.rows.clear();
+            self.rows.diff(keyed_rows);
+            self.columns = Some(columns.to_vec());
+            return Ok(KeyedResult::Full);
+        }
+
+        let delta = self.rows.diff(keyed_rows);
+        if delta.is_empty() {
+            Ok(KeyedResult::Unchanged)
+        } else {
+            Ok(KeyedResult::Delta(delta))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn refresh(query: &mut ReactiveQuery<i64>, conn: &Connection) -> rusqlite::Result<Vec<i64>> {
+        query
+            .refresh(conn, |_, row| row.get(0))
+            .map(|(_, rows)| rows)
+    }
+
+    // returns the number of storage changes needed before the query is dirty
+    fn changes_until_dirty(query: &mut ReactiveQuery<i64>) -> usize {
+        let mut changes = 1;
+        while !query.handle_storage_change(&StorageChange::Full) {
+            changes += 1;
+        }
+        changes
+    }
+
+    #[test]
+    fn test_error_backoff() {
+        let conn = Connection::open_in_memory().unwrap();
+        let mut query = ReactiveQuery::new("SELECT n FROM numbers".into(), vec![]);
+
+        // every consecutive error doubles the backoff
+        for expected_changes in [1, 2, 4, 8] {
+            assert!(query.is_dirty());
+            assert!(refresh(&mut query, &conn).is_err());
+            query.mark_error();
+            assert!(!query.is_dirty());
+            assert_eq!(changes_until_dirty(&mut query), expected_changes);
+        }
+        assert_eq!(query.consecutive_errors(), 4);
+
+        // once the query succeeds, the backoff resets
+        conn.execute_batch("CREATE TABLE numbers (n); INSERT INTO numbers VALUES (1), (2);")
+            .unwrap();
+        assert_eq!(refresh(&mut query, &conn).unwrap(), vec![1, 2]);
+        assert_eq!(query.consecutive_errors(), 0);
+
+        assert!(query.handle_storage_change(&StorageChange::Full));
+        conn.execute("DROP TABLE numbers", []).unwrap();
+        assert!(refresh(&mut query, &conn).is_err());
+        query.mark_error();
+        assert_eq!(query.consecutive_errors(), 1);
+        assert_eq!(changes_until_dirty(&mut query), 1);
+    }
+
+    #[test]
+    fn test_dependencies() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE a (n); CREATE TABLE b (n);
+            CREATE VIEW a_view AS SELECT n FROM a WHERE n > 0;
+            INSERT INTO a VALUES (1); INSERT INTO b VALUES (2);",
+        )
+        .unwrap();
+        let root_page = |name: &str| -> PageIdx {
+            conn.query_row(
+                "SELECT rootpage FROM sqlite_master WHERE name = ?",
+                [name],
+                |row| row.get(0),
+            )
+            .unwrap()
+        };
+        let (a, b) = (root_page("a"), root_page("b"));
+
+        let mut over_a = ReactiveQuery::new("SELECT n FROM a".into(), vec![]);
+        let mut over_view = ReactiveQuery::new("SELECT n FROM a_view".into(), vec![]);
+        let mut over_b = ReactiveQuery::new(
+            "SELECT n FROM b WHERE n NOT IN (SELECT n FROM a)".into(),
+            vec![],
+        );
+        let mut unrelated = ReactiveQuery::new("SELECT n FROM b".into(), vec![]);
+
+        assert_eq!(over_a.dependencies(&conn).unwrap(), HashSet::from([a]));
+        assert_eq!(over_view.dependencies(&conn).unwrap(), HashSet::from([a]));
+        assert_eq!(over_b.dependencies(&conn).unwrap(), HashSet::from([a, b]));
+        assert_eq!(unrelated.dependencies(&conn).unwrap(), HashSet::from([b]));
+
+        for query in [&mut over_a, &mut over_view, &mut over_b, &mut unrelated] {
+            refresh(query, &conn).unwrap();
+        }
+
+        // a change to a refreshes the queries which read it
+        let change = StorageChange::Tables { root_pages_sorted: vec![a] };
+        assert!(over_a.handle_storage_change(&change));
+        assert!(over_view.handle_storage_change(&change));
+        assert!(over_b.handle_storage_change(&change));
+        assert!(!unrelated.handle_storage_change(&change));
+
+        let write: ReactiveQuery<i64> = ReactiveQuery::new("DELETE FROM a".into(), vec![]);
+        assert_eq!(
+            write.dependencies(&conn).unwrap_err(),
+            rusqlite::Error::InvalidQuery
+        );
+    }
+
+    #[test]
+    fn test_keyed_rows() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE tasks (id INTEGER PRIMARY KEY, description TEXT, completed BOOLEAN);
+            INSERT INTO tasks VALUES (1, 'a', false), (2, 'b', false), (3, 'c', false);",
+        )
+        .unwrap();
+
+        let mut query: ReactiveQuery<i64> = ReactiveQuery::new(
+            "SELECT id, description, completed FROM tasks ORDER BY id".into(),
+            vec![],
+        );
+        let mut keyed = KeyedRows::default();
+        let mut refresh = |query: &mut ReactiveQuery<i64>| {
+            let (_, rows) = query
+                .refresh(&conn, |_, row| {
+                    let row: (i64, String, bool) = (row.get(0)?, row.get(1)?, row.get(2)?);
+                    Ok::<_, rusqlite::Error>((row.0, row))
+                })
+                .unwrap();
+            keyed.diff(rows)
+        };
+
+        // the first result upserts every row
+        assert_eq!(refresh(&mut query).upserts.len(), 3);
+
+        // toggling a task only upserts that task
+        conn.execute("UPDATE tasks SET completed = true WHERE id = 2", [])
+            .unwrap();
+        assert_eq!(
+            refresh(&mut query),
+            RowDelta {
+                upserts: vec![(2, "b".into(), true)],
+                deletes: vec![],
+                order: None,
+            }
+        );
+
+        // an unchanged result is empty
+        assert!(refresh(&mut query).is_empty());
+
+        conn.execute_batch(
+            "DELETE FROM tasks WHERE id = 1; INSERT INTO tasks VALUES (4, 'd', false);",
+        )
+        .unwrap();
+        assert_eq!(
+            refresh(&mut query),
+            RowDelta {
+                upserts: vec![(4, "d".into(), false)],
+                deletes: vec![1],
+                order: Some(vec![2, 3, 4]),
+            }
+        );
+    }
+
+    #[test]
+    fn test_keyed_query() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE tasks (id BLOB PRIMARY KEY, description TEXT, position INTEGER);
+            INSERT INTO tasks VALUES (x'01', 'a', 10), (x'02', 'b', 20), (x'03', 'c', 30);",
+        )
+        .unwrap();
+
+        let columns = vec!["id".to_owned(), "description".to_owned()];
+        let row = |id: u8, description: &str| vec![vec![id], description.as_bytes().to_vec()];
+        let mut keyed = KeyedQuery::new("id".into());
+        let update = |keyed: &mut KeyedQuery<Vec<u8>>| {
+            let mut stmt = conn
+                .prepare("SELECT id, description FROM tasks ORDER BY position")
+                .unwrap();
+            let rows = stmt
+                .query_map([], |row| {
+                    Ok(vec![row.get(0)?, row.get::<_, String>(1)?.into_bytes()])
+                })
+                .unwrap()
+                .collect::<rusqlite::Result<Vec<_>>>()
+                .unwrap();
+            keyed.update(&columns, &rows).unwrap()
+        };
+
+        assert_eq!(update(&mut keyed), KeyedResult::Full);
+        assert_eq!(update(&mut keyed), KeyedResult::Unchanged);
+
+        // a row added in the middle of the result includes the order
+        conn.execute("INSERT INTO tasks VALUES (x'04', 'd', 15)", [])
+            .unwrap();
+        assert_eq!(
+            update(&mut keyed),
+            KeyedResult::Delta(RowDelta {
+                upserts: vec![row(4, "d")],
+                deletes: vec![],
+                order: Some(vec![vec![1], vec![4], vec![2], vec![3]]),
+            })
+        );
+
+        // as do rows which moved without changing
+        conn.execute("UPDATE tasks SET position = 0 WHERE id = x'03'", [])
+            .unwrap();
+        assert_eq!(
+            update(&mut keyed),
+            KeyedResult::Delta(RowDelta {
+                upserts: vec![],
+                deletes: vec![],
+                order: Some(vec![vec![3], vec![1], vec![4], vec![2]]),
+            })
+        );
+
+        // while deletes and changes in place don't
+        conn.execute_batch(
+            "DELETE FROM tasks WHERE id = x'01';
+            UPDATE tasks SET description = 'B' WHERE id = x'02';",
+        )
+        .unwrap();
+        assert_eq!(
+            update(&mut keyed),
+            KeyedResult::Delta(RowDelta {
+                upserts: vec![row(2, "B")],
+                deletes: vec![vec![1]],
+                order: None,
+            })
+        );
+
+        // new subscribers need the full result
+        keyed.reset();
+        assert_eq!(update(&mut keyed), KeyedResult::Full);
+
+        let mut missing = KeyedQuery::<Vec<u8>>::new("missing".into());
+        assert!(missing.update(&columns, &[]).is_err());
+    }
+
+    #[test]
+    fn test_error_backoff_is_capped() {
+        let mut query: ReactiveQuery<i64> = ReactiveQuery::new("SELECT 1".into(), vec![]);
+        for _ in 0..100 {
+            query.mark_error();
+        }
+        assert_eq!(
+            changes_until_dirty(&mut query),
+            MAX_ERROR_BACKOFF as usize + 1
+        );
+    }
+}
@@ -1,18 +1,28 @@
-use std::collections::BTreeMap;
+use std::{collections::BTreeMap, sync::Arc};
 
 use rusqlite::{
     params_from_iter,
-    types::{Value, ValueRef},
-    Transaction,
+    types::{ToSql, Value, ValueRef},
+    Batch, Transaction,
 };
 use sqlsync_reducer::{
     host_ffi::{register_log_handler, WasmFFI, WasmFFIError},
-    types::{ErrorResponse, ExecResponse, QueryResponse, Request, Row, SqliteValue},
+    types::{
+        ErrorResponse, ExecResponse, QueryResponse, Request, Requests, Row, SqliteError,
+        SqliteValue,
+    },
 };
+
+pub use sqlsync_reducer::mutation::{MutationCodec, MutationError};
+pub use sqlsync_reducer::types::{MutationContext, PanicRecord};
 use thiserror::Error;
-use wasmi::{errors::LinkerError, Engine, Linker, Module, Store};
+pub use wasmi::Module as WasmModule;
+use wasmi::{
+    core::TrapCode, errors::LinkerError, Config, Engine, Linker, Store, StoreLimits,
+    StoreLimitsBuilder,
+};
 
-use crate::unixtime::unix_timestamp_milliseconds;
+use crate::{logging, meta::decode_set_meta, unixtime::unix_timestamp_milliseconds};
 
 #[derive(Error, Debug)]
 pub enum ReducerError {
@@ -30,75 +40,438 @@ pub enum ReducerError {
 
     #[error(transparent)]
     External(Box<dyn std::error::Error + Send + Sync>),
+
+    #[error("reducer ran out of fuel")]
+    FuelExhausted,
+
+    #[error("reducer did not finish before its deadline")]
+    Timeout,
+
+    #[error("reducer tried to grow its memory beyond the limit")]
+    MemoryExhausted,
+
+    #[error("reducer panicked: {0}")]
+    Panic(PanicRecord),
+
+    #[error("document schema is at version {stored}, but the reducer only supports up to version {supported}")]
+    SchemaTooNew { stored: u32, supported: u32 },
+
+    #[error("mutation doesn't match the reducer's {codec:?} codec: {source}")]
+    InvalidMutation {
+        codec: MutationCodec,
+        source: MutationError,
+    },
+}
+
+impl ReducerError {
+    /// returns the trap code if this error was caused by the reducer trapping
+    fn trap_code(&self) -> Option<TrapCode> {
+        let err = match self {
+            ReducerError::Runtime(err) => err,
+            ReducerError::Interface(WasmFFIError::WasmError(err)) => err,
+            _ => return None,
+        };
+        match err {
+            wasmi::Error::Trap(trap) => trap.trap_code(),
+            _ => None,
+        }
+    }
 }
 
 pub type Result<T> = std::result::Result<T, ReducerError>;
 type SqlResult<T> = std::result::Result<T, ErrorResponse>;
 
+/// reducers may return an output for each mutation, such as the id of an
+/// inserted row
+pub type ReducerOutput = Option<Vec<u8>>;
+
+/// reducers must be deterministic, random() and randomblob() are safe to use
+/// as they are seeded per mutation but other sources of entropy are not
 pub trait Reducer {
-    fn apply(&mut self, tx: &mut Transaction, mutation: &[u8]) -> Result<()>;
+    fn apply(&mut self, tx: &mut Transaction, mutation: &[u8]) -> Result<ReducerOutput>;
+
+    /// like apply, but fails with ReducerError::Timeout if the mutation is
+    /// still running once the deadline (in unix milliseconds) has passed
+    /// reducers which can't be interrupted may ignore the deadline
+    fn apply_with_deadline(
+        &mut self,
+        tx: &mut Transaction,
+        mutation: &[u8],
+        deadline: Option<i64>,
+    ) -> Result<ReducerOutput> {
+        let _ = deadline;
+        self.apply(tx, mutation)
+    }
+
+    /// like apply_with_deadline, but also receives the context the mutation
+    /// is applied in, such as the timeline which authored it. reducers which
+    /// don't need the context may ignore it
+    fn apply_with_context(
+        &mut self,
+        tx: &mut Transaction,
+        context: &MutationContext,
+        mutation: &[u8],
+        deadline: Option<i64>,
+    ) -> Result<ReducerOutput> {
+        let _ = context;
+        self.apply_with_deadline(tx, mutation, deadline)
+    }
+
+    /// the schema version this reducer expects, reducers which don't version
+    /// their schema are at version 0
+    fn schema_version(&self) -> u32 {
+        0
+    }
+
+    /// the encoding this reducer expects mutations to use, if it declared
+    /// one. documents reject mutations which don't match it before applying
+    /// them, see validate_mutation
+    fn mutation_codec(&self) -> Option<MutationCodec> {
+        None
+    }
+
+    /// upgrade the document's schema from one version to another, this is
+    /// called when a document is opened by a reducer with a newer version
+    fn migrate(&mut self, tx: &mut Transaction, from_version: u32, to_version: u32) -> Result<()> {
+        let _ = (tx, from_version, to_version);
+        Ok(())
+    }
+}
+
+/// check that mutation matches a reducer's codec, if it declared one. meta
+/// mutations are applied by sqlsync rather than the reducer, so they are
+/// exempt
+pub fn validate_mutation(codec: Option<MutationCodec>, mutation: &[u8]) -> Result<()> {
+    if matches!(decode_set_meta(mutation), Ok(Some(_))) {
+        return Ok(());
+    }
+    match codec {
+        Some(codec) => codec
+            .validate(mutation)
+            .map_err(|source| ReducerError::InvalidMutation { codec, source }),
+        None => Ok(()),
+    }
 }
 
 impl Reducer for WasmReducer {
-    fn apply(&mut self, tx: &mut Transaction, mutation: &[u8]) -> Result<()> {
+    fn apply(&mut self, tx: &mut Transaction, mutation: &[u8]) -> Result<ReducerOutput> {
         WasmReducer::apply(self, tx, mutation)
     }
+
+    fn apply_with_deadline(
+        &mut self,
+        tx: &mut Transaction,
+        mutation: &[u8],
+        deadline: Option<i64>,
+    ) -> Result<ReducerOutput> {
+        WasmReducer::apply_with_deadline(self, tx, mutation, deadline)
+    }
+
+    fn apply_with_context(
+        &mut self,
+        tx: &mut Transaction,
+        context: &MutationContext,
+        mutation: &[u8],
+        deadline: Option<i64>,
+    ) -> Result<ReducerOutput> {
+        WasmReducer::apply_with_context(self, tx, context, mutation, deadline)
+    }
+
+    fn schema_version(&self) -> u32 {
+        self.schema_version
+    }
+
+    fn mutation_codec(&self) -> Option<MutationCodec> {
+        self.mutation_codec
+    }
+
+    fn migrate(&mut self, tx: &mut Transaction, from_version: u32, to_version: u32) -> Result<()> {
+        let result = self.migrate_inner(tx, from_version, to_version);
+        self.recover(result)
+    }
 }
 
 pub struct WasmReducer {
-    store: Store<WasmFFI>,
+    store: Store<HostState>,
+    module: Arc<WasmModule>,
+    config: WasmReducerConfig,
+    schema_version: u32,
+    mutation_codec: Option<MutationCodec>,
+}
+
+const WASM_PAGE_SIZE: usize = 64 * 1024;
+
+/// WasmReducerConfig tunes how a WasmReducer runs its guest
+#[derive(Debug, Clone, Copy)]
+pub struct WasmReducerConfig {
+    /// if set each mutation may only consume this much fuel across every
+    /// reactor step, after which it fails with ReducerError::FuelExhausted
+    pub fuel_per_mutation: Option<u64>,
+    /// log records from the guest above this level are dropped, e.g. the
+    /// coordinator can run reducers at Warn while development runs at Debug
+    pub max_log_level: log::LevelFilter,
+    /// if set the reducer's linear memory may not grow beyond this many 64KiB
+    /// pages, past that the mutation fails with ReducerError::MemoryExhausted
+    pub max_memory_pages: Option<u32>,
+}
+
+/// the data stored alongside the reducer's wasm instance
+struct HostState {
+    ffi: WasmFFI,
+    limits: StoreLimits,
+}
+
+impl AsRef<WasmFFI> for HostState {
+    fn as_ref(&self) -> &WasmFFI {
+        &self.ffi
+    }
+}
+
+impl Default for WasmReducerConfig {
+    fn default() -> Self {
+        Self {
+            fuel_per_mutation: None,
+            max_log_level: log::LevelFilter::Trace,
+            max_memory_pages: None,
+        }
+    }
 }
 
 impl WasmReducer {
     pub fn new(wasm_bytes: impl std::io::Read) -> Result<Self> {
-        let engine = Engine::default();
-        let module = Module::new(&engine, wasm_bytes)?;
+        Self::with_config(wasm_bytes, WasmReducerConfig::default())
+    }
+
+    /// like new, but if fuel_per_mutation is set each mutation may only
+    /// consume that much fuel, after which it fails with
+    /// ReducerError::FuelExhausted
+    pub fn with_fuel(
+        wasm_bytes: impl std::io::Read,
+        fuel_per_mutation: Option<u64>,
+    ) -> Result<Self> {
+        Self::with_config(
+            wasm_bytes,
+            WasmReducerConfig { fuel_per_mutation, ..Default::default() },
+        )
+    }
+
+    pub fn with_config(wasm_bytes: impl std::io::Read, config: WasmReducerConfig) -> Result<Self> {
+        Self::from_module_with_config(Self::compile(wasm_bytes, &config)?, config)
+    }
+
+    /// compile a reducer once so it can be shared by every document which
+    /// uses it, see from_module. compiling is much more expensive than
+    /// instantiating, and only needs to happen once per reducer
+    ///
+    /// fuel metering is decided when compiling, so config should set
+    /// fuel_per_mutation if any of the reducers sharing the module do
+    pub fn compile(
+        wasm_bytes: impl std::io::Read,
+        config: &WasmReducerConfig,
+    ) -> Result<Arc<WasmModule>> {
+        let mut wasm_config = Config::default();
+        wasm_config.consume_fuel(config.fuel_per_mutation.is_some());
+        let engine = Engine::new(&wasm_config);
+        Ok(Arc::new(WasmModule::new(&engine, wasm_bytes)?))
+    }
+
+    /// create a reducer from a module compiled by compile
+    pub fn from_module(module: Arc<WasmModule>) -> Result<Self> {
+        Self::from_module_with_config(module, WasmReducerConfig::default())
+    }
+
+    pub fn from_module_with_config(
+        module: Arc<WasmModule>,
+        config: WasmReducerConfig,
+    ) -> Result<Self> {
+        let mut store = Self::instantiate(&module, &config)?;
+
+        let ffi = store.data().ffi;
+        refuel(&mut store, config.fuel_per_mutation)?;
+        let schema_version = ffi.schema_version(&mut store)?;
+        let mutation_codec = ffi.mutation_codec(&mut store)?;
+
+        Ok(Self {
+            store,
+            module,
+            config,
+            schema_version,
+            mutation_codec,
+        })
+    }
+
+    fn instantiate(module: &WasmModule, config: &WasmReducerConfig) -> Result<Store<HostState>> {
+        let engine = module.engine();
+        let mut linker = Linker::new(engine);
+        register_log_handler(&mut linker, config.max_log_level)?;
 
-        let mut linker = Linker::new(&engine);
-        register_log_handler(&mut linker)?;
+        // trap rather than failing the grow, so the reducer can't mistake the
+        // limit for a recoverable allocation failure
+        let mut limits = StoreLimitsBuilder::new().trap_on_grow_failure(true);
+        if let Some(pages) = config.max_memory_pages {
+            limits = limits.memory_size(pages as usize * WASM_PAGE_SIZE);
+        }
+        let state = HostState {
+            ffi: WasmFFI::uninitialized(),
+            limits: limits.build(),
+        };
+        let mut store = Store::new(engine, state);
+        store.limiter(|state| &mut state.limits);
 
-        let mut store = Store::new(&engine, WasmFFI::uninitialized());
-        let instance = linker.instantiate(&mut store, &module)?.start(&mut store)?;
+        // a module compiled with fuel metering may be shared with reducers
+        // that don't limit fuel, give them as much as they could need
+        if config.fuel_per_mutation.is_none() && store.consume_fuel(0).is_ok() {
+            store.add_fuel(u64::MAX).map_err(wasmi::Error::from)?;
+        }
+        refuel(&mut store, config.fuel_per_mutation)?;
+        let instance = linker.instantiate(&mut store, module)?.start(&mut store)?;
 
         // initialize the FFI
-        let ffi = WasmFFI::initialized(&store, &instance)?;
-        (*store.data_mut()) = ffi;
+        let mut ffi = WasmFFI::initialized(&store, &instance)?;
+        store.data_mut().ffi = ffi;
 
-        // initialize the reducer
+        // initialize the reducer, which reports the abi version it speaks
+        refuel(&mut store, config.fuel_per_mutation)?;
         ffi.init_reducer(&mut store)?;
+        store.data_mut().ffi = ffi;
+
+        Ok(store)
+    }
+
+    pub fn apply(&mut self, tx: &mut Transaction, mutation: &[u8]) -> Result<ReducerOutput> {
+        self.apply_with_deadline(tx, mutation, None)
+    }
 
-        Ok(Self { store })
+    /// the deadline is checked between reactor steps, so a reducer which
+    /// issues many slow queries can't block the caller indefinitely
+    pub fn apply_with_deadline(
+        &mut self,
+        tx: &mut Transaction,
+        mutation: &[u8],
+        deadline: Option<i64>,
+    ) -> Result<ReducerOutput> {
+        self.apply_with_context(tx, &MutationContext::default(), mutation, deadline)
     }
 
-    pub fn apply(&mut self, tx: &mut Transaction, mutation: &[u8]) -> Result<()> {
-        let ffi = self.store.data().to_owned();
+    /// the context is passed to reducers which call init_reducer!(fn, context)
+    pub fn apply_with_context(
+        &mut self,
+        tx: &mut Transaction,
+        context: &MutationContext,
+        mutation: &[u8],
+        deadline: Option<i64>,
+    ) -> Result<ReducerOutput> {
+        let result = self.apply_inner(tx, context, mutation, deadline);
+        self.recover(result)
+    }
+
+    fn recover<T>(&mut self, result: Result<T>) -> Result<T> {
+        let err = match result {
+            Err(err) if matches!(err.trap_code(), Some(TrapCode::OutOfFuel)) => {
+                ReducerError::FuelExhausted
+            }
+            Err(err) if matches!(err.trap_code(), Some(TrapCode::GrowthOperationLimited)) => {
+                ReducerError::MemoryExhausted
+            }
+            Err(ReducerError::Timeout) => ReducerError::Timeout,
+            // rust reducers abort on panic, which traps as unreachable
+            Err(err) if matches!(err.trap_code(), Some(TrapCode::UnreachableCodeReached)) => {
+                match self.last_panic() {
+                    Some(panic) => ReducerError::Panic(panic),
+                    None => err,
+                }
+            }
+            result => return result,
+        };
+
+        // the reducer was interrupted part way through the mutation, so its
+        // memory can't be trusted; start over with a fresh instance and let
+        // the caller roll back the transaction
+        self.store = Self::instantiate(&self.module, &self.config)?;
+        Err(err)
+    }
+
+    /// ask the trapped reducer why it panicked, any failure is ignored as the
+    /// original trap is reported instead
+    fn last_panic(&mut self) -> Option<PanicRecord> {
+        let ffi = self.store.data().ffi;
+        refuel(&mut self.store, self.config.fuel_per_mutation).ok()?;
+        ffi.last_panic(&mut self.store).ok().flatten()
+    }
+
+    fn apply_inner(
+        &mut self,
+        tx: &mut Transaction,
+        context: &MutationContext,
+        mutation: &[u8],
+        deadline: Option<i64>,
+    ) -> Result<ReducerOutput> {
+        let ffi = self.store.data().ffi;
 
         // start the reducer
-        let mut requests = ffi.reduce(&mut self.store, mutation)?;
+        refuel(&mut self.store, self.config.fuel_per_mutation)?;
+        let requests = ffi.reduce(&mut self.store, context, mutation)?;
+        self.run_reactor(tx, requests, deadline)
+    }
+
+    fn migrate_inner(
+        &mut self,
+        tx: &mut Transaction,
+        from_version: u32,
+        to_version: u32,
+    ) -> Result<()> {
+        let ffi = self.store.data().ffi;
+
+        refuel(&mut self.store, self.config.fuel_per_mutation)?;
+        let requests = ffi.migrate(&mut self.store, from_version, to_version)?;
+        self.run_reactor(tx, requests, None)?;
+        Ok(())
+    }
+
+    /// handle requests from the reducer until it finishes, returning its output
+    fn run_reactor(
+        &mut self,
+        tx: &mut Transaction,
+        mut requests: Requests,
+        deadline: Option<i64>,
+    ) -> Result<ReducerOutput> {
+        let ffi = self.store.data().ffi;
 
         while let Some(requests_inner) = requests {
-            // process requests
+            if matches!(deadline, Some(deadline) if unix_timestamp_milliseconds() >= deadline) {
+                return Err(ReducerError::Timeout);
+            }
+
+            // process requests one at a time, even read-only queries can't
+            // run concurrently on other connections as they must observe the
+            // uncommitted writes in tx, and storage is single threaded
             let mut responses = BTreeMap::new();
             for (id, req) in requests_inner {
                 match req {
-                    Request::Query { sql, params } => {
-                        let response = self.run_query(tx, &sql, params);
+                    Request::Query { sql, params, named_params } => {
+                        let response = self.run_query(tx, &sql, params, named_params);
                         let ptr = ffi.encode(&mut self.store, &response)?;
                         responses.insert(id, ptr);
                     }
-                    Request::Exec { sql, params } => {
-                        let response = self.run_exec(tx, &sql, params);
-                        let ptr = ffi.encode(&mut self.store, &response)?;
+                    Request::Exec { sql, params, named_params } => {
+                        let response = self.run_exec(tx, &sql, params, named_params);
+                        let ptr = ffi.encode_exec_response(&mut self.store, &response)?;
+                        responses.insert(id, ptr);
+                    }
+                    Request::ExecBatch { sql } => {
+                        let response = self.run_exec_batch(tx, &sql);
+                        let ptr = ffi.encode_exec_response(&mut self.store, &response)?;
                         responses.insert(id, ptr);
                     }
                 }
             }
 
-            // step the reactor forward
+            // step the reactor forward, the fuel set when the mutation started
+            // is shared by every step so a reducer can't loop on requests
             requests = ffi.reactor_step(&mut self.store, Some(responses))?;
         }
 
-        Ok(())
+        Ok(ffi.reducer_output(&mut self.store)?)
     }
 
     fn run_query(
@@ -106,8 +479,16 @@ impl WasmReducer {
         tx: &mut Transaction,
         sql: &str,
         params: Vec<SqliteValue>,
+        named_params: BTreeMap<String, SqliteValue>,
     ) -> SqlResult<QueryResponse> {
-        log::info!("received query req: {}, {:?}", sql, params);
+        log::info!(
+            target: logging::REDUCER,
+            "received query req: {}, {:?}, {:?}",
+            sql,
+            params,
+            named_params
+        );
+        let named_params = from_named_params(&params, named_params)?;
         let params = params_from_iter(params.into_iter().map(from_sqlite_value));
         let mut stmt = tx.prepare(sql).map_err(rusqlite_err_to_response_err)?;
 
@@ -120,18 +501,22 @@ impl WasmReducer {
 
         let start = unix_timestamp_milliseconds();
 
-        let rows = stmt
-            .query_and_then(params, move |row| {
-                (0..num_columns)
-                    .map(|i| Ok(to_sqlite_value(row.get_ref(i)?)))
-                    .collect::<std::result::Result<Row, rusqlite::Error>>()
-            })
-            .map_err(rusqlite_err_to_response_err)?
-            .collect::<std::result::Result<Vec<_>, _>>()
-            .map_err(rusqlite_err_to_response_err)?;
+        let to_row = move |row: &rusqlite::Row| {
+            (0..num_columns)
+                .map(|i| Ok(to_sqlite_value(row.get_ref(i)?)))
+                .collect::<std::result::Result<Row, rusqlite::Error>>()
+        };
+        let rows = if named_params.is_empty() {
+            stmt.query_and_then(params, to_row)
+        } else {
+            stmt.query_and_then(named_param_refs(&named_params).as_slice(), to_row)
+        }
+        .map_err(rusqlite_err_to_response_err)?
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(rusqlite_err_to_response_err)?;
 
         let end = unix_timestamp_milliseconds();
-        log::info!("query took {}ms", end - start);
+        log::info!(target: logging::REDUCER, "query took {}ms", end - start);
 
         Ok(QueryResponse { columns, rows })
     }
@@ -141,21 +526,109 @@ impl WasmReducer {
         tx: &mut Transaction,
         sql: &str,
         params: Vec<SqliteValue>,
+        named_params: BTreeMap<String, SqliteValue>,
     ) -> SqlResult<ExecResponse> {
-        log::info!("received exec req: {}, {:?}", sql, params);
+        log::info!(
+            target: logging::REDUCER,
+            "received exec req: {}, {:?}, {:?}",
+            sql,
+            params,
+            named_params
+        );
+        let named_params = from_named_params(&params, named_params)?;
         let params = params_from_iter(params.into_iter().map(from_sqlite_value));
 
         let start = unix_timestamp_milliseconds();
 
-        let changes = tx
-            .execute(sql, params)
-            .map_err(rusqlite_err_to_response_err)?;
+        let changes = if named_params.is_empty() {
+            tx.execute(sql, params)
+        } else {
+            tx.execute(sql, named_param_refs(&named_params).as_slice())
+        }
+        .map_err(rusqlite_err_to_response_err)?;
+        let last_insert_rowid = tx.last_insert_rowid();
+
+        let end = unix_timestamp_milliseconds();
+        log::info!(target: logging::REDUCER, "exec took {}ms", end - start);
+
+        Ok(ExecResponse { changes, last_insert_rowid })
+    }
+
+    fn run_exec_batch(&mut self, tx: &mut Transaction, sql: &str) -> SqlResult<ExecResponse> {
+        log::info!(target: logging::REDUCER, "received exec batch req: {}", sql);
+
+        let start = unix_timestamp_milliseconds();
+
+        // the batch may contain statements which don't change any rows, so
+        // the total change counter is used rather than summing changes()
+        let total_changes = || {
+            tx.query_row("SELECT total_changes()", [], |row| row.get::<_, usize>(0))
+                .map_err(rusqlite_err_to_response_err)
+        };
+        let before = total_changes()?;
+
+        let mut batch = Batch::new(tx, sql);
+        let mut n = 1;
+        let statement_err = |n, e| match rusqlite_err_to_response_err(e) {
+            ErrorResponse::SqliteError(SqliteError { code, message }) => {
+                ErrorResponse::SqliteError(SqliteError {
+                    code,
+                    message: format!("statement {} in batch: {}", n, message),
+                })
+            }
+            ErrorResponse::Unknown(message) => {
+                ErrorResponse::Unknown(format!("statement {} in batch: {}", n, message))
+            }
+        };
+        while let Some(mut stmt) = batch.next().map_err(|e| statement_err(n, e))? {
+            let mut rows = stmt.raw_query();
+            while rows.next().map_err(|e| statement_err(n, e))?.is_some() {}
+            n += 1;
+        }
+
+        let changes = total_changes()? - before;
+        let last_insert_rowid = tx.last_insert_rowid();
 
         let end = unix_timestamp_milliseconds();
-        log::info!("exec took {}ms", end - start);
+        log::info!(target: logging::REDUCER, "exec batch took {}ms", end - start);
+
+        Ok(ExecResponse { changes, last_insert_rowid })
+    }
+}
+
+/// reset the fuel available to the reducer, this happens once per mutation
+fn refuel(store: &mut Store<HostState>, fuel: Option<u64>) -> Result<()> {
+    if let Some(fuel) = fuel {
+        // wasmi can only add or consume fuel, so burn whatever is left first
+        let remaining = store.consume_fuel(0).map_err(wasmi::Error::from)?;
+        store.consume_fuel(remaining).map_err(wasmi::Error::from)?;
+        store.add_fuel(fuel).map_err(wasmi::Error::from)?;
+    }
+    Ok(())
+}
 
-        Ok(ExecResponse { changes })
+/// convert a request's named parameters, which can't be combined with
+/// positional parameters
+fn from_named_params(
+    params: &[SqliteValue],
+    named_params: BTreeMap<String, SqliteValue>,
+) -> SqlResult<Vec<(String, Value)>> {
+    if !params.is_empty() && !named_params.is_empty() {
+        return Err(ErrorResponse::Unknown(
+            "request can't mix positional and named parameters".into(),
+        ));
     }
+    Ok(named_params
+        .into_iter()
+        .map(|(name, v)| (name, from_sqlite_value(v)))
+        .collect())
+}
+
+fn named_param_refs(named_params: &[(String, Value)]) -> Vec<(&str, &dyn ToSql)> {
+    named_params
+        .iter()
+        .map(|(name, v)| (name.as_str(), v as &dyn ToSql))
+        .collect()
 }
 
 #[inline]
@@ -182,13 +655,485 @@ fn to_sqlite_value(v: ValueRef) -> SqliteValue {
 
 fn rusqlite_err_to_response_err(e: rusqlite::Error) -> ErrorResponse {
     match e {
-        rusqlite::Error::SqliteFailure(e, extra) => ErrorResponse::SqliteError {
+        rusqlite::Error::SqliteFailure(e, extra) => ErrorResponse::SqliteError(SqliteError {
             code: e.extended_code,
             message: match extra {
                 Some(extra) => format!("{}: {}", e, extra),
                 None => format!("{}", e),
             },
-        },
+        }),
         other => ErrorResponse::Unknown(format!("{}", other)),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+
+    use crate::db::run_in_tx;
+
+    use super::*;
+
+    fn test_reducer() -> anyhow::Result<Vec<u8>> {
+        test_reducer_with_request(Request::Query {
+            sql: "SELECT 1".into(),
+            params: vec![],
+            named_params: BTreeMap::new(),
+        })
+    }
+
+    // a reducer which busy loops if the first byte of the mutation is 1,
+    // issues request if it's 2, grows its memory by 100 pages if it's 3,
+    // panics at src/lib.rs:12:5 if it's 4, and issues request from every
+    // reactor step forever if it's 5, otherwise it returns Ok(None)
+    // every mutation which completes outputs [42]
+    fn test_reducer_with_request(request: Request) -> anyhow::Result<Vec<u8>> {
+        let requests: std::result::Result<Requests, sqlsync_reducer::types::ReducerError> =
+            Ok(Some(BTreeMap::from([(0, request)])));
+        let requests = bincode::serialize(&requests)?;
+        let escaped: String = requests.iter().map(|b| format!("\\{:02x}", b)).collect();
+        let panic = bincode::serialize(&Some(PanicRecord {
+            message: "boom".into(),
+            file: Some("src/lib.rs".into()),
+            line: Some(12),
+            column: Some(5),
+        }))?;
+        let escaped_panic: String = panic.iter().map(|b| format!("\\{:02x}", b)).collect();
+
+        Ok(wat::parse_str(format!(
+            r#"
+            (module
+                (memory (export "memory") 1)
+                (data (i32.const 1100) "{escaped}")
+                (data (i32.const 1050) "\01\01\00\00\00\00\00\00\00\2a")
+                (data (i32.const 4000) "{escaped_panic}")
+                (func (export "ffi_buf_allocate") (param i32) (result i32) i32.const 2048)
+                (func (export "ffi_buf_deallocate") (param i32))
+                (func (export "ffi_buf_len") (param i32) (result i32)
+                    (if (i32.eq (local.get 0) (i32.const 1100))
+                        (then (return (i32.const {len}))))
+                    (if (i32.eq (local.get 0) (i32.const 1050))
+                        (then (return (i32.const 10))))
+                    (if (i32.eq (local.get 0) (i32.const 4000))
+                        (then (return (i32.const {panic_len}))))
+                    i32.const 5)
+                (func (export "ffi_init_reducer") (result i32) i32.const {abi_version})
+                (func (export "ffi_reduce") (param i32) (result i32)
+                    (if (i32.eq (i32.load8_u (local.get 0)) (i32.const 1))
+                        (then (loop $spin (br $spin))))
+                    (if (i32.eq (i32.load8_u (local.get 0)) (i32.const 2))
+                        (then (return (i32.const 1100))))
+                    (if (i32.eq (i32.load8_u (local.get 0)) (i32.const 3))
+                        (then (drop (memory.grow (i32.const 100)))))
+                    (if (i32.eq (i32.load8_u (local.get 0)) (i32.const 4))
+                        (then unreachable))
+                    (if (i32.eq (i32.load8_u (local.get 0)) (i32.const 5))
+                        (then
+                            (i32.store8 (i32.const 3000) (i32.const 1))
+                            (return (i32.const 1100))))
+                    i32.const 1024)
+                (func (export "ffi_reactor_step") (param i32) (result i32)
+                    (if (i32.eq (i32.load8_u (i32.const 3000)) (i32.const 1))
+                        (then (return (i32.const 1100))))
+                    i32.const 1024)
+                (func (export "ffi_reducer_output") (result i32) i32.const 1050)
+                (func (export "ffi_last_panic") (result i32) i32.const 4000))
+            "#,
+            len = requests.len(),
+            panic_len = panic.len(),
+            abi_version = sqlsync_reducer::types::ABI_VERSION,
+        ))?)
+    }
+
+    #[test]
+    fn test_mutation_codec() -> anyhow::Result<()> {
+        let reducer_with_codec = |codec: u32| {
+            wat::parse_str(format!(
+                r#"
+                (module
+                    (memory (export "memory") 1)
+                    (func (export "ffi_buf_allocate") (param i32) (result i32) i32.const 2048)
+                    (func (export "ffi_buf_deallocate") (param i32))
+                    (func (export "ffi_buf_len") (param i32) (result i32) i32.const 5)
+                    (func (export "ffi_init_reducer"))
+                    (func (export "ffi_reduce") (param i32) (result i32) i32.const 1024)
+                    (func (export "ffi_reactor_step") (param i32) (result i32) i32.const 1024)
+                    (func (export "ffi_mutation_codec") (result i32) i32.const {codec}))
+                "#
+            ))
+        };
+
+        let reducer = WasmReducer::new(test_reducer()?.as_slice())?;
+        assert_eq!(Reducer::mutation_codec(&reducer), None);
+        let reducer = WasmReducer::new(reducer_with_codec(2)?.as_slice())?;
+        assert_eq!(Reducer::mutation_codec(&reducer), Some(MutationCodec::Json));
+        assert!(matches!(
+            WasmReducer::new(reducer_with_codec(7)?.as_slice()),
+            Err(ReducerError::Interface(WasmFFIError::UnknownMutationCodec(
+                7
+            )))
+        ));
+
+        validate_mutation(Some(MutationCodec::Json), br#"{"tag":"InitSchema"}"#)?;
+        assert!(matches!(
+            validate_mutation(Some(MutationCodec::Json), b"\x01\x00"),
+            Err(ReducerError::InvalidMutation { codec: MutationCodec::Json, .. })
+        ));
+        validate_mutation(Some(MutationCodec::Bincode), b"\x01\x00")?;
+        validate_mutation(None, b"\x01\x00")?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fuel_exhausted() -> anyhow::Result<()> {
+        let wasm = test_reducer()?;
+        let mut reducer = WasmReducer::with_fuel(wasm.as_slice(), Some(10_000))?;
+
+        let mut sqlite = Connection::open_in_memory()?;
+        sqlite.execute("CREATE TABLE t (x INTEGER)", [])?;
+
+        let result = run_in_tx(&mut sqlite, |tx| {
+            tx.execute("INSERT INTO t VALUES (1)", [])?;
+            reducer.apply(tx, &[1])
+        });
+        assert!(matches!(result, Err(ReducerError::FuelExhausted)));
+
+        // the transaction was rolled back
+        let count: i64 = sqlite.query_row("SELECT count(*) FROM t", [], |row| row.get(0))?;
+        assert_eq!(count, 0);
+
+        // and the reducer can still apply well behaved mutations
+        run_in_tx(&mut sqlite, |tx| reducer.apply(tx, &[0]))?;
+        run_in_tx(&mut sqlite, |tx| reducer.apply(tx, &[2]))?;
+
+        // the fuel is shared by every reactor step, so a reducer which never
+        // stops issuing queries runs out rather than looping forever. the
+        // deadline only stops the test from hanging if that regresses
+        let deadline = unix_timestamp_milliseconds() + 10_000;
+        let result = run_in_tx(&mut sqlite, |tx| {
+            reducer.apply_with_deadline(tx, &[5], Some(deadline))
+        });
+        assert!(matches!(result, Err(ReducerError::FuelExhausted)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_missing_export() -> anyhow::Result<()> {
+        // a reducer crate which forgot to call init_reducer!
+        let wasm = wat::parse_str(
+            r#"
+            (module
+                (memory (export "memory") 1)
+                (func (export "ffi_buf_allocate") (param i32) (result i32) local.get 0)
+                (func (export "ffi_buf_deallocate") (param i32))
+                (func (export "ffi_buf_len") (param i32) (result i32) local.get 0)
+                (func (export "ffi_reactor_step") (param i32) (result i32) local.get 0))
+            "#,
+        )?;
+        let err = WasmReducer::new(wasm.as_slice()).err().unwrap();
+        assert!(matches!(
+            err,
+            ReducerError::Interface(WasmFFIError::MissingExport { name: "ffi_init_reducer", .. })
+        ));
+        assert!(err.to_string().contains("init_reducer!"), "{}", err);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_mutation_context() -> anyhow::Result<()> {
+        // a reducer whose ffi_reduce takes the context as a second argument
+        // and outputs it unchanged, as a bincode encoded Option<Vec<u8>>
+        let wasm = wat::parse_str(format!(
+            r#"
+            (module
+                (memory (export "memory") 1)
+                (global $next (mut i32) (i32.const 1024))
+                (global $output (mut i32) (i32.const 0))
+                ;; each buffer is preceded by its length
+                (func $alloc (export "ffi_buf_allocate") (param i32) (result i32)
+                    (local $ptr i32)
+                    (i32.store (global.get $next) (local.get 0))
+                    (local.set $ptr (i32.add (global.get $next) (i32.const 4)))
+                    (global.set $next (i32.add (local.get $ptr) (local.get 0)))
+                    (local.get $ptr))
+                (func (export "ffi_buf_deallocate") (param i32))
+                (func $len (export "ffi_buf_len") (param i32) (result i32)
+                    (i32.load (i32.sub (local.get 0) (i32.const 4))))
+                (func (export "ffi_init_reducer") (result i32) i32.const {abi_version})
+                (func (export "ffi_reduce") (param $mutation i32) (param $context i32) (result i32)
+                    (local $len i32)
+                    (local $out i32)
+                    (local.set $len (call $len (local.get $context)))
+                    (local.set $out (call $alloc (i32.add (local.get $len) (i32.const 9))))
+                    (i32.store8 (local.get $out) (i32.const 1))
+                    (i64.store
+                        (i32.add (local.get $out) (i32.const 1))
+                        (i64.extend_i32_u (local.get $len)))
+                    (memory.copy
+                        (i32.add (local.get $out) (i32.const 9))
+                        (local.get $context)
+                        (local.get $len))
+                    (global.set $output (local.get $out))
+                    ;; fresh memory is zeroed, which encodes Ok(None) requests
+                    (call $alloc (i32.const 5)))
+                (func (export "ffi_reactor_step") (param i32) (result i32)
+                    (call $alloc (i32.const 5)))
+                (func (export "ffi_reducer_output") (result i32) (global.get $output)))
+            "#,
+            abi_version = sqlsync_reducer::types::ABI_VERSION,
+        ))?;
+        let mut reducer = WasmReducer::new(wasm.as_slice())?;
+        let mut sqlite = Connection::open_in_memory()?;
+
+        let context = MutationContext {
+            timeline_id: vec![1, 2, 3],
+            lsn: 7,
+            mutation_time: 1234,
+        };
+        let output = run_in_tx(&mut sqlite, |tx| {
+            reducer.apply_with_context(tx, &context, b"mutation", None)
+        })?;
+        let received: MutationContext = bincode::deserialize(&output.unwrap())?;
+        assert_eq!(received, context);
+
+        // apply passes an empty context
+        let output = run_in_tx(&mut sqlite, |tx| reducer.apply(tx, b"mutation"))?;
+        let received: MutationContext = bincode::deserialize(&output.unwrap())?;
+        assert_eq!(received, MutationContext::default());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_shared_module() -> anyhow::Result<()> {
+        let mut sqlite = Connection::open_in_memory()?;
+        let module =
+            WasmReducer::compile(test_reducer()?.as_slice(), &WasmReducerConfig::default())?;
+        let mut first = WasmReducer::from_module(module.clone())?;
+        let mut second = WasmReducer::from_module(module.clone())?;
+
+        // both reducers instantiate the module they were given, rather than
+        // compiling it again with an engine of their own
+        assert_eq!(Arc::strong_count(&module), 3);
+        assert!(Arc::ptr_eq(&first.module, &second.module));
+        assert!(Engine::same(first.store.engine(), module.engine()));
+        assert!(Engine::same(second.store.engine(), module.engine()));
+
+        // in stores of their own, so one exhausting its memory doesn't affect
+        // the others
+        let config = WasmReducerConfig {
+            max_memory_pages: Some(50),
+            ..Default::default()
+        };
+        let mut limited = WasmReducer::from_module_with_config(module.clone(), config)?;
+        let result = run_in_tx(&mut sqlite, |tx| limited.apply(tx, &[3]));
+        assert!(matches!(result, Err(ReducerError::MemoryExhausted)));
+        run_in_tx(&mut sqlite, |tx| first.apply(tx, &[3]))?;
+        run_in_tx(&mut sqlite, |tx| second.apply(tx, &[0]))?;
+
+        // a module compiled with fuel metering can be shared with reducers
+        // which don't limit fuel
+        let config = WasmReducerConfig {
+            fuel_per_mutation: Some(10_000),
+            ..Default::default()
+        };
+        let module = WasmReducer::compile(test_reducer()?.as_slice(), &config)?;
+        let mut limited = WasmReducer::from_module_with_config(module.clone(), config)?;
+        let mut unlimited = WasmReducer::from_module(module)?;
+        let result = run_in_tx(&mut sqlite, |tx| limited.apply(tx, &[1]));
+        assert!(matches!(result, Err(ReducerError::FuelExhausted)));
+        run_in_tx(&mut sqlite, |tx| unlimited.apply(tx, &[0]))?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_panic() -> anyhow::Result<()> {
+        let wasm = test_reducer()?;
+        let mut reducer = WasmReducer::new(wasm.as_slice())?;
+        let mut sqlite = Connection::open_in_memory()?;
+
+        let err = run_in_tx(&mut sqlite, |tx| reducer.apply(tx, &[4])).unwrap_err();
+        assert!(
+            matches!(&err, ReducerError::Panic(panic) if panic.line == Some(12)),
+            "{:?}",
+            err
+        );
+        assert_eq!(err.to_string(), "reducer panicked: boom at src/lib.rs:12:5");
+
+        // the reducer is reset and can still apply well behaved mutations
+        run_in_tx(&mut sqlite, |tx| reducer.apply(tx, &[0]))?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_memory_exhausted() -> anyhow::Result<()> {
+        let wasm = test_reducer()?;
+        let config = WasmReducerConfig {
+            max_memory_pages: Some(4),
+            ..Default::default()
+        };
+        let mut reducer = WasmReducer::with_config(wasm.as_slice(), config)?;
+        let mut sqlite = Connection::open_in_memory()?;
+
+        let result = run_in_tx(&mut sqlite, |tx| reducer.apply(tx, &[3]));
+        assert!(matches!(result, Err(ReducerError::MemoryExhausted)));
+
+        // the reducer is reset and can still apply well behaved mutations
+        run_in_tx(&mut sqlite, |tx| reducer.apply(tx, &[0]))?;
+
+        // without a limit the memory can grow
+        let mut reducer = WasmReducer::new(wasm.as_slice())?;
+        run_in_tx(&mut sqlite, |tx| reducer.apply(tx, &[3]))?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_deadline() -> anyhow::Result<()> {
+        let wasm = test_reducer()?;
+        let mut reducer = WasmReducer::new(wasm.as_slice())?;
+        let mut sqlite = Connection::open_in_memory()?;
+
+        // a deadline in the past interrupts the reducer before its next step
+        let result = run_in_tx(&mut sqlite, |tx| {
+            reducer.apply_with_deadline(tx, &[2], Some(0))
+        });
+        assert!(matches!(result, Err(ReducerError::Timeout)));
+
+        // mutations which don't step the reactor are unaffected
+        run_in_tx(&mut sqlite, |tx| {
+            reducer.apply_with_deadline(tx, &[0], Some(0))
+        })?;
+
+        // and the reducer recovers once given enough time
+        let deadline = unix_timestamp_milliseconds() + 60_000;
+        let output = run_in_tx(&mut sqlite, |tx| {
+            reducer.apply_with_deadline(tx, &[2], Some(deadline))
+        })?;
+        assert_eq!(output, Some(vec![42]));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_named_params() -> anyhow::Result<()> {
+        let wasm = test_reducer_with_request(Request::Exec {
+            sql: "INSERT INTO tasks (id, description) VALUES (:id, :desc)".into(),
+            params: vec![],
+            named_params: BTreeMap::from([
+                (":desc".into(), SqliteValue::Text("write tests".into())),
+                (":id".into(), SqliteValue::Integer(7)),
+            ]),
+        })?;
+        let mut reducer = WasmReducer::new(wasm.as_slice())?;
+
+        let mut sqlite = Connection::open_in_memory()?;
+        sqlite.execute("CREATE TABLE tasks (id INTEGER, description TEXT)", [])?;
+        run_in_tx(&mut sqlite, |tx| reducer.apply(tx, &[2]))?;
+
+        let task: (i64, String) =
+            sqlite.query_row("SELECT id, description FROM tasks", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })?;
+        assert_eq!(task, (7, "write tests".into()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_exec_batch() -> anyhow::Result<()> {
+        let wasm = test_reducer_with_request(Request::ExecBatch {
+            sql: "CREATE TABLE tasks (id INTEGER PRIMARY KEY, description TEXT);
+                INSERT INTO tasks (description) VALUES ('a'), ('b');
+                CREATE INDEX tasks_description ON tasks (description);"
+                .into(),
+        })?;
+        let mut reducer = WasmReducer::new(wasm.as_slice())?;
+
+        let mut sqlite = Connection::open_in_memory()?;
+        run_in_tx(&mut sqlite, |tx| reducer.apply(tx, &[2]))?;
+        let count: i64 = sqlite.query_row("SELECT count(*) FROM tasks", [], |row| row.get(0))?;
+        assert_eq!(count, 2);
+
+        // the response counts changes across the batch
+        let tx = &mut sqlite.transaction()?;
+        let response = reducer.run_exec_batch(
+            tx,
+            "INSERT INTO tasks (description) VALUES ('c');
+            SELECT * FROM tasks;
+            UPDATE tasks SET description = 'd' WHERE id < 3;",
+        );
+        assert!(matches!(response, Ok(ExecResponse { changes: 3, .. })));
+
+        // errors report which statement failed
+        let response = reducer.run_exec_batch(
+            tx,
+            "INSERT INTO tasks (id) VALUES (10);
+            INSERT INTO tasks (id) VALUES (10);",
+        );
+        assert!(matches!(
+            response,
+            Err(ErrorResponse::SqliteError(SqliteError { message, .. })) if message.starts_with("statement 2 in batch")
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_exec_last_insert_rowid() -> anyhow::Result<()> {
+        let mut reducer = WasmReducer::new(test_reducer()?.as_slice())?;
+        let mut sqlite = Connection::open_in_memory()?;
+        let tx = &mut sqlite.transaction()?;
+        tx.execute(
+            "CREATE TABLE tasks (id INTEGER PRIMARY KEY, description TEXT)",
+            [],
+        )?;
+        tx.execute("INSERT INTO tasks (id, description) VALUES (41, 'a')", [])?;
+
+        let response = reducer.run_exec(
+            tx,
+            "INSERT INTO tasks (description) VALUES (?)",
+            vec!["b".into()],
+            BTreeMap::new(),
+        );
+        assert!(matches!(
+            response,
+            Ok(ExecResponse { changes: 1, last_insert_rowid: 42 })
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sqlite_error_code() -> anyhow::Result<()> {
+        let mut reducer = WasmReducer::new(test_reducer()?.as_slice())?;
+        let mut sqlite = Connection::open_in_memory()?;
+        let tx = &mut sqlite.transaction()?;
+        tx.execute("CREATE TABLE tasks (description TEXT UNIQUE)", [])?;
+        tx.execute("INSERT INTO tasks VALUES ('a')", [])?;
+
+        let response = reducer.run_exec(
+            tx,
+            "INSERT INTO tasks VALUES (?)",
+            vec!["a".into()],
+            BTreeMap::new(),
+        );
+
+        // the guest receives the extended code when it propagates the error
+        let err = sqlsync_reducer::types::ReducerError::from(response.unwrap_err());
+        assert!(matches!(
+            err,
+            sqlsync_reducer::types::ReducerError::Sqlite(SqliteError {
+                code: rusqlite::ffi::SQLITE_CONSTRAINT_UNIQUE,
+                ..
+            })
+        ));
+
+        Ok(())
+    }
+}
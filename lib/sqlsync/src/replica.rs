@@ -0,0 +1,217 @@
+use std::fmt::Debug;
+use std::io;
+use std::pin::Pin;
+
+use rusqlite::Connection;
+
+use crate::db::{open_with_vfs, ConnectionPair, OpenConfig};
+use crate::error::Result;
+use crate::page::DEFAULT_PAGESIZE;
+use crate::replication::{ReplicationDestination, ReplicationError};
+use crate::{
+    journal::{Journal, JournalId},
+    lsn::{Lsn, LsnRange},
+    storage::Storage,
+};
+
+/// ReplicaDocument is a read-only follower of a document, it receives
+/// storage from the coordinator and serves queries, but has no timeline and
+/// can't be mutated. this makes it much cheaper to open than a LocalDocument.
+pub struct ReplicaDocument<J: Journal> {
+    // see open_with_storage for why this is declared before storage
+    sqlite: ConnectionPair,
+    storage: Pin<Box<Storage<J>>>,
+}
+
+impl<J: Journal> Debug for ReplicaDocument<J> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("ReplicaDocument")
+            .field(&self.storage)
+            .finish()
+    }
+}
+
+impl<J: Journal> ReplicaDocument<J> {
+    pub fn open(storage: J) -> Result<Self> {
+        Self::open_with_page_size(storage, DEFAULT_PAGESIZE)
+    }
+
+    /// open a replica whose database uses the given page size, which must
+    /// match the page size of the coordinator
+    pub fn open_with_page_size(storage: J, page_size: usize) -> Result<Self> {
+        let (sqlite, storage) = open_with_vfs(storage, page_size, &OpenConfig::default())?;
+        Ok(Self { sqlite, storage })
+    }
+
+    pub fn query<F, O, E>(&self, f: F) -> std::result::Result<O, E>
+    where
+        F: FnOnce(&Connection) -> std::result::Result<O, E>,
+        E: std::convert::From<rusqlite::Error>,
+    {
+        f(&self.sqlite.readonly)
+    }
+}
+
+impl<J: Journal + ReplicationDestination> ReplicaDocument<J> {
+    /// load a snapshot written by CoordinatorDocument::export_snapshot into
+    /// this replica, which must not have received any frames yet. replication
+    /// resumes from the lsn following the snapshot, which is returned
+    pub fn import_snapshot(&mut self, mut reader: impl io::Read) -> Result<Lsn> {
+        Ok(self.storage.import_snapshot(&mut reader)?)
+    }
+}
+
+/// ReplicaDocument receives a storage journal from elsewhere, frames become
+/// visible to queries as soon as they are written
+impl<J: Journal + ReplicationDestination> ReplicationDestination for ReplicaDocument<J> {
+    fn range(&mut self, id: JournalId) -> std::result::Result<LsnRange, ReplicationError> {
+        self.storage.range(id)
+    }
+
+    fn write_lsn<R>(
+        &mut self,
+        id: JournalId,
+        lsn: Lsn,
+        reader: &mut R,
+    ) -> std::result::Result<(), ReplicationError>
+    where
+        R: io::Read,
+    {
+        self.storage.write_lsn(id, lsn, reader)?;
+        self.storage.reset()?;
+        Ok(())
+    }
+
+    fn write_coalesced<R>(
+        &mut self,
+        id: JournalId,
+        range: LsnRange,
+        reader: &mut R,
+    ) -> std::result::Result<(), ReplicationError>
+    where
+        R: io::Read,
+    {
+        self.storage.write_coalesced(id, range, reader)?;
+        self.storage.reset()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem::size_of;
+
+    use crate::{
+        page::PageIdx,
+        replication::{ReplicationProtocol, ReplicationSource},
+        storage::SNAPSHOT_HEADER_SIZE,
+        test_helpers::{open_coordinator, open_local, replicate, TestCoordinator, TestLocal},
+        MemoryJournal,
+    };
+
+    use super::*;
+
+    fn query_names(doc: &ReplicaDocument<MemoryJournal>) -> anyhow::Result<Vec<String>> {
+        Ok(doc.query(|conn| {
+            let mut stmt = conn.prepare("SELECT name FROM people ORDER BY name")?;
+            let rows = stmt.query_map([], |row| row.get(0))?;
+            rows.collect::<rusqlite::Result<Vec<String>>>()
+        })?)
+    }
+
+    #[test]
+    fn test_replica() -> anyhow::Result<()> {
+        let doc_id = JournalId::new128(&mut rand::thread_rng());
+        let mut local = open_local(doc_id)?;
+        let mut coordinator = open_coordinator(doc_id)?;
+        let mut replica = ReplicaDocument::open(MemoryJournal::open(doc_id)?)?;
+
+        let mut local_to_coordinator = ReplicationProtocol::new();
+        let mut coordinator_to_replica = ReplicationProtocol::new();
+        let mut sync = |local: &TestLocal, coordinator: &mut TestCoordinator, replica: &mut _| {
+            replicate(&mut local_to_coordinator, local, coordinator)?;
+            while coordinator.has_pending_work() {
+                coordinator.step()?;
+            }
+            replicate(&mut coordinator_to_replica, &*coordinator, replica)?;
+            Ok::<_, anyhow::Error>(())
+        };
+
+        local.mutate(b"CREATE TABLE people (name TEXT)")?;
+        local.mutate(b"INSERT INTO people VALUES ('alice'), ('bob')")?;
+        sync(&local, &mut coordinator, &mut replica)?;
+        assert_eq!(query_names(&replica)?, vec!["alice", "bob"]);
+
+        // later changes are visible as soon as they are replicated
+        local.mutate(b"DELETE FROM people WHERE name = 'alice'")?;
+        local.mutate(b"INSERT INTO people VALUES ('carol')")?;
+        assert_eq!(query_names(&replica)?, vec!["alice", "bob"]);
+        sync(&local, &mut coordinator, &mut replica)?;
+        assert_eq!(query_names(&replica)?, vec!["bob", "carol"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_import_snapshot() -> anyhow::Result<()> {
+        let doc_id = JournalId::new128(&mut rand::thread_rng());
+        let mut local = open_local(doc_id)?;
+        let mut coordinator = open_coordinator(doc_id)?;
+        let mut local_to_coordinator = ReplicationProtocol::new();
+
+        let mut sync = |local: &TestLocal, coordinator: &mut TestCoordinator| {
+            replicate(&mut local_to_coordinator, local, coordinator)?;
+            while coordinator.has_pending_work() {
+                coordinator.step()?;
+            }
+            Ok::<_, anyhow::Error>(())
+        };
+
+        // build up a long storage journal which repeatedly changes one page
+        local.mutate(b"CREATE TABLE people (name TEXT)")?;
+        local.mutate(b"INSERT INTO people VALUES ('alice'), ('bob')")?;
+        sync(&local, &mut coordinator)?;
+        for i in 0..100 {
+            local.mutate(
+                format!("UPDATE people SET name = 'bob{}' WHERE rowid = 2", i).as_bytes(),
+            )?;
+            sync(&local, &mut coordinator)?;
+        }
+        let last_lsn = coordinator.source_range().last().unwrap();
+        assert!(last_lsn > 100);
+
+        let mut snapshot = Vec::new();
+        coordinator.export_snapshot(&mut snapshot)?;
+        let num_pages = u32::from_le_bytes(snapshot[12..16].try_into()?) as usize;
+        let page_len = size_of::<PageIdx>() + DEFAULT_PAGESIZE;
+        assert_eq!(snapshot.len(), SNAPSHOT_HEADER_SIZE + num_pages * page_len);
+
+        // the snapshot only contains the latest version of each page, so the
+        // page indexes (which are sorted desc) are exactly 1..=num_pages
+        let max_page_idx = PageIdx::from_le_bytes(snapshot[16..20].try_into()?);
+        assert_eq!(num_pages, max_page_idx as usize);
+
+        // a truncated snapshot is rejected
+        let mut replica = ReplicaDocument::open(MemoryJournal::open(doc_id)?)?;
+        assert!(replica
+            .import_snapshot(&snapshot[..snapshot.len() - 1])
+            .is_err());
+        assert!(replica.range(doc_id)?.is_empty());
+
+        assert_eq!(replica.import_snapshot(snapshot.as_slice())?, last_lsn);
+        assert_eq!(query_names(&replica)?, vec!["alice", "bob99"]);
+        assert!(replica.import_snapshot(snapshot.as_slice()).is_err());
+
+        // replication continues from the lsn following the snapshot
+        local.mutate(b"INSERT INTO people VALUES ('carol')")?;
+        sync(&local, &mut coordinator)?;
+        let mut coordinator_to_replica = ReplicationProtocol::new();
+        assert_eq!(
+            replicate(&mut coordinator_to_replica, &coordinator, &mut replica)?,
+            1
+        );
+        assert_eq!(query_names(&replica)?, vec!["alice", "bob99", "carol"]);
+
+        Ok(())
+    }
+}
@@ -1,25 +1,199 @@
-use std::{cmp, io};
+use std::{cmp, collections::HashMap, io};
 
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-use crate::{lsn::LsnRange, positioned_io::PositionedReader, JournalId, Lsn};
+use crate::{
+    logging,
+    lsn::LsnRange,
+    positioned_io::{PositionedCursor, PositionedReader},
+    JournalId, Lsn,
+};
 
-// maximum number of frames we will send without receiving an acknowledgement
+// default maximum number of frames we will send without receiving an acknowledgement
 // note: this does not affect durability, as we keep don't truncate the source journal until rebase
 const MAX_OUTSTANDING_FRAMES: usize = 100;
 
+// default maximum number of bytes a compressed frame may decompress to
+const MAX_FRAME_SIZE: usize = 256 * 1024 * 1024;
+
+// default maximum number of channels open on a MultiplexedProtocol
+const MAX_CHANNELS: usize = 64;
+
+/// ReplicationMsg is encoded with bincode, which can't skip unknown fields or
+/// default missing ones, so adding a field breaks compatibility between
+/// versions and must be noted in the changelog
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub enum ReplicationMsg {
     /// request the lsn range of the specified journal
     RangeRequest {
         id: JournalId,
         source_range: LsnRange,
+        /// the codec the source would like to compress frames with
+        compression: Compression,
+    },
+    /// reply to a RangeRequest or Frame with the range of the specified journal
+    /// along with how much of it the destination has applied, if known
+    Range {
+        range: LsnRange,
+        applied: Option<AppliedWatermark>,
+        /// the codec the destination accepted, only set in reply to a RangeRequest
+        compression: Option<Compression>,
     },
-    /// reply to a RangeRequest with the range of the specified journal
-    Range { range: LsnRange },
     /// send one LSN frame from the specified journal
-    Frame { id: JournalId, lsn: Lsn, len: u64 },
+    /// len is the length of the frame after compression
+    Frame {
+        id: JournalId,
+        lsn: Lsn,
+        len: u64,
+        compression: Compression,
+    },
+    /// send a single frame containing the net effect of every frame in range
+    CoalescedFrame {
+        id: JournalId,
+        range: LsnRange,
+        len: u64,
+        compression: Compression,
+    },
+    /// sent by the coordinator when it refused to apply the timeline entry
+    /// at lsn, the client drops the entry the next time it rebases
+    Reject {
+        timeline_id: JournalId,
+        lsn: Lsn,
+        reason: String,
+    },
+    /// sent by ReplicationProtocol::tick when the connection has been idle
+    Ping,
+    /// reply to a Ping
+    Pong,
+}
+
+/// Rejection records that the coordinator refused to apply a timeline entry
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct Rejection {
+    pub timeline_id: JournalId,
+    pub lsn: Lsn,
+    pub reason: String,
+}
+
+impl From<Rejection> for ReplicationMsg {
+    fn from(rejection: Rejection) -> Self {
+        let Rejection { timeline_id, lsn, reason } = rejection;
+        ReplicationMsg::Reject { timeline_id, lsn, reason }
+    }
+}
+
+/// Compression is the codec used to compress frames sent over the wire
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    /// the lz4 block format, prefixed by the uncompressed length
+    Lz4,
+    /// a zstd frame, only available with the zstd feature
+    Zstd,
+}
+
+impl Compression {
+    /// returns false if this build can't compress frames with the codec
+    pub fn is_supported(&self) -> bool {
+        match self {
+            Compression::None | Compression::Lz4 => true,
+            Compression::Zstd => cfg!(feature = "zstd"),
+        }
+    }
+
+    fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Lz4 => Ok(lz4_flex::compress_prepend_size(data)),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => zstd::encode_all(data, zstd::DEFAULT_COMPRESSION_LEVEL),
+            #[cfg(not(feature = "zstd"))]
+            Compression::Zstd => Err(zstd_unsupported()),
+        }
+    }
+
+    /// decompress data, failing rather than producing more than max_size bytes
+    fn decompress(&self, data: &[u8], max_size: usize) -> io::Result<Vec<u8>> {
+        let out = match self {
+            Compression::None => data.to_vec(),
+            Compression::Lz4 => {
+                // check the prepended size before it's used to allocate the output
+                let (size, block) = lz4_flex::block::uncompressed_size(data)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                if size > max_size {
+                    return Err(frame_too_large(size as u64, max_size));
+                }
+                lz4_flex::block::decompress(block, size)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+            }
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => {
+                use io::Read;
+                let mut out = Vec::new();
+                zstd::Decoder::new(data)?
+                    .take(max_size as u64 + 1)
+                    .read_to_end(&mut out)?;
+                out
+            }
+            #[cfg(not(feature = "zstd"))]
+            Compression::Zstd => return Err(zstd_unsupported()),
+        };
+        if out.len() > max_size {
+            return Err(frame_too_large(out.len() as u64, max_size));
+        }
+        Ok(out)
+    }
+}
+
+fn frame_too_large(len: u64, max_size: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "frame is {} bytes, which exceeds the maximum frame size of {} bytes",
+            len, max_size
+        ),
+    )
+}
+
+#[cfg(not(feature = "zstd"))]
+fn zstd_unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "zstd compression requires the zstd feature",
+    )
+}
+
+/// AppliedWatermark reports that a destination has applied every lsn up to
+/// and including `lsn` from journal `id`, and that the result is visible in
+/// the destination's storage as of `storage_lsn`
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct AppliedWatermark {
+    pub id: JournalId,
+    pub lsn: Lsn,
+    pub storage_lsn: Lsn,
+}
+
+/// Heartbeat detects connections which have silently dropped. a Ping is sent
+/// once nothing has been sent for interval_ms, and the connection fails if
+/// nothing is received within timeout_ms of sending it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Heartbeat {
+    pub interval_ms: i64,
+    pub timeout_ms: i64,
+}
+
+/// ReplicationMode controls how a source catches up a destination
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ReplicationMode {
+    /// send every frame, preserving the full history
+    #[default]
+    Full,
+    /// if the destination is more than one frame behind, send a single
+    /// coalesced frame containing only the latest state, falling back to Full
+    /// replication if the source can't coalesce frames
+    LatestOnly,
 }
 
 #[derive(Error, Debug)]
@@ -36,13 +210,81 @@ pub enum ReplicationError {
         "replication must be contiguous, received lsn {received} but expected lsn in range {range}"
     )]
     NonContiguousLsn { received: Lsn, range: LsnRange },
+
+    #[error("journal {0} does not support coalesced frames")]
+    CoalescingUnsupported(JournalId),
+
+    #[error("no response to heartbeat within {timeout_ms}ms")]
+    HeartbeatTimeout { timeout_ms: i64 },
+
+    #[error("can't open more than {max} channels")]
+    TooManyChannels { max: usize },
+}
+
+/// SyncFrame is a message returned by ReplicationProtocol::sync, along with
+/// the frame which must be sent after it
+pub type SyncFrame<'a, D> = (
+    ReplicationMsg,
+    CodecReader<<D as ReplicationSource>::Reader<'a>>,
+);
+
+/// MultiplexedFrame is a SyncFrame addressed to a channel, returned by
+/// MultiplexedProtocol::sync
+pub type MultiplexedFrame<'a, D> = (Envelope, CodecReader<<D as ReplicationSource>::Reader<'a>>);
+
+/// SyncPlan describes the frames that ReplicationProtocol::sync would send
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyncPlan {
+    /// the number of frames that would be sent
+    pub frames: usize,
+    /// the total size of the frames in bytes, before compression
+    pub bytes: u64,
+    /// the lsns of the frames that would be sent
+    pub lsn_range: LsnRange,
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Clone)]
 pub struct ReplicationProtocol {
     // outstanding lsn frames sent to the destination but awaiting acknowledgement
     // this is an Option because we need the to initialize it from the initial RangeRequest
     outstanding_range: Option<LsnRange>,
+
+    // maximum number of outstanding frames
+    max_outstanding: usize,
+
+    mode: ReplicationMode,
+
+    // the codec we request when sending frames, and accept when receiving them
+    compression: Compression,
+    // the codec the destination accepted in reply to our RangeRequest
+    send_compression: Compression,
+    // compressed frames which decompress to more than this are refused
+    max_frame_size: usize,
+
+    heartbeat: Option<Heartbeat>,
+    // true if we have produced a message since the last tick
+    sent_since_tick: bool,
+    // the time of the tick which last observed us sending a message
+    last_sent_at: Option<i64>,
+    // the time we sent a Ping which hasn't been answered yet
+    ping_sent_at: Option<i64>,
+}
+
+impl Default for ReplicationProtocol {
+    fn default() -> Self {
+        Self {
+            outstanding_range: None,
+            max_outstanding: MAX_OUTSTANDING_FRAMES,
+            mode: ReplicationMode::default(),
+            compression: Compression::default(),
+            send_compression: Compression::default(),
+            max_frame_size: MAX_FRAME_SIZE,
+            heartbeat: None,
+            sent_since_tick: false,
+            last_sent_at: None,
+            ping_sent_at: None,
+        }
+    }
 }
 
 impl ReplicationProtocol {
@@ -50,6 +292,73 @@ impl ReplicationProtocol {
         Self::default()
     }
 
+    pub fn with_mode(mode: ReplicationMode) -> Self {
+        Self { mode, ..Self::default() }
+    }
+
+    /// limit the number of frames sent without receiving an acknowledgement
+    /// larger windows improve throughput on high latency connections at the
+    /// cost of buffering more frames
+    pub fn with_window(self, max_outstanding: usize) -> Self {
+        assert!(max_outstanding > 0, "max_outstanding must be > 0");
+        Self { max_outstanding, ..self }
+    }
+
+    /// compress frames with the given codec, this is negotiated when
+    /// replication starts and falls back to Compression::None unless both
+    /// sides of the connection use the same codec
+    pub fn with_compression(self, compression: Compression) -> Self {
+        Self { compression, ..self }
+    }
+
+    /// refuse compressed frames which decompress to more than max_frame_size
+    /// bytes, protecting the destination from decompression bombs
+    pub fn with_max_frame_size(self, max_frame_size: usize) -> Self {
+        Self { max_frame_size, ..self }
+    }
+
+    /// send heartbeats while the connection is idle, see tick
+    pub fn with_heartbeat(self, heartbeat: Heartbeat) -> Self {
+        Self { heartbeat: Some(heartbeat), ..self }
+    }
+
+    /// tick must be called periodically with the current time in
+    /// milliseconds if heartbeats are enabled. returns a Ping to send if
+    /// we have been idle for too long, or an error if the remote hasn't
+    /// responded to the last Ping in time
+    pub fn tick(&mut self, now: i64) -> Result<Option<ReplicationMsg>, ReplicationError> {
+        let heartbeat = match self.heartbeat {
+            Some(heartbeat) => heartbeat,
+            None => return Ok(None),
+        };
+
+        if let Some(ping_sent_at) = self.ping_sent_at {
+            if now - ping_sent_at >= heartbeat.timeout_ms {
+                return Err(ReplicationError::HeartbeatTimeout {
+                    timeout_ms: heartbeat.timeout_ms,
+                });
+            }
+            return Ok(None);
+        }
+
+        let last_sent_at = match self.last_sent_at {
+            Some(last_sent_at) if !self.sent_since_tick => last_sent_at,
+            _ => {
+                self.sent_since_tick = false;
+                self.last_sent_at = Some(now);
+                return Ok(None);
+            }
+        };
+
+        if now - last_sent_at >= heartbeat.interval_ms {
+            log::trace!(target: logging::REPLICATION, "sending heartbeat");
+            self.last_sent_at = Some(now);
+            self.ping_sent_at = Some(now);
+            return Ok(Some(ReplicationMsg::Ping));
+        }
+        Ok(None)
+    }
+
     /// start replication, must be called on both sides of the connection
     pub fn start<D: ReplicationSource>(&self, doc: &D) -> ReplicationMsg {
         // before we can start sending frames to the destination, we need to know
@@ -57,6 +366,19 @@ impl ReplicationProtocol {
         ReplicationMsg::RangeRequest {
             id: doc.source_id(),
             source_range: doc.source_range(),
+            compression: self.compression,
+        }
+    }
+
+    /// resume replication to a destination which acknowledged every lsn up to
+    /// and including last_acked on a previous connection, allowing sync to
+    /// send frames without waiting for the reply to start
+    /// if the destination turns out to be missing frames, the reply resets
+    /// the protocol to the destination's range
+    pub fn resume(self, last_acked: Lsn) -> Self {
+        Self {
+            outstanding_range: Some(LsnRange::Empty { nextlsn: last_acked + 1 }),
+            ..self
         }
     }
 
@@ -66,30 +388,88 @@ impl ReplicationProtocol {
         self.outstanding_range.is_some()
     }
 
+    /// the last lsn acknowledged by the destination, which can be passed to
+    /// resume when reconnecting
+    pub fn last_acked(&self) -> Option<Lsn> {
+        self.outstanding_range
+            .map(|range| LsnRange::empty_preceeding(&range).next())
+            .and_then(|next| next.checked_sub(1))
+    }
+
     /// sync a frame from the source journal to the destination
     /// the protocol layer will need to send the replication msg
     /// followed by the contents of the reader to the destination
     pub fn sync<'a, D: ReplicationSource>(
         &mut self,
         doc: &'a D,
-    ) -> Result<Option<(ReplicationMsg, D::Reader<'a>)>, ReplicationError> {
+    ) -> Result<Option<SyncFrame<'a, D>>, ReplicationError> {
+        let frame = self.next_frame(doc)?;
+        self.sent_since_tick |= frame.is_some();
+        Ok(frame)
+    }
+
+    fn next_frame<'a, D: ReplicationSource>(
+        &mut self,
+        doc: &'a D,
+    ) -> Result<Option<SyncFrame<'a, D>>, ReplicationError> {
         if let Some(outstanding_range) = self.outstanding_range {
-            if outstanding_range.len() >= MAX_OUTSTANDING_FRAMES {
+            if outstanding_range.len() >= self.max_outstanding {
                 // we have too many outstanding frames, so we can't send any more
+                log::trace!(
+                    target: logging::REPLICATION,
+                    "waiting for acknowledgement of outstanding frames: {}",
+                    outstanding_range
+                );
                 return Ok(None);
             }
 
+            if let Some(range) = self.catch_up_range(outstanding_range, doc) {
+                if let Some(data) = doc.read_coalesced(range)? {
+                    log::trace!(
+                        target: logging::REPLICATION,
+                        "sending coalesced frame {} from journal {}",
+                        range,
+                        doc.source_id()
+                    );
+
+                    // nothing else was outstanding, see catch_up_range
+                    self.outstanding_range = Some(range);
+                    doc.frames_sent(range);
+
+                    let (compression, data) = self.compress_frame(data)?;
+                    return Ok(Some((
+                        ReplicationMsg::CoalescedFrame {
+                            id: doc.source_id(),
+                            range,
+                            len: data.size()? as u64,
+                            compression,
+                        },
+                        data,
+                    )));
+                }
+            }
+
             let lsn = outstanding_range.next();
             if let Some(data) = doc.read_lsn(lsn)? {
+                log::trace!(
+                    target: logging::REPLICATION,
+                    "sending frame {} from journal {}",
+                    lsn,
+                    doc.source_id()
+                );
+
                 // update outstanding
                 self.outstanding_range = Some(outstanding_range.append(lsn));
+                doc.frames_sent(LsnRange::new(lsn, lsn));
 
                 // send frame
+                let (compression, data) = self.compress_frame(data)?;
                 return Ok(Some((
                     ReplicationMsg::Frame {
                         id: doc.source_id(),
                         lsn,
                         len: data.size()? as u64,
+                        compression,
                     },
                     data,
                 )));
@@ -99,6 +479,94 @@ impl ReplicationProtocol {
         Ok(None)
     }
 
+    /// compress a frame with the negotiated codec
+    /// frames which don't shrink are sent uncompressed
+    fn compress_frame<R: PositionedReader>(
+        &self,
+        data: R,
+    ) -> io::Result<(Compression, CodecReader<R>)> {
+        if self.send_compression != Compression::None {
+            let compressed = self.send_compression.compress(&data.read_all()?)?;
+            if compressed.len() < data.size()? {
+                return Ok((
+                    self.send_compression,
+                    CodecReader::Buffered(PositionedCursor::new(compressed)),
+                ));
+            }
+        }
+        Ok((Compression::None, CodecReader::Raw(data)))
+    }
+
+    /// sync_plan computes what sync would send without sending anything
+    /// the plan is bounded by the outstanding frame window, just like sync
+    pub fn sync_plan<D: ReplicationSource>(&self, doc: &D) -> Result<SyncPlan, ReplicationError> {
+        let outstanding_range = match self.outstanding_range {
+            Some(outstanding_range) => outstanding_range,
+            // we can't send anything until we receive a range from the destination
+            None => {
+                return Ok(SyncPlan {
+                    frames: 0,
+                    bytes: 0,
+                    lsn_range: LsnRange::empty(),
+                })
+            }
+        };
+
+        if let Some(range) = self.catch_up_range(outstanding_range, doc) {
+            if let Some(data) = doc.read_coalesced(range)? {
+                return Ok(SyncPlan {
+                    frames: 1,
+                    bytes: data.size()? as u64,
+                    lsn_range: range,
+                });
+            }
+        }
+
+        let mut lsn_range = LsnRange::empty_following(&outstanding_range);
+        let mut bytes = 0;
+        while outstanding_range.len() + lsn_range.len() < self.max_outstanding {
+            let lsn = lsn_range.next();
+            match doc.read_lsn(lsn)? {
+                Some(data) => {
+                    bytes += data.size()? as u64;
+                    lsn_range = lsn_range.append(lsn);
+                }
+                None => break,
+            }
+        }
+
+        Ok(SyncPlan {
+            frames: lsn_range.len(),
+            bytes,
+            lsn_range,
+        })
+    }
+
+    /// in LatestOnly mode, returns the range of frames to coalesce if the
+    /// destination is more than one frame behind. in either mode, returns
+    /// the range of frames to coalesce if the destination needs frames which
+    /// were dropped from the start of the source
+    /// we only coalesce when nothing is outstanding to keep acks simple
+    fn catch_up_range<D: ReplicationSource>(
+        &self,
+        outstanding_range: LsnRange,
+        doc: &D,
+    ) -> Option<LsnRange> {
+        if outstanding_range.is_non_empty() {
+            return None;
+        }
+        let next = outstanding_range.next();
+        let source_range = doc.source_range();
+        let last = match self.mode {
+            ReplicationMode::LatestOnly => source_range.last(),
+            ReplicationMode::Full => source_range.first(),
+        };
+        match last {
+            Some(last) if last > next => Some(LsnRange::new(next, last)),
+            _ => None,
+        }
+    }
+
     /// handle a replication message from the remote side
     /// connection is needed to read additional bytes from the remote side
     /// this is used to synchronize frames without excessive buffering
@@ -107,9 +575,25 @@ impl ReplicationProtocol {
         doc: &mut D,
         msg: ReplicationMsg,
         connection: &mut impl io::Read,
+    ) -> Result<Option<ReplicationMsg>, ReplicationError> {
+        log::debug!(target: logging::REPLICATION, "handling {:?}", msg);
+
+        // any message from the remote shows that the connection is alive
+        self.ping_sent_at = None;
+
+        let resp = self.handle_msg(doc, msg, connection)?;
+        self.sent_since_tick |= resp.is_some();
+        Ok(resp)
+    }
+
+    fn handle_msg<D: ReplicationDestination>(
+        &mut self,
+        doc: &mut D,
+        msg: ReplicationMsg,
+        connection: &mut impl io::Read,
     ) -> Result<Option<ReplicationMsg>, ReplicationError> {
         match msg {
-            ReplicationMsg::RangeRequest { id, source_range } => {
+            ReplicationMsg::RangeRequest { id, source_range, compression } => {
                 let mut range = doc.range(id)?;
 
                 // if our range is empty, then we should reset to the remote's source range
@@ -118,27 +602,201 @@ impl ReplicationProtocol {
                     range = LsnRange::empty_preceeding(&source_range);
                 }
 
-                Ok(Some(ReplicationMsg::Range { range }))
+                // fall back to uncompressed frames unless we use the same codec
+                let compression = if compression == self.compression && compression.is_supported() {
+                    compression
+                } else {
+                    Compression::None
+                };
+
+                Ok(Some(ReplicationMsg::Range {
+                    range,
+                    applied: doc.applied_watermark(id)?,
+                    compression: Some(compression),
+                }))
             }
-            ReplicationMsg::Range { range } => {
-                self.outstanding_range = self.outstanding_range.map_or_else(
+            ReplicationMsg::Range { range, applied, compression } => {
+                if let Some(applied) = applied {
+                    doc.acknowledge_applied(applied)?;
+                }
+                if let Some(compression) = compression {
+                    self.send_compression = compression;
+                }
+
+                self.outstanding_range = match self.outstanding_range {
                     // first range response, initialize outstanding_range from destination range
-                    || Some(LsnRange::empty_following(&range)),
+                    None => Some(LsnRange::empty_following(&range)),
+                    // reply to start after resuming, the destination is missing
+                    // frames we assumed it had so we have to start over from
+                    // its range
+                    Some(outstanding_range)
+                        if compression.is_some()
+                            && range.next()
+                                < LsnRange::empty_preceeding(&outstanding_range).next() =>
+                    {
+                        log::warn!(
+                            target: logging::REPLICATION,
+                            "destination is behind the resumed range {}, restarting from {}",
+                            outstanding_range,
+                            range
+                        );
+                        Some(LsnRange::empty_following(&range))
+                    }
                     // subsequent range response, update outstanding range
-                    |outstanding_range| {
+                    Some(outstanding_range) => {
                         let next = range.next();
                         assert!(next > 0, "subsequent range responses should never be empty");
                         Some(outstanding_range.trim_prefix(next - 1))
-                    },
-                );
+                    }
+                };
                 Ok(None)
             }
-            ReplicationMsg::Frame { id, lsn, len } => {
-                let mut reader = LimitedReader { limit: len, inner: connection };
+            ReplicationMsg::Frame { id, lsn, len, compression } => {
+                let reader = LimitedReader { limit: len, inner: connection };
+                let mut reader = decompress_frame(compression, len, reader, self.max_frame_size)?;
                 doc.write_lsn(id, lsn, &mut reader)?;
-                Ok(Some(ReplicationMsg::Range { range: doc.range(id)? }))
+                Ok(Some(ReplicationMsg::Range {
+                    range: doc.range(id)?,
+                    applied: doc.applied_watermark(id)?,
+                    compression: None,
+                }))
+            }
+            ReplicationMsg::CoalescedFrame { id, range, len, compression } => {
+                let reader = LimitedReader { limit: len, inner: connection };
+                let mut reader = decompress_frame(compression, len, reader, self.max_frame_size)?;
+                doc.write_coalesced(id, range, &mut reader)?;
+                Ok(Some(ReplicationMsg::Range {
+                    range: doc.range(id)?,
+                    applied: doc.applied_watermark(id)?,
+                    compression: None,
+                }))
+            }
+            ReplicationMsg::Reject { timeline_id, lsn, reason } => {
+                doc.reject(Rejection { timeline_id, lsn, reason })?;
+                Ok(None)
+            }
+            ReplicationMsg::Ping => Ok(Some(ReplicationMsg::Pong)),
+            ReplicationMsg::Pong => Ok(None),
+        }
+    }
+}
+
+/// Envelope tags a replication message with the channel it belongs to, which
+/// allows several documents to be replicated over a single connection. the
+/// channel is usually the document id.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct Envelope {
+    pub channel: JournalId,
+    pub msg: ReplicationMsg,
+}
+
+/// MultiplexedProtocol runs an independent ReplicationProtocol for each
+/// channel, so that every channel has its own outstanding range and window.
+/// both sides must open a channel before using it, and messages for channels
+/// which aren't open are rejected, so a remote can't allocate channels
+#[derive(Debug)]
+pub struct MultiplexedProtocol {
+    // new channels are configured like this protocol
+    template: ReplicationProtocol,
+    max_channels: usize,
+    channels: HashMap<JournalId, ReplicationProtocol>,
+}
+
+impl Default for MultiplexedProtocol {
+    fn default() -> Self {
+        Self::with_protocol(ReplicationProtocol::new())
+    }
+}
+
+impl MultiplexedProtocol {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// configure every channel like protocol, which must not have been started
+    pub fn with_protocol(protocol: ReplicationProtocol) -> Self {
+        Self {
+            template: protocol,
+            max_channels: MAX_CHANNELS,
+            channels: HashMap::new(),
+        }
+    }
+
+    /// limit the number of channels which can be open at once
+    pub fn with_max_channels(self, max_channels: usize) -> Self {
+        assert!(max_channels > 0, "max_channels must be > 0");
+        Self { max_channels, ..self }
+    }
+
+    /// open a channel, which does nothing if it's already open. fails if
+    /// max_channels are already open
+    pub fn open(&mut self, channel: JournalId) -> Result<(), ReplicationError> {
+        if !self.channels.contains_key(&channel) {
+            if self.channels.len() >= self.max_channels {
+                return Err(ReplicationError::TooManyChannels { max: self.max_channels });
             }
+            self.channels.insert(channel, self.template.clone());
         }
+        Ok(())
+    }
+
+    /// start replicating doc over an open channel, see
+    /// ReplicationProtocol::start
+    pub fn start<D: ReplicationSource>(
+        &mut self,
+        channel: JournalId,
+        doc: &D,
+    ) -> Result<Envelope, ReplicationError> {
+        let protocol = self.channel(channel)?;
+        Ok(Envelope { channel, msg: protocol.start(doc) })
+    }
+
+    pub fn initialized(&self, channel: JournalId) -> bool {
+        self.channels
+            .get(&channel)
+            .is_some_and(ReplicationProtocol::initialized)
+    }
+
+    /// stop tracking a channel, any frames still in flight are ignored
+    pub fn close(&mut self, channel: JournalId) {
+        self.channels.remove(&channel);
+    }
+
+    /// sync a frame from doc over channel, see ReplicationProtocol::sync
+    pub fn sync<'a, D: ReplicationSource>(
+        &mut self,
+        channel: JournalId,
+        doc: &'a D,
+    ) -> Result<Option<MultiplexedFrame<'a, D>>, ReplicationError> {
+        let protocol = self.channel(channel)?;
+        Ok(protocol
+            .sync(doc)?
+            .map(|(msg, reader)| (Envelope { channel, msg }, reader)))
+    }
+
+    /// handle a message addressed to doc, the caller is responsible for
+    /// routing envelopes to the document for their channel, which must be
+    /// open
+    pub fn handle<D: ReplicationDestination>(
+        &mut self,
+        doc: &mut D,
+        envelope: Envelope,
+        connection: &mut impl io::Read,
+    ) -> Result<Option<Envelope>, ReplicationError> {
+        let Envelope { channel, msg } = envelope;
+        let protocol = self.channel(channel)?;
+        Ok(protocol
+            .handle(doc, msg, connection)?
+            .map(|msg| Envelope { channel, msg }))
+    }
+
+    fn channel(
+        &mut self,
+        channel: JournalId,
+    ) -> Result<&mut ReplicationProtocol, ReplicationError> {
+        self.channels
+            .get_mut(&channel)
+            .ok_or(ReplicationError::UnknownJournal(channel))
     }
 }
 
@@ -155,6 +813,16 @@ pub trait ReplicationSource {
 
     /// read the given lsn from the source journal if it exists
     fn read_lsn(&self, lsn: Lsn) -> io::Result<Option<Self::Reader<'_>>>;
+
+    /// read a single frame containing the net effect of every frame in range
+    /// returns None if this source can't coalesce the range
+    fn read_coalesced(&self, _range: LsnRange) -> io::Result<Option<Self::Reader<'_>>> {
+        Ok(None)
+    }
+
+    /// called by ReplicationProtocol::sync once it returns the frames in
+    /// range to be sent, unlike read_lsn which is also used for planning
+    fn frames_sent(&self, _range: LsnRange) {}
 }
 
 pub trait ReplicationDestination {
@@ -169,6 +837,160 @@ pub trait ReplicationDestination {
     ) -> Result<(), ReplicationError>
     where
         R: io::Read;
+
+    /// write a frame produced by ReplicationSource::read_coalesced which
+    /// covers every lsn in range
+    fn write_coalesced<R>(
+        &mut self,
+        id: JournalId,
+        _range: LsnRange,
+        _reader: &mut R,
+    ) -> Result<(), ReplicationError>
+    where
+        R: io::Read,
+    {
+        Err(ReplicationError::CoalescingUnsupported(id))
+    }
+
+    /// report how much of journal id this destination has applied
+    fn applied_watermark(
+        &mut self,
+        _id: JournalId,
+    ) -> Result<Option<AppliedWatermark>, ReplicationError> {
+        Ok(None)
+    }
+
+    /// called on the source side of a connection when the remote reports
+    /// that it has applied part of a journal we sent it
+    fn acknowledge_applied(&mut self, _applied: AppliedWatermark) -> Result<(), ReplicationError> {
+        Ok(())
+    }
+
+    /// called on the source side of a connection when the remote refused to
+    /// apply an entry from a journal we sent it
+    fn reject(&mut self, _rejection: Rejection) -> Result<(), ReplicationError> {
+        Ok(())
+    }
+}
+
+/// write a sequence of previously persisted frames from journal id into dest,
+/// for example to rehydrate storage from durable frames when a backend
+/// starts. frames must have contiguous lsns, and dest must accept the first
+/// one. returns the range of lsns that were replayed
+pub fn replay_frames<D, R>(
+    dest: &mut D,
+    id: JournalId,
+    frames: impl Iterator<Item = (Lsn, R)>,
+) -> Result<LsnRange, ReplicationError>
+where
+    D: ReplicationDestination,
+    R: io::Read,
+{
+    let mut replayed: Option<LsnRange> = None;
+    for (lsn, mut reader) in frames {
+        if let Some(range) = replayed {
+            if lsn != range.next() {
+                return Err(ReplicationError::NonContiguousLsn {
+                    received: lsn,
+                    range: LsnRange::new(range.next(), range.next()),
+                });
+            }
+        }
+        dest.write_lsn(id, lsn, &mut reader)?;
+        replayed = Some(match replayed {
+            Some(range) => range.append(lsn),
+            None => LsnRange::new(lsn, lsn),
+        });
+    }
+    Ok(replayed.unwrap_or_else(LsnRange::empty))
+}
+
+/// FrameReader reads either a frame stored in a journal or a coalesced frame
+/// which was built on demand
+pub enum FrameReader<R> {
+    Frame(R),
+    Coalesced(PositionedCursor<Vec<u8>>),
+}
+
+impl<R: PositionedReader> PositionedReader for FrameReader<R> {
+    fn read_at(&self, pos: usize, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            FrameReader::Frame(reader) => reader.read_at(pos, buf),
+            FrameReader::Coalesced(reader) => reader.read_at(pos, buf),
+        }
+    }
+
+    fn size(&self) -> io::Result<usize> {
+        match self {
+            FrameReader::Frame(reader) => reader.size(),
+            FrameReader::Coalesced(reader) => reader.size(),
+        }
+    }
+}
+
+impl<R: io::Read> io::Read for FrameReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            FrameReader::Frame(reader) => reader.read(buf),
+            FrameReader::Coalesced(reader) => reader.read(buf),
+        }
+    }
+}
+
+/// CodecReader either passes a frame through untouched, or holds a frame
+/// which was compressed or decompressed in memory
+pub enum CodecReader<R> {
+    Raw(R),
+    Buffered(PositionedCursor<Vec<u8>>),
+}
+
+impl<R: PositionedReader> PositionedReader for CodecReader<R> {
+    fn read_at(&self, pos: usize, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            CodecReader::Raw(reader) => reader.read_at(pos, buf),
+            CodecReader::Buffered(reader) => reader.read_at(pos, buf),
+        }
+    }
+
+    fn size(&self) -> io::Result<usize> {
+        match self {
+            CodecReader::Raw(reader) => reader.size(),
+            CodecReader::Buffered(reader) => reader.size(),
+        }
+    }
+}
+
+impl<R: io::Read> io::Read for CodecReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            CodecReader::Raw(reader) => reader.read(buf),
+            CodecReader::Buffered(reader) => reader.read(buf),
+        }
+    }
+}
+
+/// decompress a frame of len bytes read from reader, uncompressed frames are
+/// passed through to be streamed into the destination
+fn decompress_frame<R: io::Read>(
+    compression: Compression,
+    len: u64,
+    mut reader: R,
+    max_frame_size: usize,
+) -> io::Result<CodecReader<R>> {
+    if compression == Compression::None {
+        return Ok(CodecReader::Raw(reader));
+    }
+    // frames are only compressed if doing so shrinks them, so a compressed
+    // frame which is larger than max_frame_size can be refused before it's
+    // read into memory
+    if len > max_frame_size as u64 {
+        return Err(frame_too_large(len, max_frame_size));
+    }
+    let mut data = Vec::with_capacity(len as usize);
+    reader.read_to_end(&mut data)?;
+    Ok(CodecReader::Buffered(PositionedCursor::new(
+        compression.decompress(&data, max_frame_size)?,
+    )))
 }
 
 /// LimitedReader is basically io::Take but over a mutable ref
@@ -189,3 +1011,617 @@ impl<'a, R: io::Read> io::Read for LimitedReader<'a, R> {
         Ok(n)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        duplex::DuplexReplication,
+        page::DEFAULT_PAGESIZE,
+        storage::Storage,
+        test_helpers::{open_coordinator, open_local, replicate, TestLocal},
+        Journal, MemoryJournal, Scannable,
+    };
+    use rand::RngCore;
+
+    use super::*;
+
+    #[test]
+    fn test_sync_plan() -> anyhow::Result<()> {
+        let id = JournalId::new128(&mut rand::thread_rng());
+        let mut src = MemoryJournal::open(id)?;
+        let mut dest = MemoryJournal::open(id)?;
+        for i in 0..150 {
+            src.append(vec![0u8; i].as_slice())?;
+        }
+
+        let mut protocol = ReplicationProtocol::new();
+        let mut dest_protocol = ReplicationProtocol::new();
+
+        // nothing can be sent until the destination replies with its range
+        assert_eq!(protocol.sync_plan(&src)?.frames, 0);
+
+        let msg = protocol.start(&src);
+        let resp = dest_protocol
+            .handle(&mut dest, msg, &mut io::empty())?
+            .unwrap();
+        protocol.handle(&mut src, resp, &mut io::empty())?;
+
+        let plan = protocol.sync_plan(&src)?;
+        assert_eq!(plan.frames, MAX_OUTSTANDING_FRAMES);
+        assert_eq!(
+            plan.lsn_range,
+            LsnRange::new(0, MAX_OUTSTANDING_FRAMES as Lsn - 1)
+        );
+
+        // the plan should match what sync actually sends
+        let mut sent_lsns = LsnRange::empty();
+        let mut sent_bytes = 0;
+        let mut last_resp = None;
+        while let Some((msg, reader)) = protocol.sync(&src)? {
+            if let ReplicationMsg::Frame { lsn, len, .. } = msg {
+                sent_lsns = sent_lsns.append(lsn);
+                sent_bytes += len;
+            }
+            let frame = reader.read_all()?;
+            last_resp = dest_protocol.handle(&mut dest, msg, &mut frame.as_slice())?;
+        }
+        assert_eq!(plan.frames, sent_lsns.len());
+        assert_eq!(plan.bytes, sent_bytes);
+        assert_eq!(plan.lsn_range, sent_lsns);
+
+        // the window is full, so nothing more can be sent
+        assert_eq!(protocol.sync_plan(&src)?.frames, 0);
+
+        // once the destination acknowledges the frames, the remainder can be sent
+        protocol.handle(&mut src, last_resp.unwrap(), &mut io::empty())?;
+        let plan = protocol.sync_plan(&src)?;
+        assert_eq!(plan.frames, 50);
+        assert_eq!(plan.lsn_range, LsnRange::new(100, 149));
+        assert_eq!(plan.bytes, (100..150).sum::<u64>());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_heartbeat() -> anyhow::Result<()> {
+        let id = JournalId::new128(&mut rand::thread_rng());
+        let mut src = MemoryJournal::open(id)?;
+        let mut dest = MemoryJournal::open(id)?;
+
+        let heartbeat = Heartbeat { interval_ms: 1000, timeout_ms: 500 };
+        let mut protocol = ReplicationProtocol::new().with_heartbeat(heartbeat);
+        let mut dest_protocol = ReplicationProtocol::new();
+        let msg = protocol.start(&src);
+        let resp = dest_protocol
+            .handle(&mut dest, msg, &mut io::empty())?
+            .unwrap();
+        protocol.handle(&mut src, resp, &mut io::empty())?;
+
+        // idle connections send a ping every interval
+        assert!(protocol.tick(0)?.is_none());
+        assert!(protocol.tick(999)?.is_none());
+        let ping = protocol.tick(1000)?.unwrap();
+        assert!(matches!(ping, ReplicationMsg::Ping));
+
+        // which the remote answers, even without heartbeats enabled
+        let pong = dest_protocol
+            .handle(&mut dest, ping, &mut io::empty())?
+            .unwrap();
+        assert!(matches!(pong, ReplicationMsg::Pong));
+        assert!(protocol.tick(1400)?.is_none());
+        assert!(protocol.handle(&mut src, pong, &mut io::empty())?.is_none());
+
+        // sending frames postpones the next ping
+        src.append([1u8].as_slice())?;
+        assert!(protocol.sync(&src)?.is_some());
+        assert!(protocol.tick(1500)?.is_none());
+        assert!(protocol.tick(2400)?.is_none());
+        assert!(matches!(protocol.tick(2500)?, Some(ReplicationMsg::Ping)));
+
+        // an unanswered ping fails the connection after the timeout
+        assert!(protocol.tick(2999)?.is_none());
+        assert!(matches!(
+            protocol.tick(3000),
+            Err(ReplicationError::HeartbeatTimeout { timeout_ms: 500 })
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_window() -> anyhow::Result<()> {
+        let id = JournalId::new128(&mut rand::thread_rng());
+        let mut src = MemoryJournal::open(id)?;
+        let mut dest = MemoryJournal::open(id)?;
+        for i in 0..3u8 {
+            src.append([i].as_slice())?;
+        }
+
+        let mut protocol = ReplicationProtocol::new().with_window(1);
+        let mut dest_protocol = ReplicationProtocol::new();
+        let msg = protocol.start(&src);
+        let resp = dest_protocol
+            .handle(&mut dest, msg, &mut io::empty())?
+            .unwrap();
+        protocol.handle(&mut src, resp, &mut io::empty())?;
+
+        for lsn in 0..3 {
+            let (msg, reader) = protocol.sync(&src)?.unwrap();
+            assert!(matches!(msg, ReplicationMsg::Frame { lsn: l, .. } if l == lsn));
+            let frame = reader.read_all()?;
+
+            // nothing more can be sent until the frame is acknowledged
+            assert!(protocol.sync(&src)?.is_none());
+            assert_eq!(protocol.sync_plan(&src)?.frames, 0);
+
+            let ack = dest_protocol
+                .handle(&mut dest, msg, &mut frame.as_slice())?
+                .unwrap();
+            protocol.handle(&mut src, ack, &mut io::empty())?;
+        }
+        assert!(protocol.sync(&src)?.is_none());
+        assert_eq!(dest.range(), LsnRange::new(0, 2));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resume() -> anyhow::Result<()> {
+        let id = JournalId::new128(&mut rand::thread_rng());
+        let mut src = MemoryJournal::open(id)?;
+        let mut dest = MemoryJournal::open(id)?;
+        for i in 0..10u8 {
+            src.append([i].as_slice())?;
+        }
+
+        let mut protocol = ReplicationProtocol::new();
+        let mut dest_protocol = ReplicationProtocol::new();
+        assert_eq!(protocol.last_acked(), None);
+        let msg = protocol.start(&src);
+        let resp = dest_protocol
+            .handle(&mut dest, msg, &mut io::empty())?
+            .unwrap();
+        protocol.handle(&mut src, resp, &mut io::empty())?;
+        assert_eq!(protocol.last_acked(), None);
+
+        // send four frames, but disconnect after only two are acknowledged
+        let mut frames = vec![];
+        for _ in 0..4 {
+            let (msg, reader) = protocol.sync(&src)?.unwrap();
+            frames.push((msg, reader.read_all()?));
+        }
+        for (msg, frame) in frames.drain(..2) {
+            let ack = dest_protocol
+                .handle(&mut dest, msg, &mut frame.as_slice())?
+                .unwrap();
+            protocol.handle(&mut src, ack, &mut io::empty())?;
+        }
+        let last_acked = protocol.last_acked().unwrap();
+        assert_eq!(last_acked, 1);
+
+        // the resumed protocol can send frames before the destination replies
+        let mut protocol = ReplicationProtocol::new().resume(last_acked);
+        let mut dest_protocol = ReplicationProtocol::new();
+        let msg = protocol.start(&src);
+        let range_resp = dest_protocol
+            .handle(&mut dest, msg, &mut io::empty())?
+            .unwrap();
+
+        let mut sent = LsnRange::empty_following(&LsnRange::new(0, last_acked));
+        let mut acks = vec![];
+        while let Some((msg, reader)) = protocol.sync(&src)? {
+            if let ReplicationMsg::Frame { lsn, .. } = msg {
+                sent = sent.append(lsn);
+            }
+            let frame = reader.read_all()?;
+            acks.push(dest_protocol.handle(&mut dest, msg, &mut frame.as_slice())?);
+        }
+        // only the unacknowledged suffix is transmitted
+        assert_eq!(sent, LsnRange::new(2, 9));
+
+        protocol.handle(&mut src, range_resp, &mut io::empty())?;
+        for ack in acks.into_iter().flatten() {
+            protocol.handle(&mut src, ack, &mut io::empty())?;
+        }
+        assert_eq!(protocol.last_acked(), Some(9));
+        assert_eq!(dest.range(), LsnRange::new(0, 9));
+
+        // resuming past a destination which is missing frames starts over
+        // from the destination's range
+        let mut behind = MemoryJournal::open(id)?;
+        let mut protocol = ReplicationProtocol::new().resume(9);
+        let msg = protocol.start(&src);
+        let resp = ReplicationProtocol::new()
+            .handle(&mut behind, msg, &mut io::empty())?
+            .unwrap();
+        protocol.handle(&mut src, resp, &mut io::empty())?;
+        assert_eq!(protocol.last_acked(), None);
+        assert_eq!(protocol.sync_plan(&src)?.lsn_range, LsnRange::new(0, 9));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_compression_round_trip() -> anyhow::Result<()> {
+        let mut random = vec![0u8; 4096];
+        rand::thread_rng().fill_bytes(&mut random);
+        let mut sparse = vec![0u8; 4096];
+        sparse[10..20].copy_from_slice(b"0123456789");
+        let frames = [sparse, random, b"tiny".to_vec()];
+        let raw_bytes: u64 = frames.iter().map(|f| f.len() as u64).sum();
+
+        let mut codecs = vec![Compression::None, Compression::Lz4];
+        if Compression::Zstd.is_supported() {
+            codecs.push(Compression::Zstd);
+        }
+        for codec in codecs {
+            let id = JournalId::new128(&mut rand::thread_rng());
+            let mut src = MemoryJournal::open(id)?;
+            let mut dest = MemoryJournal::open(id)?;
+            for frame in frames.iter() {
+                src.append(frame.as_slice())?;
+            }
+
+            let mut protocol = ReplicationProtocol::new().with_compression(codec);
+            let mut dest_protocol = ReplicationProtocol::new().with_compression(codec);
+            let msg = protocol.start(&src);
+            let resp = dest_protocol
+                .handle(&mut dest, msg, &mut io::empty())?
+                .unwrap();
+            protocol.handle(&mut src, resp, &mut io::empty())?;
+
+            let mut sent_bytes = 0;
+            while let Some((msg, reader)) = protocol.sync(&src)? {
+                let frame = reader.read_all()?;
+                if let ReplicationMsg::Frame { len, .. } = msg {
+                    assert_eq!(len, frame.len() as u64);
+                    sent_bytes += len;
+                }
+                dest_protocol.handle(&mut dest, msg, &mut frame.as_slice())?;
+            }
+
+            // every frame must arrive byte for byte
+            for (lsn, frame) in frames.iter().enumerate() {
+                assert_eq!(&dest.get(lsn as Lsn)?.unwrap().read_all()?, frame);
+            }
+            match codec {
+                Compression::None => assert_eq!(sent_bytes, raw_bytes),
+                _ => assert!(sent_bytes < raw_bytes),
+            }
+        }
+
+        // a destination which doesn't use the requested codec falls back to none
+        let id = JournalId::new128(&mut rand::thread_rng());
+        let src = MemoryJournal::open(id)?;
+        let mut dest = MemoryJournal::open(id)?;
+        let msg = ReplicationProtocol::new()
+            .with_compression(Compression::Lz4)
+            .start(&src);
+        let resp = ReplicationProtocol::new().handle(&mut dest, msg, &mut io::empty())?;
+        assert!(matches!(
+            resp,
+            Some(ReplicationMsg::Range { compression: Some(Compression::None), .. })
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_frame_size() -> anyhow::Result<()> {
+        let mut codecs = vec![Compression::Lz4];
+        if Compression::Zstd.is_supported() {
+            codecs.push(Compression::Zstd);
+        }
+        for codec in codecs {
+            let id = JournalId::new128(&mut rand::thread_rng());
+            let mut dest = MemoryJournal::open(id)?;
+            let mut dest_protocol = ReplicationProtocol::new()
+                .with_compression(codec)
+                .with_max_frame_size(4096);
+            let frame = |len: u64| ReplicationMsg::Frame { id, lsn: 0, len, compression: codec };
+
+            // a frame which fits is accepted
+            let small = codec.compress(&[0; 4096])?;
+            dest_protocol.handle(&mut dest, frame(small.len() as u64), &mut small.as_slice())?;
+
+            // while one which decompresses past the limit is refused
+            let bomb = codec.compress(&vec![0; 512 * 1024])?;
+            assert!(bomb.len() < 4096);
+            assert!(dest_protocol
+                .handle(&mut dest, frame(bomb.len() as u64), &mut bomb.as_slice())
+                .is_err());
+
+            // as is a compressed frame which is larger than the limit
+            let mut connection = io::repeat(0);
+            assert!(dest_protocol
+                .handle(&mut dest, frame(1 << 40), &mut connection)
+                .is_err());
+            assert_eq!(dest.range(), LsnRange::new(0, 0));
+        }
+
+        // an lz4 frame claiming a huge size is refused before allocating it
+        let mut forged = u32::MAX.to_le_bytes().to_vec();
+        forged.extend_from_slice(&[0; 8]);
+        assert!(Compression::Lz4.decompress(&forged, 4096).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_latest_only() -> anyhow::Result<()> {
+        let doc_id = JournalId::new128(&mut rand::thread_rng());
+        let mut writer = open_local(doc_id)?;
+        let mut coordinator = open_coordinator(doc_id)?;
+        let mut writer_to_coordinator = ReplicationProtocol::new();
+
+        writer.mutate(b"CREATE TABLE counter (value INTEGER)")?;
+        writer.mutate(b"INSERT INTO counter VALUES (0)")?;
+        for _ in 0..20 {
+            writer.mutate(b"UPDATE counter SET value = value + 1")?;
+            replicate(&mut writer_to_coordinator, &writer, &mut coordinator)?;
+            while coordinator.has_pending_work() {
+                coordinator.step()?;
+            }
+        }
+        let history = coordinator.source_range();
+        assert!(history.len() > 20);
+
+        let query_counter = |doc: &crate::test_helpers::TestLocal| -> anyhow::Result<i64> {
+            Ok(doc
+                .query(|conn| conn.query_row("SELECT value FROM counter", [], |row| row.get(0)))?)
+        };
+
+        // a client far behind receives a single frame containing only the
+        // latest version of each changed page
+        let mut reader = open_local(doc_id)?;
+        let mut coordinator_to_reader = ReplicationProtocol::with_mode(ReplicationMode::LatestOnly);
+        let msg = coordinator_to_reader.start(&coordinator);
+        let resp = ReplicationProtocol::new()
+            .handle(&mut reader, msg, &mut io::empty())?
+            .unwrap();
+        coordinator_to_reader.handle(&mut coordinator, resp, &mut io::empty())?;
+
+        let plan = coordinator_to_reader.sync_plan(&coordinator)?;
+        assert_eq!(plan.frames, 1);
+        assert_eq!(plan.lsn_range, history);
+        let mut history_bytes = 0;
+        for lsn in history.iter() {
+            history_bytes += coordinator.read_lsn(lsn)?.unwrap().size()? as u64;
+        }
+        assert!(plan.bytes < history_bytes);
+
+        assert_eq!(
+            replicate(&mut coordinator_to_reader, &coordinator, &mut reader)?,
+            1
+        );
+        reader.rebase()?;
+        assert_eq!(reader.storage_lsn(), history.last());
+        assert_eq!(query_counter(&reader)?, 20);
+        reader.verify()?;
+
+        // once caught up, new frames are sent as usual
+        writer.mutate(b"UPDATE counter SET value = value + 1")?;
+        replicate(&mut writer_to_coordinator, &writer, &mut coordinator)?;
+        coordinator.step()?;
+        assert_eq!(
+            replicate(&mut coordinator_to_reader, &coordinator, &mut reader)?,
+            1
+        );
+        reader.rebase()?;
+        assert_eq!(reader.storage_lsn(), coordinator.source_range().last());
+        assert_eq!(query_counter(&reader)?, 21);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_multiplexed() -> anyhow::Result<()> {
+        use std::collections::VecDeque;
+
+        let ids: Vec<_> = (0..2)
+            .map(|_| JournalId::new128(&mut rand::thread_rng()))
+            .collect();
+        let mut srcs = HashMap::new();
+        let mut dests = HashMap::new();
+        for (&id, frames) in ids.iter().zip([3u8, 5]) {
+            let mut src = MemoryJournal::open(id)?;
+            for i in 0..frames {
+                src.append([i].as_slice())?;
+            }
+            srcs.insert(id, src);
+            dests.insert(id, MemoryJournal::open(id)?);
+        }
+
+        // a single duplex pipe carries both documents, a small window ensures
+        // each channel is acknowledged separately
+        let mut client =
+            MultiplexedProtocol::with_protocol(ReplicationProtocol::new().with_window(2));
+        let mut server = MultiplexedProtocol::new();
+        let mut to_server: VecDeque<Vec<u8>> = VecDeque::new();
+        let mut to_client: VecDeque<Vec<u8>> = VecDeque::new();
+        let send = |pipe: &mut VecDeque<Vec<u8>>, envelope: &Envelope, frame: &[u8]| {
+            let mut buf = bincode::serialize(envelope).unwrap();
+            buf.extend_from_slice(frame);
+            pipe.push_back(buf);
+        };
+
+        for &id in &ids {
+            client.open(id)?;
+            server.open(id)?;
+            send(&mut to_server, &client.start(id, &srcs[&id])?, &[]);
+        }
+        loop {
+            for &id in &ids {
+                if client.initialized(id) {
+                    while let Some((envelope, reader)) = client.sync(id, &srcs[&id])? {
+                        send(&mut to_server, &envelope, &reader.read_all()?);
+                    }
+                }
+            }
+            if to_server.is_empty() {
+                break;
+            }
+            while let Some(buf) = to_server.pop_front() {
+                let mut buf = buf.as_slice();
+                let envelope: Envelope = bincode::deserialize_from(&mut buf)?;
+                let dest = dests.get_mut(&envelope.channel).unwrap();
+                if let Some(resp) = server.handle(dest, envelope, &mut buf)? {
+                    send(&mut to_client, &resp, &[]);
+                }
+            }
+            while let Some(buf) = to_client.pop_front() {
+                let mut buf = buf.as_slice();
+                let envelope: Envelope = bincode::deserialize_from(&mut buf)?;
+                let src = srcs.get_mut(&envelope.channel).unwrap();
+                client.handle(src, envelope, &mut buf)?;
+            }
+        }
+
+        // each destination only received its own document's frames
+        for &id in &ids {
+            assert_eq!(dests[&id].range(), srcs[&id].range());
+            let mut src = srcs[&id].scan();
+            let mut dest = dests[&id].scan();
+            while src.advance()? {
+                assert!(dest.advance()?);
+                assert_eq!(src.read_all()?, dest.read_all()?);
+            }
+            assert!(!dest.advance()?);
+        }
+        assert_eq!(dests[&ids[0]].range(), LsnRange::new(0, 2));
+        assert_eq!(dests[&ids[1]].range(), LsnRange::new(0, 4));
+
+        // channels must be opened before they are used
+        let unknown = JournalId::new128(&mut rand::thread_rng());
+        assert!(matches!(
+            client.sync(unknown, &srcs[&ids[0]]),
+            Err(ReplicationError::UnknownJournal(id)) if id == unknown
+        ));
+        let envelope = Envelope {
+            channel: unknown,
+            msg: ReplicationMsg::Ping,
+        };
+        assert!(matches!(
+            server.handle(dests.get_mut(&ids[0]).unwrap(), envelope, &mut io::empty()),
+            Err(ReplicationError::UnknownJournal(id)) if id == unknown
+        ));
+
+        // and only max_channels can be open at once
+        let mut capped = MultiplexedProtocol::new().with_max_channels(2);
+        capped.open(ids[0])?;
+        capped.open(ids[1])?;
+        capped.open(ids[1])?;
+        assert!(matches!(
+            capped.open(unknown),
+            Err(ReplicationError::TooManyChannels { max: 2 })
+        ));
+        capped.close(ids[0]);
+        capped.open(unknown)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_replay_frames() -> anyhow::Result<()> {
+        let doc_id = JournalId::new128(&mut rand::thread_rng());
+        let mut local = open_local(doc_id)?;
+        let mut coordinator = open_coordinator(doc_id)?;
+        let mut protocol = ReplicationProtocol::new();
+        for mutation in [
+            "CREATE TABLE people (name TEXT)",
+            "INSERT INTO people VALUES ('alice'), ('bob')",
+            "DELETE FROM people WHERE name = 'alice'",
+        ] {
+            local.mutate(mutation.as_bytes())?;
+            replicate(&mut protocol, &local, &mut coordinator)?;
+            while coordinator.has_pending_work() {
+                coordinator.step()?;
+            }
+        }
+
+        // capture the coordinator's frames as a backend would persist them
+        let range = coordinator.source_range();
+        assert_eq!(range, LsnRange::new(0, 3));
+        let frames = range
+            .iter()
+            .map(|lsn| {
+                let reader = coordinator.read_lsn(lsn)?.expect("lsn is in range");
+                Ok((lsn, reader.read_all()?))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let mut storage = Storage::new(MemoryJournal::open(doc_id)?, DEFAULT_PAGESIZE)?;
+        let replayed = replay_frames(
+            &mut storage,
+            doc_id,
+            frames.iter().map(|(lsn, frame)| (*lsn, frame.as_slice())),
+        )?;
+        assert_eq!(replayed, range);
+        storage.reset()?;
+
+        let mut expected = Vec::new();
+        coordinator.export_snapshot(&mut expected)?;
+        let mut actual = Vec::new();
+        storage.export_snapshot(&mut actual)?;
+        assert_eq!(actual, expected);
+
+        // a gap in the persisted frames is rejected
+        let mut storage = Storage::new(MemoryJournal::open(doc_id)?, DEFAULT_PAGESIZE)?;
+        let gapped = frames
+            .iter()
+            .filter(|(lsn, _)| *lsn != 1)
+            .map(|(lsn, frame)| (*lsn, frame.as_slice()));
+        assert!(matches!(
+            replay_frames(&mut storage, doc_id, gapped),
+            Err(ReplicationError::NonContiguousLsn { received: 2, .. })
+        ));
+
+        Ok(())
+    }
+
+    fn query_names(doc: &TestLocal) -> anyhow::Result<Vec<String>> {
+        Ok(doc.query(|conn| {
+            let mut stmt = conn.prepare("SELECT name FROM people ORDER BY name")?;
+            let rows = stmt.query_map([], |row| row.get(0))?;
+            rows.collect::<rusqlite::Result<Vec<String>>>()
+        })?)
+    }
+
+    #[test]
+    fn test_duplex_replication() -> anyhow::Result<()> {
+        let doc_id = JournalId::new128(&mut rand::thread_rng());
+        let mut alice = open_local(doc_id)?;
+        let mut bob = open_local(doc_id)?;
+        let mut coordinator = open_coordinator(doc_id)?;
+        let mut alice_conn = DuplexReplication::new();
+        let mut bob_conn = DuplexReplication::new();
+
+        alice.mutate(b"CREATE TABLE people (name TEXT)")?;
+        alice.mutate(b"INSERT INTO people VALUES ('alice')")?;
+
+        // timelines flow to the coordinator, and storage flows back
+        let mut sync = |client: &mut TestLocal, conn: &mut DuplexReplication| {
+            conn.replicate(client, &mut coordinator)?;
+            while coordinator.has_pending_work() {
+                coordinator.step()?;
+            }
+            conn.replicate(client, &mut coordinator)?;
+            client.rebase()?;
+            assert_eq!(client.storage_lsn(), coordinator.source_range().last());
+            anyhow::Ok(())
+        };
+        sync(&mut alice, &mut alice_conn)?;
+        sync(&mut bob, &mut bob_conn)?;
+        assert_eq!(query_names(&bob)?, vec!["alice"]);
+
+        bob.mutate(b"INSERT INTO people VALUES ('bob')")?;
+        sync(&mut bob, &mut bob_conn)?;
+        sync(&mut alice, &mut alice_conn)?;
+        assert_eq!(query_names(&alice)?, vec!["alice", "bob"]);
+
+        // once quiescent, nothing is left to exchange
+        assert_eq!(alice_conn.replicate(&mut alice, &mut coordinator)?, 0);
+
+        Ok(())
+    }
+}
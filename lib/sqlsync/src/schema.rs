@@ -0,0 +1,109 @@
+use std::collections::{HashMap, HashSet};
+
+use rusqlite::Connection;
+
+use crate::PageIdx;
+
+const ROOT_PAGES_READ_SQL: &str = "
+    SELECT rootpage, tbl_name
+    FROM sqlite_master
+    WHERE rootpage > 0
+";
+
+const SCHEMA_READ_SQL: &str = "
+    SELECT type, name, tbl_name, rootpage, sql
+    FROM sqlite_master
+";
+
+#[derive(Debug, PartialEq)]
+struct SchemaEntry {
+    tbl_name: String,
+    rootpage: PageIdx,
+    sql: Option<String>,
+}
+
+/// SchemaTracker remembers the contents of sqlite_master, which allows a
+/// schema change that only dropped tables to be distinguished from any other
+/// kind of schema change
+#[derive(Debug, Default)]
+pub struct SchemaTracker {
+    // keyed by (type, name), None until the schema has been read
+    entries: Option<HashMap<(String, String), SchemaEntry>>,
+}
+
+impl SchemaTracker {
+    /// read the current schema and compare it to the schema seen by the last
+    /// call, returning the sorted root pages of every dropped btree if the
+    /// only change was dropping tables
+    pub fn update(&mut self, sqlite: &Connection) -> rusqlite::Result<Option<Vec<PageIdx>>> {
+        let mut stmt = sqlite.prepare_cached(SCHEMA_READ_SQL)?;
+        let entries = stmt
+            .query_map([], |row| {
+                Ok((
+                    (row.get(0)?, row.get(1)?),
+                    SchemaEntry {
+                        tbl_name: row.get(2)?,
+                        rootpage: row.get::<_, Option<PageIdx>>(3)?.unwrap_or(0),
+                        sql: row.get(4)?,
+                    },
+                ))
+            })?
+            .collect::<rusqlite::Result<HashMap<_, _>>>()?;
+
+        let dropped = self
+            .entries
+            .as_ref()
+            .and_then(|prev| dropped_root_pages(prev, &entries));
+        self.entries = Some(entries);
+        Ok(dropped)
+    }
+}
+
+fn dropped_root_pages(
+    prev: &HashMap<(String, String), SchemaEntry>,
+    next: &HashMap<(String, String), SchemaEntry>,
+) -> Option<Vec<PageIdx>> {
+    // every remaining entry must be unchanged, including its root page which
+    // sqlite relocates when dropping a table in auto_vacuum mode
+    if next.iter().any(|(key, entry)| prev.get(key) != Some(entry)) {
+        return None;
+    }
+
+    let dropped: Vec<_> = prev
+        .iter()
+        .filter(|(key, _)| !next.contains_key(*key))
+        .collect();
+    let dropped_tables: HashSet<&str> = dropped
+        .iter()
+        .filter(|((kind, _), _)| kind == "table")
+        .map(|((_, name), _)| name.as_str())
+        .collect();
+    if dropped_tables.is_empty() {
+        return None;
+    }
+
+    // indexes and triggers are dropped along with their table, dropping
+    // anything else (such as a view) is a general schema change
+    if !dropped
+        .iter()
+        .all(|(_, entry)| dropped_tables.contains(entry.tbl_name.as_str()))
+    {
+        return None;
+    }
+
+    // views and triggers don't have a btree
+    let mut root_pages: Vec<_> = dropped
+        .iter()
+        .map(|(_, entry)| entry.rootpage)
+        .filter(|&page| page != 0)
+        .collect();
+    root_pages.sort();
+    Some(root_pages)
+}
+
+/// map the root page of every table and index to the name of its table
+pub fn root_page_tables(sqlite: &Connection) -> rusqlite::Result<HashMap<PageIdx, String>> {
+    let mut stmt = sqlite.prepare_cached(ROOT_PAGES_READ_SQL)?;
+    let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    rows.collect()
+}
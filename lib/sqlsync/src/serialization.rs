@@ -5,6 +5,12 @@ use crate::positioned_io::PositionedReader;
 pub trait Serializable {
     /// serialize the object into the given writer
     fn serialize_into<W: io::Write>(&self, writer: &mut W) -> io::Result<()>;
+
+    /// the exact number of bytes serialize_into will write, if known
+    /// journals use this to allocate their backing buffer up front
+    fn serialized_len(&self) -> Option<usize> {
+        None
+    }
 }
 
 pub trait Deserializable: Sized {
@@ -16,4 +22,8 @@ impl Serializable for &[u8] {
     fn serialize_into<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
         writer.write_all(self)
     }
+
+    fn serialized_len(&self) -> Option<usize> {
+        Some(self.len())
+    }
 }
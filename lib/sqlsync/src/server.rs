@@ -0,0 +1,616 @@
+use std::{collections::BTreeMap, io};
+
+use thiserror::Error;
+
+use crate::{
+    coordinator::{CoordinatorDocument, CoordinatorMetrics, NoopMetrics},
+    error::Result,
+    journal::{Journal, JournalId},
+    logging,
+    persistence::FramePersistence,
+    positioned_io::PositionedReader,
+    reducer::Reducer,
+    replication::{
+        ReplicationDestination, ReplicationError, ReplicationMsg, ReplicationProtocol,
+        ReplicationSource,
+    },
+    unixtime::unix_timestamp_milliseconds,
+    Lsn, LsnRange,
+};
+
+pub type ClientId = u64;
+
+/// ClientSink queues messages for a connected client, usually for a task
+/// which writes them to the client's connection. sinks must never wait: a
+/// sink which is full fails with SendError::Full, and the client is dropped
+/// rather than stalling every other client
+pub trait ClientSink {
+    /// queue msg followed by frame, which is empty unless msg is a frame
+    fn try_send(&mut self, msg: ReplicationMsg, frame: &[u8]) -> Result<(), SendError>;
+}
+
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendError {
+    #[error("client outbox is full")]
+    Full,
+
+    #[error("client disconnected")]
+    Disconnected,
+}
+
+#[derive(Error, Debug)]
+pub enum ClientError {
+    #[error("unknown client {0}")]
+    UnknownClient(ClientId),
+
+    #[error(transparent)]
+    SendError(#[from] SendError),
+
+    #[error(transparent)]
+    ReplicationError(#[from] ReplicationError),
+
+    #[error("io error: {0}")]
+    IoError(#[from] io::Error),
+}
+
+#[derive(Error, Debug)]
+pub enum PersistError<E> {
+    #[error("io error: {0}")]
+    IoError(#[from] io::Error),
+
+    #[error("persistence error: {0}")]
+    PersistenceError(E),
+}
+
+/// CoordinatorServer runs a CoordinatorDocument on behalf of its clients,
+/// independently of how they connect. the caller passes each message a
+/// client sends to handle, calls step once messages have arrived and tick
+/// periodically, and everything sent to a client goes through its sink.
+/// clients which fail are dropped along with their sink, and the methods
+/// which act on every client return the clients they dropped
+pub struct CoordinatorServer<J: Journal, R, P, S, M = NoopMetrics> {
+    doc: CoordinatorDocument<J, R>,
+    persistence: P,
+    metrics: M,
+    // every client's protocol starts as a copy of this one
+    protocol: ReplicationProtocol,
+    clients: BTreeMap<ClientId, Client<S>>,
+    next_client_id: ClientId,
+}
+
+struct Client<S> {
+    protocol: ReplicationProtocol,
+    sink: S,
+    // the storage range reported by the client's last Range message
+    storage_range: Option<LsnRange>,
+    // the id of the client's timeline, known once it requests our range
+    timeline_id: Option<JournalId>,
+}
+
+impl<S: ClientSink> Client<S> {
+    fn send(&mut self, msg: ReplicationMsg) -> Result<(), ClientError> {
+        Ok(self.sink.try_send(msg, &[])?)
+    }
+
+    fn handle<D: ReplicationDestination>(
+        &mut self,
+        doc: &mut D,
+        msg: ReplicationMsg,
+        reader: &mut impl io::Read,
+    ) -> Result<(), ClientError> {
+        match msg {
+            ReplicationMsg::Range { range, .. } => self.storage_range = Some(range),
+            ReplicationMsg::RangeRequest { id, .. } => self.timeline_id = Some(id),
+            _ => {}
+        }
+        if let Some(resp) = self.protocol.handle(doc, msg, reader)? {
+            self.send(resp)?;
+        }
+        Ok(())
+    }
+
+    fn sync<D: ReplicationSource>(
+        &mut self,
+        doc: &D,
+        metrics: &mut impl CoordinatorMetrics,
+    ) -> Result<(), ClientError> {
+        while let Some((msg, reader)) = self.protocol.sync(doc)? {
+            let frame = reader.read_all()?;
+            self.sink.try_send(msg, &frame)?;
+            metrics.on_frame_sent(frame.len());
+        }
+        Ok(())
+    }
+}
+
+impl<J: Journal, R, P, S> CoordinatorServer<J, R, P, S> {
+    pub fn new(doc: CoordinatorDocument<J, R>, persistence: P) -> Self {
+        Self {
+            doc,
+            persistence,
+            metrics: NoopMetrics,
+            protocol: ReplicationProtocol::new(),
+            clients: BTreeMap::new(),
+            next_client_id: 0,
+        }
+    }
+}
+
+impl<J: Journal, R, P, S, M> CoordinatorServer<J, R, P, S, M> {
+    /// start each client's replication protocol from a copy of protocol,
+    /// which configures compression, heartbeats and the frame window
+    pub fn with_protocol(self, protocol: ReplicationProtocol) -> Self {
+        Self { protocol, ..self }
+    }
+
+    /// report events from this server to metrics
+    pub fn with_metrics<N: CoordinatorMetrics>(
+        self,
+        metrics: N,
+    ) -> CoordinatorServer<J, R, P, S, N> {
+        CoordinatorServer {
+            doc: self.doc,
+            persistence: self.persistence,
+            metrics,
+            protocol: self.protocol,
+            clients: self.clients,
+            next_client_id: self.next_client_id,
+        }
+    }
+
+    pub fn doc(&self) -> &CoordinatorDocument<J, R> {
+        &self.doc
+    }
+
+    pub fn doc_mut(&mut self) -> &mut CoordinatorDocument<J, R> {
+        &mut self.doc
+    }
+
+    pub fn metrics(&self) -> &M {
+        &self.metrics
+    }
+
+    pub fn num_clients(&self) -> usize {
+        self.clients.len()
+    }
+
+    /// the last storage lsn received by every connected client, or None if
+    /// there are no clients or any client hasn't received a frame yet
+    pub fn min_client_storage_lsn(&self) -> Option<Lsn> {
+        self.clients
+            .values()
+            .map(|client| client.storage_range.and_then(|range| range.last()))
+            .min()
+            .flatten()
+    }
+}
+
+impl<J, R, P, S, M> CoordinatorServer<J, R, P, S, M>
+where
+    J: Journal + ReplicationSource + ReplicationDestination,
+    R: Reducer,
+    P: FramePersistence,
+    S: ClientSink,
+    M: CoordinatorMetrics,
+{
+    pub fn has_pending_work(&self) -> bool {
+        self.doc.has_pending_work()
+    }
+
+    /// connect a client which receives messages through sink
+    pub fn connect(&mut self, sink: S) -> Result<ClientId, ClientError> {
+        let mut client = Client {
+            protocol: self.protocol.clone(),
+            sink,
+            storage_range: None,
+            timeline_id: None,
+        };
+        let msg = client.protocol.start(&self.doc);
+        client.send(msg)?;
+
+        self.next_client_id += 1;
+        let client_id = self.next_client_id;
+        self.clients.insert(client_id, client);
+        self.metrics.on_client_connect(self.clients.len());
+        Ok(client_id)
+    }
+
+    /// drop a client, usually because its connection closed
+    pub fn disconnect(&mut self, client_id: ClientId) {
+        if self.clients.remove(&client_id).is_some() {
+            self.metrics.on_client_disconnect(self.clients.len());
+        }
+    }
+
+    /// handle a message from a client, reading any frame which follows it
+    /// from reader. the client is dropped if this fails
+    pub fn handle(
+        &mut self,
+        client_id: ClientId,
+        msg: ReplicationMsg,
+        reader: &mut impl io::Read,
+    ) -> Result<(), ClientError> {
+        let client = self
+            .clients
+            .get_mut(&client_id)
+            .ok_or(ClientError::UnknownClient(client_id))?;
+        let result = client.handle(&mut self.doc, msg, reader);
+        if result.is_err() {
+            self.disconnect(client_id);
+        }
+        result
+    }
+
+    /// apply pending mutations for at most budget_ms, any remaining work is
+    /// left for the next step
+    pub fn step(&mut self, budget_ms: i64) -> Result<()> {
+        let start = unix_timestamp_milliseconds();
+        let result = self.doc.step_with_budget(budget_ms);
+        self.metrics.on_step(unix_timestamp_milliseconds() - start);
+        result
+    }
+
+    /// durably write every storage frame which hasn't been persisted yet
+    pub async fn persist(&mut self) -> Result<(), PersistError<P::Error>> {
+        let mut next_lsn = self.persistence.expected_lsn();
+        while let Some(frame) = self.doc.read_lsn(next_lsn)? {
+            let frame = frame.read_all()?;
+            let bytes = frame.len();
+            self.persistence
+                .write_lsn(next_lsn, frame)
+                .await
+                .map_err(PersistError::PersistenceError)?;
+            self.metrics.on_persist(next_lsn, bytes);
+            next_lsn = self.persistence.expected_lsn();
+        }
+        Ok(())
+    }
+
+    /// truncate storage to the last frame which is persisted and received
+    /// by every client, clients which connect later catch up from the
+    /// remaining frames
+    pub fn truncate(&mut self) -> Result<()> {
+        let persisted = self.persistence.expected_lsn().checked_sub(1);
+        if let (Some(persisted), Some(received)) = (persisted, self.min_client_storage_lsn()) {
+            self.doc.truncate_storage(persisted.min(received))?;
+        }
+        Ok(())
+    }
+
+    /// delete the outputs each client's timeline recorded in storage frames
+    /// the client has received, which it reads when it rebases
+    pub fn prune_outputs(&mut self) -> Result<()> {
+        for client in self.clients.values() {
+            let received = client.storage_range.and_then(|range| range.last());
+            if let (Some(id), Some(received)) = (client.timeline_id, received) {
+                self.doc.prune_outputs(id, received)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// send each rejection to the client which owns the rejected timeline,
+    /// clients that aren't connected drop the mutation when they next rebase
+    pub fn send_rejections(&mut self) -> Vec<(ClientId, ClientError)> {
+        let mut failed = vec![];
+        for rejection in self.doc.take_rejections() {
+            let owner = self
+                .clients
+                .iter_mut()
+                .find(|(_, client)| client.timeline_id == Some(rejection.timeline_id));
+            if let Some((&client_id, client)) = owner {
+                if let Err(e) = client.send(rejection.into()) {
+                    failed.push((client_id, e));
+                }
+            }
+        }
+        self.drop_failed(failed)
+    }
+
+    /// send every client the storage frames it's missing
+    pub fn sync(&mut self) -> Vec<(ClientId, ClientError)> {
+        let mut failed = vec![];
+        for (&client_id, client) in self.clients.iter_mut() {
+            if let Err(e) = client.sync(&self.doc, &mut self.metrics) {
+                failed.push((client_id, e));
+            }
+        }
+        self.drop_failed(failed)
+    }
+
+    /// ping idle clients and drop clients which stopped responding, now is
+    /// the current time in milliseconds
+    pub fn tick(&mut self, now: i64) -> Vec<(ClientId, ClientError)> {
+        let mut failed = vec![];
+        for (&client_id, client) in self.clients.iter_mut() {
+            let result = client
+                .protocol
+                .tick(now)
+                .map_err(ClientError::from)
+                .and_then(|msg| msg.map_or(Ok(()), |msg| client.send(msg)));
+            if let Err(e) = result {
+                failed.push((client_id, e));
+            }
+        }
+        self.drop_failed(failed)
+    }
+
+    fn drop_failed(
+        &mut self,
+        failed: Vec<(ClientId, ClientError)>,
+    ) -> Vec<(ClientId, ClientError)> {
+        for (client_id, e) in failed.iter() {
+            log::warn!(target: logging::REPLICATION, "dropping client {}: {}", client_id, e);
+            self.disconnect(*client_id);
+        }
+        failed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+
+    use futures::executor::block_on;
+
+    use crate::{
+        persistence::MemoryPersistence,
+        test_helpers::{open_coordinator, open_local, SqlReducer, TestLocal},
+        MemoryJournal,
+    };
+
+    use super::*;
+
+    type Packet = (ReplicationMsg, Vec<u8>);
+    type TestServer<M = NoopMetrics> =
+        CoordinatorServer<MemoryJournal, SqlReducer, MemoryPersistence, SyncSender<Packet>, M>;
+
+    impl ClientSink for SyncSender<Packet> {
+        fn try_send(&mut self, msg: ReplicationMsg, frame: &[u8]) -> Result<(), SendError> {
+            SyncSender::try_send(self, (msg, frame.to_vec())).map_err(|e| match e {
+                TrySendError::Full(_) => SendError::Full,
+                TrySendError::Disconnected(_) => SendError::Disconnected,
+            })
+        }
+    }
+
+    /// TestClient is a local document connected to a server, which only
+    /// receives what the server sent it when it exchanges messages
+    struct TestClient {
+        id: ClientId,
+        doc: TestLocal,
+        protocol: ReplicationProtocol,
+        started: bool,
+        inbox: Receiver<Packet>,
+    }
+
+    impl TestClient {
+        fn connect<M: CoordinatorMetrics>(
+            server: &mut TestServer<M>,
+            outbox_depth: usize,
+        ) -> anyhow::Result<Self> {
+            let doc = open_local(server.doc().source_id())?;
+            let (sink, inbox) = sync_channel(outbox_depth);
+            Ok(Self {
+                id: server.connect(sink)?,
+                doc,
+                protocol: ReplicationProtocol::new(),
+                started: false,
+                inbox,
+            })
+        }
+
+        /// handle everything the server sent us and send it our timeline,
+        /// until neither side has anything left to say
+        fn exchange<M: CoordinatorMetrics>(
+            &mut self,
+            server: &mut TestServer<M>,
+        ) -> anyhow::Result<()> {
+            if !self.started {
+                self.started = true;
+                server.handle(self.id, self.protocol.start(&self.doc), &mut io::empty())?;
+            }
+            loop {
+                let mut exchanged = 0;
+                while let Ok((msg, frame)) = self.inbox.try_recv() {
+                    let mut frame = frame.as_slice();
+                    if let Some(resp) = self.protocol.handle(&mut self.doc, msg, &mut frame)? {
+                        server.handle(self.id, resp, &mut io::empty())?;
+                    }
+                    exchanged += 1;
+                }
+                while let Some((msg, reader)) = self.protocol.sync(&self.doc)? {
+                    server.handle(self.id, msg, &mut reader.read_all()?.as_slice())?;
+                    exchanged += 1;
+                }
+                if exchanged == 0 {
+                    return Ok(());
+                }
+            }
+        }
+
+        fn storage_lsn(&mut self) -> Option<Lsn> {
+            self.doc.storage_lsn()
+        }
+    }
+
+    fn open_server() -> anyhow::Result<TestServer> {
+        let doc_id = JournalId::new128(&mut rand::thread_rng());
+        Ok(TestServer::new(
+            open_coordinator(doc_id)?,
+            MemoryPersistence::new(),
+        ))
+    }
+
+    /// apply and replicate a mutation from client
+    fn mutate<M: CoordinatorMetrics>(
+        server: &mut TestServer<M>,
+        client: &mut TestClient,
+        sql: &str,
+    ) -> anyhow::Result<()> {
+        client.doc.mutate(sql.as_bytes())?;
+        client.exchange(server)?;
+        server.step(60_000)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_min_client_storage_lsn() -> anyhow::Result<()> {
+        let mut server = open_server()?;
+        assert_eq!(server.min_client_storage_lsn(), None);
+
+        let mut a = TestClient::connect(&mut server, 128)?;
+        mutate(&mut server, &mut a, "CREATE TABLE t (x)")?;
+        assert!(server.sync().is_empty());
+        a.exchange(&mut server)?;
+        let first = a.storage_lsn().unwrap();
+        assert_eq!(server.min_client_storage_lsn(), Some(first));
+
+        // b hasn't received anything, so nothing can be truncated
+        let mut b = TestClient::connect(&mut server, 128)?;
+        assert_eq!(server.min_client_storage_lsn(), None);
+        b.exchange(&mut server)?;
+        assert_eq!(server.min_client_storage_lsn(), None);
+        assert!(server.sync().is_empty());
+        b.exchange(&mut server)?;
+        assert_eq!(b.storage_lsn(), Some(first));
+        assert_eq!(server.min_client_storage_lsn(), Some(first));
+
+        // a moves ahead of b
+        mutate(&mut server, &mut a, "INSERT INTO t VALUES (1)")?;
+        assert!(server.sync().is_empty());
+        a.exchange(&mut server)?;
+        let second = a.storage_lsn().unwrap();
+        assert!(second > first);
+        assert_eq!(server.min_client_storage_lsn(), Some(first));
+
+        // until b catches up
+        b.exchange(&mut server)?;
+        assert_eq!(server.min_client_storage_lsn(), Some(second));
+
+        // and storage is only truncated once both have the frames
+        block_on(server.persist())?;
+        server.truncate()?;
+        assert_eq!(server.doc().source_range().first(), Some(second));
+
+        // the min ignores clients which disconnect
+        server.disconnect(b.id);
+        mutate(&mut server, &mut a, "INSERT INTO t VALUES (2)")?;
+        assert!(server.sync().is_empty());
+        a.exchange(&mut server)?;
+        assert_eq!(server.min_client_storage_lsn(), a.storage_lsn());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_slow_client() -> anyhow::Result<()> {
+        let mut server = open_server()?;
+        let mut healthy = TestClient::connect(&mut server, 128)?;
+
+        // the slow client starts replication, but never reads again
+        let mut slow = TestClient::connect(&mut server, 2)?;
+        slow.exchange(&mut server)?;
+
+        mutate(&mut server, &mut healthy, "CREATE TABLE t (x)")?;
+        for i in 0..5 {
+            mutate(
+                &mut server,
+                &mut healthy,
+                &format!("INSERT INTO t VALUES ({})", i),
+            )?;
+        }
+
+        // syncing drops the slow client once its outbox is full
+        let dropped = server.sync();
+        assert_eq!(dropped.len(), 1);
+        assert_eq!(dropped[0].0, slow.id);
+        assert!(matches!(
+            dropped[0].1,
+            ClientError::SendError(SendError::Full)
+        ));
+        assert_eq!(server.num_clients(), 1);
+        assert!(matches!(
+            server.handle(slow.id, ReplicationMsg::Ping, &mut io::empty()),
+            Err(ClientError::UnknownClient(_))
+        ));
+
+        // while the healthy client still receives everything
+        healthy.exchange(&mut server)?;
+        assert_eq!(healthy.storage_lsn(), server.doc().source_range().last());
+        mutate(&mut server, &mut healthy, "INSERT INTO t VALUES (5)")?;
+        assert!(server.sync().is_empty());
+        healthy.exchange(&mut server)?;
+        assert_eq!(healthy.storage_lsn(), server.doc().source_range().last());
+
+        Ok(())
+    }
+
+    #[derive(Default)]
+    struct RecordingMetrics {
+        steps: usize,
+        frames_sent: usize,
+        bytes_sent: usize,
+        clients: usize,
+        connects: usize,
+        disconnects: usize,
+        persisted: Vec<Lsn>,
+    }
+
+    impl CoordinatorMetrics for RecordingMetrics {
+        fn on_step(&mut self, _elapsed_ms: i64) {
+            self.steps += 1;
+        }
+
+        fn on_frame_sent(&mut self, bytes: usize) {
+            self.frames_sent += 1;
+            self.bytes_sent += bytes;
+        }
+
+        fn on_client_connect(&mut self, clients: usize) {
+            self.connects += 1;
+            self.clients = clients;
+        }
+
+        fn on_client_disconnect(&mut self, clients: usize) {
+            self.disconnects += 1;
+            self.clients = clients;
+        }
+
+        fn on_persist(&mut self, lsn: Lsn, _bytes: usize) {
+            self.persisted.push(lsn);
+        }
+    }
+
+    #[test]
+    fn test_metrics() -> anyhow::Result<()> {
+        let mut server = open_server()?.with_metrics(RecordingMetrics::default());
+        let mut a = TestClient::connect(&mut server, 128)?;
+        let b = TestClient::connect(&mut server, 128)?;
+        assert_eq!(server.metrics().connects, 2);
+        assert_eq!(server.metrics().clients, 2);
+
+        mutate(&mut server, &mut a, "CREATE TABLE t (x)")?;
+        mutate(&mut server, &mut a, "INSERT INTO t VALUES (1)")?;
+        assert_eq!(server.metrics().steps, 2);
+
+        block_on(server.persist())?;
+        let storage_range = server.doc().source_range();
+        assert_eq!(
+            server.metrics().persisted,
+            storage_range.iter().collect::<Vec<_>>()
+        );
+
+        // only a has answered our RangeRequest, so b isn't sent anything yet
+        assert!(server.sync().is_empty());
+        assert_eq!(server.metrics().frames_sent, storage_range.len());
+        assert!(server.metrics().bytes_sent > 0);
+
+        server.disconnect(b.id);
+        server.disconnect(b.id);
+        assert_eq!(server.metrics().disconnects, 1);
+        assert_eq!(server.metrics().clients, 1);
+
+        Ok(())
+    }
+}
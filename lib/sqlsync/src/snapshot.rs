@@ -0,0 +1,70 @@
+use std::{any::Any, fmt::Debug, pin::Pin};
+
+use rusqlite::Connection;
+
+use crate::{
+    db::{open_with_storage, ConnectionPair, OpenConfig, RegisterFunctions},
+    error::Result,
+    journal::PinnableJournal,
+    lsn::LsnRange,
+    storage::Storage,
+};
+
+/// Snapshot is a read-only view of a document as of the moment it was
+/// created. Every query run against a snapshot sees the same state,
+/// regardless of any mutations or rebases applied to the document since.
+///
+/// A snapshot pins the visible frames of the document's storage journal
+/// rather than copying them, only local changes which haven't been rebased
+/// are copied.
+pub struct Snapshot {
+    // see open_with_storage for why this is declared before storage
+    sqlite: ConnectionPair,
+    _storage: Pin<Box<dyn Any>>,
+
+    range: LsnRange,
+}
+
+impl Debug for Snapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Snapshot").field(&self.range).finish()
+    }
+}
+
+impl Snapshot {
+    pub(crate) fn new<J: PinnableJournal>(
+        storage: &Storage<J>,
+        register_functions: Option<RegisterFunctions>,
+    ) -> Result<Self> {
+        let config = OpenConfig {
+            register_functions,
+            ..OpenConfig::default()
+        };
+        let (sqlite, snapshot_storage) = open_with_storage(storage.pin()?, &config)?;
+
+        Ok(Self {
+            sqlite,
+            _storage: snapshot_storage,
+            range: storage.visible_lsn_range(),
+        })
+    }
+
+    /// the range of storage lsns visible to this snapshot, in addition to any
+    /// local changes which hadn't been rebased when it was created
+    pub fn range(&self) -> LsnRange {
+        self.range
+    }
+
+    /// the read-only connection used by query
+    pub(crate) fn connection(&self) -> &Connection {
+        &self.sqlite.readonly
+    }
+
+    pub fn query<F, O, E>(&self, f: F) -> std::result::Result<O, E>
+    where
+        F: FnOnce(&Connection) -> std::result::Result<O, E>,
+        E: std::convert::From<rusqlite::Error>,
+    {
+        f(&self.sqlite.readonly)
+    }
+}
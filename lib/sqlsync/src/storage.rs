@@ -1,16 +1,23 @@
-use std::{collections::HashSet, fmt::Debug, io};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    fmt::Debug,
+    io::{self, Read},
+    mem::size_of,
+};
 
 use pin_project::pin_project;
 use serde::{Deserialize, Serialize};
 use sqlite_vfs::SQLITE_IOERR;
 
-use super::page::{SerializedPagesReader, SparsePages, PAGESIZE};
+use super::page::{is_valid_page_size, SerializedPagesReader, SparsePages};
 use crate::{
-    journal::Journal,
+    journal::{Journal, JournalError, PinnableJournal},
+    logging,
     lsn::LsnRange,
     page::{Page, PageIdx},
-    replication::{ReplicationDestination, ReplicationSource},
-    Lsn,
+    positioned_io::PositionedCursor,
+    replication::{FrameReader, ReplicationDestination, ReplicationSource},
+    Lsn, Serializable,
 };
 
 // Useful SQLite header offsets
@@ -20,9 +27,19 @@ use crate::{
 // We use the file change counter to control SQLite caching
 const FILE_CHANGE_COUNTER_OFFSET: usize = 24;
 
+// SQLite sets the version-valid-for number to the file change counter
+// whenever it writes the header
+const VERSION_VALID_FOR_OFFSET: usize = 92;
+
+// The in-header database size, in pages
+const DATABASE_SIZE_OFFSET: usize = 28;
+
 // The schema cookie is used to determine if the schema has changed
 const SCHEMA_COOKIE_OFFSET: usize = 40;
 
+// The largest root b-tree page, which is zero unless auto_vacuum is enabled
+const LARGEST_ROOT_PAGE_OFFSET: usize = 52;
+
 /// StorageChange specifies the type of change that occurred in storage
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum StorageChange {
@@ -31,22 +48,81 @@ pub enum StorageChange {
     Full,
 
     /// one or more table btrees have changed
-    /// the root page indexes for each table are provided
+    /// the root page indexes for each table are provided. indexes are
+    /// separate btrees, so a change to an index is reported as its own root
+    /// page rather than its table's, which lets queries reading only a
+    /// covering index ignore changes to columns it doesn't contain
     Tables { root_pages_sorted: Vec<PageIdx> },
+
+    /// the schema has changed, but only by dropping tables along with their
+    /// indexes and triggers. the root pages of every other btree are
+    /// unchanged, so only queries depending on the dropped root pages or on
+    /// root_pages_sorted need to be invalidated
+    Schema {
+        dropped_root_pages: Vec<PageIdx>,
+        root_pages_sorted: Vec<PageIdx>,
+    },
+}
+
+/// DocumentStats describes the size of a document
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct DocumentStats {
+    /// the last lsn in the storage journal
+    pub storage_lsn: Option<Lsn>,
+    /// the number of frames in the storage journal
+    pub journal_frames: usize,
+    /// the number of pages in the database
+    pub live_pages: usize,
+    /// the number of pages changed since the last commit or reset
+    pub pending_pages: usize,
+    /// the last lsn in the local timeline, always None for a coordinator
+    pub timeline_lsn: Option<Lsn>,
 }
 
 #[pin_project]
 pub struct Storage<J> {
     journal: J,
+    page_size: usize,
     visible_lsn_range: LsnRange,
     pending: SparsePages,
+    spill: Option<Spill<J>>,
 
     file_change_counter: u32,
 
-    // the following three fields are reset whenever Storage::changes() is called
+    // the following four fields are reset whenever Storage::changes() is called
     last_schema_cookie: u32,
     changed_root_pages: HashSet<PageIdx>,
     changed_pages: HashSet<PageIdx>,
+    // set when a changed page couldn't be resolved to its root page, which
+    // causes the next call to changes() to return StorageChange::Full
+    changed_unresolved: bool,
+
+    // set by begin_rebase until finish_rebase
+    rebase_base: Option<RebaseBase>,
+}
+
+/// RebaseBase is what storage looked like when a rebase began, which lets
+/// finish_rebase tell whether the rebase changed any pages
+struct RebaseBase {
+    visible_lsn_range: LsnRange,
+    pending: SparsePages,
+    // spilled pages are discarded by reset, so they can't be compared
+    spilled: bool,
+    changed_root_pages: HashSet<PageIdx>,
+    changed_pages: HashSet<PageIdx>,
+    changed_unresolved: bool,
+}
+
+/// ResolvedPage is the result of tracing a page to the btree it belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ResolvedPage {
+    /// the page belongs to the btree with this root page
+    Root(PageIdx),
+    /// the page isn't part of any btree, e.g. a ptrmap or freelist page, or a
+    /// page past the end of the database
+    Unowned,
+    /// the ptrmap couldn't be trusted, so any btree may have changed
+    Unresolved,
 }
 
 impl<J: Journal> Debug for Storage<J> {
@@ -54,39 +130,464 @@ impl<J: Journal> Debug for Storage<J> {
         f.debug_tuple("Storage")
             .field(&self.journal)
             .field(&("pending pages", &self.pending.num_pages()))
+            .field(&(
+                "spilled pages",
+                self.spill.as_ref().map_or(0, |s| s.pages.len()),
+            ))
             .finish()
     }
 }
 
+/// Spill holds pending pages which didn't fit in memory, see
+/// Storage::set_spill
+struct Spill<J> {
+    journal: J,
+    page_size: usize,
+    max_pending_pages: usize,
+    // every page which has been spilled since the last commit or reset, and
+    // the lsn of the frame holding its latest version
+    pages: BTreeMap<PageIdx, Lsn>,
+}
+
+/// read a page from the frame at lsn in journal
+fn read_frame_page<J: Journal>(
+    journal: &J,
+    lsn: Lsn,
+    page_size: usize,
+    page_idx: PageIdx,
+    page_offset: usize,
+    buf: &mut [u8],
+) -> io::Result<usize> {
+    let mut cursor = journal.scan_range(LsnRange::new(lsn, lsn));
+    if !cursor.advance()? {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("frame {} is missing", lsn),
+        ));
+    }
+    SerializedPagesReader::new(&cursor, page_size).read(page_idx, page_offset, buf)
+}
+
+impl<J: Journal> Spill<J> {
+    /// read the latest spilled version of a page
+    fn read(&self, page_idx: PageIdx, page_offset: usize, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(&lsn) = self.pages.get(&page_idx) else {
+            return Ok(0);
+        };
+        read_frame_page(
+            &self.journal,
+            lsn,
+            self.page_size,
+            page_idx,
+            page_offset,
+            buf,
+        )
+    }
+
+    fn append(&mut self, pages: SparsePages) -> io::Result<()> {
+        let page_idxs: Vec<PageIdx> = pages.page_idxs().copied().collect();
+        self.journal.append(pages)?;
+        let lsn = self
+            .journal
+            .range()
+            .last()
+            .expect("the frame was just appended");
+        for page_idx in page_idxs {
+            self.pages.insert(page_idx, lsn);
+        }
+        Ok(())
+    }
+
+    fn clear(&mut self) -> io::Result<()> {
+        if let Some(last) = self.journal.range().last() {
+            self.journal.drop_prefix(last)?;
+        }
+        self.pages.clear();
+        Ok(())
+    }
+}
+
+/// CommittedPages indexes the frames in a range of a journal by the lsn of
+/// the frame holding the latest version of each page, so that pages can be
+/// read in place rather than copied
+struct CommittedPages<'a, J> {
+    journal: &'a J,
+    page_size: usize,
+    pages: BTreeMap<PageIdx, Lsn>,
+}
+
+impl<'a, J: Journal> CommittedPages<'a, J> {
+    fn new(journal: &'a J, range: LsnRange, page_size: usize) -> io::Result<Self> {
+        let mut pages = BTreeMap::new();
+
+        // later frames shadow earlier ones, so only the first lsn seen while
+        // scanning backwards is kept
+        let mut cursor = journal.scan_range(range).into_rev();
+        while cursor.advance()? {
+            let lsn = cursor.lsn().expect("cursor has a frame");
+            for page_idx in SerializedPagesReader::new(&cursor, page_size).page_idxs()? {
+                pages.entry(page_idx).or_insert(lsn);
+            }
+        }
+        Ok(Self { journal, page_size, pages })
+    }
+
+    fn read(&self, page_idx: PageIdx, page_offset: usize, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(&lsn) = self.pages.get(&page_idx) else {
+            return Ok(0);
+        };
+        read_frame_page(
+            self.journal,
+            lsn,
+            self.page_size,
+            page_idx,
+            page_offset,
+            buf,
+        )
+    }
+}
+
+/// MergedPages serializes the latest version of pages from several sources
+/// in the same layout as SparsePages, without copying them. pending pages
+/// are newer than spilled pages, which are newer than committed pages
+pub(crate) struct MergedPages<'a, J> {
+    committed: Option<CommittedPages<'a, J>>,
+    pending: &'a SparsePages,
+    spill: Option<&'a Spill<J>>,
+    page_size: usize,
+    page_idxs: BTreeSet<PageIdx>,
+}
+
+impl<'a, J: Journal> MergedPages<'a, J> {
+    fn new(
+        committed: Option<CommittedPages<'a, J>>,
+        pending: &'a SparsePages,
+        spill: Option<&'a Spill<J>>,
+    ) -> Self {
+        let mut page_idxs: BTreeSet<PageIdx> = pending.page_idxs().copied().collect();
+        if let Some(ref committed) = committed {
+            page_idxs.extend(committed.pages.keys());
+        }
+        if let Some(spill) = spill {
+            page_idxs.extend(spill.pages.keys());
+        }
+        Self {
+            page_size: pending.page_size(),
+            committed,
+            pending,
+            spill,
+            page_idxs,
+        }
+    }
+
+    pub fn num_pages(&self) -> usize {
+        self.page_idxs.len()
+    }
+
+    pub fn max_page_idx(&self) -> Option<PageIdx> {
+        self.page_idxs.last().copied()
+    }
+
+    /// read the latest version of a page, returning 0 if no source has it
+    pub fn read(&self, page_idx: PageIdx, page_offset: usize, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.pending.read(page_idx, page_offset, buf);
+        if n != 0 {
+            return Ok(n);
+        }
+        if let Some(spill) = self.spill {
+            let n = spill.read(page_idx, page_offset, buf)?;
+            if n != 0 {
+                return Ok(n);
+            }
+        }
+        match self.committed {
+            Some(ref committed) => committed.read(page_idx, page_offset, buf),
+            None => Ok(0),
+        }
+    }
+}
+
+impl<J: Journal> Serializable for MergedPages<'_, J> {
+    fn serialize_into<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+        // page indexes followed by pages, both sorted by page_idx desc
+        for page_idx in self.page_idxs.iter().rev() {
+            writer.write_all(&page_idx.to_le_bytes())?;
+        }
+
+        let mut page = vec![0; self.page_size];
+        for &page_idx in self.page_idxs.iter().rev() {
+            self.read(page_idx, 0, &mut page)?;
+            writer.write_all(&page)?;
+        }
+        Ok(())
+    }
+
+    fn serialized_len(&self) -> Option<usize> {
+        Some(self.page_idxs.len() * (size_of::<PageIdx>() + self.page_size))
+    }
+}
+
 impl<J: Journal> Storage<J> {
-    pub fn new(journal: J) -> Self {
+    /// page_size must be a power of two between 512 and 65536 and match the
+    /// page size of any pages already in the journal
+    pub fn new(journal: J, page_size: usize) -> io::Result<Self> {
+        if !is_valid_page_size(page_size) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid page size: {}", page_size),
+            ));
+        }
         let visible_lsn_range = journal.range();
-        Self {
+        Ok(Self {
             journal,
+            page_size,
             visible_lsn_range,
-            pending: SparsePages::new(),
+            pending: SparsePages::new(page_size),
+            spill: None,
             file_change_counter: 0,
             last_schema_cookie: 0,
             changed_root_pages: HashSet::new(),
             changed_pages: HashSet::new(),
+            changed_unresolved: false,
+            rebase_base: None,
+        })
+    }
+
+    pub fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    /// spill pending pages into journal whenever more than max_pending_pages
+    /// are pending, bounding the memory used by large transactions. spilled
+    /// pages are read back transparently, and discarded on commit or reset
+    pub fn set_spill(&mut self, journal: J, max_pending_pages: usize) -> io::Result<()> {
+        assert!(max_pending_pages > 0, "max_pending_pages must be > 0");
+        assert!(
+            !matches!(self.spill, Some(ref spill) if !spill.pages.is_empty()),
+            "can't replace a spill journal which contains pages"
+        );
+        let mut spill = Spill {
+            journal,
+            page_size: self.page_size,
+            max_pending_pages,
+            pages: BTreeMap::new(),
+        };
+        spill.clear()?;
+        self.spill = Some(spill);
+        Ok(())
+    }
+
+    /// the number of pending pages held in memory
+    pub fn num_pending_pages(&self) -> usize {
+        self.pending.num_pages()
+    }
+
+    /// compute statistics about this storage, timeline_lsn is left unset
+    pub fn stats(&self) -> io::Result<DocumentStats> {
+        let pending_pages = match &self.spill {
+            Some(spill) => {
+                let mut page_idxs: BTreeSet<PageIdx> = spill.pages.keys().copied().collect();
+                page_idxs.extend(self.pending.page_idxs().copied());
+                page_idxs.len()
+            }
+            None => self.pending.num_pages(),
+        };
+        Ok(DocumentStats {
+            storage_lsn: self.last_committed_lsn(),
+            journal_frames: self.journal.range().len(),
+            live_pages: self.max_page_idx()?.unwrap_or(0) as usize,
+            pending_pages,
+            timeline_lsn: None,
+        })
+    }
+
+    /// the largest visible page idx, page idxs start at 1 so this is also
+    /// the number of pages in the database
+    fn max_page_idx(&self) -> io::Result<Option<PageIdx>> {
+        let mut max_page_idx = self.pending.max_page_idx();
+        if let Some(spill) = &self.spill {
+            max_page_idx = max_page_idx.max(spill.pages.keys().next_back().copied());
+        }
+
+        // if we have visible lsns in storage, then we need to scan them
+        // to find the max page idx
+        let mut cursor = self.journal.scan_range(self.visible_lsn_range);
+        while cursor.advance()? {
+            let pages = SerializedPagesReader::new(&cursor, self.page_size);
+            max_page_idx = max_page_idx.max(pages.max_page_idx()?);
         }
+        Ok(max_page_idx)
+    }
+
+    fn maybe_spill(&mut self) -> io::Result<()> {
+        if let Some(spill) = self.spill.as_mut() {
+            if self.pending.num_pages() > spill.max_pending_pages {
+                log::debug!(
+                    target: logging::STORAGE,
+                    "spilling {} pending pages",
+                    self.pending.num_pages()
+                );
+                let pending =
+                    std::mem::replace(&mut self.pending, SparsePages::new(self.page_size));
+                spill.append(pending)?;
+            }
+        }
+        Ok(())
     }
 
     pub fn last_committed_lsn(&self) -> Option<Lsn> {
         self.journal.range().last()
     }
 
+    /// the lsn the next commit will be written at
+    pub fn next_commit_lsn(&self) -> Lsn {
+        self.journal.range().next()
+    }
+
     pub fn has_committed_pages(&self) -> bool {
         self.journal.range().is_non_empty()
     }
 
+    pub fn visible_lsn_range(&self) -> LsnRange {
+        self.visible_lsn_range
+    }
+
     pub fn has_invisible_pages(&self) -> bool {
         self.visible_lsn_range.last() < self.journal.range().last()
     }
 
+    /// verify the storage journal, checking that every frame contains
+    /// well formed pages
+    pub fn verify(&self) -> Result<(), JournalError> {
+        self.journal
+            .verify_frames(|frame| SerializedPagesReader::new(frame, self.page_size).validate())
+    }
+
+    /// the latest version of every visible page, including pending pages,
+    /// which are all read in place
+    pub(crate) fn snapshot_pages(&self) -> io::Result<MergedPages<'_, J>> {
+        Ok(MergedPages::new(
+            Some(CommittedPages::new(
+                &self.journal,
+                self.visible_lsn_range,
+                self.page_size,
+            )?),
+            &self.pending,
+            self.spill.as_ref(),
+        ))
+    }
+
+    /// create a storage which sees the same pages as this one by pinning the
+    /// visible frames of the journal. pending pages aren't in the journal,
+    /// so they are copied
+    pub(crate) fn pin(&self) -> io::Result<Storage<J::Pinned>>
+    where
+        J: PinnableJournal,
+    {
+        let journal = self.journal.pin(self.visible_lsn_range)?;
+        let mut storage = Storage::new(journal, self.page_size)?;
+        let pending = MergedPages::new(None, &self.pending, self.spill.as_ref());
+        for &page_idx in pending.page_idxs.iter() {
+            let mut page: Page = vec![0; self.page_size].into();
+            pending.read(page_idx, 0, &mut page)?;
+            storage.pending.write(page_idx, page);
+        }
+        Ok(storage)
+    }
+
+    /// copy the latest version of every page changed by the frames in range,
+    /// copying each page once no matter how many frames changed it
+    fn merge_frames(&self, range: LsnRange) -> io::Result<SparsePages> {
+        let committed = CommittedPages::new(&self.journal, range, self.page_size)?;
+        let mut merged = SparsePages::new(self.page_size);
+        for &page_idx in committed.pages.keys() {
+            let mut page: Page = vec![0; self.page_size].into();
+            committed.read(page_idx, 0, &mut page)?;
+            merged.write(page_idx, page);
+        }
+        Ok(merged)
+    }
+
+    /// write the latest version of every visible page to writer, which can
+    /// be loaded into an empty storage with import_snapshot. pages are read
+    /// from the journal as they are written, so the snapshot is never held
+    /// in memory
+    ///
+    /// Binary layout of an exported snapshot is:
+    /// lsn: u64 (the last visible lsn)
+    /// page_size: u32
+    /// num_pages: u32
+    /// pages: SparsePages (omitted if there are no pages)
+    pub fn export_snapshot<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+        let lsn = self.visible_lsn_range.last().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "storage has no visible lsns to export",
+            )
+        })?;
+        let pages = self.snapshot_pages()?;
+
+        writer.write_all(&lsn.to_le_bytes())?;
+        writer.write_all(&(self.page_size as u32).to_le_bytes())?;
+        writer.write_all(&(pages.num_pages() as u32).to_le_bytes())?;
+        if pages.num_pages() > 0 {
+            pages.serialize_into(writer)?;
+        }
+        Ok(())
+    }
+
+    /// write every visible page, including pending pages, to writer as a
+    /// standalone SQLite database file, see import_sqlite_file for the inverse.
+    /// pages which were never written are filled with zeros
+    pub fn export_sqlite<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+        let pages = self.snapshot_pages()?;
+        let max_page_idx = match pages.max_page_idx() {
+            Some(max_page_idx) => max_page_idx,
+            // an empty file is a valid empty database
+            None => return Ok(()),
+        };
+
+        // pages past the in-header database size may be left over from
+        // before the database shrank
+        let db_size = self.read_header_field(self.visible_lsn_range, true, DATABASE_SIZE_OFFSET)?;
+        let num_pages = match db_size {
+            0 => max_page_idx,
+            db_size => db_size,
+        };
+
+        let mut buf = vec![0; self.page_size];
+        for page_idx in 1..=num_pages {
+            buf.fill(0);
+            pages.read(page_idx, 0, &mut buf)?;
+            if page_idx == 1 {
+                // sqlite only trusts the in-header database size when the
+                // version-valid-for number matches the file change counter
+                let counter = self.file_change_counter.to_be_bytes();
+                overlay_header_field(&mut buf, 0, FILE_CHANGE_COUNTER_OFFSET, &counter);
+                overlay_header_field(&mut buf, 0, VERSION_VALID_FOR_OFFSET, &counter);
+                overlay_header_field(&mut buf, 0, DATABASE_SIZE_OFFSET, &num_pages.to_be_bytes());
+            }
+            writer.write_all(&buf)?;
+        }
+        Ok(())
+    }
+
     pub fn commit(&mut self) -> io::Result<()> {
-        if self.pending.num_pages() > 0 {
-            self.journal.append(std::mem::take(&mut self.pending))?;
+        let spilled = matches!(self.spill, Some(ref spill) if !spill.pages.is_empty());
+        if self.pending.num_pages() > 0 || spilled {
+            match self.spill {
+                Some(ref mut spill) if spilled => {
+                    self.journal
+                        .append(MergedPages::new(None, &self.pending, Some(spill)))?;
+                    self.pending.clear();
+                    spill.clear()?;
+                }
+                _ => {
+                    let pending =
+                        std::mem::replace(&mut self.pending, SparsePages::new(self.page_size));
+                    self.journal.append(pending)?;
+                }
+            }
 
             // calculate the LsnRange between the current visible range and the committed range
             let new_lsns = self.journal.range().difference(&self.visible_lsn_range);
@@ -110,6 +611,10 @@ impl<J: Journal> Storage<J> {
 
         // clear pending to revert uncommitted changes
         self.pending.clear();
+        if let Some(spill) = self.spill.as_mut() {
+            self.changed_pages.extend(spill.pages.keys().copied());
+            spill.clear()?;
+        }
 
         // calculate the LsnRange between the current visible range and the committed range
         let new_lsns = self.journal.range().difference(&self.visible_lsn_range);
@@ -125,83 +630,217 @@ impl<J: Journal> Storage<J> {
         Ok(())
     }
 
+    /// like reset, but remembers the visible pages so that once local changes
+    /// have been reapplied, finish_rebase can tell whether anything changed
+    pub fn begin_rebase(&mut self) -> io::Result<()> {
+        let base = RebaseBase {
+            visible_lsn_range: self.visible_lsn_range,
+            pending: self.pending.clone(),
+            spilled: matches!(&self.spill, Some(spill) if !spill.pages.is_empty()),
+            changed_root_pages: self.changed_root_pages.clone(),
+            changed_pages: self.changed_pages.clone(),
+            changed_unresolved: self.changed_unresolved,
+        };
+        self.reset()?;
+        self.rebase_base = Some(base);
+        Ok(())
+    }
+
+    /// finish a rebase started by begin_rebase, returning the sorted root
+    /// pages of every btree whose pages differ from before the rebase, or
+    /// None if they can't be determined. only those btrees are reported as
+    /// changed by changes(), so queries which would return the same results
+    /// aren't invalidated
+    pub fn finish_rebase(&mut self) -> io::Result<Option<Vec<PageIdx>>> {
+        let Some(base) = self.rebase_base.take() else {
+            return Ok(None);
+        };
+        if base.spilled {
+            return Ok(None);
+        }
+
+        // only pages which were pending before or after the rebase, or which
+        // were committed in the meantime, may have changed
+        let mut page_idxs: BTreeSet<PageIdx> = base.pending.page_idxs().copied().collect();
+        page_idxs.extend(self.pending.page_idxs().copied());
+        if let Some(spill) = &self.spill {
+            page_idxs.extend(spill.pages.keys().copied());
+        }
+        let new_lsns = self.visible_lsn_range.difference(&base.visible_lsn_range);
+        let mut cursor = self.journal.scan_range(new_lsns);
+        while cursor.advance()? {
+            page_idxs.extend(SerializedPagesReader::new(&cursor, self.page_size).page_idxs()?);
+        }
+        drop(cursor);
+
+        // replace the changes recorded by reset with the pages which differ
+        self.changed_root_pages = base.changed_root_pages;
+        self.changed_pages = base.changed_pages;
+        self.changed_unresolved = base.changed_unresolved;
+
+        let mut root_pages = BTreeSet::new();
+        let mut unresolved = false;
+        let mut before = vec![0; self.page_size];
+        let mut after = vec![0; self.page_size];
+        for page_idx in page_idxs {
+            let pos = (page_idx as u64 - 1) * (self.page_size as u64);
+            before.fill(0);
+            after.fill(0);
+            let mut n = base.pending.read(page_idx, 0, &mut before);
+            if n == 0 {
+                n = self.read_at_range(base.visible_lsn_range, false, pos, &mut before)?;
+            }
+            let m = self.read_at_range(self.visible_lsn_range, true, pos, &mut after)?;
+
+            // the out of band header fields are expected to differ
+            if page_idx == 1 {
+                for offset in [FILE_CHANGE_COUNTER_OFFSET, VERSION_VALID_FOR_OFFSET] {
+                    before[offset..offset + 4].copy_from_slice(&after[offset..offset + 4]);
+                }
+            }
+            if n == m && before == after {
+                continue;
+            }
+
+            let resolved = self.resolve_root_page(self.visible_lsn_range, true, page_idx)?;
+            match resolved {
+                ResolvedPage::Root(root_page_idx) => {
+                    root_pages.insert(root_page_idx);
+                }
+                ResolvedPage::Unowned => {}
+                ResolvedPage::Unresolved => unresolved = true,
+            }
+            self.record_resolved_page(page_idx, resolved);
+        }
+
+        Ok((!unresolved).then(|| root_pages.into_iter().collect()))
+    }
+
     /// update_changed_root_pages does two things
     /// 1. it scans the journal, updating changed_root_pages for each frame
     /// 2. it updates changed_root_pages for every page in self.changed_pages
     fn update_changed_root_pages(&mut self, range: LsnRange) -> io::Result<()> {
-        // scan the journal, updating changed_root_pages for each frame
+        let mut resolved = Vec::new();
+
+        // scan the journal, resolving the pages in each frame
         let mut cursor = self.journal.scan_range(range);
         while cursor.advance()? {
             let lsn = cursor.lsn().unwrap();
-            let pages = SerializedPagesReader(&cursor);
+            let pages = SerializedPagesReader::new(&cursor, self.page_size);
             for page_idx in pages.page_idxs()?.iter() {
                 // we need to resolve each page_idx to it's root page by only
                 // looking at ptrmap pages that existed as of this lsn
-                if let Some(root_page_idx) =
-                    self.resolve_root_page(LsnRange::new(0, lsn), false, *page_idx)?
-                {
-                    self.changed_root_pages.insert(root_page_idx);
-                }
+                resolved.push((
+                    *page_idx,
+                    self.resolve_root_page(LsnRange::new(0, lsn), false, *page_idx)?,
+                ));
             }
         }
+        drop(cursor);
 
-        // finally, if we have any changed pages, update changed_root_pages for each page
+        // finally, if we have any changed pages, resolve each of them
         for page_idx in self.changed_pages.iter() {
             // we need to resolve each page_idx to it's root page by only
             // looking at ptrmap pages that existed as of the last visible lsn
-            if let Some(root_page_idx) =
-                self.resolve_root_page(self.visible_lsn_range, true, *page_idx)?
-            {
-                self.changed_root_pages.insert(root_page_idx);
-            }
+            resolved.push((
+                *page_idx,
+                self.resolve_root_page(self.visible_lsn_range, true, *page_idx)?,
+            ));
         }
 
         // clear changed_pages
         self.changed_pages.clear();
 
+        for (page_idx, resolved) in resolved {
+            self.record_resolved_page(page_idx, resolved);
+        }
+
         Ok(())
     }
 
-    /// resolve_root_page returns the root page index for the given page at the
-    /// given lsn range (potentially including pending pages)
-    /// if the page does not map to a b-tree root page, then None is returned
+    fn record_resolved_page(&mut self, page_idx: PageIdx, resolved: ResolvedPage) {
+        match resolved {
+            ResolvedPage::Root(root_page_idx) => {
+                self.changed_root_pages.insert(root_page_idx);
+            }
+            ResolvedPage::Unowned => {}
+            ResolvedPage::Unresolved => {
+                log::debug!(
+                    target: logging::STORAGE,
+                    "unable to resolve root page of changed page {}",
+                    page_idx
+                );
+                self.changed_unresolved = true;
+            }
+        }
+    }
+
+    /// resolve_root_page traces the given page to the root page of its btree
+    /// at the given lsn range (potentially including pending pages). table,
+    /// index and WITHOUT ROWID btrees all record their pages in the ptrmap in
+    /// the same way, so they are resolved identically
     fn resolve_root_page(
         &self,
         range: LsnRange,
         include_pending: bool,
         page_idx: PageIdx,
-    ) -> io::Result<Option<PageIdx>> {
-        const PENDING_BYTE_PAGE_IDX: u64 = (0x40000000 / (PAGESIZE as u64)) + 1;
+    ) -> io::Result<ResolvedPage> {
+        let page_size = self.page_size as u64;
+        let pending_byte_page_idx: u64 = (0x40000000 / page_size) + 1;
 
         // XXX: SQLSync does not currently support SQLite extensions, so we
-        // calculate usable page size == PAGESIZE
+        // calculate usable page size == page_size
         // If we ever support SQLite extensions this will need to be updated to
         // take into account the reserved region for extensions at the end of
         // each page
-        const USABLE_PAGE_SIZE: u64 = PAGESIZE as u64;
+        let usable_page_size: u64 = page_size;
 
         const PTRMAP_ENTRY_SIZE: u64 = 5;
 
-        // when calculating PAGES_PER_PTRMAP we add 1 to make the math nicer by
+        // when calculating pages_per_ptrmap we add 1 to make the math nicer by
         // effectively taking into account the ptrmap page itself
         // math mostly copied from:
         //  https://github.com/sqlite/sqlite/blob/1eca330a08e18fd0930491302802141f5ce6298e/src/btree.c#L989C1-L1001C2
-        const PAGES_PER_PTRMAP: u64 = (USABLE_PAGE_SIZE / PTRMAP_ENTRY_SIZE) + 1;
+        let pages_per_ptrmap: u64 = (usable_page_size / PTRMAP_ENTRY_SIZE) + 1;
 
         if page_idx == 1 {
             // page 1 is the schema root page
-            return Ok(Some(1));
+            return Ok(ResolvedPage::Root(1));
         }
 
+        let database_size =
+            self.read_header_field(range, include_pending, DATABASE_SIZE_OFFSET)? as u64;
         let mut page_idx = page_idx as u64;
+        if page_idx > database_size {
+            // page is past the end of the database, this can happen while we
+            // are rebasing right after we create a local table or index (for
+            // example)
+            return Ok(ResolvedPage::Unowned);
+        }
+
+        // ptrmap pages only exist when auto_vacuum is enabled, in which case
+        // sqlite records the largest root page in the header. otherwise we
+        // have no way to trace the page
+        if self.read_header_field(range, include_pending, LARGEST_ROOT_PAGE_OFFSET)? == 0 {
+            return Ok(ResolvedPage::Unresolved);
+        }
+
+        // every page in a chain exists, and a chain can't visit a page twice,
+        // so we use the database size to bound the walk in case the ptrmap
+        // contains a cycle
         let mut ptrmap_entry = [0u8; PTRMAP_ENTRY_SIZE as usize];
-        loop {
+        for _ in 0..database_size {
+            if page_idx < 2 || page_idx > database_size {
+                // the chain points outside of the database
+                return Ok(ResolvedPage::Unresolved);
+            }
+
             // which ptrmap are we referring to
-            let ptrmap_n = (page_idx - 2) / PAGES_PER_PTRMAP;
+            let ptrmap_n = (page_idx - 2) / pages_per_ptrmap;
             // what is the page index of the ptrmap
-            let mut ptrmap_page_idx = (ptrmap_n * PAGES_PER_PTRMAP) + 2;
+            let mut ptrmap_page_idx = (ptrmap_n * pages_per_ptrmap) + 2;
 
-            if ptrmap_page_idx == PENDING_BYTE_PAGE_IDX {
+            if ptrmap_page_idx == pending_byte_page_idx {
                 // for certain usable page sizes, it's possible for a ptrmap
                 // page to share the same location as the pending byte lock page
                 // in this case, sqlite simply moves the ptrmap to the next page
@@ -209,35 +848,31 @@ impl<J: Journal> Storage<J> {
                 ptrmap_page_idx += 1;
             }
 
-            if ptrmap_page_idx == page_idx {
-                // looking for a ptrmap, no root page
-                return Ok(None);
+            if ptrmap_page_idx == page_idx || page_idx == pending_byte_page_idx {
+                // looking for a ptrmap or the pending byte page, no root page
+                return Ok(ResolvedPage::Unowned);
             }
 
             // calculate the offset of the page_idx within the ptrmap page
             let page_idx_offset = (page_idx - ptrmap_page_idx - 1) * PTRMAP_ENTRY_SIZE;
             // convert the relative offset to an absolute offset within the file
-            let page_idx_pos = ((ptrmap_page_idx - 1) * (PAGESIZE as u64)) + page_idx_offset;
+            let page_idx_pos = ((ptrmap_page_idx - 1) * page_size) + page_idx_offset;
 
             // read the ptrmap_entry for this page
             self.read_at_range(range, include_pending, page_idx_pos, &mut ptrmap_entry)?;
             match ptrmap_entry[0] {
-                0 => {
-                    // page is missing, this can happen while we are rebasing
-                    // right after we create a local table or index (for example)
-                    return Ok(None);
-                }
                 1 => {
                     // page is a b-tree root page
                     // return the page_idx
-                    return Ok(Some(page_idx as PageIdx));
+                    return Ok(ResolvedPage::Root(page_idx as PageIdx));
                 }
                 2 => {
                     // page is a freelist page
-                    return Ok(None);
+                    return Ok(ResolvedPage::Unowned);
                 }
-                _ => {
-                    // ptrmap entry points at the next page in the chain
+                3..=5 => {
+                    // page is an overflow or non-root b-tree page, the ptrmap
+                    // entry points at the next page in the chain
                     page_idx = u32::from_be_bytes([
                         ptrmap_entry[1],
                         ptrmap_entry[2],
@@ -245,57 +880,105 @@ impl<J: Journal> Storage<J> {
                         ptrmap_entry[4],
                     ]) as u64;
                 }
+                _ => {
+                    // the ptrmap is missing an entry for a page in the
+                    // database, or contains an invalid one
+                    return Ok(ResolvedPage::Unresolved);
+                }
             }
         }
+
+        // the chain is longer than the database, so it must contain a cycle
+        Ok(ResolvedPage::Unresolved)
     }
 
-    fn schema_cookie(&self) -> io::Result<u32> {
+    /// read a big endian u32 from the database header
+    fn read_header_field(
+        &self,
+        range: LsnRange,
+        include_pending: bool,
+        offset: usize,
+    ) -> io::Result<u32> {
         let mut buf = [0; 4];
-        self.read_at_range(
-            self.visible_lsn_range,
-            true,
-            SCHEMA_COOKIE_OFFSET as u64,
-            &mut buf,
-        )?;
+        self.read_at_range(range, include_pending, offset as u64, &mut buf)?;
         Ok(u32::from_be_bytes(buf))
     }
 
+    /// the schema cookie as of the visible range and any pending pages,
+    /// sqlite changes it whenever the schema changes
+    pub fn schema_cookie(&self) -> io::Result<u32> {
+        self.read_header_field(self.visible_lsn_range, true, SCHEMA_COOKIE_OFFSET)
+    }
+
     pub fn has_changes(&self) -> bool {
         // it's not possible for the schema to change without also modifying pages
         // so we don't have to check the schema cookie here
-        !(self.changed_pages.is_empty() && self.changed_root_pages.is_empty())
+        !(self.changed_pages.is_empty()
+            && self.changed_root_pages.is_empty()
+            && !self.changed_unresolved)
     }
 
-    pub fn changes(&mut self) -> io::Result<StorageChange> {
+    /// returns true if the schema has changed since the last call to changes
+    pub fn schema_changed(&self) -> io::Result<bool> {
+        Ok(self.schema_cookie()? != self.last_schema_cookie)
+    }
+
+    /// returns the changes since the last call. if the schema has changed and
+    /// the caller has determined that only tables were dropped, it should
+    /// provide the dropped root pages, see SchemaTracker
+    pub fn changes(
+        &mut self,
+        dropped_root_pages: Option<Vec<PageIdx>>,
+    ) -> io::Result<StorageChange> {
         // check to see if the schema has changed
         let schema_cookie = self.schema_cookie()?;
         if schema_cookie != self.last_schema_cookie {
             log::info!(
+                target: logging::STORAGE,
                 "schema changed: {} -> {}",
                 self.last_schema_cookie,
                 schema_cookie
             );
             self.last_schema_cookie = schema_cookie;
+
+            // the remaining root pages haven't moved, so we can still trace
+            // which btrees have changed
+            if let Some(dropped_root_pages) = dropped_root_pages {
+                if let Some(root_pages_sorted) = self.take_changed_root_pages()? {
+                    return Ok(StorageChange::Schema { dropped_root_pages, root_pages_sorted });
+                }
+            }
+
             self.changed_root_pages.clear();
             self.changed_pages.clear();
+            self.changed_unresolved = false;
             return Ok(StorageChange::Full);
         }
 
-        // if the schema hasn't changed, then we need to trace which btrees have changed
+        // if the schema hasn't changed, then we need to trace which btrees
+        // have changed. if we can't, we have to assume everything changed
+        // rather than risk missing a change
+        Ok(match self.take_changed_root_pages()? {
+            Some(root_pages_sorted) => StorageChange::Tables { root_pages_sorted },
+            None => StorageChange::Full,
+        })
+    }
 
+    /// returns the sorted root pages of every btree which changed, or None if
+    /// some changed pages couldn't be resolved
+    fn take_changed_root_pages(&mut self) -> io::Result<Option<Vec<PageIdx>>> {
         // accumulate any outstanding pages into changed_root_pages
         self.update_changed_root_pages(LsnRange::empty())?;
 
         // gather changed root pages into sorted vec
-        let mut root_pages_sorted: Vec<_> = self.changed_root_pages.iter().copied().collect();
+        let mut root_pages_sorted: Vec<_> = self.changed_root_pages.drain().collect();
         root_pages_sorted.sort();
 
-        // reset variables
-        self.last_schema_cookie = schema_cookie;
-        self.changed_root_pages.clear();
-        self.changed_pages.clear();
-
-        Ok(StorageChange::Tables { root_pages_sorted })
+        // update_changed_root_pages has already cleared changed_pages
+        if std::mem::take(&mut self.changed_unresolved) {
+            return Ok(None);
+        }
+        Ok(Some(root_pages_sorted))
     }
 
     fn read_at_range(
@@ -305,37 +988,31 @@ impl<J: Journal> Storage<J> {
         pos: u64,
         buf: &mut [u8],
     ) -> io::Result<usize> {
-        let page_idx = ((pos / (PAGESIZE as u64)) + 1) as PageIdx;
-        let page_offset = (pos as usize) % PAGESIZE;
+        let page_idx = ((pos / (self.page_size as u64)) + 1) as PageIdx;
+        let page_offset = (pos as usize) % self.page_size;
 
         // find the page by searching down through pending and then the journal
         let mut n = if include_pending {
-            self.pending.read(page_idx, page_offset, buf)
+            self.read_pending(page_idx, page_offset, buf)?
         } else {
             0
         };
 
         let mut cursor = self.journal.scan_range(range).into_rev();
         while n == 0 && cursor.advance()? {
-            let pages = SerializedPagesReader(&cursor);
+            let pages = SerializedPagesReader::new(&cursor, self.page_size);
             n = pages.read(page_idx, page_offset, buf)?;
         }
 
         if n != 0 {
             assert!(n == buf.len(), "read should always fill the buffer");
 
-            // if SQLite is potentially reading the file change counter
-            // replace what is in the file with the current value
-            if page_idx == 1
-                && page_offset <= FILE_CHANGE_COUNTER_OFFSET
-                && page_offset + buf.len() >= FILE_CHANGE_COUNTER_OFFSET + 4
-            {
-                // if pos = 0, then this should be FILE_CHANGE_COUNTER_OFFSET
-                // if pos = FILE_CHANGE_COUNTER_OFFSET, this this should be 0
-                let file_change_buf_offset = FILE_CHANGE_COUNTER_OFFSET - page_offset;
-
-                buf[file_change_buf_offset..(file_change_buf_offset + 4)]
-                    .copy_from_slice(&self.file_change_counter.to_be_bytes());
+            // the file change counter is managed out of band, so we replace
+            // whatever is stored in page 1 with the current value
+            if page_idx == 1 {
+                let counter = self.file_change_counter.to_be_bytes();
+                overlay_header_field(buf, page_offset, FILE_CHANGE_COUNTER_OFFSET, &counter);
+                overlay_header_field(buf, page_offset, VERSION_VALID_FOR_OFFSET, &counter);
             }
 
             Ok(buf.len())
@@ -343,10 +1020,291 @@ impl<J: Journal> Storage<J> {
             Ok(0)
         }
     }
+
+    /// read a page from pending or the spill journal
+    fn read_pending(
+        &self,
+        page_idx: PageIdx,
+        page_offset: usize,
+        buf: &mut [u8],
+    ) -> io::Result<usize> {
+        match self.pending.read(page_idx, page_offset, buf) {
+            0 => match &self.spill {
+                Some(spill) => spill.read(page_idx, page_offset, buf),
+                None => Ok(0),
+            },
+            n => Ok(n),
+        }
+    }
+
+    /// returns true if page is identical to the current version of the page
+    /// at page_idx, ignoring the out of band header fields in page 1.
+    /// this runs on every write, so other than page 1 (which sqlite rewrites
+    /// in every transaction) it only checks pending pages and the newest
+    /// visible frame. pages which were last written in older frames are
+    /// treated as changed
+    fn is_unchanged_write(&self, page_idx: PageIdx, page: &[u8]) -> io::Result<bool> {
+        let mut current = vec![0; self.page_size];
+        let n = if page_idx == 1 {
+            self.read_at_range(self.visible_lsn_range, true, 0, &mut current)?
+        } else {
+            match self.read_pending(page_idx, 0, &mut current)? {
+                0 => {
+                    let mut cursor = self.journal.scan_range(self.visible_lsn_range).into_rev();
+                    if cursor.advance()? {
+                        SerializedPagesReader::new(&cursor, self.page_size).read(
+                            page_idx,
+                            0,
+                            &mut current,
+                        )?
+                    } else {
+                        0
+                    }
+                }
+                n => n,
+            }
+        };
+        if n == 0 {
+            return Ok(false);
+        }
+
+        // copy the counter fields from the incoming page before comparing
+        if page_idx == 1 {
+            for offset in [FILE_CHANGE_COUNTER_OFFSET, VERSION_VALID_FOR_OFFSET] {
+                current[offset..offset + 4].copy_from_slice(&page[offset..offset + 4]);
+            }
+        }
+        Ok(current == page)
+    }
+}
+
+impl<J: Journal + ReplicationDestination> Storage<J> {
+    /// load a snapshot written by export_snapshot into this storage, which
+    /// must not have any frames. the pages are streamed into the journal as
+    /// a single frame at the snapshot's lsn, so replication resumes from the
+    /// following lsn. returns the snapshot's lsn
+    pub fn import_snapshot<R: io::Read>(
+        &mut self,
+        reader: &mut R,
+    ) -> Result<Lsn, crate::replication::ReplicationError> {
+        let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+
+        if self.journal.range().is_non_empty() {
+            return Err(invalid("can't import a snapshot into non-empty storage").into());
+        }
+
+        let mut lsn = [0; size_of::<Lsn>()];
+        reader.read_exact(&mut lsn)?;
+        let lsn = Lsn::from_le_bytes(lsn);
+        let mut page_size = [0; size_of::<u32>()];
+        reader.read_exact(&mut page_size)?;
+        if u32::from_le_bytes(page_size) as usize != self.page_size {
+            return Err(invalid("snapshot page size doesn't match storage").into());
+        }
+
+        let mut num_pages = [0; size_of::<u32>()];
+        reader.read_exact(&mut num_pages)?;
+        let num_pages = u32::from_le_bytes(num_pages) as usize;
+
+        // the page indexes are small enough to check up front, page indexes
+        // are 1-based and must be unique and sorted desc
+        let mut page_idxs = vec![0; num_pages * size_of::<PageIdx>()];
+        reader.read_exact(&mut page_idxs)?;
+        let mut prev = None;
+        for chunk in page_idxs.chunks_exact(size_of::<PageIdx>()) {
+            let page_idx = PageIdx::from_le_bytes(chunk.try_into().unwrap());
+            if page_idx == 0 || prev.is_some_and(|prev| prev <= page_idx) {
+                return Err(invalid("snapshot page indexes are invalid").into());
+            }
+            prev = Some(page_idx);
+        }
+
+        let mut pages = ExactReader(io::Read::take(
+            &mut *reader,
+            (num_pages * self.page_size) as u64,
+        ));
+        let id = self.journal.id();
+        let mut frame = self
+            .journal
+            .seal(lsn, io::Read::chain(page_idxs.as_slice(), &mut pages))?;
+        self.journal.write_lsn(id, lsn, &mut frame)?;
+        drop(frame);
+
+        // the frame has been written, so drop it if the snapshot is longer
+        // than it claimed to be
+        if reader.read(&mut [0])? != 0 {
+            self.journal.drop_prefix(lsn)?;
+            return Err(invalid("snapshot has trailing data").into());
+        }
+        self.reset()?;
+        Ok(lsn)
+    }
+
+    /// merge every visible frame into the last visible frame, which holds
+    /// only the latest version of each page afterwards. this bounds the
+    /// number of frames a read scans, see truncate for how this affects
+    /// destinations which are missing the merged frames
+    pub fn compact(&mut self) -> Result<(), crate::replication::ReplicationError> {
+        match self.visible_lsn_range.last() {
+            Some(last) => self.truncate(last),
+            None => Ok(()),
+        }
+    }
+
+    /// drop every frame before up_to from the journal, after merging their
+    /// pages into the frame at up_to. the result is the same storage, but
+    /// destinations that are missing the dropped frames must receive the
+    /// frame at up_to as a coalesced frame, see ReplicationSource::read_coalesced
+    pub fn truncate(&mut self, up_to: Lsn) -> Result<(), crate::replication::ReplicationError> {
+        let first = match self.journal.range().first() {
+            Some(first) if first < up_to => first,
+            // nothing to drop
+            _ => return Ok(()),
+        };
+        if !self.visible_lsn_range.contains(up_to) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "can't truncate storage to lsn {}, it's not in the visible range {}",
+                    up_to, self.visible_lsn_range
+                ),
+            )
+            .into());
+        }
+        let range = LsnRange::new(first, up_to);
+
+        log::debug!(target: logging::STORAGE, "truncating storage range {}", range);
+
+        let merged = self.merge_frames(range)?;
+        let mut data = Vec::with_capacity(merged.serialized_len().unwrap_or(0));
+        if merged.num_pages() > 0 {
+            merged.serialize_into(&mut data)?;
+        }
+
+        // replace the frame at up_to before dropping anything, so the journal
+        // never loses pages
+        let id = self.journal.id();
+        let mut frame = self.journal.seal(up_to, data.as_slice())?;
+        self.journal.write_lsn(id, up_to, &mut frame)?;
+        self.journal.drop_prefix(up_to - 1)?;
+        self.visible_lsn_range = self.visible_lsn_range.trim_prefix(up_to - 1);
+        Ok(())
+    }
+}
+
+/// the size of the lsn, page_size, and num_pages fields which start every
+/// snapshot, see Storage::export_snapshot
+pub const SNAPSHOT_HEADER_SIZE: usize = size_of::<Lsn>() + 2 * size_of::<u32>();
+
+/// ExactReader fails with UnexpectedEof if the reader it wraps ends before
+/// its limit, so a truncated snapshot isn't stored as a short frame
+struct ExactReader<R>(io::Take<R>);
+
+impl<R: io::Read> io::Read for ExactReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.0.read(buf)?;
+        if n == 0 && !buf.is_empty() && self.0.limit() > 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(n)
+    }
 }
 
-impl<J: ReplicationSource> ReplicationSource for Storage<J> {
-    type Reader<'a> = <J as ReplicationSource>::Reader<'a>
+/// overwrite the 4 byte header field at field_offset if buf (which starts
+/// at page_offset within page 1) fully contains it
+fn overlay_header_field(buf: &mut [u8], page_offset: usize, field_offset: usize, value: &[u8; 4]) {
+    if page_offset <= field_offset && page_offset + buf.len() >= field_offset + 4 {
+        let start = field_offset - page_offset;
+        buf[start..start + 4].copy_from_slice(value);
+    }
+}
+
+// The header string every SQLite database file starts with
+const SQLITE_HEADER_MAGIC: &[u8; 16] = b"SQLite format 3\0";
+
+// The page size, a big-endian u16 where 1 means 65536
+const PAGE_SIZE_OFFSET: usize = 16;
+
+// The file format write and read versions, 1 for rollback journals and 2 for WAL
+const FILE_FORMAT_VERSION_OFFSETS: [usize; 2] = [18, 19];
+
+/// read an existing SQLite database file into a set of pages, which can be
+/// appended to an empty storage journal as the first frame of a new document.
+/// the file must not have uncommitted changes in a hot journal or WAL file,
+/// and its page size must match page_size.
+/// the file change counter is managed by storage, so it's reset along with
+/// the WAL flags. databases which weren't created with auto_vacuum =
+/// incremental can't report changes per table, so subscriptions refresh on
+/// every change until the database is vacuumed with it enabled
+pub fn import_sqlite_file<R: io::Read>(mut reader: R, page_size: usize) -> io::Result<SparsePages> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+
+    // the header is checked before the rest of the file is read
+    let mut header = [0; 100];
+    match reader.read_exact(&mut header) {
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+            return Err(invalid("not a SQLite database file".into()));
+        }
+        result => result?,
+    }
+    if &header[..SQLITE_HEADER_MAGIC.len()] != SQLITE_HEADER_MAGIC {
+        return Err(invalid("not a SQLite database file".into()));
+    }
+
+    let raw_page_size = [header[PAGE_SIZE_OFFSET], header[PAGE_SIZE_OFFSET + 1]];
+    let file_page_size = match u16::from_be_bytes(raw_page_size) {
+        1 => 65536,
+        n => n as usize,
+    };
+    if file_page_size != page_size {
+        return Err(invalid(format!(
+            "database page size {} doesn't match the document's page size {}",
+            file_page_size, page_size
+        )));
+    }
+
+    // read a page at a time, the first page is kept aside until the number
+    // of pages is known
+    let mut pages = SparsePages::new(page_size);
+    let mut first_page = None;
+    let mut num_pages: PageIdx = 0;
+    let mut file_size = header.len();
+    let mut page = header.to_vec();
+    loop {
+        let remaining = (page_size - page.len()) as u64;
+        file_size += (&mut reader).take(remaining).read_to_end(&mut page)?;
+        if page.is_empty() {
+            break;
+        }
+        if page.len() != page_size {
+            return Err(invalid(format!(
+                "database file size {} isn't a multiple of the page size {}",
+                file_size, page_size
+            )));
+        }
+        num_pages += 1;
+        let page = std::mem::replace(&mut page, Vec::with_capacity(page_size));
+        if num_pages == 1 {
+            first_page = Some(page);
+        } else {
+            pages.write(num_pages, page.into());
+        }
+    }
+
+    let mut first_page = first_page.expect("the header is part of the first page");
+    for offset in FILE_FORMAT_VERSION_OFFSETS {
+        first_page[offset] = 1;
+    }
+    overlay_header_field(&mut first_page, 0, FILE_CHANGE_COUNTER_OFFSET, &[0; 4]);
+    overlay_header_field(&mut first_page, 0, VERSION_VALID_FOR_OFFSET, &[0; 4]);
+    let size = num_pages.to_be_bytes();
+    overlay_header_field(&mut first_page, 0, DATABASE_SIZE_OFFSET, &size);
+    pages.write(1, first_page.into());
+    Ok(pages)
+}
+
+impl<J: Journal + ReplicationSource> ReplicationSource for Storage<J> {
+    type Reader<'a> = FrameReader<<J as ReplicationSource>::Reader<'a>>
     where
         Self: 'a;
 
@@ -359,16 +1317,49 @@ impl<J: ReplicationSource> ReplicationSource for Storage<J> {
     }
 
     fn read_lsn(&self, lsn: crate::Lsn) -> io::Result<Option<Self::Reader<'_>>> {
-        self.journal.read_lsn(lsn)
+        Ok(self.journal.read_lsn(lsn)?.map(FrameReader::Frame))
+    }
+
+    fn read_coalesced(&self, range: LsnRange) -> io::Result<Option<Self::Reader<'_>>> {
+        let source_range = self.journal.source_range();
+        if !matches!(range.last(), Some(last) if source_range.contains(last)) {
+            return Ok(None);
+        }
+
+        // later frames overwrite earlier ones, leaving the latest version of
+        // every page changed in range. frames before the source range were
+        // merged into its first frame by truncate
+        let mut merged = SparsePages::new(self.page_size);
+        for lsn in range.intersect(&source_range).iter() {
+            let frame = self.journal.get(lsn)?.expect("lsn is in source range");
+            let pages = SerializedPagesReader::new(frame, self.page_size);
+            for page_idx in pages.page_idxs()? {
+                let mut page: Page = vec![0; self.page_size].into();
+                pages.read(page_idx, 0, &mut page)?;
+                merged.write(page_idx, page);
+            }
+        }
+
+        let mut data = Vec::with_capacity(merged.serialized_len().unwrap_or(0));
+        if merged.num_pages() > 0 {
+            merged.serialize_into(&mut data)?;
+        }
+        // the merged frame is sent like any other frame from the journal
+        let last = range.last().expect("range is non-empty");
+        let mut sealed = Vec::with_capacity(data.len());
+        self.journal
+            .seal(last, data.as_slice())?
+            .read_to_end(&mut sealed)?;
+        Ok(Some(FrameReader::Coalesced(PositionedCursor::new(sealed))))
     }
 }
 
-impl<J: ReplicationDestination> ReplicationDestination for Storage<J> {
+impl<J: Journal + ReplicationDestination> ReplicationDestination for Storage<J> {
     fn range(
         &mut self,
         id: crate::JournalId,
     ) -> Result<LsnRange, crate::replication::ReplicationError> {
-        self.journal.range(id)
+        ReplicationDestination::range(&mut self.journal, id)
     }
 
     fn write_lsn<R>(
@@ -382,22 +1373,39 @@ impl<J: ReplicationDestination> ReplicationDestination for Storage<J> {
     {
         self.journal.write_lsn(id, lsn, reader)
     }
+
+    fn write_coalesced<R>(
+        &mut self,
+        id: crate::JournalId,
+        range: LsnRange,
+        reader: &mut R,
+    ) -> Result<(), crate::replication::ReplicationError>
+    where
+        R: io::Read,
+    {
+        // every lsn but the last is stored as an empty frame, which keeps our
+        // lsns aligned with the source while only storing the latest pages
+        if let Some(last) = range.last() {
+            for lsn in range.iter().filter(|&lsn| lsn != last) {
+                let mut frame = self.journal.seal(lsn, io::empty())?;
+                self.journal.write_lsn(id, lsn, &mut frame)?;
+            }
+            self.journal.write_lsn(id, last, reader)?;
+        }
+        Ok(())
+    }
 }
 
 impl<J: Journal> sqlite_vfs::File for Storage<J> {
-    fn file_size(&self) -> sqlite_vfs::VfsResult<u64> {
-        let mut max_page_idx = self.pending.max_page_idx();
-
-        // if we have visible lsns in storage, then we need to scan them
-        // to find the max page idx
-        let mut cursor = self.journal.scan_range(self.visible_lsn_range);
-        while cursor.advance().map_err(|_| SQLITE_IOERR)? {
-            let pages = SerializedPagesReader(&cursor);
-            max_page_idx = max_page_idx.max(Some(pages.max_page_idx().map_err(|_| SQLITE_IOERR)?));
-        }
+    fn sector_size(&self) -> usize {
+        // sqlite only ever writes whole pages to storage
+        self.page_size
+    }
 
+    fn file_size(&self) -> sqlite_vfs::VfsResult<u64> {
+        let max_page_idx = self.max_page_idx().map_err(|_| SQLITE_IOERR)?;
         Ok(max_page_idx
-            .map(|n| (n as u64) * (PAGESIZE as u64))
+            .map(|n| (n as u64) * (self.page_size as u64))
             .unwrap_or(0))
     }
 
@@ -407,18 +1415,29 @@ impl<J: Journal> sqlite_vfs::File for Storage<J> {
     }
 
     fn write(&mut self, pos: u64, buf: &[u8]) -> sqlite_vfs::VfsResult<usize> {
-        let page_idx = ((pos / (PAGESIZE as u64)) + 1) as PageIdx;
-        log::debug!("writing page {}", page_idx);
+        let page_idx = ((pos / (self.page_size as u64)) + 1) as PageIdx;
+        log::debug!(target: logging::STORAGE, "writing page {}", page_idx);
 
         // for now we panic if we attempt to write less than a full page
-        assert!(buf.len() == PAGESIZE);
-
-        let page: Page = buf.try_into().unwrap();
-        self.pending.write(page_idx, page);
+        assert!(buf.len() == self.page_size);
 
         // update the file change counter
         self.file_change_counter = self.file_change_counter.wrapping_add(1);
 
+        // SQLite often rewrites pages without changing them, for example it
+        // rewrites page 1 on every write transaction to bump the file change
+        // counter (which we manage out of band). we drop these writes rather
+        // than storing and invalidating an unchanged page
+        if self
+            .is_unchanged_write(page_idx, buf)
+            .map_err(|_| SQLITE_IOERR)?
+        {
+            return Ok(buf.len());
+        }
+
+        self.pending.write(page_idx, buf.into());
+        self.maybe_spill().map_err(|_| SQLITE_IOERR)?;
+
         // mark the page as changed
         self.changed_pages.insert(page_idx);
 
@@ -434,3 +1453,702 @@ impl<J: Journal> sqlite_vfs::File for Storage<J> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        coordinator::CoordinatorDocument,
+        error::Error,
+        local::{DocumentSignals, LocalDocument},
+        replication::ReplicationProtocol,
+        test_helpers::{open_coordinator, open_local, replicate, SqlReducer, TestLocal},
+        JournalId, MemoryJournal, MemoryJournalFactory, ReactiveQuery,
+    };
+    use crate::{
+        db::{open_with_vfs, OpenConfig},
+        journal::Scannable,
+        page::DEFAULT_PAGESIZE,
+    };
+
+    use super::*;
+
+    fn root_page(doc: &TestLocal, name: &str) -> anyhow::Result<PageIdx> {
+        Ok(doc.query(|conn| {
+            conn.query_row(
+                "SELECT rootpage FROM sqlite_master WHERE name = ?",
+                [name],
+                |row| row.get(0),
+            )
+        })?)
+    }
+
+    #[test]
+    fn test_generated_columns() -> anyhow::Result<()> {
+        let doc_id = JournalId::new128(&mut rand::thread_rng());
+        let mut local = open_local(doc_id)?;
+        let mut local2 = open_local(doc_id)?;
+        let mut coordinator = open_coordinator(doc_id)?;
+
+        let mut local_to_coordinator = ReplicationProtocol::new();
+        let mut coordinator_to_local2 = ReplicationProtocol::new();
+
+        macro_rules! sync {
+            () => {
+                replicate(&mut local_to_coordinator, &local, &mut coordinator)?;
+                while coordinator.has_pending_work() {
+                    coordinator.step()?;
+                }
+                replicate(&mut coordinator_to_local2, &coordinator, &mut local2)?;
+                local2.rebase()?;
+            };
+        }
+
+        local.mutate(
+            b"CREATE TABLE items (
+                price REAL NOT NULL,
+                qty INTEGER NOT NULL,
+                total REAL GENERATED ALWAYS AS (price * qty) STORED
+            );
+            CREATE INDEX items_total ON items (total);
+            CREATE TABLE other (value);",
+        )?;
+        sync!();
+        assert!(matches!(local2.storage_changes()?, StorageChange::Full));
+
+        local.mutate(b"INSERT INTO items (price, qty) VALUES (1.5, 2), (2.0, 3)")?;
+        sync!();
+
+        let totals: Vec<f64> = local2.query(|conn| {
+            let mut stmt = conn.prepare("SELECT total FROM items ORDER BY total")?;
+            let rows = stmt.query_map([], |row| row.get(0))?;
+            rows.collect::<rusqlite::Result<_>>()
+        })?;
+        assert_eq!(totals, vec![3.0, 6.0]);
+
+        // the change should be attributed to the table and its index, but not other tables
+        match local2.storage_changes()? {
+            StorageChange::Full | StorageChange::Schema { .. } => {
+                panic!("expected table level changes")
+            }
+            StorageChange::Tables { root_pages_sorted } => {
+                assert!(root_pages_sorted.contains(&root_page(&local2, "items")?));
+                assert!(root_pages_sorted.contains(&root_page(&local2, "items_total")?));
+                assert!(!root_pages_sorted.contains(&root_page(&local2, "other")?));
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_without_rowid_changes() -> anyhow::Result<()> {
+        let doc_id = JournalId::new128(&mut rand::thread_rng());
+        let mut local = open_local(doc_id)?;
+        let mut local2 = open_local(doc_id)?;
+        let mut coordinator = open_coordinator(doc_id)?;
+
+        let mut local_to_coordinator = ReplicationProtocol::new();
+        let mut coordinator_to_local2 = ReplicationProtocol::new();
+
+        macro_rules! sync {
+            () => {
+                replicate(&mut local_to_coordinator, &local, &mut coordinator)?;
+                while coordinator.has_pending_work() {
+                    coordinator.step()?;
+                }
+                replicate(&mut coordinator_to_local2, &coordinator, &mut local2)?;
+                local2.rebase()?;
+            };
+        }
+
+        // enough rows that each btree spans many pages
+        local.mutate(
+            b"CREATE TABLE kv (k TEXT PRIMARY KEY, v BLOB) WITHOUT ROWID;
+            CREATE TABLE pairs (a, b, v, PRIMARY KEY (a, b)) WITHOUT ROWID;
+            CREATE TABLE other (value);
+            WITH RECURSIVE seq(n) AS (SELECT 0 UNION ALL SELECT n + 1 FROM seq WHERE n < 499)
+            INSERT INTO kv SELECT printf('key%04d', n), randomblob(100) FROM seq;
+            WITH RECURSIVE seq(n) AS (SELECT 0 UNION ALL SELECT n + 1 FROM seq WHERE n < 499)
+            INSERT INTO pairs SELECT n % 10, n, randomblob(100) FROM seq;
+            INSERT INTO other VALUES (1);",
+        )?;
+        sync!();
+        assert!(matches!(local.storage_changes()?, StorageChange::Full));
+        assert!(matches!(local2.storage_changes()?, StorageChange::Full));
+
+        let assert_changed = |doc: &mut TestLocal, table: &str| -> anyhow::Result<()> {
+            match doc.storage_changes()? {
+                StorageChange::Full | StorageChange::Schema { .. } => {
+                    panic!("expected table level changes")
+                }
+                StorageChange::Tables { root_pages_sorted } => {
+                    for name in ["kv", "pairs", "other"] {
+                        assert_eq!(
+                            root_pages_sorted.contains(&root_page(doc, name)?),
+                            name == table,
+                            "{} changed",
+                            name
+                        );
+                    }
+                }
+            }
+            Ok(())
+        };
+
+        // changes to leaf and overflow pages are traced to the table, whether
+        // they are pending or received from the coordinator
+        local.mutate(b"UPDATE kv SET v = randomblob(10000) WHERE k = 'key0250'")?;
+        assert_changed(&mut local, "kv")?;
+        sync!();
+        assert_changed(&mut local2, "kv")?;
+
+        local.mutate(b"UPDATE pairs SET v = 1 WHERE a = 3 AND b = 253")?;
+        assert_changed(&mut local, "pairs")?;
+        sync!();
+        assert_changed(&mut local2, "pairs")?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_unresolved_changes() -> anyhow::Result<()> {
+        let journal = MemoryJournal::open(JournalId::new128(&mut rand::thread_rng()))?;
+        let (sqlite, mut storage) =
+            open_with_vfs(journal, DEFAULT_PAGESIZE, &OpenConfig::default())?;
+
+        sqlite
+            .readwrite
+            .execute_batch("CREATE TABLE items (value); INSERT INTO items VALUES (1)")?;
+        storage.commit()?;
+        storage.changes(None)?;
+        let root_page: u64 = sqlite.readonly.query_row(
+            "SELECT rootpage FROM sqlite_master WHERE name = 'items'",
+            [],
+            |row| row.get(0),
+        )?;
+
+        // corrupt the table's entry in the first ptrmap page
+        let mut ptrmap = vec![0; DEFAULT_PAGESIZE];
+        let ptrmap_pos = DEFAULT_PAGESIZE as u64;
+        storage.read_at_range(storage.visible_lsn_range, true, ptrmap_pos, &mut ptrmap)?;
+        ptrmap[(root_page as usize - 3) * 5] = 9;
+        sqlite_vfs::File::write(&mut *storage, ptrmap_pos, &ptrmap).unwrap();
+        storage.commit()?;
+        assert!(matches!(
+            storage.changes(None)?,
+            StorageChange::Tables { root_pages_sorted } if root_pages_sorted.is_empty()
+        ));
+
+        // a change to the table can't be traced, so everything changed
+        sqlite
+            .readwrite
+            .execute_batch("UPDATE items SET value = 2")?;
+        assert!(storage.has_changes());
+        assert!(matches!(storage.changes(None)?, StorageChange::Full));
+        assert!(!storage.has_changes());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_auto_vacuum_changes() -> anyhow::Result<()> {
+        for auto_vacuum in ["incremental", "none"] {
+            let journal = MemoryJournal::open(JournalId::new128(&mut rand::thread_rng()))?;
+            let (sqlite, mut storage) =
+                open_with_vfs(journal, DEFAULT_PAGESIZE, &OpenConfig::default())?;
+
+            // changing auto_vacuum requires rebuilding the database
+            sqlite
+                .readwrite
+                .pragma_update(None, "auto_vacuum", auto_vacuum)?;
+            sqlite.readwrite.execute_batch("VACUUM")?;
+            sqlite.readwrite.execute_batch(
+                "CREATE TABLE a (value); CREATE TABLE b (value);
+                WITH RECURSIVE seq(n) AS (SELECT 0 UNION ALL SELECT n + 1 FROM seq WHERE n < 99)
+                INSERT INTO a SELECT randomblob(500) FROM seq;
+                INSERT INTO b VALUES (1);",
+            )?;
+            storage.commit()?;
+            assert!(matches!(storage.changes(None)?, StorageChange::Full));
+            let root_page = |name: &str| -> rusqlite::Result<PageIdx> {
+                sqlite.readonly.query_row(
+                    "SELECT rootpage FROM sqlite_master WHERE name = ?",
+                    [name],
+                    |row| row.get(0),
+                )
+            };
+
+            // without ptrmap pages a change to a leaf page can't be traced
+            sqlite
+                .readwrite
+                .execute("UPDATE a SET value = randomblob(500) WHERE rowid = 50", [])?;
+            storage.commit()?;
+            match (auto_vacuum, storage.changes(None)?) {
+                ("incremental", StorageChange::Tables { root_pages_sorted }) => {
+                    assert!(root_pages_sorted.contains(&root_page("a")?));
+                    assert!(!root_pages_sorted.contains(&root_page("b")?));
+                }
+                ("none", StorageChange::Full) => {}
+                (_, change) => panic!("unexpected change {:?} for {}", change, auto_vacuum),
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_covering_index_changes() -> anyhow::Result<()> {
+        let mut local = open_local(JournalId::new128(&mut rand::thread_rng()))?;
+        local.mutate(
+            b"CREATE TABLE users (email TEXT, bio TEXT);
+            CREATE INDEX users_email ON users (email);
+            INSERT INTO users VALUES ('alice@example.com', 'hi'), ('bob@example.com', 'hey');",
+        )?;
+        assert!(matches!(local.storage_changes()?, StorageChange::Full));
+
+        // the first query is answered from the index alone
+        let mut queries = [
+            "SELECT email FROM users WHERE email > 'b'",
+            "SELECT bio FROM users",
+        ]
+        .map(|sql| ReactiveQuery::<i64>::new(sql.into(), vec![]));
+        for query in queries.iter_mut() {
+            query.refresh(local.sqlite_readonly(), |_, row| row.get::<_, String>(0))?;
+        }
+        let deps = queries[0].dependencies(local.sqlite_readonly())?;
+        assert_eq!(deps, HashSet::from([root_page(&local, "users_email")?]));
+
+        let mut handle_change = |local: &mut TestLocal| -> anyhow::Result<Vec<bool>> {
+            let change = local.storage_changes()?;
+            assert!(matches!(change, StorageChange::Tables { .. }));
+            Ok(queries
+                .iter_mut()
+                .map(|query| {
+                    let dirty = query.handle_storage_change(&change);
+                    if dirty {
+                        query
+                            .refresh(local.sqlite_readonly(), |_, row| row.get::<_, String>(0))
+                            .unwrap();
+                    }
+                    dirty
+                })
+                .collect())
+        };
+
+        // changing a column outside the index only touches the table
+        local.mutate(b"UPDATE users SET bio = 'hello' WHERE email = 'bob@example.com'")?;
+        assert_eq!(handle_change(&mut local)?, vec![false, true]);
+
+        // changing an indexed column touches both
+        local.mutate(b"UPDATE users SET email = 'carol@example.com' WHERE bio = 'hi'")?;
+        assert_eq!(handle_change(&mut local)?, vec![true, true]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_page_sizes() -> anyhow::Result<()> {
+        for page_size in [4096, 8192, 16384] {
+            let doc_id = JournalId::new128(&mut rand::thread_rng());
+            let open_local = || {
+                LocalDocument::open_with_page_size(
+                    MemoryJournal::open(doc_id)?,
+                    MemoryJournal::open(JournalId::new128(&mut rand::thread_rng()))?,
+                    SqlReducer,
+                    DocumentSignals::default(),
+                    page_size,
+                )
+            };
+            let mut local = open_local()?;
+            let mut local2 = open_local()?;
+            let mut coordinator = CoordinatorDocument::open_with_page_size(
+                MemoryJournal::open(doc_id)?,
+                MemoryJournalFactory,
+                SqlReducer,
+                page_size,
+            )?;
+            let mut local_to_coordinator = ReplicationProtocol::new();
+            let mut coordinator_to_local2 = ReplicationProtocol::new();
+
+            // storage asserts that sqlite only writes pages of page_size
+
+            // blobs larger than a page spill into overflow pages, which are
+            // resolved to their table through a chain of ptrmap entries
+            local.mutate(
+                b"CREATE TABLE a (value); CREATE TABLE b (value); CREATE TABLE c (value);
+                INSERT INTO a VALUES (zeroblob(20000));
+                INSERT INTO c VALUES (zeroblob(20000));",
+            )?;
+            assert!(matches!(local.storage_changes()?, StorageChange::Full));
+
+            local.mutate(b"INSERT INTO b VALUES (randomblob(50000))")?;
+            replicate(&mut local_to_coordinator, &local, &mut coordinator)?;
+            while coordinator.has_pending_work() {
+                coordinator.step()?;
+            }
+            replicate(&mut coordinator_to_local2, &coordinator, &mut local2)?;
+            local2.rebase()?;
+            assert!(matches!(local2.storage_changes()?, StorageChange::Full));
+
+            for doc in [&mut local, &mut local2] {
+                doc.mutate(b"UPDATE b SET value = randomblob(60000)")?;
+                match doc.storage_changes()? {
+                    StorageChange::Full | StorageChange::Schema { .. } => {
+                        panic!("expected table level changes")
+                    }
+                    StorageChange::Tables { root_pages_sorted } => {
+                        assert!(root_pages_sorted.contains(&root_page(doc, "b")?));
+                        assert!(!root_pages_sorted.contains(&root_page(doc, "a")?));
+                        assert!(!root_pages_sorted.contains(&root_page(doc, "c")?));
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_page_size() -> anyhow::Result<()> {
+        let doc_id = JournalId::new128(&mut rand::thread_rng());
+        for page_size in [0, 1000, 1 << 17] {
+            let result = CoordinatorDocument::open_with_page_size(
+                MemoryJournal::open(doc_id)?,
+                MemoryJournalFactory,
+                SqlReducer,
+                page_size,
+            );
+            assert!(
+                matches!(result, Err(Error::IoError(ref e)) if e.kind() == io::ErrorKind::InvalidInput)
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_drop_table_changes() -> anyhow::Result<()> {
+        let mut local = open_local(JournalId::new128(&mut rand::thread_rng()))?;
+        local.mutate(
+            b"CREATE TABLE a (value); CREATE TABLE b (value);
+            CREATE TABLE c (value); CREATE INDEX c_value ON c (value);
+            INSERT INTO a VALUES (1); INSERT INTO b VALUES (2); INSERT INTO c VALUES (3);",
+        )?;
+        assert!(matches!(local.storage_changes()?, StorageChange::Full));
+
+        let mut queries: Vec<_> = ["a", "b", "c"]
+            .map(|name| ReactiveQuery::<i64>::new(format!("SELECT value FROM {}", name), vec![]))
+            .into();
+        for query in queries.iter_mut() {
+            query.refresh(local.sqlite_readonly(), |_, row| row.get::<_, i64>(0))?;
+        }
+        let c_root_pages = [root_page(&local, "c")?, root_page(&local, "c_value")?];
+
+        // dropping c only invalidates the queries which depend on it
+        local.mutate(b"DROP TABLE c; INSERT INTO b VALUES (4)")?;
+        let change = local.storage_changes()?;
+        match change {
+            StorageChange::Schema {
+                ref dropped_root_pages,
+                ref root_pages_sorted,
+            } => {
+                assert_eq!(dropped_root_pages, &c_root_pages);
+                assert!(root_pages_sorted.contains(&root_page(&local, "b")?));
+                assert!(!root_pages_sorted.contains(&root_page(&local, "a")?));
+            }
+            _ => panic!("expected a schema change, got {:?}", change),
+        }
+        let dirty: Vec<_> = queries
+            .iter_mut()
+            .map(|query| query.handle_storage_change(&change))
+            .collect();
+        assert_eq!(dirty, vec![false, true, true]);
+
+        // dropping a table which isn't last relocates root pages, and any
+        // other schema change invalidates everything
+        local.mutate(b"DROP TABLE a")?;
+        assert!(matches!(local.storage_changes()?, StorageChange::Full));
+        local.mutate(b"CREATE TABLE d (value)")?;
+        assert!(matches!(local.storage_changes()?, StorageChange::Full));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_page_one_not_dirtied_by_counter() -> anyhow::Result<()> {
+        let mut local = open_local(JournalId::new128(&mut rand::thread_rng()))?;
+        local.mutate(b"CREATE TABLE items (value); INSERT INTO items VALUES (1)")?;
+        assert!(matches!(local.storage_changes()?, StorageChange::Full));
+
+        // a read only workload should not change anything
+        for _ in 0..3 {
+            let count: i64 = local
+                .query(|conn| conn.query_row("SELECT COUNT(*) FROM items", [], |row| row.get(0)))?;
+            assert_eq!(count, 1);
+        }
+        match local.storage_changes()? {
+            StorageChange::Full | StorageChange::Schema { .. } => {
+                panic!("expected table level changes")
+            }
+            StorageChange::Tables { root_pages_sorted } => assert!(root_pages_sorted.is_empty()),
+        }
+
+        // writes which don't touch the rest of the header only change the
+        // file change counter, which should not mark page 1 as changed
+        local.mutate(b"INSERT INTO items VALUES (2)")?;
+        match local.storage_changes()? {
+            StorageChange::Full | StorageChange::Schema { .. } => {
+                panic!("expected table level changes")
+            }
+            StorageChange::Tables { root_pages_sorted } => {
+                assert!(root_pages_sorted.contains(&root_page(&local, "items")?));
+                assert!(!root_pages_sorted.contains(&1));
+            }
+        }
+
+        // sqlite still notices the change
+        let count: i64 = local
+            .query(|conn| conn.query_row("SELECT COUNT(*) FROM items", [], |row| row.get(0)))?;
+        assert_eq!(count, 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_spill_pending_pages() -> anyhow::Result<()> {
+        let journal = MemoryJournal::open(JournalId::new128(&mut rand::thread_rng()))?;
+        let (sqlite, mut storage) =
+            open_with_vfs(journal, DEFAULT_PAGESIZE, &OpenConfig::default())?;
+        storage.set_spill(
+            MemoryJournal::open(JournalId::new128(&mut rand::thread_rng()))?,
+            8,
+        )?;
+
+        let read_blobs = |conn: &rusqlite::Connection| -> rusqlite::Result<Vec<Vec<u8>>> {
+            let mut stmt = conn.prepare("SELECT b FROM blobs ORDER BY rowid")?;
+            let rows = stmt.query_map([], |row| row.get(0))?;
+            rows.collect()
+        };
+
+        // a single transaction writes many more pages than fit in memory
+        sqlite.readwrite.execute_batch(
+            "BEGIN;
+            CREATE TABLE blobs (b BLOB);
+            WITH RECURSIVE seq(n) AS (SELECT 0 UNION ALL SELECT n + 1 FROM seq WHERE n < 63)
+            INSERT INTO blobs SELECT randomblob(2000) FROM seq;
+            COMMIT;",
+        )?;
+        assert!(storage.num_pending_pages() <= 8);
+        assert!(storage.spill.as_ref().unwrap().pages.len() > 16);
+
+        // pages rewritten after spilling are indexed by their newest frame
+        let spill = storage.spill.as_ref().unwrap();
+        assert!(spill.journal.range().len() > 1);
+        let mut newest = BTreeMap::new();
+        let mut cursor = spill.journal.scan();
+        while cursor.advance()? {
+            for page_idx in SerializedPagesReader::new(&cursor, DEFAULT_PAGESIZE).page_idxs()? {
+                newest.insert(page_idx, cursor.lsn().unwrap());
+            }
+        }
+        assert_eq!(spill.pages, newest);
+
+        // spilled pages are read back transparently
+        let blobs = read_blobs(&sqlite.readonly)?;
+        assert_eq!(blobs.len(), 64);
+        let integrity: String =
+            sqlite
+                .readwrite
+                .query_row("PRAGMA integrity_check", [], |row| row.get(0))?;
+        assert_eq!(integrity, "ok");
+
+        // and committed as a single frame
+        storage.commit()?;
+        assert_eq!(storage.journal.range(), LsnRange::new(0, 0));
+        assert_eq!(storage.num_pending_pages(), 0);
+        assert!(storage.spill.as_ref().unwrap().journal.range().is_empty());
+        assert_eq!(read_blobs(&sqlite.readonly)?, blobs);
+
+        // reset discards spilled pages
+        sqlite
+            .readwrite
+            .execute_batch("UPDATE blobs SET b = randomblob(2000)")?;
+        assert!(!storage.spill.as_ref().unwrap().pages.is_empty());
+        storage.reset()?;
+        assert!(storage.spill.as_ref().unwrap().pages.is_empty());
+        assert_eq!(read_blobs(&sqlite.readonly)?, blobs);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_compact() -> anyhow::Result<()> {
+        let journal = MemoryJournal::open(JournalId::new128(&mut rand::thread_rng()))?;
+        let (sqlite, mut storage) =
+            open_with_vfs(journal, DEFAULT_PAGESIZE, &OpenConfig::default())?;
+        let read_items = |conn: &rusqlite::Connection| -> rusqlite::Result<Vec<i64>> {
+            let mut stmt = conn.prepare("SELECT value FROM items ORDER BY rowid")?;
+            let rows = stmt.query_map([], |row| row.get(0))?;
+            rows.collect()
+        };
+
+        // every commit rewrites the same pages
+        sqlite
+            .readwrite
+            .execute_batch("CREATE TABLE items (value); INSERT INTO items VALUES (0)")?;
+        storage.commit()?;
+        for i in 1..20 {
+            sqlite
+                .readwrite
+                .execute("UPDATE items SET value = ?", [i])?;
+            sqlite
+                .readwrite
+                .execute("INSERT INTO items VALUES (?)", [i])?;
+            storage.commit()?;
+        }
+        let range = storage.journal.range();
+        assert!(range.len() > 10);
+        let items = read_items(&sqlite.readonly)?;
+        let mut snapshot = Vec::new();
+        storage.export_snapshot(&mut snapshot)?;
+
+        // reads only scan the last frame, which contains every page
+        storage.compact()?;
+        assert_eq!(
+            storage.journal.range(),
+            LsnRange::new(range.last().unwrap(), range.last().unwrap())
+        );
+        assert_eq!(read_items(&sqlite.readonly)?, items);
+        let mut compacted = Vec::new();
+        storage.export_snapshot(&mut compacted)?;
+        assert_eq!(compacted, snapshot);
+
+        // compacting again is a noop, and writes continue from the same lsn
+        storage.compact()?;
+        sqlite
+            .readwrite
+            .execute("INSERT INTO items VALUES (20)", [])?;
+        storage.commit()?;
+        assert_eq!(storage.journal.range().len(), 2);
+        assert_eq!(read_items(&sqlite.readonly)?.len(), items.len() + 1);
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "encryption")]
+    fn test_compact_encrypted() -> anyhow::Result<()> {
+        use crate::{ChaChaCipher, EncryptedJournal};
+
+        let journal = EncryptedJournal::open(
+            MemoryJournal::open(JournalId::new128(&mut rand::thread_rng()))?,
+            ChaChaCipher::new(&[1; 32]),
+        );
+        let (sqlite, mut storage) =
+            open_with_vfs(journal, DEFAULT_PAGESIZE, &OpenConfig::default())?;
+        for i in 0..5 {
+            sqlite
+                .readwrite
+                .execute_batch(&format!("CREATE TABLE t{} (x)", i))?;
+            storage.commit()?;
+        }
+
+        // the merged frame is encrypted like any other
+        storage.compact()?;
+        let tables: i64 = sqlite.readonly.query_row(
+            "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table'",
+            [],
+            |row| row.get(0),
+        )?;
+        assert_eq!(tables, 5);
+        storage.journal.verify_frames(|frame| {
+            SerializedPagesReader::new(frame, DEFAULT_PAGESIZE).validate()
+        })?;
+
+        // and replicated as ciphertext to a destination with the same key,
+        // which can still catch up from the merged frame
+        let range = LsnRange::new(0, storage.source_range().last().unwrap());
+        let mut frame = storage.read_coalesced(range)?.unwrap();
+        let journal = EncryptedJournal::open(
+            MemoryJournal::open(storage.source_id())?,
+            ChaChaCipher::new(&[1; 32]),
+        );
+        let (dest_sqlite, mut dest) =
+            open_with_vfs(journal, DEFAULT_PAGESIZE, &OpenConfig::default())?;
+        dest.write_coalesced(storage.source_id(), range, &mut frame)?;
+        dest.reset()?;
+        let tables: i64 = dest_sqlite.readonly.query_row(
+            "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table'",
+            [],
+            |row| row.get(0),
+        )?;
+        assert_eq!(tables, 5);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_unchanged_writes_are_dropped() -> anyhow::Result<()> {
+        let journal = MemoryJournal::open(JournalId::new128(&mut rand::thread_rng()))?;
+        let (sqlite, mut storage) =
+            open_with_vfs(journal, DEFAULT_PAGESIZE, &OpenConfig::default())?;
+
+        sqlite
+            .readwrite
+            .execute_batch("CREATE TABLE items (value); INSERT INTO items VALUES (1)")?;
+        storage.commit()?;
+        storage.changes(None)?;
+        let range = storage.journal.range();
+
+        // sqlite rewrites the table's page even though nothing changed
+        sqlite.readwrite.execute_batch(
+            "BEGIN;
+            SAVEPOINT s;
+            UPDATE items SET value = 2;
+            ROLLBACK TO s;
+            RELEASE s;
+            COMMIT;",
+        )?;
+        assert_eq!(storage.num_pending_pages(), 0);
+        assert!(!storage.has_changes());
+        storage.commit()?;
+        assert_eq!(storage.journal.range(), range);
+        match storage.changes(None)? {
+            StorageChange::Tables { root_pages_sorted } => assert!(root_pages_sorted.is_empty()),
+            change => panic!("expected no table changes, got {:?}", change),
+        }
+
+        // real changes are still recorded
+        sqlite
+            .readwrite
+            .execute_batch("UPDATE items SET value = 2")?;
+        assert_eq!(storage.num_pending_pages(), 1);
+        assert!(storage.has_changes());
+        let value: i64 = sqlite
+            .readwrite
+            .query_row("SELECT value FROM items", [], |row| row.get(0))?;
+        assert_eq!(value, 2);
+
+        // pages only found in older frames aren't compared, so rewriting
+        // them stores a copy which reads back the same
+        storage.commit()?;
+        sqlite
+            .readwrite
+            .execute_batch("CREATE TABLE others (value)")?;
+        storage.commit()?;
+        sqlite.readwrite.execute_batch(
+            "BEGIN;
+            SAVEPOINT s;
+            UPDATE items SET value = 3;
+            ROLLBACK TO s;
+            RELEASE s;
+            COMMIT;",
+        )?;
+        assert_eq!(storage.num_pending_pages(), 1);
+        let value: i64 = sqlite
+            .readwrite
+            .query_row("SELECT value FROM items", [], |row| row.get(0))?;
+        assert_eq!(value, 2);
+
+        Ok(())
+    }
+}
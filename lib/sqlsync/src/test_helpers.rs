@@ -0,0 +1,155 @@
+use std::io;
+
+use rusqlite::Transaction;
+
+use crate::{
+    coordinator::CoordinatorDocument,
+    duplex::link,
+    local::{DocumentSignals, LocalDocument, NoopSignal},
+    reducer::{Reducer, ReducerError, ReducerOutput},
+    replication::{
+        ReplicationDestination, ReplicationError, ReplicationProtocol, ReplicationSource,
+    },
+    JournalId, Lsn, LsnRange, MemoryJournal, MemoryJournalFactory,
+};
+
+/// SqlReducer is a native reducer which executes each mutation as a batch of
+/// sql statements, allowing documents to be tested without building wasm
+pub struct SqlReducer;
+
+impl Reducer for SqlReducer {
+    fn apply(
+        &mut self,
+        tx: &mut Transaction,
+        mutation: &[u8],
+    ) -> Result<ReducerOutput, ReducerError> {
+        let sql = std::str::from_utf8(mutation).map_err(|e| ReducerError::External(e.into()))?;
+        tx.execute_batch(sql)?;
+        Ok(None)
+    }
+}
+
+pub type TestLocal = LocalDocument<MemoryJournal, NoopSignal, SqlReducer>;
+pub type TestCoordinator = CoordinatorDocument<MemoryJournal, SqlReducer>;
+
+pub fn open_local(doc_id: JournalId) -> crate::error::Result<TestLocal> {
+    LocalDocument::open(
+        MemoryJournal::open(doc_id)?,
+        MemoryJournal::open(JournalId::new128(&mut rand::thread_rng()))?,
+        SqlReducer,
+        DocumentSignals::default(),
+    )
+}
+
+pub fn open_coordinator(doc_id: JournalId) -> crate::error::Result<TestCoordinator> {
+    CoordinatorDocument::open(
+        MemoryJournal::open(doc_id)?,
+        MemoryJournalFactory,
+        SqlReducer,
+    )
+}
+
+/// replicate every available frame from src to dest, returning the number of
+/// frames sent. protocol tracks the src side of the connection and must be
+/// reused between calls.
+pub fn replicate<S, D>(
+    protocol: &mut ReplicationProtocol,
+    src: &S,
+    dest: &mut D,
+) -> Result<usize, ReplicationError>
+where
+    S: ReplicationSource,
+    D: ReplicationDestination,
+{
+    replicate_acked(protocol, &mut Unacked(src), dest)
+}
+
+/// like replicate, but range acknowledgements are delivered back to src which
+/// lets src learn what dest has applied
+pub fn replicate_acked<S, D>(
+    protocol: &mut ReplicationProtocol,
+    src: &mut S,
+    dest: &mut D,
+) -> Result<usize, ReplicationError>
+where
+    S: ReplicationSource + ReplicationDestination,
+    D: ReplicationDestination,
+{
+    // the destination side of the protocol is stateless
+    let mut dest_protocol = ReplicationProtocol::new();
+    let (mut src_end, mut dest_end) = link(protocol, &mut dest_protocol);
+
+    src_end.start(src);
+    let mut num_frames = 0;
+    loop {
+        let frames = src_end.send_frames(src)?;
+        let handled = dest_end.receive(dest)? + src_end.receive(src)?;
+        if frames + handled == 0 {
+            return Ok(num_frames);
+        }
+        num_frames += frames;
+    }
+}
+
+/// Unacked wraps a source whose acknowledgements are discarded
+struct Unacked<'a, S>(&'a S);
+
+impl<S: ReplicationSource> ReplicationSource for Unacked<'_, S> {
+    type Reader<'a> = S::Reader<'a>
+    where
+        Self: 'a;
+
+    fn source_id(&self) -> JournalId {
+        self.0.source_id()
+    }
+
+    fn source_range(&self) -> LsnRange {
+        self.0.source_range()
+    }
+
+    fn read_lsn(&self, lsn: Lsn) -> io::Result<Option<Self::Reader<'_>>> {
+        self.0.read_lsn(lsn)
+    }
+
+    fn read_coalesced(&self, range: LsnRange) -> io::Result<Option<Self::Reader<'_>>> {
+        self.0.read_coalesced(range)
+    }
+
+    fn frames_sent(&self, range: LsnRange) {
+        self.0.frames_sent(range)
+    }
+}
+
+impl<S> ReplicationDestination for Unacked<'_, S> {
+    fn range(&mut self, id: JournalId) -> Result<LsnRange, ReplicationError> {
+        Acks.range(id)
+    }
+
+    fn write_lsn<R: io::Read>(
+        &mut self,
+        id: JournalId,
+        lsn: Lsn,
+        reader: &mut R,
+    ) -> Result<(), ReplicationError> {
+        Acks.write_lsn(id, lsn, reader)
+    }
+}
+
+/// the source side of a connection only receives range acknowledgements,
+/// which never touch the destination passed to ReplicationProtocol::handle
+pub struct Acks;
+
+impl ReplicationDestination for Acks {
+    fn range(&mut self, _: JournalId) -> Result<LsnRange, ReplicationError> {
+        unreachable!("acks are never asked for their range")
+    }
+
+    fn write_lsn<R: io::Read>(
+        &mut self,
+        _: JournalId,
+        _: Lsn,
+        _: &mut R,
+    ) -> Result<(), ReplicationError> {
+        unreachable!("acks never receive frames")
+    }
+}
@@ -1,35 +1,110 @@
 use std::io;
 
-use rusqlite::{named_params, Connection};
+use rusqlite::{named_params, Connection, OptionalExtension, Transaction};
 use thiserror::Error;
 
 use crate::{
-    db::run_in_tx,
-    journal::Journal,
-    lsn::{Lsn, LsnRange},
+    db::{run_in_savepoint, run_in_tx},
+    journal::{Journal, JournalId},
+    logging,
+    lsn::{Lsn, LsnRange, LsnSet},
+    meta::{decode_set_meta, get_meta, is_reserved_meta_key, run_meta_migration, set_meta},
+    page::PageIdx,
     positioned_io::PositionedReader,
-    reducer::{Reducer, ReducerError, WasmReducer},
+    random::seed_randomness,
+    reducer::{MutationContext, Reducer, ReducerError, ReducerOutput},
+    replication::Rejection,
+    unixtime::{sqlite_timestamp_milliseconds, unix_timestamp_milliseconds, with_mutation_time},
 };
 
 const TIMELINES_TABLE_SQL: &str = "
     CREATE TABLE IF NOT EXISTS __sqlsync_timelines (
         id BLOB PRIMARY KEY NOT NULL,
-        lsn INTEGER NOT NULL
+        lsn INTEGER NOT NULL,
+        time INTEGER
     ) STRICT
 ";
 
+// documents created before the coordinator recorded mutation times
+const TIMELINES_ADD_TIME_SQL: &str = "
+    ALTER TABLE __sqlsync_timelines ADD COLUMN time INTEGER
+";
+
 const TIMELINES_READ_LSN_SQL: &str = "
     SELECT lsn
     FROM __sqlsync_timelines
     WHERE id = :id
 ";
 
+const TIMELINES_READ_TIME_SQL: &str = "
+    SELECT time
+    FROM __sqlsync_timelines
+    WHERE id = :id
+";
+
 const TIMELINES_UPDATE_LSN_SQL: &str = "
-    INSERT INTO __sqlsync_timelines (id, lsn)
-    VALUES (:id, :lsn)
-    ON CONFLICT (id) DO UPDATE SET lsn = :lsn
+    INSERT INTO __sqlsync_timelines (id, lsn, time)
+    VALUES (:id, :lsn, :time)
+    ON CONFLICT (id) DO UPDATE SET lsn = :lsn, time = :time
+";
+
+// outputs returned by the reducer while the coordinator applies a timeline,
+// these replicate to the client which sent the mutation and are pruned once
+// it has received the storage frame they were recorded in
+const OUTPUTS_TABLE_SQL: &str = "
+    CREATE TABLE IF NOT EXISTS __sqlsync_outputs (
+        id BLOB NOT NULL,
+        lsn INTEGER NOT NULL,
+        idx INTEGER NOT NULL,
+        output BLOB NOT NULL,
+        PRIMARY KEY (id, lsn, idx)
+    ) STRICT
+";
+
+// documents created before outputs were pruned
+const OUTPUTS_ADD_STORAGE_LSN_SQL: &str = "
+    ALTER TABLE __sqlsync_outputs ADD COLUMN storage_lsn INTEGER NOT NULL DEFAULT 0
 ";
 
+const OUTPUTS_INSERT_SQL: &str = "
+    INSERT OR REPLACE INTO __sqlsync_outputs (id, lsn, idx, output, storage_lsn)
+    VALUES (:id, :lsn, :idx, :output, :storage_lsn)
+";
+
+const OUTPUTS_PRUNE_SQL: &str = "
+    DELETE FROM __sqlsync_outputs
+    WHERE id = :id AND storage_lsn <= :storage_lsn
+";
+
+const OUTPUTS_READ_SQL: &str = "
+    SELECT lsn, idx, output
+    FROM __sqlsync_outputs
+    WHERE id = :id AND lsn >= :lsn
+    ORDER BY lsn, idx
+";
+
+// the schema version of the reducer which last migrated the document, stored
+// in the meta table
+const SCHEMA_VERSION_KEY: &str = "__sqlsync_schema_version";
+
+/// MutationValidator is called by the coordinator with every mutation in a
+/// timeline entry before the entry is applied, returning an error rejects
+/// the whole entry with the error as the reason. this includes meta
+/// mutations, which can be recognized with decode_set_meta
+pub type MutationValidator<'a> =
+    dyn FnMut(&Connection, &MutationContext, &[u8]) -> std::result::Result<(), String> + Send + 'a;
+
+/// MutationOutput is the output of a mutation as computed by the coordinator
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MutationOutput {
+    /// the lsn of the timeline entry containing the mutation
+    pub lsn: Lsn,
+    /// the position of the mutation in the entry, which is only non-zero when
+    /// mutations have been coalesced
+    pub index: usize,
+    pub output: Vec<u8>,
+}
+
 #[derive(Error, Debug)]
 pub enum TimelineError {
     #[error("io error: {0}")]
@@ -44,39 +119,328 @@ pub enum TimelineError {
 
 type Result<T> = std::result::Result<T, TimelineError>;
 
+/// Binary layout of a batch timeline entry is:
+/// BATCH_TAG
+/// repeated:
+///   mutation_len: u32
+///   mutation: [u8; mutation_len]
+const BATCH_TAG: &[u8] = b"\0__sqlsync_batch\0";
+const MUTATION_LEN_SIZE: usize = std::mem::size_of::<u32>();
+
+/// encode several mutations into a single timeline entry, the mutations are
+/// applied in order as if they had been appended individually. LocalDocument
+/// appends each mutation as its own entry, but batch entries are still
+/// accepted from the timeline
+pub fn encode_batch(mutations: &[Vec<u8>]) -> Vec<u8> {
+    let len = mutations
+        .iter()
+        .map(|m| MUTATION_LEN_SIZE + m.len())
+        .sum::<usize>();
+    let mut out = Vec::with_capacity(BATCH_TAG.len() + len);
+    out.extend_from_slice(BATCH_TAG);
+    for mutation in mutations {
+        out.extend_from_slice(&(mutation.len() as u32).to_le_bytes());
+        out.extend_from_slice(mutation);
+    }
+    out
+}
+
+/// decode a batch timeline entry, returns None if the entry is a single mutation
+/// batches can't contain other batches, which bounds the depth at which
+/// entries are unpacked
+fn decode_batch(entry: &[u8]) -> io::Result<Option<Vec<&[u8]>>> {
+    let mut body = match entry.strip_prefix(BATCH_TAG) {
+        Some(body) => body,
+        None => return Ok(None),
+    };
+
+    let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+
+    let mut mutations = Vec::new();
+    while !body.is_empty() {
+        if body.len() < MUTATION_LEN_SIZE {
+            return Err(invalid("batch is missing a mutation length"));
+        }
+        let (len, rest) = body.split_at(MUTATION_LEN_SIZE);
+        let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+        if rest.len() < len {
+            return Err(invalid("batch mutation is truncated"));
+        }
+        let (mutation, rest) = rest.split_at(len);
+        if mutation.starts_with(BATCH_TAG) {
+            return Err(invalid("batch contains another batch"));
+        }
+        mutations.push(mutation);
+        body = rest;
+    }
+    Ok(Some(mutations))
+}
+
 pub fn run_timeline_migration(sqlite: &mut Connection) -> Result<()> {
     sqlite.execute(TIMELINES_TABLE_SQL, [])?;
+    let has_time = sqlite
+        .prepare("SELECT 1 FROM pragma_table_info('__sqlsync_timelines') WHERE name = 'time'")?
+        .exists([])?;
+    if !has_time {
+        sqlite.execute(TIMELINES_ADD_TIME_SQL, [])?;
+    }
+    sqlite.execute(OUTPUTS_TABLE_SQL, [])?;
+    let has_storage_lsn = sqlite
+        .prepare("SELECT 1 FROM pragma_table_info('__sqlsync_outputs') WHERE name = 'storage_lsn'")?
+        .exists([])?;
+    if !has_storage_lsn {
+        sqlite.execute(OUTPUTS_ADD_STORAGE_LSN_SQL, [])?;
+    }
+    run_meta_migration(sqlite)?;
+    Ok(())
+}
+
+/// bring the document's schema up to the reducer's schema version, fails if
+/// the document has already been migrated by a newer reducer
+pub fn run_reducer_migration<R: Reducer>(sqlite: &mut Connection, reducer: &mut R) -> Result<()> {
+    let supported = reducer.schema_version();
+    run_in_tx(sqlite, |tx| {
+        let stored = match get_meta(tx, SCHEMA_VERSION_KEY)? {
+            Some(version) => version
+                .parse()
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?,
+            None => 0,
+        };
+
+        if stored > supported {
+            return Err(ReducerError::SchemaTooNew { stored, supported }.into());
+        }
+        if stored < supported {
+            log::info!(
+                target: logging::TIMELINE,
+                "migrating schema from version {} to {}",
+                stored,
+                supported
+            );
+            reducer.migrate(tx, stored, supported)?;
+            set_meta(tx, SCHEMA_VERSION_KEY, &supported.to_string())?;
+        }
+        Ok(())
+    })
+}
+
+/// apply a single timeline entry, batches are unpacked, meta mutations are
+/// handled by sqlsync and everything else is passed to the reducer along
+/// with the timeline id and lsn of the entry
+/// returns the output of each mutation in the entry
+fn apply_timeline_entry<R: Reducer>(
+    tx: &mut Transaction,
+    reducer: &mut R,
+    (id, lsn): (JournalId, Lsn),
+    mutation: &[u8],
+    deadline: Option<i64>,
+) -> Result<Vec<ReducerOutput>> {
+    if let Some(mutations) = decode_batch(mutation)? {
+        let mut outputs = Vec::with_capacity(mutations.len());
+        for mutation in mutations {
+            outputs.extend(apply_timeline_entry(
+                tx,
+                reducer,
+                (id, lsn),
+                mutation,
+                deadline,
+            )?);
+        }
+        return Ok(outputs);
+    }
+    match decode_set_meta(mutation)? {
+        Some((key, value)) => {
+            set_meta(tx, key, value)?;
+            Ok(vec![None])
+        }
+        None => {
+            let context = MutationContext {
+                timeline_id: id.bytes().to_vec(),
+                lsn,
+                mutation_time: sqlite_timestamp_milliseconds(),
+            };
+            Ok(vec![
+                reducer.apply_with_context(tx, &context, mutation, deadline)?
+            ])
+        }
+    }
+}
+
+/// collect the mutations in a timeline entry, unpacking batches and checking
+/// that meta mutations are well formed
+fn entry_mutations<'a>(entry: &'a [u8], out: &mut Vec<&'a [u8]>) -> io::Result<()> {
+    if let Some(mutations) = decode_batch(entry)? {
+        for mutation in mutations {
+            entry_mutations(mutation, out)?;
+        }
+        return Ok(());
+    }
+    decode_set_meta(entry)?;
+    out.push(entry);
     Ok(())
 }
 
-pub fn apply_mutation<J: Journal>(
+/// run validator on every mutation in a timeline entry, returning the reason
+/// the entry was rejected if any mutation fails validation. meta mutations
+/// which set a key reserved by sqlsync are always rejected
+fn validate_timeline_entry(
+    sqlite: &Connection,
+    validator: &mut MutationValidator<'_>,
+    (id, lsn): (JournalId, Lsn),
+    time: i64,
+    entry: &[u8],
+) -> io::Result<Option<String>> {
+    let mut mutations = Vec::new();
+    // malformed entries are rejected rather than failing the whole range
+    if let Err(err) = entry_mutations(entry, &mut mutations) {
+        return Ok(Some(err.to_string()));
+    }
+    for mutation in mutations {
+        if let Some((key, _)) = decode_set_meta(mutation)? {
+            if is_reserved_meta_key(key) {
+                return Ok(Some(format!("meta key {} is reserved", key)));
+            }
+        }
+        let context = MutationContext {
+            timeline_id: id.bytes().to_vec(),
+            lsn,
+            mutation_time: time,
+        };
+        if let Err(reason) = validator(sqlite, &context, mutation) {
+            return Ok(Some(reason));
+        }
+    }
+    Ok(None)
+}
+
+/// returns the last lsn from the specified timeline which has been applied
+/// to the database, if any
+pub fn applied_lsn(sqlite: &Connection, id: JournalId) -> rusqlite::Result<Option<Lsn>> {
+    sqlite
+        .query_row(TIMELINES_READ_LSN_SQL, named_params! {":id": id}, |row| {
+            row.get(0)
+        })
+        .optional()
+}
+
+/// returns the time at which the coordinator applied the last entry from the
+/// specified timeline, if any
+pub fn applied_time(sqlite: &Connection, id: JournalId) -> rusqlite::Result<Option<i64>> {
+    Ok(sqlite
+        .query_row(TIMELINES_READ_TIME_SQL, named_params! {":id": id}, |row| {
+            row.get(0)
+        })
+        .optional()?
+        .flatten())
+}
+
+/// returns the root pages of the tables the coordinator uses to track applied
+/// timelines and their outputs, along with their indexes. clients never
+/// change them optimistically
+pub fn bookkeeping_root_pages(sqlite: &Connection) -> rusqlite::Result<Vec<PageIdx>> {
+    let mut stmt = sqlite.prepare_cached(
+        "SELECT rootpage FROM sqlite_master
+        WHERE tbl_name IN ('__sqlsync_timelines', '__sqlsync_outputs') AND rootpage > 0",
+    )?;
+    let rows = stmt.query_map([], |row| row.get(0))?;
+    rows.collect()
+}
+
+/// returns the outputs recorded by the coordinator for the timeline with the
+/// given id, starting at lsn
+pub fn read_outputs(
+    sqlite: &Connection,
+    id: JournalId,
+    lsn: Lsn,
+) -> rusqlite::Result<Vec<MutationOutput>> {
+    let mut stmt = sqlite.prepare(OUTPUTS_READ_SQL)?;
+    let rows = stmt.query_map(named_params! {":id": id, ":lsn": lsn}, |row| {
+        Ok(MutationOutput {
+            lsn: row.get(0)?,
+            index: row.get(1)?,
+            output: row.get(2)?,
+        })
+    })?;
+    rows.collect()
+}
+
+/// delete the outputs recorded for the timeline with the given id in storage
+/// frames up to and including storage_lsn, returning how many were deleted
+pub fn prune_outputs(
+    sqlite: &Connection,
+    id: JournalId,
+    storage_lsn: Lsn,
+) -> rusqlite::Result<usize> {
+    sqlite.execute(
+        OUTPUTS_PRUNE_SQL,
+        named_params! {":id": id, ":storage_lsn": storage_lsn},
+    )
+}
+
+pub fn apply_mutation<J: Journal, R: Reducer>(
     timeline: &mut J,
     sqlite: &mut Connection,
-    reducer: &mut WasmReducer,
+    reducer: &mut R,
     mutation: &[u8],
-) -> Result<()> {
-    run_in_tx(sqlite, |tx| reducer.apply(tx, mutation))?;
+) -> Result<ReducerOutput> {
+    let (id, lsn) = (timeline.id(), timeline.range().next());
+    seed_randomness(sqlite, id, lsn)?;
+    let output = apply_pending_mutation(sqlite, reducer, (id, lsn), mutation)?;
     timeline.append(mutation)?;
-    Ok(())
+    Ok(output)
 }
 
-pub fn rebase_timeline<J: Journal>(
+/// apply a mutation to the database without appending it to the timeline, the
+/// caller is responsible for appending it to timeline id at lsn before the
+/// next rebase and for seeding randomness with that lsn
+pub fn apply_pending_mutation<R: Reducer>(
+    sqlite: &mut Connection,
+    reducer: &mut R,
+    (id, lsn): (JournalId, Lsn),
+    mutation: &[u8],
+) -> Result<ReducerOutput> {
+    let outputs = run_in_tx(sqlite, |tx| {
+        apply_timeline_entry(tx, reducer, (id, lsn), mutation, None)
+    })?;
+    Ok(outputs.into_iter().flatten().last())
+}
+
+/// like apply_pending_mutation, but applies every mutation in a single
+/// transaction. the mutations will be appended to timeline id starting at
+/// lsn, so each is seeded with the lsn it will occupy
+pub fn apply_pending_mutations<R: Reducer>(
+    sqlite: &mut Connection,
+    reducer: &mut R,
+    (id, lsn): (JournalId, Lsn),
+    mutations: &[Vec<u8>],
+) -> Result<Vec<ReducerOutput>> {
+    run_in_tx(sqlite, |tx| {
+        let mut outputs = Vec::with_capacity(mutations.len());
+        for (lsn, mutation) in (lsn..).zip(mutations) {
+            seed_randomness(tx, id, lsn)?;
+            let entry_outputs = apply_timeline_entry(tx, reducer, (id, lsn), mutation, None)?;
+            outputs.push(entry_outputs.into_iter().flatten().last());
+        }
+        Ok(outputs)
+    })
+}
+
+/// drop entries the coordinator has applied from the timeline and reapply
+/// the rest, skipping entries which the coordinator rejected
+pub fn rebase_timeline<J: Journal, R: Reducer>(
     timeline: &mut J,
     sqlite: &mut Connection,
-    reducer: &mut WasmReducer,
+    reducer: &mut R,
+    rejected: &LsnSet,
 ) -> Result<()> {
-    let applied_lsn: Option<Lsn> = sqlite
-        .query_row(
-            TIMELINES_READ_LSN_SQL,
-            named_params! {":id": timeline.id()},
-            |row| row.get(0),
-        )
-        .or_else(|err| match err {
-            rusqlite::Error::QueryReturnedNoRows => Ok(None),
-            err => Err(err),
-        })?;
+    let applied_lsn = applied_lsn(sqlite, timeline.id())?;
 
-    log::info!("rebase timeline ({:?}) to lsn {:?}", timeline, applied_lsn);
+    log::info!(
+        target: logging::TIMELINE,
+        "rebase timeline ({:?}) to lsn {:?}",
+        timeline,
+        applied_lsn
+    );
 
     // remove mutations from the journal that have already been applied
     if let Some(applied_lsn) = applied_lsn {
@@ -87,8 +451,13 @@ pub fn rebase_timeline<J: Journal>(
     run_in_tx(sqlite, |tx| {
         let mut cursor = timeline.scan();
         while cursor.advance()? {
+            let lsn = cursor.lsn().expect("cursor is positioned after advance");
+            if rejected.contains(lsn) {
+                continue;
+            }
             let mutation = cursor.read_all()?;
-            reducer.apply(tx, &mutation)?;
+            seed_randomness(tx, timeline.id(), lsn)?;
+            apply_timeline_entry(tx, reducer, (timeline.id(), lsn), &mutation, None)?;
         }
         Ok::<_, TimelineError>(())
     })?;
@@ -96,63 +465,432 @@ pub fn rebase_timeline<J: Journal>(
     Ok(())
 }
 
+/// Deadline bounds how long apply_timeline_range spends applying a range
+#[derive(Debug, Clone, Copy)]
+pub struct Deadline {
+    /// the time in unix milliseconds after which the reducer stops with
+    /// ReducerError::Timeout, and no further entries are started
+    pub at: i64,
+    /// reject the first entry if it times out, set when the entry had the
+    /// caller's whole budget to itself, and so would never finish in time
+    pub reject_first: bool,
+}
+
+/// AppliedRange reports the outcome of apply_timeline_range
+#[derive(Debug)]
+pub struct AppliedRange {
+    /// entries which were skipped, they still count as applied
+    pub rejections: Vec<Rejection>,
+    /// the suffix of the range which wasn't applied before the deadline
+    pub remaining: LsnRange,
+}
+
+impl Default for AppliedRange {
+    fn default() -> Self {
+        Self {
+            rejections: Vec::new(),
+            remaining: LsnRange::empty(),
+        }
+    }
+}
+
+/// apply range from the timeline to the database, entries which fail
+/// validation or exhaust the reducer's resources are skipped and returned as
+/// rejections. any other error aborts the whole range
+///
+/// if the deadline passes the entries applied so far are kept, and the rest
+/// of the range is returned to be applied later
+///
+/// entries are applied from the lsn after the timeline's applied lsn, so
+/// entries from an aborted range are retried along with the next range
+///
+/// each entry is applied with sqlite's clock fixed to the time it is applied
+/// at, which is recorded alongside the applied lsn and replicates to clients
+/// with the rest of the database. clients can't choose the time their
+/// mutations are applied at
+///
+/// outputs are recorded with storage_lsn, the lsn of the storage frame the
+/// changes will be committed in, see prune_outputs
 pub fn apply_timeline_range<J: Journal, R: Reducer>(
     timeline: &J,
     sqlite: &mut Connection,
     reducer: &mut R,
     range: LsnRange,
-) -> Result<()> {
+    storage_lsn: Lsn,
+    deadline: Option<Deadline>,
+    mut validator: Option<&mut MutationValidator<'_>>,
+) -> Result<AppliedRange> {
     // nothing to apply, optimistically return
     if range.is_empty() {
-        return Ok(());
+        return Ok(AppliedRange::default());
     }
 
     run_in_tx(sqlite, |tx| {
-        // we first need to potentially trim the range if some or all of it has already been applied
-        let range = tx
-            .query_row(
-                TIMELINES_READ_LSN_SQL,
-                named_params! {":id": timeline.id()},
-                |row| row.get(0),
-            )
-            // trim the range to ensure we don't double apply a mutation
-            .map(|applied_lsn: u64| range.trim_prefix(applied_lsn))
-            .or_else(|err| match err {
-                rusqlite::Error::QueryReturnedNoRows => Ok(range),
-                _ => Err(err),
-            })?;
+        // start after the applied lsn, or at the start of the timeline if
+        // nothing has been applied. this skips entries which have already been
+        // applied, and picks up any from an earlier range which were not
+        let last = range.last().expect("range is non-empty");
+        let first = match applied_lsn(tx, timeline.id())? {
+            Some(applied_lsn) => applied_lsn + 1,
+            None => timeline.range().first().unwrap_or(0),
+        };
+        let range = if first <= last {
+            LsnRange::new(first, last)
+        } else {
+            LsnRange::empty()
+        };
 
         if range.is_empty() {
             // nothing to apply, optimistically return
-            Ok(())
-        } else {
-            log::debug!("applying range: {:?}", range);
+            return Ok(AppliedRange::default());
+        }
+        log::debug!(target: logging::TIMELINE, "applying range: {:?}", range);
 
-            // ok, some or all of the provided range needs to be applied so let's do that
-            let mut cursor = timeline.scan_range(range);
-            while cursor.advance()? {
-                let mutation = cursor.read_all()?;
-                reducer.apply(tx, &mutation)?;
+        // ok, some or all of the provided range needs to be applied so let's do that
+        let mut applied = AppliedRange::default();
+        // the last lsn applied or rejected, along with the time it was applied at
+        let mut last_applied: Option<(Lsn, i64)> = None;
+        let mut cursor = timeline.scan_range(range);
+        while cursor.advance()? {
+            let lsn = cursor.lsn().expect("cursor is positioned after advance");
+            let first = last_applied.is_none();
+            let time = unix_timestamp_milliseconds();
+
+            // stop once the deadline passes, the first entry is always started
+            // so that every call makes progress
+            if matches!(deadline, Some(deadline) if !first && time >= deadline.at) {
+                applied.remaining = LsnRange::new(lsn, last);
+                break;
+            }
+
+            // rejected entries are skipped, but still count as applied
+            // so that the client drops them when it rebases
+            let mutation = cursor.read_all()?;
+            if let Some(validator) = validator.as_deref_mut() {
+                let reason =
+                    validate_timeline_entry(tx, validator, (timeline.id(), lsn), time, &mutation)?;
+                if let Some(reason) = reason {
+                    log::info!(
+                        target: logging::TIMELINE,
+                        "rejected lsn {} from timeline {}: {}",
+                        lsn,
+                        timeline.id(),
+                        reason
+                    );
+                    applied
+                        .rejections
+                        .push(Rejection { timeline_id: timeline.id(), lsn, reason });
+                    last_applied = Some((lsn, time));
+                    continue;
+                }
             }
 
+            seed_randomness(tx, timeline.id(), lsn)?;
+            let outputs = run_in_savepoint(tx, |tx| {
+                with_mutation_time(time, || {
+                    let deadline = deadline.map(|deadline| deadline.at);
+                    apply_timeline_entry(tx, reducer, (timeline.id(), lsn), &mutation, deadline)
+                })
+            });
+            let reject_timeout =
+                matches!(deadline, Some(deadline) if first && deadline.reject_first);
+            let outputs = match outputs {
+                Ok(outputs) => outputs,
+                // a timeout keeps the entries applied so far, and leaves the
+                // rest of the range for later
+                Err(TimelineError::ReducerError(ReducerError::Timeout)) if !reject_timeout => {
+                    applied.remaining = LsnRange::new(lsn, last);
+                    break;
+                }
+                // an entry which exhausts the reducer's fuel or memory, or
+                // which can't finish within the caller's whole budget, will do
+                // so every time, so it's rejected rather than wedging the
+                // timeline
+                Err(TimelineError::ReducerError(
+                    err @ (ReducerError::FuelExhausted
+                    | ReducerError::MemoryExhausted
+                    | ReducerError::Timeout),
+                )) => {
+                    log::info!(
+                        target: logging::TIMELINE,
+                        "rejected lsn {} from timeline {}: {}",
+                        lsn,
+                        timeline.id(),
+                        err
+                    );
+                    let reason = err.to_string();
+                    applied
+                        .rejections
+                        .push(Rejection { timeline_id: timeline.id(), lsn, reason });
+                    last_applied = Some((lsn, time));
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
+
+            // record outputs so they can be delivered to the client
+            for (idx, output) in outputs.into_iter().enumerate() {
+                if let Some(output) = output {
+                    tx.execute(
+                        OUTPUTS_INSERT_SQL,
+                        named_params! {
+                            ":id": timeline.id(),
+                            ":lsn": lsn,
+                            ":idx": idx,
+                            ":output": output,
+                            ":storage_lsn": storage_lsn,
+                        },
+                    )?;
+                }
+            }
+            last_applied = Some((lsn, time));
+        }
+
+        if let Some((lsn, time)) = last_applied {
             log::debug!(
-                "updating timeline {} to lsn {:?}",
+                target: logging::TIMELINE,
+                "updating timeline {} to lsn {}",
                 timeline.id(),
-                range.last()
+                lsn
             );
 
-            // if we successfully apply all the above mutations update
-            // the cursor in the db
+            // record how far we got, so the next range picks up from there
             tx.execute(
                 TIMELINES_UPDATE_LSN_SQL,
                 rusqlite::named_params! {
                     ":id": timeline.id(),
-                    ":lsn": &range.last(),
+                    ":lsn": lsn,
+                    ":time": time,
                 },
             )?;
-            Ok(())
         }
+        Ok(applied)
     })
 
     // TODO: once the above tx commits we can GC applied entries in the timeline
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{random::register_deterministic_randomness, MemoryJournal};
+
+    use super::*;
+
+    /// AuthorReducer records the context of every mutation into the authors
+    /// table, the mutation is the name of the row
+    struct AuthorReducer;
+
+    impl Reducer for AuthorReducer {
+        fn apply(
+            &mut self,
+            _: &mut Transaction,
+            _: &[u8],
+        ) -> std::result::Result<ReducerOutput, ReducerError> {
+            unreachable!("mutations are applied with a context")
+        }
+
+        fn apply_with_context(
+            &mut self,
+            tx: &mut Transaction,
+            context: &MutationContext,
+            mutation: &[u8],
+            _: Option<i64>,
+        ) -> std::result::Result<ReducerOutput, ReducerError> {
+            tx.execute(
+                "INSERT INTO authors VALUES (?, ?, ?, ?)",
+                (
+                    mutation,
+                    &context.timeline_id,
+                    context.lsn,
+                    context.mutation_time,
+                ),
+            )?;
+            Ok(None)
+        }
+    }
+
+    /// VersionedReducer only implements migrations, which build a tasks table
+    struct VersionedReducer(u32);
+
+    impl Reducer for VersionedReducer {
+        fn apply(
+            &mut self,
+            _: &mut Transaction,
+            _: &[u8],
+        ) -> std::result::Result<ReducerOutput, ReducerError> {
+            Ok(None)
+        }
+
+        fn schema_version(&self) -> u32 {
+            self.0
+        }
+
+        fn migrate(
+            &mut self,
+            tx: &mut Transaction,
+            from_version: u32,
+            to_version: u32,
+        ) -> std::result::Result<(), ReducerError> {
+            for version in from_version + 1..=to_version {
+                match version {
+                    1 => tx.execute_batch("CREATE TABLE tasks (title TEXT NOT NULL)")?,
+                    2 => tx.execute_batch(
+                        "ALTER TABLE tasks ADD COLUMN done INTEGER NOT NULL DEFAULT 0",
+                    )?,
+                    _ => unreachable!("unknown schema version {}", version),
+                }
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_reducer_migration() -> anyhow::Result<()> {
+        let mut sqlite = Connection::open_in_memory()?;
+        run_timeline_migration(&mut sqlite)?;
+
+        run_reducer_migration(&mut sqlite, &mut VersionedReducer(1))?;
+        sqlite.execute("INSERT INTO tasks (title) VALUES ('write tests')", [])?;
+        assert_eq!(get_meta(&sqlite, SCHEMA_VERSION_KEY)?, Some("1".into()));
+
+        // migrating again at the same version is a noop
+        run_reducer_migration(&mut sqlite, &mut VersionedReducer(1))?;
+
+        // v2 adds a column, preserving existing rows
+        run_reducer_migration(&mut sqlite, &mut VersionedReducer(2))?;
+        let (title, done): (String, bool) =
+            sqlite.query_row("SELECT title, done FROM tasks", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })?;
+        assert_eq!((title.as_str(), done), ("write tests", false));
+        assert_eq!(get_meta(&sqlite, SCHEMA_VERSION_KEY)?, Some("2".into()));
+
+        // an older reducer refuses to open the document
+        let result = run_reducer_migration(&mut sqlite, &mut VersionedReducer(1));
+        assert!(matches!(
+            result,
+            Err(TimelineError::ReducerError(ReducerError::SchemaTooNew {
+                stored: 2,
+                supported: 1
+            }))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_mutation_context() -> anyhow::Result<()> {
+        let open = || {
+            let mut sqlite = Connection::open_in_memory()?;
+            register_deterministic_randomness(&sqlite)?;
+            run_timeline_migration(&mut sqlite)?;
+            sqlite.execute(
+                "CREATE TABLE authors (name BLOB, timeline_id BLOB, lsn INTEGER, time INTEGER)",
+                [],
+            )?;
+            Ok::<_, anyhow::Error>(sqlite)
+        };
+        let read_authors = |sqlite: &Connection| {
+            let mut stmt = sqlite.prepare("SELECT * FROM authors ORDER BY rowid")?;
+            let rows = stmt.query_map([], |row| {
+                Ok((
+                    row.get::<_, Vec<u8>>(0)?,
+                    row.get::<_, JournalId>(1)?,
+                    row.get::<_, Lsn>(2)?,
+                    row.get::<_, i64>(3)?,
+                ))
+            })?;
+            Ok::<_, anyhow::Error>(rows.collect::<rusqlite::Result<Vec<_>>>()?)
+        };
+
+        let id = JournalId::new128(&mut rand::thread_rng());
+        let mut timeline = MemoryJournal::open(id)?;
+        let mut local = open()?;
+        // the client's clock is wildly wrong
+        with_mutation_time(1000, || {
+            apply_mutation(&mut timeline, &mut local, &mut AuthorReducer, b"alice")?;
+            apply_mutation(
+                &mut timeline,
+                &mut local,
+                &mut AuthorReducer,
+                &encode_batch(&[b"bob".to_vec(), b"carol".to_vec()]),
+            )
+        })?;
+
+        // batched mutations share their entry's lsn
+        let expected = vec![
+            (b"alice".to_vec(), id, 0, 1000),
+            (b"bob".to_vec(), id, 1, 1000),
+            (b"carol".to_vec(), id, 1, 1000),
+        ];
+        assert_eq!(read_authors(&local)?, expected);
+
+        // the coordinator sees the same timeline id and lsn, but applies
+        // every entry at its own time, which it records
+        let mut coordinator = open()?;
+        let before = unix_timestamp_milliseconds();
+        apply_timeline_range(
+            &timeline,
+            &mut coordinator,
+            &mut AuthorReducer,
+            timeline.range(),
+            0,
+            None,
+            None,
+        )?;
+        let after = unix_timestamp_milliseconds();
+        let authors = read_authors(&coordinator)?;
+        for ((name, id, lsn, time), (expected_name, expected_id, expected_lsn, _)) in
+            authors.iter().zip(&expected)
+        {
+            assert_eq!((name, id, lsn), (expected_name, expected_id, expected_lsn));
+            assert!((before..=after).contains(time));
+        }
+        assert_eq!(authors[1].3, authors[2].3);
+        assert_eq!(applied_time(&coordinator, id)?, Some(authors[2].3));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reject_nested_batch() -> anyhow::Result<()> {
+        let mut sqlite = Connection::open_in_memory()?;
+        register_deterministic_randomness(&sqlite)?;
+        run_timeline_migration(&mut sqlite)?;
+        sqlite.execute(
+            "CREATE TABLE authors (name BLOB, timeline_id BLOB, lsn INTEGER, time INTEGER)",
+            [],
+        )?;
+
+        let nested = encode_batch(&[b"alice".to_vec(), encode_batch(&[b"bob".to_vec()])]);
+        assert!(decode_batch(&nested).is_err());
+
+        let id = JournalId::new128(&mut rand::thread_rng());
+        let mut timeline = MemoryJournal::open(id)?;
+        timeline.append(nested.as_slice())?;
+        timeline.append(b"carol".as_slice())?;
+
+        // the nested batch is rejected, and the rest of the range applies
+        let mut validator = |_: &Connection, _: &MutationContext, _: &[u8]| Ok(());
+        let rejections = apply_timeline_range(
+            &timeline,
+            &mut sqlite,
+            &mut AuthorReducer,
+            timeline.range(),
+            0,
+            None,
+            Some(&mut validator),
+        )?
+        .rejections;
+        assert_eq!(rejections.len(), 1);
+        assert_eq!(rejections[0].lsn, 0);
+        let names: Vec<Vec<u8>> = sqlite
+            .prepare("SELECT name FROM authors")?
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        assert_eq!(names, vec![b"carol".to_vec()]);
+        assert_eq!(applied_lsn(&sqlite, id)?, Some(1));
+
+        Ok(())
+    }
+}
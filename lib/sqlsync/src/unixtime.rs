@@ -1,3 +1,5 @@
+use std::cell::Cell;
+
 #[cfg(not(target_family = "wasm"))]
 pub fn unix_timestamp_milliseconds() -> i64 {
     std::time::SystemTime::now()
@@ -10,3 +12,30 @@ pub fn unix_timestamp_milliseconds() -> i64 {
 pub fn unix_timestamp_milliseconds() -> i64 {
     js_sys::Date::now() as i64
 }
+
+thread_local! {
+    static MUTATION_TIME: Cell<Option<i64>> = const { Cell::new(None) };
+}
+
+/// the current time as seen by sqlite, which is fixed to the mutation's
+/// timestamp while it is being applied, see with_mutation_time
+pub fn sqlite_timestamp_milliseconds() -> i64 {
+    MUTATION_TIME
+        .with(Cell::get)
+        .unwrap_or_else(unix_timestamp_milliseconds)
+}
+
+/// run f with sqlite's clock fixed at time, so that datetime('now') and
+/// friends return the same value on every replica which applies a mutation
+pub fn with_mutation_time<T>(time: i64, f: impl FnOnce() -> T) -> T {
+    // restore the previous time even if f panics
+    struct Restore(Option<i64>);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            MUTATION_TIME.with(|t| t.set(self.0));
+        }
+    }
+
+    let _restore = Restore(MUTATION_TIME.with(|t| t.replace(Some(time))));
+    f()
+}
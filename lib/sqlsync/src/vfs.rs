@@ -2,9 +2,9 @@ use std::pin::Pin;
 
 use libsqlite3_sys::SQLITE_IOERR;
 use log::{debug, trace};
-use sqlite_vfs::{File, OpenKind, Vfs, VfsResult};
+use sqlite_vfs::{File, LockLevel, OpenKind, Vfs, VfsResult};
 
-use crate::{journal::Journal, storage::Storage, unixtime::unix_timestamp_milliseconds};
+use crate::{journal::Journal, logging, storage::Storage, unixtime::sqlite_timestamp_milliseconds};
 
 pub struct StorageVfs<J: Journal> {
     storage: FilePtr<Storage<J>>,
@@ -25,7 +25,7 @@ impl<J: Journal> Vfs for StorageVfs<J> {
         opts: sqlite_vfs::OpenOptions,
     ) -> VfsResult<Self::File> {
         let path = path.to_str().map_err(|_err| SQLITE_IOERR)?;
-        debug!("open {} {:?}", path, opts);
+        debug!(target: logging::STORAGE, "open {} {:?}", path, opts);
         assert!(
             opts.kind == OpenKind::MainDb,
             "only main.db is supported, got {:?}",
@@ -36,13 +36,13 @@ impl<J: Journal> Vfs for StorageVfs<J> {
 
     fn delete(&mut self, path: &std::ffi::CStr) -> VfsResult<()> {
         let path = path.to_str().map_err(|_err| SQLITE_IOERR)?;
-        debug!("delete {}", path);
+        debug!(target: logging::STORAGE, "delete {}", path);
         Ok(())
     }
 
     fn exists(&mut self, path: &std::ffi::CStr) -> VfsResult<bool> {
         let path = path.to_str().map_err(|_err| SQLITE_IOERR)?;
-        trace!("exists {}", path);
+        trace!(target: logging::STORAGE, "exists {}", path);
         Ok(match path {
             "main.db" => self.storage.file_size().unwrap_or(0) > 0,
             _ => false,
@@ -51,13 +51,13 @@ impl<J: Journal> Vfs for StorageVfs<J> {
 
     /// The xCurrentTime() method returns a Julian Day Number for the current date and time as a floating point value.
     fn current_time(&self) -> f64 {
-        let now = unix_timestamp_milliseconds() as f64;
+        let now = sqlite_timestamp_milliseconds() as f64;
         2440587.5 + now / 864.0e5
     }
 
     /// The xCurrentTime() method returns a Julian Day Number for the current date and time as a floating point value.
     fn current_time_int64(&self) -> i64 {
-        let now = unix_timestamp_milliseconds() as f64;
+        let now = sqlite_timestamp_milliseconds() as f64;
         ((2440587.5 + now / 864.0e5) * 864.0e5) as i64
     }
 }
@@ -108,4 +108,16 @@ impl<T: File> File for FilePtr<T> {
     fn sync(&mut self) -> VfsResult<()> {
         unsafe { (*self.0).sync() }
     }
+
+    fn lock(&mut self, level: LockLevel) -> VfsResult<bool> {
+        unsafe { (*self.0).lock(level) }
+    }
+
+    fn unlock(&mut self, level: LockLevel) -> VfsResult<()> {
+        unsafe { (*self.0).unlock(level) }
+    }
+
+    fn reserved(&self) -> VfsResult<bool> {
+        unsafe { (*self.0).reserved() }
+    }
 }